@@ -6,6 +6,7 @@ use std::{
   collections::{HashMap, HashSet},
   fs, io,
   path::{Path, PathBuf},
+  time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
@@ -23,8 +24,16 @@ pub enum ConfigError {
   #[error("cannot parse configuration: {err}")]
   CannotParseConfig { err: String },
 
+  #[error("cannot serialize configuration: {err}")]
+  CannotSerializeConfig { err: String },
+
   #[error("missing configuration option: {opt}")]
   MissingOption { opt: String },
+
+  #[error(
+    "invalid face prefix {prefix:?}: only ASCII letters, digits and underscores are allowed"
+  )]
+  InvalidFacePrefix { prefix: String },
 }
 
 impl ConfigError {
@@ -40,6 +49,15 @@ impl ConfigError {
 pub struct Config {
   pub highlight: HighlightConfig,
 
+  #[serde(default)]
+  pub fallback: FallbackConfig,
+
+  #[serde(default)]
+  pub server: ServerConfig,
+
+  #[serde(default)]
+  pub indent: IndentConfig,
+
   #[serde(flatten)]
   pub languages: LanguagesConfig,
 }
@@ -58,6 +76,16 @@ impl Config {
     })
   }
 
+  /// Serialize the configuration to TOML, as it would be written in a configuration file.
+  pub fn to_toml(&self) -> Result<String, ConfigError> {
+    // going through a value sorts tables (e.g. languages) by name, instead of the arbitrary order of their maps
+    toml::Value::try_from(self)
+      .and_then(|value| toml::to_string_pretty(&value))
+      .map_err(|err| ConfigError::CannotSerializeConfig {
+        err: err.to_string(),
+      })
+  }
+
   /// Default configuration using the `default-config.toml` file.
   const DEFAULT_CONFIG_CONTENT: &'static str = include_str!("../../default-config.toml");
 
@@ -88,7 +116,19 @@ impl Config {
   /// Merge the config with a user-provided one.
   pub fn merge_user_config(&mut self, user_config: UserConfig) -> Result<(), ConfigError> {
     if let Some(user_highlight) = user_config.highlight {
-      self.highlight.merge_user_config(user_highlight);
+      self.highlight.merge_user_config(user_highlight)?;
+    }
+
+    if let Some(user_fallback) = user_config.fallback {
+      self.fallback.merge_user_config(user_fallback);
+    }
+
+    if let Some(user_server) = user_config.server {
+      self.server.merge_user_config(user_server);
+    }
+
+    if let Some(user_indent) = user_config.indent {
+      self.indent.merge_user_config(user_indent);
     }
 
     if let Some(languages) = user_config.languages {
@@ -105,11 +145,277 @@ impl Config {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct HighlightConfig {
   pub groups: HashSet<String>,
+
+  /// Capture groups to never highlight.
+  ///
+  /// Disabling a group also disables its subgroups; e.g. disabling `punctuation` disables `punctuation.delimiter`.
+  #[serde(default)]
+  pub disabled: HashSet<String>,
+
+  /// Whether to highlight anonymous nodes, such as keywords, operators and punctuation.
+  ///
+  /// Languages can override it (see [`LanguageConfig::highlight_anonymous_nodes`]).
+  pub anonymous_nodes: bool,
+
+  /// Size, in bytes, past which buffers are not highlighted, so that huge (e.g. generated) files do not stall the server.
+  pub max_buffer_bytes: u64,
+
+  /// Priorities of capture groups, deciding which face wins where captures are nested; see
+  /// [`HighlightConfig::priority`].
+  #[serde(default)]
+  pub priorities: HashMap<String, u32>,
+
+  /// Priority of the capture groups not listed in [`HighlightConfig::priorities`].
+  pub default_priority: u32,
+
+  /// What to do with a highlight request older than the last highlighted one of its buffer.
+  pub on_stale: OnStale,
+
+  /// Prefix of the Kakoune faces highlights are emitted with; e.g. `ts_` for `ts_keyword_control`.
+  ///
+  /// Faces of different prefixes do not collide, so tree-sitter highlighting can be themed independently of Kakoune’s
+  /// own faces.
+  pub face_prefix: String,
+}
+
+/// What to do with a stale highlight request; i.e. about an older timestamp than the last highlighted one of its
+/// buffer.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnStale {
+  /// Ignore the request; the most recent highlights stay.
+  #[default]
+  Drop,
+
+  /// Ask Kakoune for the current content of the buffer, and highlight it from scratch.
+  Reparse,
 }
 
 impl HighlightConfig {
-  fn merge_user_config(&mut self, user_config: UserHighlightConfig) {
+  fn merge_user_config(&mut self, user_config: UserHighlightConfig) -> Result<(), ConfigError> {
     self.groups.extend(user_config.groups);
+    self.disabled.extend(user_config.disabled);
+
+    if let Some(anonymous_nodes) = user_config.anonymous_nodes {
+      self.anonymous_nodes = anonymous_nodes;
+    }
+
+    if let Some(max_buffer_bytes) = user_config.max_buffer_bytes {
+      self.max_buffer_bytes = max_buffer_bytes;
+    }
+
+    self.priorities.extend(user_config.priorities);
+
+    if let Some(default_priority) = user_config.default_priority {
+      self.default_priority = default_priority;
+    }
+
+    if let Some(on_stale) = user_config.on_stale {
+      self.on_stale = on_stale;
+    }
+
+    if let Some(face_prefix) = user_config.face_prefix {
+      let valid = !face_prefix.is_empty()
+        && face_prefix
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '_');
+      if !valid {
+        return Err(ConfigError::InvalidFacePrefix {
+          prefix: face_prefix,
+        });
+      }
+
+      self.face_prefix = face_prefix;
+    }
+
+    Ok(())
+  }
+
+  /// Whether a capture group is disabled, either directly or via one of its parent groups.
+  pub fn is_disabled(&self, group: &str) -> bool {
+    self
+      .disabled
+      .iter()
+      .any(|disabled| is_same_or_subgroup(group, disabled))
+  }
+
+  /// Priority of a capture group.
+  ///
+  /// A group without a priority of its own gets the one of its most specific parent group; e.g. `comment.line` gets
+  /// the priority of `comment`. Groups without any get [`HighlightConfig::default_priority`].
+  pub fn priority(&self, group: &str) -> u32 {
+    self
+      .priorities
+      .iter()
+      .filter(|(parent, _)| is_same_or_subgroup(group, parent))
+      .max_by_key(|(parent, _)| parent.len())
+      .map_or(self.default_priority, |(_, priority)| *priority)
+  }
+
+  /// Capture groups to highlight; i.e. groups that are not disabled.
+  pub fn enabled_groups(&self) -> impl Iterator<Item = &str> {
+    self
+      .groups
+      .iter()
+      .map(String::as_str)
+      .filter(|group| !self.is_disabled(group))
+  }
+}
+
+/// Whether `group` is `parent`, or one of its subgroups (e.g. `punctuation.bracket` for `punctuation`).
+fn is_same_or_subgroup(group: &str, parent: &str) -> bool {
+  group
+    .strip_prefix(parent)
+    .map_or(false, |rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Server configuration.
+///
+/// Limits applied to the requests sent to the UNIX socket, so that a misbehaving client cannot tie up the server, to the
+/// files the output of a daemonized server is written to and to the size of some responses, and how Kakoune is reached.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ServerConfig {
+  /// Maximum size of a request, in bytes.
+  pub max_request_size: usize,
+
+  /// Maximum time to wait for a connected client to send its request, in milliseconds; 0 means forever.
+  pub request_timeout_ms: u64,
+
+  /// Size, in bytes, past which the standard output and error files of a daemonized server are rotated.
+  pub max_log_size: u64,
+
+  /// `kak` binary used to list sessions and send commands to them; overridden by the `KAK_BINARY` environment variable.
+  pub kak_binary: PathBuf,
+
+  /// Maximum number of trees kept per session; the least recently used ones are evicted past it. Unlimited if not set.
+  pub max_trees: Option<usize>,
+
+  /// Maximum number of selections returned by a single request; e.g. when selecting all the nodes matching a query.
+  pub max_selections: usize,
+
+  /// Time without requests after which a server without sessions shuts down, in milliseconds; 0 means never.
+  pub idle_timeout_ms: u64,
+
+  /// Size, in bytes, past which the commands sent to a session are written to a file it sources instead.
+  pub max_inline_response_bytes: usize,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self {
+      max_request_size: 64 * 1024,
+      request_timeout_ms: 1000,
+      max_log_size: 10 * 1024 * 1024,
+      kak_binary: PathBuf::from("kak"),
+      max_trees: None,
+      max_selections: 1000,
+      idle_timeout_ms: 0,
+      max_inline_response_bytes: 256 * 1024,
+    }
+  }
+}
+
+impl ServerConfig {
+  fn merge_user_config(&mut self, user_config: UserServerConfig) {
+    if let Some(max_request_size) = user_config.max_request_size {
+      self.max_request_size = max_request_size;
+    }
+
+    if let Some(request_timeout_ms) = user_config.request_timeout_ms {
+      self.request_timeout_ms = request_timeout_ms;
+    }
+
+    if let Some(max_log_size) = user_config.max_log_size {
+      self.max_log_size = max_log_size;
+    }
+
+    if let Some(kak_binary) = user_config.kak_binary {
+      self.kak_binary = kak_binary;
+    }
+
+    if let Some(max_trees) = user_config.max_trees {
+      self.max_trees = Some(max_trees);
+    }
+
+    if let Some(max_selections) = user_config.max_selections {
+      self.max_selections = max_selections;
+    }
+
+    if let Some(idle_timeout_ms) = user_config.idle_timeout_ms {
+      self.idle_timeout_ms = idle_timeout_ms;
+    }
+
+    if let Some(max_inline_response_bytes) = user_config.max_inline_response_bytes {
+      self.max_inline_response_bytes = max_inline_response_bytes;
+    }
+  }
+
+  /// Maximum time to wait for a connected client to send its request, if any.
+  pub fn request_timeout(&self) -> Option<Duration> {
+    (self.request_timeout_ms > 0).then(|| Duration::from_millis(self.request_timeout_ms))
+  }
+
+  /// Time without requests after which a server without sessions shuts down, if it ever does.
+  pub fn idle_timeout(&self) -> Option<Duration> {
+    (self.idle_timeout_ms > 0).then(|| Duration::from_millis(self.idle_timeout_ms))
+  }
+}
+
+/// Fallback languages configuration.
+///
+/// Fallback languages are used for buffers whose language is not supported. They are opt-in: without configuration,
+/// unsupported buffers are left alone.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FallbackConfig {
+  /// Language to use for any unsupported buffer, if any.
+  pub default_language: Option<String>,
+
+  /// Languages to use for unsupported buffers, by file extension; those take precedence over `default_language`.
+  #[serde(default)]
+  pub extensions: HashMap<String, String>,
+}
+
+impl FallbackConfig {
+  fn merge_user_config(&mut self, user_config: UserFallbackConfig) {
+    if let Some(default_language) = user_config.default_language {
+      self.default_language = Some(default_language);
+    }
+
+    self.extensions.extend(user_config.extensions);
+  }
+
+  /// Get the fallback language for a buffer, if any.
+  pub fn language_for(&self, buffer: impl AsRef<Path>) -> Option<&str> {
+    buffer
+      .as_ref()
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .and_then(|ext| self.extensions.get(ext))
+      .or(self.default_language.as_ref())
+      .map(String::as_str)
+  }
+}
+
+/// Indentation unit, used to turn indent levels into actual indentation.
+///
+/// Options left unset fall back to the indentation settings of the buffer (e.g. `%opt{indentwidth}` in Kakoune).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndentConfig {
+  /// Width of an indent level, in columns; `0` means one tab per level, as with `%opt{indentwidth}`.
+  pub width: Option<usize>,
+
+  /// Whether to indent with tabs instead of spaces.
+  pub use_tabs: Option<bool>,
+}
+
+impl IndentConfig {
+  fn merge_user_config(&mut self, user_config: UserIndentConfig) {
+    if let Some(width) = user_config.width {
+      self.width = Some(width);
+    }
+    if let Some(use_tabs) = user_config.use_tabs {
+      self.use_tabs = Some(use_tabs);
+    }
   }
 }
 
@@ -119,10 +425,33 @@ impl HighlightConfig {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct LanguagesConfig {
   pub language: HashMap<String, LanguageConfig>,
+
+  /// Directories searched for the queries of every language before the queries directory of the language itself.
+  ///
+  /// Each directory contains a directory per language; e.g. `<dir>/rust/highlights.scm`.
+  #[serde(default)]
+  pub queries_dirs: Vec<PathBuf>,
+
+  /// Other names of languages, mapped to the language they stand for; e.g. `js = "javascript"`.
+  ///
+  /// Aliases are matched case-insensitively, as language names are.
+  #[serde(default)]
+  pub aliases: HashMap<String, String>,
 }
 
 impl LanguagesConfig {
   fn merge_user_config(&mut self, user_config: UserLanguagesConfig) -> Result<(), ConfigError> {
+    if let Some(queries_dirs) = user_config.queries_dirs {
+      self.queries_dirs = queries_dirs;
+    }
+
+    self.aliases.extend(
+      user_config
+        .aliases
+        .into_iter()
+        .map(|(alias, lang)| (alias.to_lowercase(), lang)),
+    );
+
     for (lang, user_config) in user_config.language {
       if let Some(config) = self.language.get_mut(&lang) {
         // if we already have a config, everything is optional so we can merge
@@ -171,6 +500,24 @@ impl LanguagesConfig {
       }
     }
   }
+
+  /// Get the directories to search the queries of a given language in, in order.
+  ///
+  /// [`LanguagesConfig::queries_dirs`] come first, so that they override the queries of the language. Unknown
+  /// languages have no directory.
+  pub fn get_queries_dirs(&self, lang: impl AsRef<str>) -> Vec<PathBuf> {
+    let lang = lang.as_ref();
+    let Some(lang_config) = self.get_lang_conf(lang) else {
+      return Vec::new();
+    };
+
+    self
+      .queries_dirs
+      .iter()
+      .map(|dir| dir.join(lang))
+      .chain(Self::get_queries_dir(lang_config, lang))
+      .collect()
+  }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
@@ -204,9 +551,44 @@ pub struct LanguageConfig {
 
   #[serde(default)]
   pub remove_default_highlighter: RemoveDefaultHighlighter,
+
+  #[serde(default)]
+  pub comment: CommentConfig,
+
+  /// Whether to highlight anonymous nodes; if unset, [`HighlightConfig::anonymous_nodes`] applies.
+  #[serde(default)]
+  pub highlight_anonymous_nodes: Option<bool>,
+
+  /// Width of an indent level; if unset, [`IndentConfig::width`] applies.
+  #[serde(default)]
+  pub indent_width: Option<usize>,
+
+  /// Whether to indent with tabs; if unset, [`IndentConfig::use_tabs`] applies.
+  #[serde(default)]
+  pub use_tabs: Option<bool>,
+
+  /// Whether indentation is part of the syntax of the language (e.g. Python, YAML), in which case tabs and spaces are
+  /// never mixed.
+  #[serde(default)]
+  pub significant_indent: bool,
 }
 
 impl LanguageConfig {
+  /// Whether to highlight anonymous nodes for this language, falling back to the global highlight configuration.
+  pub fn highlights_anonymous_nodes(&self, highlight: &HighlightConfig) -> bool {
+    self
+      .highlight_anonymous_nodes
+      .unwrap_or(highlight.anonymous_nodes)
+  }
+
+  /// Indentation unit of this language, falling back to the global indent configuration.
+  pub fn indent_unit(&self, indent: &IndentConfig) -> IndentConfig {
+    IndentConfig {
+      width: self.indent_width.or(indent.width),
+      use_tabs: self.use_tabs.or(indent.use_tabs),
+    }
+  }
+
   fn merge_user_config(&mut self, user_config: UserLanguageConfig) {
     if let Some(user_grammar) = user_config.grammar {
       self.grammar.merge_user_config(user_grammar);
@@ -219,6 +601,24 @@ impl LanguageConfig {
       .remove_default_highlighter
       .unwrap_or(self.remove_default_highlighter.0)
       .into();
+
+    if let Some(user_comment) = user_config.comment {
+      self.comment.merge_user_config(user_comment);
+    }
+
+    if let Some(highlight_anonymous_nodes) = user_config.highlight_anonymous_nodes {
+      self.highlight_anonymous_nodes = Some(highlight_anonymous_nodes);
+    }
+
+    if let Some(indent_width) = user_config.indent_width {
+      self.indent_width = Some(indent_width);
+    }
+    if let Some(use_tabs) = user_config.use_tabs {
+      self.use_tabs = Some(use_tabs);
+    }
+    if let Some(significant_indent) = user_config.significant_indent {
+      self.significant_indent = significant_indent;
+    }
   }
 }
 
@@ -240,10 +640,43 @@ impl TryFrom<UserLanguageConfig> for LanguageConfig {
         .remove_default_highlighter
         .unwrap_or(true)
         .into(),
+      comment: user_config.comment.unwrap_or_default(),
+      highlight_anonymous_nodes: user_config.highlight_anonymous_nodes,
+      indent_width: user_config.indent_width,
+      use_tabs: user_config.use_tabs,
+      significant_indent: user_config.significant_indent.unwrap_or_default(),
     })
   }
 }
 
+/// Comment tokens of a language, used to comment and uncomment code.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CommentConfig {
+  /// Token starting a line comment; e.g. `//`.
+  pub line: Option<String>,
+
+  /// Tokens delimiting a block comment; e.g. `/*` and `*/`.
+  pub block: Option<BlockCommentConfig>,
+}
+
+impl CommentConfig {
+  fn merge_user_config(&mut self, user_config: CommentConfig) {
+    if let Some(line) = user_config.line {
+      self.line = Some(line);
+    }
+    if let Some(block) = user_config.block {
+      self.block = Some(block);
+    }
+  }
+}
+
+/// Delimiters of a block comment.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BlockCommentConfig {
+  pub start: String,
+  pub end: String,
+}
+
 /// Grammar configuration.
 ///
 /// Most of the options are used by the controller only.
@@ -280,9 +713,25 @@ pub struct LanguageGrammarConfig {
   ///
   /// Should be used to pass optimization and debug flags, mainly.
   pub link_flags: Vec<String>,
+
+  /// Name of the function exported by the grammar to get its language.
+  ///
+  /// If unset, the conventional `tree_sitter_<lang>` is used — see [`LanguageGrammarConfig::symbol`].
+  #[serde(default)]
+  pub symbol: Option<String>,
 }
 
 impl LanguageGrammarConfig {
+  /// Name of the function exported by the grammar of `lang` to get its language.
+  ///
+  /// Dots and dashes are not valid in C identifiers, so they are replaced with underscores in the conventional name.
+  pub fn symbol(&self, lang: &str) -> String {
+    self
+      .symbol
+      .clone()
+      .unwrap_or_else(|| format!("tree_sitter_{}", lang.replace(['.', '-'], "_")))
+  }
+
   fn merge_user_config(&mut self, user_config: UserLanguageGrammarConfig) {
     if let Some(source) = user_config.source {
       self.source = source;
@@ -315,6 +764,10 @@ impl LanguageGrammarConfig {
     if let Some(link_flags) = user_config.link_flags {
       self.link_flags = link_flags;
     }
+
+    if let Some(symbol) = user_config.symbol {
+      self.symbol = Some(symbol);
+    }
   }
 }
 
@@ -351,6 +804,7 @@ impl TryFrom<UserLanguageGrammarConfig> for LanguageGrammarConfig {
       link_flags: user_config
         .link_flags
         .unwrap_or_else(|| vec!["-O3".to_owned()]),
+      symbol: user_config.symbol,
     })
   }
 }
@@ -365,6 +819,12 @@ pub struct LanguageQueriesConfig {
 
   /// Path to go to where to find the queries directory.
   pub path: PathBuf,
+
+  /// Additional highlights query files, compiled along with the highlights query of the language.
+  ///
+  /// Their captures take precedence over the ones of the highlights query on the same node.
+  #[serde(default)]
+  pub extra_highlights: Vec<PathBuf>,
 }
 
 impl LanguageQueriesConfig {
@@ -372,6 +832,10 @@ impl LanguageQueriesConfig {
     if let Some(source) = user_config.source {
       self.source = Some(source);
     }
+
+    if let Some(extra_highlights) = user_config.extra_highlights {
+      self.extra_highlights = extra_highlights;
+    }
   }
 }
 
@@ -386,6 +850,7 @@ impl TryFrom<UserLanguageQueriesConfig> for LanguageQueriesConfig {
     Ok(Self {
       source: user_config.source,
       path,
+      extra_highlights: user_config.extra_highlights.unwrap_or_default(),
     })
   }
 }
@@ -394,6 +859,9 @@ impl TryFrom<UserLanguageQueriesConfig> for LanguageQueriesConfig {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserConfig {
   pub highlight: Option<UserHighlightConfig>,
+  pub fallback: Option<UserFallbackConfig>,
+  pub server: Option<UserServerConfig>,
+  pub indent: Option<UserIndentConfig>,
   #[serde(flatten)]
   pub languages: Option<UserLanguagesConfig>,
 }
@@ -427,12 +895,51 @@ impl UserConfig {
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserHighlightConfig {
+  #[serde(default)]
   pub groups: HashSet<String>,
+  #[serde(default)]
+  pub disabled: HashSet<String>,
+  pub anonymous_nodes: Option<bool>,
+  pub max_buffer_bytes: Option<u64>,
+  #[serde(default)]
+  pub priorities: HashMap<String, u32>,
+  pub default_priority: Option<u32>,
+  pub on_stale: Option<OnStale>,
+  pub face_prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserFallbackConfig {
+  pub default_language: Option<String>,
+  #[serde(default)]
+  pub extensions: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserServerConfig {
+  pub max_request_size: Option<usize>,
+  pub request_timeout_ms: Option<u64>,
+  pub max_log_size: Option<u64>,
+  pub kak_binary: Option<PathBuf>,
+  pub max_trees: Option<usize>,
+  pub max_selections: Option<usize>,
+  pub idle_timeout_ms: Option<u64>,
+  pub max_inline_response_bytes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserIndentConfig {
+  pub width: Option<usize>,
+  pub use_tabs: Option<bool>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserLanguagesConfig {
+  #[serde(default)]
   pub language: HashMap<String, UserLanguageConfig>,
+  pub queries_dirs: Option<Vec<PathBuf>>,
+  #[serde(default)]
+  pub aliases: HashMap<String, String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -440,6 +947,11 @@ pub struct UserLanguageConfig {
   pub grammar: Option<UserLanguageGrammarConfig>,
   pub queries: Option<UserLanguageQueriesConfig>,
   pub remove_default_highlighter: Option<bool>,
+  pub comment: Option<CommentConfig>,
+  pub highlight_anonymous_nodes: Option<bool>,
+  pub indent_width: Option<usize>,
+  pub use_tabs: Option<bool>,
+  pub significant_indent: Option<bool>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -452,22 +964,29 @@ pub struct UserLanguageGrammarConfig {
   pub link: Option<String>,
   pub link_args: Option<Vec<String>>,
   pub link_flags: Option<Vec<String>>,
+  pub symbol: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserLanguageQueriesConfig {
   pub source: Option<Source>,
   pub path: Option<PathBuf>,
+  pub extra_highlights: Option<Vec<PathBuf>>,
 }
 
 #[cfg(test)]
 mod tests {
-  use std::path::PathBuf;
+  use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+  };
 
   use crate::{
-    source::Source, Config, HighlightConfig, LanguageConfig, LanguageGrammarConfig,
-    LanguageQueriesConfig, LanguagesConfig, UserConfig, UserLanguageConfig,
-    UserLanguageGrammarConfig, UserLanguagesConfig,
+    source::Source, BlockCommentConfig, CommentConfig, Config, ConfigError, FallbackConfig,
+    HighlightConfig, IndentConfig, LanguageConfig, LanguageGrammarConfig, LanguageQueriesConfig,
+    LanguagesConfig, OnStale, ServerConfig, UserConfig, UserFallbackConfig, UserHighlightConfig,
+    UserIndentConfig, UserLanguageConfig, UserLanguageGrammarConfig, UserLanguageQueriesConfig,
+    UserLanguagesConfig,
   };
 
   #[test]
@@ -478,6 +997,19 @@ mod tests {
         groups: ["foo".to_owned(), "bar".to_owned(), "zoo".to_owned()]
           .into_iter()
           .collect(),
+        disabled: HashSet::new(),
+        anonymous_nodes: true,
+        max_buffer_bytes: 1024,
+        priorities: HashMap::new(),
+        default_priority: 100,
+        on_stale: OnStale::Drop,
+        face_prefix: "ts_".to_owned(),
+      },
+      fallback: FallbackConfig::default(),
+      server: ServerConfig::default(),
+      indent: IndentConfig {
+        width: Some(8),
+        use_tabs: None,
       },
       languages: LanguagesConfig {
         language: [(
@@ -492,16 +1024,28 @@ mod tests {
               link: "".to_owned(),
               link_args: Vec::default(),
               link_flags: Vec::default(),
+              symbol: None,
             },
             queries: LanguageQueriesConfig {
               source: None,
               path: PathBuf::from("runtime/queries/rust"),
+              extra_highlights: Vec::new(),
             },
             remove_default_highlighter: true.into(),
+            comment: CommentConfig {
+              line: Some("//".to_owned()),
+              block: None,
+            },
+            highlight_anonymous_nodes: None,
+            indent_width: None,
+            use_tabs: None,
+            significant_indent: false,
           },
         )]
         .into_iter()
         .collect(),
+        queries_dirs: Vec::new(),
+        aliases: [("rs".to_owned(), "rust".to_owned())].into_iter().collect(),
       },
     };
 
@@ -518,6 +1062,12 @@ mod tests {
       let mut config = main_config.clone();
       let user_config = UserConfig {
         highlight: None,
+        fallback: None,
+        server: None,
+        indent: Some(UserIndentConfig {
+          width: None,
+          use_tabs: Some(true),
+        }),
         languages: Some(UserLanguagesConfig {
           language: [(
             "rust".to_owned(),
@@ -527,19 +1077,52 @@ mod tests {
                 link_args: Some(vec!["link".to_owned(), "args".to_owned()]),
                 ..Default::default()
               }),
+              comment: Some(CommentConfig {
+                line: None,
+                block: Some(BlockCommentConfig {
+                  start: "/*".to_owned(),
+                  end: "*/".to_owned(),
+                }),
+              }),
+              queries: Some(UserLanguageQueriesConfig {
+                extra_highlights: Some(vec![PathBuf::from("/tmp/rust-extra.scm")]),
+                ..Default::default()
+              }),
+              highlight_anonymous_nodes: Some(false),
+              indent_width: Some(2),
               ..Default::default()
             },
           )]
           .into_iter()
           .collect(),
+          queries_dirs: Some(vec![PathBuf::from("/tmp/queries")]),
+          aliases: [("RustLang".to_owned(), "rust".to_owned())]
+            .into_iter()
+            .collect(),
         }),
       };
       assert!(config.merge_user_config(user_config).is_ok());
 
+      // user aliases are added to the default ones
+      assert_eq!(config.languages.aliases["rs"], "rust");
+      assert_eq!(config.languages.aliases["rustlang"], "rust");
+
+      // user queries directories come first
+      assert_eq!(
+        config.languages.get_queries_dirs("rust").first(),
+        Some(&PathBuf::from("/tmp/queries/rust"))
+      );
+      assert!(config.languages.get_queries_dirs("nope").is_empty());
+
       let prev_rust_config = main_config.languages.get_lang_conf("rust").unwrap();
       let new_rust_config = config.languages.get_lang_conf("rust").unwrap();
 
-      assert_eq!(prev_rust_config.queries, new_rust_config.queries);
+      // only the extra highlights were set
+      assert_eq!(prev_rust_config.queries.path, new_rust_config.queries.path);
+      assert_eq!(
+        new_rust_config.queries.extra_highlights,
+        [PathBuf::from("/tmp/rust-extra.scm")]
+      );
 
       assert_eq!(
         new_rust_config.grammar.source,
@@ -553,6 +1136,169 @@ mod tests {
         new_rust_config.grammar.link_args,
         vec!["link".to_owned(), "args".to_owned()]
       );
+
+      // only the block comment tokens were set
+      assert_eq!(new_rust_config.comment.line.as_deref(), Some("//"));
+      assert_eq!(
+        new_rust_config
+          .comment
+          .block
+          .as_ref()
+          .map(|block| block.end.as_str()),
+        Some("*/")
+      );
+
+      // the language overrides the global option
+      assert!(prev_rust_config.highlights_anonymous_nodes(&main_config.highlight));
+      assert!(!new_rust_config.highlights_anonymous_nodes(&config.highlight));
+
+      // the language overrides the global indent width, and inherits the rest
+      assert_eq!(
+        prev_rust_config.indent_unit(&main_config.indent),
+        IndentConfig {
+          width: Some(8),
+          use_tabs: None
+        }
+      );
+      assert_eq!(
+        new_rust_config.indent_unit(&config.indent),
+        IndentConfig {
+          width: Some(2),
+          use_tabs: Some(true)
+        }
+      );
     }
   }
+
+  #[test]
+  fn disabled_highlight_groups() {
+    let mut config = HighlightConfig {
+      groups: [
+        "punctuation",
+        "punctuation.delimiter",
+        "punctuationish",
+        "keyword",
+      ]
+      .into_iter()
+      .map(str::to_owned)
+      .collect(),
+      disabled: HashSet::new(),
+      anonymous_nodes: true,
+      max_buffer_bytes: 1024,
+      priorities: HashMap::new(),
+      default_priority: 100,
+      on_stale: OnStale::Drop,
+      face_prefix: "ts_".to_owned(),
+    };
+    config
+      .merge_user_config(UserHighlightConfig {
+        disabled: ["punctuation".to_owned()].into_iter().collect(),
+        ..Default::default()
+      })
+      .unwrap();
+
+    assert!(config.is_disabled("punctuation"));
+    assert!(config.is_disabled("punctuation.delimiter"));
+    assert!(config.is_disabled("punctuation.bracket.round"));
+    assert!(!config.is_disabled("punctuationish"));
+
+    let mut enabled: Vec<_> = config.enabled_groups().collect();
+    enabled.sort_unstable();
+    assert_eq!(enabled, ["keyword", "punctuationish"]);
+  }
+
+  #[test]
+  fn highlight_priorities() {
+    let mut config = HighlightConfig {
+      groups: HashSet::new(),
+      disabled: HashSet::new(),
+      anonymous_nodes: true,
+      max_buffer_bytes: 1024,
+      priorities: [("comment".to_owned(), 110)].into_iter().collect(),
+      default_priority: 100,
+      on_stale: OnStale::Drop,
+      face_prefix: "ts_".to_owned(),
+    };
+    config
+      .merge_user_config(UserHighlightConfig {
+        priorities: [("comment.documentation".to_owned(), 90)]
+          .into_iter()
+          .collect(),
+        default_priority: Some(50),
+        ..Default::default()
+      })
+      .unwrap();
+
+    // the most specific group wins
+    assert_eq!(config.priority("comment"), 110);
+    assert_eq!(config.priority("comment.line"), 110);
+    assert_eq!(config.priority("comment.documentation"), 90);
+    assert_eq!(config.priority("comment.documentation.block"), 90);
+    assert_eq!(config.priority("commentary"), 50);
+    assert_eq!(config.priority("keyword"), 50);
+  }
+
+  #[test]
+  fn face_prefix() {
+    let mut config = Config::load_default_config().unwrap();
+    assert_eq!(config.highlight.face_prefix, "ts_");
+
+    for prefix in ["", "ts-", "my ts_"] {
+      let user_config = UserHighlightConfig {
+        face_prefix: Some(prefix.to_owned()),
+        ..Default::default()
+      };
+      assert!(matches!(
+        config.highlight.merge_user_config(user_config),
+        Err(ConfigError::InvalidFacePrefix { .. })
+      ));
+    }
+
+    let user_config = UserHighlightConfig {
+      face_prefix: Some("tree_sitter_".to_owned()),
+      ..Default::default()
+    };
+    config.highlight.merge_user_config(user_config).unwrap();
+    assert_eq!(config.highlight.face_prefix, "tree_sitter_");
+  }
+
+  #[test]
+  fn fallback() {
+    let mut config = FallbackConfig::default();
+    assert_eq!(config.language_for("/tmp/foo.conf"), None);
+
+    config.merge_user_config(UserFallbackConfig {
+      default_language: Some("bash".to_owned()),
+      extensions: [("conf".to_owned(), "ini".to_owned())]
+        .into_iter()
+        .collect(),
+    });
+    assert_eq!(config.language_for("/tmp/foo.conf"), Some("ini"));
+    assert_eq!(config.language_for("/tmp/foo.rc"), Some("bash"));
+    assert_eq!(config.language_for("*debug*"), Some("bash"));
+  }
+
+  #[test]
+  fn toml_roundtrip() {
+    let config = Config::load_default_config().unwrap();
+    let toml = config.to_toml().unwrap();
+    assert_eq!(toml::from_str::<Config>(&toml).unwrap(), config);
+  }
+
+  #[test]
+  fn grammar_symbol() {
+    let mut config = LanguageGrammarConfig::try_from(UserLanguageGrammarConfig {
+      source: Some(Source::local("/tmp/grammars")),
+      link_args: Some(Vec::new()),
+      ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(config.symbol("c-sharp"), "tree_sitter_c_sharp");
+
+    config.merge_user_config(UserLanguageGrammarConfig {
+      symbol: Some("tree_sitter_cpp".to_owned()),
+      ..Default::default()
+    });
+    assert_eq!(config.symbol("c++"), "tree_sitter_cpp");
+  }
 }
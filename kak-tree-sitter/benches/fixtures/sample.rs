@@ -0,0 +1,98 @@
+//! A small, representative Rust module used as a benchmark fixture.
+
+use std::{collections::HashMap, fmt};
+
+/// A key-value store with a bounded capacity.
+#[derive(Debug, Default)]
+pub struct Store<V> {
+  entries: HashMap<String, V>,
+  capacity: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum StoreError {
+  Full { capacity: usize },
+  Missing(String),
+}
+
+impl fmt::Display for StoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StoreError::Full { capacity } => write!(f, "store is full ({capacity} entries)"),
+      StoreError::Missing(key) => write!(f, "missing key: {key}"),
+    }
+  }
+}
+
+impl<V> Store<V>
+where
+  V: Clone,
+{
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      entries: HashMap::with_capacity(capacity),
+      capacity,
+    }
+  }
+
+  /// Insert a value, failing if the store is full.
+  pub fn insert(&mut self, key: impl Into<String>, value: V) -> Result<Option<V>, StoreError> {
+    let key = key.into();
+
+    if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+      return Err(StoreError::Full {
+        capacity: self.capacity,
+      });
+    }
+
+    Ok(self.entries.insert(key, value))
+  }
+
+  pub fn get(&self, key: &str) -> Result<V, StoreError> {
+    self
+      .entries
+      .get(key)
+      .cloned()
+      .ok_or_else(|| StoreError::Missing(key.to_owned()))
+  }
+
+  pub fn keys<'a>(&'a self) -> impl Iterator<Item = &'a str> + 'a {
+    self.entries.keys().map(String::as_str)
+  }
+}
+
+fn fibonacci(n: u64) -> u64 {
+  let (mut a, mut b) = (0, 1);
+
+  for _ in 0..n {
+    (a, b) = (b, a + b);
+  }
+
+  a
+}
+
+macro_rules! square {
+  ($x:expr) => {
+    $x * $x
+  };
+}
+
+pub fn checksum(input: &[u8]) -> u32 {
+  const PRIME: u32 = 16_777_619;
+
+  input.iter().fold(0x811c_9dc5, |hash, byte| {
+    (hash ^ *byte as u32).wrapping_mul(PRIME)
+  })
+}
+
+pub fn demo() -> Result<(), StoreError> {
+  let mut store = Store::with_capacity(4);
+  store.insert("fib", fibonacci(42))?;
+  store.insert("square", square!(12))?;
+  store.insert("checksum", checksum(b"kak-tree-sitter") as u64)?;
+
+  let fib = store.get("fib")?;
+  println!("fib(42) = {fib}, keys = {:?}", store.keys().collect::<Vec<_>>());
+
+  Ok(())
+}
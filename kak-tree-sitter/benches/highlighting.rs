@@ -0,0 +1,155 @@
+//! Parse and highlight throughput.
+//!
+//! Each fixture is benchmarked at several sizes (by repeating its content), and each step of the highlighting pipeline
+//! is measured separately:
+//!
+//! - `parse`: parsing the buffer into a tree.
+//! - `query`: running the highlight query and collecting the highlight events; tree-sitter-highlight parses the buffer
+//!   on its own, so this includes a parse.
+//! - `convert`: converting the highlight events into Kakoune ranges (byte offsets to line / column coordinates).
+//! - `capture_faces_by_name` and `capture_faces_by_index`: resolving the face of every capture of the highlights query,
+//!   as `dump-captures` does, from the name of the capture or from a table built once per query.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kak_tree_sitter::highlighting::{capture_group, face_name, KakHighlightRange};
+use tree_sitter::{Language, Parser, QueryCursor};
+use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent, Highlighter};
+
+/// Number of times the content of a fixture is repeated.
+const SIZES: [usize; 3] = [1, 10, 100];
+
+struct Fixture {
+  name: &'static str,
+  lang: Language,
+  highlights: &'static str,
+  injections: &'static str,
+  source: &'static str,
+}
+
+fn fixtures() -> [Fixture; 2] {
+  [
+    Fixture {
+      name: "rust",
+      lang: tree_sitter_rust::language(),
+      highlights: tree_sitter_rust::HIGHLIGHT_QUERY,
+      injections: tree_sitter_rust::INJECTIONS_QUERY,
+      source: include_str!("fixtures/sample.rs"),
+    },
+    Fixture {
+      name: "html",
+      lang: tree_sitter_html::language(),
+      highlights: tree_sitter_html::HIGHLIGHTS_QUERY,
+      injections: tree_sitter_html::INJECTIONS_QUERY,
+      source: include_str!("fixtures/sample.html"),
+    },
+  ]
+}
+
+impl Fixture {
+  fn hl_config(&self) -> (HighlightConfiguration, Vec<String>) {
+    let mut hl_config =
+      HighlightConfiguration::new(self.lang, self.highlights, self.injections, "")
+        .expect("fixture highlight configuration");
+    let hl_names: Vec<_> = hl_config.query.capture_names().to_vec();
+    hl_config.configure(&hl_names);
+
+    (hl_config, hl_names)
+  }
+}
+
+fn highlight_events(
+  highlighter: &mut Highlighter,
+  hl_config: &HighlightConfiguration,
+  source: &str,
+) -> Vec<HighlightEvent> {
+  highlighter
+    .highlight(hl_config, source.as_bytes(), None, |_| None)
+    .expect("highlight events")
+    .flatten()
+    .collect()
+}
+
+/// Capture indices of all the captures of the highlights query on the buffer.
+fn capture_indices(
+  fixture: &Fixture,
+  hl_config: &HighlightConfiguration,
+  source: &str,
+) -> Vec<usize> {
+  let mut parser = Parser::new();
+  parser.set_language(fixture.lang).expect("fixture language");
+  let tree = parser.parse(source, None).expect("fixture tree");
+
+  QueryCursor::new()
+    .captures(&hl_config.query, tree.root_node(), source.as_bytes())
+    .map(|(query_match, index)| query_match.captures[index].index as usize)
+    .collect()
+}
+
+fn bench_highlighting(c: &mut Criterion) {
+  for fixture in fixtures() {
+    let (hl_config, hl_names) = fixture.hl_config();
+    let mut group = c.benchmark_group(fixture.name);
+
+    for size in SIZES {
+      let source = fixture.source.repeat(size);
+      group.throughput(Throughput::Bytes(source.len() as u64));
+
+      group.bench_with_input(BenchmarkId::new("parse", size), &source, |b, source| {
+        let mut parser = Parser::new();
+        parser.set_language(fixture.lang).expect("fixture language");
+        b.iter(|| parser.parse(black_box(source), None))
+      });
+
+      group.bench_with_input(BenchmarkId::new("query", size), &source, |b, source| {
+        let mut highlighter = Highlighter::new();
+        b.iter(|| highlight_events(&mut highlighter, &hl_config, black_box(source)))
+      });
+
+      let events = highlight_events(&mut Highlighter::new(), &hl_config, &source);
+      group.bench_with_input(BenchmarkId::new("convert", size), &source, |b, source| {
+        b.iter(|| {
+          KakHighlightRange::from_iter(black_box(source), "ts_", &hl_names, events.iter().copied())
+        })
+      });
+
+      let captures = capture_indices(&fixture, &hl_config, &source);
+      let capture_names = hl_config.query.capture_names();
+      group.bench_with_input(
+        BenchmarkId::new("capture_faces_by_name", size),
+        &captures,
+        |b, captures| {
+          b.iter(|| {
+            black_box(captures)
+              .iter()
+              .map(|&index| {
+                capture_group(&capture_names[index], &hl_names).map(|group| face_name("ts_", group))
+              })
+              .collect::<Vec<_>>()
+          })
+        },
+      );
+
+      let capture_faces: Vec<_> = capture_names
+        .iter()
+        .map(|name| capture_group(name, &hl_names).map(|group| face_name("ts_", group)))
+        .collect();
+      group.bench_with_input(
+        BenchmarkId::new("capture_faces_by_index", size),
+        &captures,
+        |b, captures| {
+          b.iter(|| {
+            black_box(captures)
+              .iter()
+              .map(|&index| capture_faces[index].clone())
+              .collect::<Vec<_>>()
+          })
+        },
+      );
+    }
+
+    group.finish();
+  }
+}
+
+criterion_group!(benches, bench_highlighting);
+criterion_main!(benches);
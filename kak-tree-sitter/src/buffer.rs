@@ -16,4 +16,69 @@ impl BufferId {
       buffer: buffer.into(),
     }
   }
+
+  pub fn session(&self) -> &str {
+    &self.session
+  }
+
+  pub fn buffer(&self) -> &str {
+    &self.buffer
+  }
+}
+
+/// Character replacing each byte of a buffer that is not valid UTF-8; see [`decode`].
+pub const INVALID_BYTE_REPLACEMENT: char = '?';
+
+/// Decode the content of a buffer, replacing each byte that is not valid UTF-8 (e.g. in a latin-1 file) with
+/// [`INVALID_BYTE_REPLACEMENT`].
+///
+/// Kakoune keeps such bytes as-is, and counts columns in bytes; replacing them byte per byte, rather than with
+/// `U+FFFD` as lossy decoding does, keeps the positions of everything else in the buffer right.
+pub fn decode(bytes: &[u8]) -> String {
+  let mut decoded = String::with_capacity(bytes.len());
+  let mut invalid_bytes = 0;
+  let mut rest = bytes;
+
+  loop {
+    match std::str::from_utf8(rest) {
+      Ok(valid) => {
+        decoded.push_str(valid);
+        break;
+      }
+
+      Err(err) => {
+        let (valid, invalid) = rest.split_at(err.valid_up_to());
+        // SAFETY: the bytes up to valid_up_to are valid UTF-8
+        decoded.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+
+        // a truncated sequence at the end has no length
+        let len = err.error_len().unwrap_or(invalid.len());
+        decoded.extend(std::iter::repeat(INVALID_BYTE_REPLACEMENT).take(len));
+        invalid_bytes += len;
+        rest = &invalid[len..];
+      }
+    }
+  }
+
+  if invalid_bytes > 0 {
+    log::warn!("buffer is not valid UTF-8; {invalid_bytes} invalid bytes replaced");
+  }
+
+  decoded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::decode;
+
+  #[test]
+  fn decode_invalid_utf8() {
+    assert_eq!(decode("fn é() {}".as_bytes()), "fn é() {}");
+
+    // latin-1 é, then a truncated sequence; positions are kept
+    let bytes = b"// caf\xe9\nlet x = \"\xe2\x82\"";
+    let decoded = decode(bytes);
+    assert_eq!(decoded, "// caf?\nlet x = \"??\"");
+    assert_eq!(decoded.len(), bytes.len());
+  }
 }
@@ -1,4 +1,8 @@
-use clap::Parser;
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::request::{Capability, UnixRequest};
 
 #[derive(Debug, Parser)]
 #[clap(
@@ -8,38 +12,166 @@ use clap::Parser;
   about = "A client/server interface between Kakoune and tree-sitter."
 )]
 pub struct Cli {
+  #[command(subcommand)]
+  pub cmd: Option<Cmd>,
+
+  /// Verbosity.
+  ///
+  /// Can be accumulated to get more verbosity. Without this flag, logging is disabled. Then, for each applicaton of the
+  /// flag, the obtained verbosity follows this order: error, warn, info, debug, trace. Thus, if you use -v, you will
+  /// only get error messages. If you use -vv, you will also see warnings. The maximum verbosity is achieved with -vvvvv
+  /// for trace logs.
+  #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+  pub verbose: u8,
+
+  /// Path of the UNIX socket the server listens on, and clients connect to.
+  ///
+  /// Defaults to a socket in the runtime directory. Overriding it allows to run isolated servers (e.g. for testing)
+  /// without interfering with the default one: a directory next to the socket (e.g. `kts.sock.d`) is then used as the
+  /// runtime directory.
+  #[arg(long, env = "KAK_TREE_SITTER_SOCKET", global = true)]
+  pub socket: Option<PathBuf>,
+
+  /// Maximum time, in milliseconds, a client waits for the server to handle its request before giving up.
+  ///
+  /// This prevents a stuck server from hanging its clients, such as the shell blocks of Kakoune. `0` waits forever.
+  #[arg(
+    long,
+    env = "KAK_TREE_SITTER_TIMEOUT_MS",
+    default_value = "5000",
+    global = true
+  )]
+  pub timeout_ms: u64,
+
+  /// Load the grammar and queries of every configured language, report which ones load, and exit.
+  ///
+  /// The server is not started. The exit status is non-zero if a language fails to load; languages whose grammar is
+  /// not installed are reported, but are not failures.
+  #[arg(long)]
+  pub check_config: bool,
+
+  /// Print the effective configuration — the default one merged with the user one — as TOML, and exit.
+  ///
+  /// The server is not started. The grammar and queries paths of every language are resolved and printed as comments
+  /// at the end.
+  #[arg(long)]
+  pub print_config: bool,
+
+  // flat flags, kept for compatibility with the interface prior to subcommands
+  #[command(flatten)]
+  pub legacy: LegacyArgs,
+}
+
+impl Cli {
+  /// Maximum time a client waits for the server, if it does not wait forever; see [`Cli::timeout_ms`].
+  pub fn timeout(&self) -> Option<Duration> {
+    (self.timeout_ms > 0).then(|| Duration::from_millis(self.timeout_ms))
+  }
+}
+
+// Flags of the interface prior to subcommands; those are hidden from the help, as `server start` and `request` should
+// be used instead.
+#[derive(Debug, Args)]
+pub struct LegacyArgs {
   /// Whether we start from Kakoune and then we should issue an initial request for setup.
-  #[clap(short, long)]
+  #[arg(short, long, hide = true)]
   pub kakoune: bool,
 
   /// Start the server, if not already started.
-  #[clap(short, long)]
+  #[arg(short, long, hide = true)]
   pub server: bool,
 
   /// Try to daemonize, if not already done.
-  #[clap(short, long)]
+  #[arg(short, long, hide = true)]
   pub daemonize: bool,
 
   /// Kakoune session to connect to.
-  #[clap(long)]
+  #[arg(long, hide = true)]
   pub session: Option<String>,
 
   /// Kakoune client to connect with, if any.
-  #[clap(short, long)]
+  #[arg(short, long, hide = true)]
   pub client: Option<String>,
 
-  /// JSON-serialized request.
-  #[clap(short, long)]
+  /// JSON-serialized request; `-` reads it from the standard input.
+  #[arg(short, long, hide = true)]
   pub request: Option<String>,
 
-  /// Verbosity.
+  /// File to read the JSON-serialized request from, for requests too large to be passed as an argument; `-` reads it
+  /// from the standard input.
+  #[arg(long, hide = true, conflicts_with = "request")]
+  pub request_file: Option<PathBuf>,
+
+  /// Insert Kakoune code related to highlighting.
+  #[arg(long, hide = true)]
+  pub with_highlighting: bool,
+
+  /// Insert Kakoune commands, user modes and mappings related to text-objects.
+  #[arg(long, hide = true)]
+  pub with_text_objects: bool,
+}
+
+impl LegacyArgs {
+  /// Arguments to start the server with, as expressed with the legacy flags.
+  pub fn start_args(&self) -> StartArgs {
+    StartArgs {
+      kakoune: self.kakoune,
+      daemonize: self.daemonize,
+      with_highlighting: self.with_highlighting,
+      with_text_objects: self.with_text_objects,
+      watch_queries: false,
+    }
+  }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+  /// Manage the server.
+  Server {
+    #[command(subcommand)]
+    cmd: ServerCmd,
+  },
+
+  /// Send a request to a running server.
+  Request {
+    #[command(subcommand)]
+    req: RequestCmd,
+  },
+
+  /// List configured languages, whether their grammar loads and which queries they have.
+  Languages {
+    /// Output JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+  },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServerCmd {
+  /// Start the server, if not already started.
+  Start(StartArgs),
+
+  /// Ask the server to shutdown.
+  Stop,
+
+  /// Check whether the server is running.
   ///
-  /// Can be accumulated to get more verbosity. Without this flag, logging is disabled. Then, for each applicaton of the
-  /// flag, the obtained verbosity follows this order: error, warn, info, debug, trace. Thus, if you use -v, you will
-  /// only get error messages. If you use -vv, you will also see warnings. The maximum verbosity is achieved with -vvvvv
-  /// for trace logs.
-  #[arg(short, long, action = clap::ArgAction::Count)]
-  pub verbose: u8,
+  /// The exit status is non-zero if the server is not running.
+  Status,
+}
+
+#[derive(Debug, Args)]
+pub struct StartArgs {
+  /// Whether we start from Kakoune and then we should issue an initial request for setup.
+  ///
+  /// The Kakoune commands required to communicate with the server are written to stdout, and logs go to the *debug*
+  /// buffer. The server exits as soon as the last connected session exits.
+  #[arg(short, long)]
+  pub kakoune: bool,
+
+  /// Try to daemonize, if not already done.
+  #[arg(short, long)]
+  pub daemonize: bool,
 
   /// Insert Kakoune code related to highlighting.
   ///
@@ -55,4 +187,124 @@ pub struct Cli {
   /// Text-objects user-modes will be available via the 'tree-sitter' user-mode.
   #[arg(long)]
   pub with_text_objects: bool,
+
+  /// Reload languages when their query files change on disk; meant for writing queries.
+  #[arg(long)]
+  pub watch_queries: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RequestCmd {
+  /// Inform the server that a session exists and that it should send back the Kakoune commands to get KTS features.
+  RegisterSession {
+    /// Kakoune session to register.
+    #[arg(long)]
+    session: String,
+
+    /// Kakoune client to send the initial commands to, if any.
+    #[arg(short, long)]
+    client: Option<String>,
+
+    /// Optional feature supported by the session; can be repeated.
+    #[arg(long = "capability", value_enum)]
+    capabilities: Vec<Capability>,
+  },
+
+  /// Inform the server that a session has exited.
+  SessionExit {
+    /// Kakoune session that exited.
+    #[arg(long)]
+    session: String,
+  },
+
+  /// Ask the server to reload its configuration, grammars and queries.
+  Reload,
+
+  /// Ask the server to shutdown.
+  Shutdown,
+
+  /// Highlight a buffer whose content is read from the standard input.
+  ///
+  /// The standard input itself (e.g. a pipe Kakoune writes the buffer to) is passed to the server, which reads it
+  /// directly; the content is not copied by this command. The session must have registered with the fd-passing
+  /// capability.
+  Highlight {
+    /// Kakoune session of the buffer.
+    #[arg(long)]
+    session: String,
+
+    /// Kakoune client to send the highlights to.
+    #[arg(short, long)]
+    client: String,
+
+    /// Name of the buffer.
+    #[arg(long)]
+    buffer: String,
+
+    /// Language of the buffer.
+    #[arg(long)]
+    lang: String,
+
+    /// Timestamp of the buffer.
+    #[arg(long)]
+    timestamp: u64,
+
+    /// Parse the buffer from scratch, ignoring the tree kept for it.
+    #[arg(long)]
+    force: bool,
+  },
+
+  /// Print, as JSON, the captures of the highlights query on a file, along with the face each of them gets.
+  ///
+  /// This is meant to debug queries: captures are listed in the order the query yields them, nested ones included.
+  DumpCaptures {
+    /// Language of the file.
+    #[arg(long)]
+    lang: String,
+
+    /// File to run the highlights query on.
+    path: PathBuf,
+  },
+}
+
+impl RequestCmd {
+  /// Whether the request is sent along with the standard input.
+  pub fn passes_stdin(&self) -> bool {
+    matches!(self, RequestCmd::Highlight { .. })
+  }
+}
+
+impl From<RequestCmd> for UnixRequest {
+  fn from(req: RequestCmd) -> Self {
+    match req {
+      RequestCmd::RegisterSession {
+        session,
+        client,
+        capabilities,
+      } => UnixRequest::RegisterSession {
+        name: session,
+        client,
+        capabilities,
+      },
+      RequestCmd::SessionExit { session } => UnixRequest::SessionExit { name: session },
+      RequestCmd::Reload => UnixRequest::Reload,
+      RequestCmd::Shutdown => UnixRequest::Shutdown,
+      RequestCmd::Highlight {
+        session,
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+      } => UnixRequest::Highlight {
+        session,
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+      },
+      RequestCmd::DumpCaptures { lang, path } => UnixRequest::DumpCaptures { lang, path },
+    }
+  }
 }
@@ -0,0 +1,460 @@
+//! Commenting and uncommenting code.
+//!
+//! Comment tokens come from the configuration of the language (see [`CommentConfig`]), or from its grammar if none is
+//! configured (see [`comment_tokens`]). The tree is used to tell actual comments apart from comment tokens in strings,
+//! and to avoid commenting lines in the middle of a multiline string.
+
+use std::collections::BTreeSet;
+
+use kak_tree_sitter_config::{BlockCommentConfig, CommentConfig};
+use tree_sitter::Node;
+
+use crate::{
+  edit::TextEdit,
+  error::OhNo,
+  selection::{Pos, Sel},
+};
+
+/// Compute the edits commenting the given selections, or uncommenting them if they are already commented.
+///
+/// Line comments are used if the language has them; whole lines spanned by the selections are then (un)commented.
+/// Otherwise, each selection is wrapped in (or unwrapped from) a block comment. Edits are sorted by position.
+pub fn toggle_comment(
+  root: Node,
+  buf: &str,
+  config: &CommentConfig,
+  sels: &[Sel],
+) -> Result<Vec<TextEdit>, OhNo> {
+  let lines = Lines::new(buf);
+
+  let mut edits = match (&config.line, &config.block) {
+    (Some(token), _) => toggle_line_comments(root, &lines, token, sels),
+    (None, Some(block)) => sels
+      .iter()
+      .flat_map(|sel| toggle_block_comment(root, &lines, block, sel))
+      .collect(),
+    (None, None) => return Err(OhNo::UnsupportedComments),
+  };
+
+  edits.sort_by_key(TextEdit::start);
+  Ok(edits)
+}
+
+/// Line comment tokens that are tried with grammars, in order of preference.
+const LINE_TOKENS: [&str; 5] = ["//", "#", "--", ";", "%"];
+
+/// Block comment delimiters that are tried with grammars, in order of preference.
+const BLOCK_TOKENS: [(&str, &str); 5] = [
+  ("/*", "*/"),
+  ("<!--", "-->"),
+  ("{-", "-}"),
+  ("(*", "*)"),
+  ("#|", "|#"),
+];
+
+/// Comment tokens of a language: the configured ones, or the ones its grammar parses as comments if none is
+/// configured.
+///
+/// Configured tokens win, as grammars are sometimes more lenient than the language itself (e.g. some CSS grammars
+/// accept `//` comments).
+pub fn comment_tokens(lang: tree_sitter::Language, config: &CommentConfig) -> CommentConfig {
+  if config.line.is_some() || config.block.is_some() {
+    return config.clone();
+  }
+
+  grammar_tokens(lang)
+}
+
+/// Comment tokens the grammar parses as comments.
+///
+/// Grammars do not tell their comment tokens, so common tokens are tried instead: a token is a comment token if a
+/// sample comment made with it is parsed as a single comment node.
+pub fn grammar_tokens(lang: tree_sitter::Language) -> CommentConfig {
+  let mut parser = tree_sitter::Parser::new();
+  if parser.set_language(lang).is_err() {
+    return CommentConfig::default();
+  }
+
+  let mut is_comment = |sample: &str| {
+    parser.parse(sample, None).map_or(false, |tree| {
+      tree
+        .root_node()
+        .descendant_for_byte_range(0, sample.len())
+        .map_or(false, |node| {
+          node.kind().contains("comment")
+            && node.start_byte() == 0
+            && node.end_byte() == sample.len()
+        })
+    })
+  };
+
+  let line = LINE_TOKENS
+    .into_iter()
+    .find(|token| is_comment(&format!("{token} kts")))
+    .map(str::to_owned);
+
+  // the sample spans two lines, so that line comments are not mistaken for block comments
+  let block = BLOCK_TOKENS
+    .into_iter()
+    .find(|(start, end)| is_comment(&format!("{start} kts\nkts {end}")))
+    .map(|(start, end)| BlockCommentConfig {
+      start: start.to_owned(),
+      end: end.to_owned(),
+    });
+
+  CommentConfig { line, block }
+}
+
+fn toggle_line_comments(root: Node, lines: &Lines, token: &str, sels: &[Sel]) -> Vec<TextEdit> {
+  let rows: BTreeSet<_> = sels
+    .iter()
+    .flat_map(|sel| {
+      let (start, end) = ordered(sel);
+      start.line..=end.line
+    })
+    .collect();
+
+  // blank lines and lines starting inside a multiline string are left untouched; the latter would corrupt the string
+  let targets: Vec<_> = rows
+    .into_iter()
+    .filter_map(|line| {
+      let text = lines.get(line)?;
+      let indent = text.len() - text.trim_start().len();
+      if indent == text.len() {
+        return None;
+      }
+
+      let offset = lines.offset(Pos {
+        line,
+        col: indent + 1,
+      });
+      let in_string = enclosing(root, offset, "string")
+        .map(|node| node.start_position().row + 1 < line)
+        .unwrap_or_default();
+
+      (!in_string).then_some((line, text, indent, offset))
+    })
+    .collect();
+
+  let is_commented = targets.iter().all(|(_, text, indent, offset)| {
+    text[*indent..].starts_with(token) && enclosing(root, *offset, "comment").is_some()
+  });
+
+  if is_commented {
+    targets
+      .into_iter()
+      .map(|(line, text, indent, _)| {
+        // also remove the space following the token, as we insert one when commenting
+        let mut len = token.len();
+        if text[indent + len..].starts_with(' ') {
+          len += 1;
+        }
+
+        TextEdit::Delete {
+          sel: Sel {
+            anchor: Pos {
+              line,
+              col: indent + 1,
+            },
+            cursor: Pos {
+              line,
+              col: indent + len,
+            },
+          },
+        }
+      })
+      .collect()
+  } else {
+    // align all the tokens on the least indented line
+    let col = targets
+      .iter()
+      .map(|(_, _, indent, _)| *indent)
+      .min()
+      .unwrap_or_default()
+      + 1;
+
+    targets
+      .into_iter()
+      .map(|(line, ..)| TextEdit::Insert {
+        pos: Pos { line, col },
+        text: format!("{token} "),
+      })
+      .collect()
+  }
+}
+
+fn toggle_block_comment(
+  root: Node,
+  lines: &Lines,
+  block: &BlockCommentConfig,
+  sel: &Sel,
+) -> Vec<TextEdit> {
+  let (start, end) = ordered(sel);
+  let buf = lines.buf;
+  let start_offset = lines.offset(start).min(buf.len());
+  let end_offset = lines.offset(end).min(buf.len());
+
+  // the end of the selection is included
+  let end_offset = end_offset + buf[end_offset..].chars().next().map_or(0, char::len_utf8);
+  let text = &buf[start_offset..end_offset];
+  let inner = text.trim();
+  if inner.is_empty() {
+    return Vec::new();
+  }
+
+  let open = start_offset + (text.len() - text.trim_start().len());
+  let close = open + inner.len();
+
+  let is_commented = inner.len() >= block.start.len() + block.end.len()
+    && inner.starts_with(&block.start)
+    && inner.ends_with(&block.end)
+    && enclosing(root, open, "comment").is_some();
+
+  if is_commented {
+    // also remove the spaces inside the tokens, as we insert them when commenting
+    let mut open_end = open + block.start.len();
+    let mut close_start = close - block.end.len();
+    if open_end < close_start && buf[open_end..].starts_with(' ') {
+      open_end += 1;
+    }
+    if open_end < close_start && buf[..close_start].ends_with(' ') {
+      close_start -= 1;
+    }
+
+    vec![
+      TextEdit::Delete {
+        sel: Sel {
+          anchor: lines.pos(open),
+          cursor: lines.pos(open_end - 1),
+        },
+      },
+      TextEdit::Delete {
+        sel: Sel {
+          anchor: lines.pos(close_start),
+          cursor: lines.pos(close - 1),
+        },
+      },
+    ]
+  } else {
+    vec![
+      TextEdit::Insert {
+        pos: lines.pos(open),
+        text: format!("{} ", block.start),
+      },
+      TextEdit::Insert {
+        pos: lines.pos(close),
+        text: format!(" {}", block.end),
+      },
+    ]
+  }
+}
+
+/// Start and end of a selection, in buffer order.
+fn ordered(sel: &Sel) -> (Pos, Pos) {
+  (sel.anchor.min(sel.cursor), sel.anchor.max(sel.cursor))
+}
+
+/// Innermost node containing the byte at `offset` whose kind contains `kind`, if any.
+fn enclosing<'a>(root: Node<'a>, offset: usize, kind: &str) -> Option<Node<'a>> {
+  let mut node = root.descendant_for_byte_range(offset, offset + 1)?;
+
+  loop {
+    if node.kind().contains(kind) {
+      return Some(node);
+    }
+
+    node = node.parent()?;
+  }
+}
+
+/// Lines of a buffer, used to convert between Kakoune positions and byte offsets.
+struct Lines<'a> {
+  buf: &'a str,
+
+  /// Byte offset of the start of each line.
+  starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+  fn new(buf: &'a str) -> Self {
+    let starts = std::iter::once(0)
+      .chain(buf.match_indices('\n').map(|(i, _)| i + 1))
+      .filter(|&start| start < buf.len())
+      .collect();
+
+    Self { buf, starts }
+  }
+
+  /// Content of a line (1-based), without its newline.
+  fn get(&self, line: usize) -> Option<&'a str> {
+    let start = *self.starts.get(line.checked_sub(1)?)?;
+    let end = self
+      .starts
+      .get(line)
+      .map_or(self.buf.len(), |next| next - 1);
+
+    Some(&self.buf[start..end])
+  }
+
+  /// Byte offset of a position.
+  fn offset(&self, pos: Pos) -> usize {
+    let start = self
+      .starts
+      .get(pos.line.saturating_sub(1))
+      .copied()
+      .unwrap_or(self.buf.len());
+
+    start + pos.col.saturating_sub(1)
+  }
+
+  /// Position of a byte offset.
+  fn pos(&self, offset: usize) -> Pos {
+    let line = self.starts.partition_point(|&start| start <= offset).max(1);
+
+    Pos {
+      line,
+      col: offset - self.starts.get(line - 1).copied().unwrap_or_default() + 1,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use kak_tree_sitter_config::{BlockCommentConfig, CommentConfig};
+  use tree_sitter::{Parser, Tree};
+
+  use super::{comment_tokens, grammar_tokens, toggle_comment};
+  use crate::{
+    edit::TextEdit,
+    selection::{Pos, Sel},
+  };
+
+  fn parse(buf: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    parser.parse(buf, None).unwrap()
+  }
+
+  /// Apply edits the way Kakoune does: from the last one to the first one.
+  fn apply(buf: &str, edits: &[TextEdit]) -> String {
+    let mut lines: Vec<String> = buf.lines().map(str::to_owned).collect();
+
+    for edit in edits.iter().rev() {
+      match edit {
+        TextEdit::Insert { pos, text } => lines[pos.line - 1].insert_str(pos.col - 1, text),
+        TextEdit::Delete { sel } => {
+          let line = &mut lines[sel.anchor.line - 1];
+          line.replace_range(sel.anchor.col - 1..sel.cursor.col, "");
+        }
+      }
+    }
+
+    lines.iter().map(|line| format!("{line}\n")).collect()
+  }
+
+  fn toggle(buf: &str, config: &CommentConfig, sel: &str) -> String {
+    let tree = parse(buf);
+    let sels = Sel::parse_many(sel);
+    let edits = toggle_comment(tree.root_node(), buf, config, &sels).unwrap();
+    apply(buf, &edits)
+  }
+
+  fn line_config() -> CommentConfig {
+    CommentConfig {
+      line: Some("//".to_owned()),
+      block: None,
+    }
+  }
+
+  #[test]
+  fn line_comments() {
+    let buf = "fn main() {\n  let a = 1;\n\n    let b = 2;\n}\n";
+    let commented = toggle(buf, &line_config(), "2.1,4.3");
+    assert_eq!(
+      commented,
+      "fn main() {\n  // let a = 1;\n\n  //   let b = 2;\n}\n"
+    );
+
+    // toggling again restores the buffer
+    assert_eq!(toggle(&commented, &line_config(), "2.5,4.1"), buf);
+  }
+
+  #[test]
+  fn line_comments_in_strings() {
+    // the third line starts with a comment token, but is part of a string
+    let buf = "fn f() {\n  let s = \"\n// not a comment\n\";\n}\n";
+    assert_eq!(
+      toggle(buf, &line_config(), "3.1,3.1"),
+      buf,
+      "lines inside strings must be left untouched"
+    );
+    assert_eq!(
+      toggle(buf, &line_config(), "2.1,3.1"),
+      "fn f() {\n  // let s = \"\n// not a comment\n\";\n}\n"
+    );
+  }
+
+  #[test]
+  fn block_comments() {
+    let config = CommentConfig {
+      line: None,
+      block: Some(BlockCommentConfig {
+        start: "/*".to_owned(),
+        end: "*/".to_owned(),
+      }),
+    };
+
+    let buf = "fn f() { let a = 1 + 2; }\n";
+    let commented = toggle(buf, &config, "1.18,1.22");
+    assert_eq!(commented, "fn f() { let a = /* 1 + 2 */; }\n");
+    assert_eq!(toggle(&commented, &config, "1.18,1.28"), buf);
+  }
+
+  #[test]
+  fn no_comment_tokens() {
+    let buf = "fn main() {}\n";
+    let tree = parse(buf);
+    let sels = [Sel {
+      anchor: Pos { line: 1, col: 1 },
+      cursor: Pos { line: 1, col: 1 },
+    }];
+
+    assert!(toggle_comment(tree.root_node(), buf, &CommentConfig::default(), &sels).is_err());
+  }
+
+  #[test]
+  fn grammar_comment_tokens() {
+    let rust = tree_sitter_rust::language();
+    assert_eq!(
+      grammar_tokens(rust),
+      CommentConfig {
+        line: Some("//".to_owned()),
+        block: Some(BlockCommentConfig {
+          start: "/*".to_owned(),
+          end: "*/".to_owned(),
+        }),
+      }
+    );
+    assert_eq!(
+      grammar_tokens(tree_sitter_html::language()),
+      CommentConfig {
+        line: None,
+        block: Some(BlockCommentConfig {
+          start: "<!--".to_owned(),
+          end: "-->".to_owned(),
+        }),
+      }
+    );
+
+    // configured tokens win
+    let config = CommentConfig {
+      line: Some("#".to_owned()),
+      block: None,
+    };
+    assert_eq!(comment_tokens(rust, &config), config);
+    assert_eq!(
+      comment_tokens(rust, &CommentConfig::default()),
+      grammar_tokens(rust)
+    );
+  }
+}
@@ -0,0 +1,26 @@
+//! Text edits, sent to Kakoune to modify buffers.
+
+use serde::Serialize;
+
+use crate::selection::{Pos, Sel};
+
+/// A modification of a buffer.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextEdit {
+  /// Insert text before a position.
+  Insert { pos: Pos, text: String },
+
+  /// Delete the text of a selection, both ends included.
+  Delete { sel: Sel },
+}
+
+impl TextEdit {
+  /// Position at which the edit starts.
+  pub fn start(&self) -> Pos {
+    match self {
+      TextEdit::Insert { pos, .. } => *pos,
+      TextEdit::Delete { sel } => sel.anchor.min(sel.cursor),
+    }
+  }
+}
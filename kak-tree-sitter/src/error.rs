@@ -1,17 +1,19 @@
-use std::{io, path::PathBuf};
+use std::{io, path::PathBuf, time::Duration};
 
 use kak_tree_sitter_config::ConfigError;
 use log::SetLoggerError;
 use thiserror::Error;
-use tree_sitter::{LanguageError, QueryError};
+use tree_sitter::LanguageError;
+
+use crate::request::ProtocolVersion;
 
 #[derive(Debug, Error)]
 pub enum OhNo {
-  #[error("nothing to do; please either use --server or --request")]
+  #[error("nothing to do; please use a subcommand (see --help)")]
   NothingToDo,
 
-  #[error("no runtime directory")]
-  NoRuntimeDir,
+  #[error("the server is not running")]
+  ServerNotRunning,
 
   #[error("cannot initialize logging: {err}")]
   LoggerInit {
@@ -28,12 +30,21 @@ pub enum OhNo {
   #[error("cannot create directory {dir}: {err}")]
   CannotCreateDir { dir: PathBuf, err: io::Error },
 
+  #[error("runtime directory {dir} is owned by another user (UID {uid}); refusing to use it")]
+  RuntimeDirNotOwned { dir: PathBuf, uid: u32 },
+
+  #[error("cannot set permissions of {path}: {err}")]
+  CannotSetPermissions { path: PathBuf, err: io::Error },
+
   #[error("cannot create file {file}: {err}")]
   CannotCreateFile { file: PathBuf, err: io::Error },
 
   #[error("cannot write to file {file}: {err}")]
   CannotWriteFile { file: PathBuf, err: io::Error },
 
+  #[error("cannot rotate file {file}: {err}")]
+  CannotRotateFile { file: PathBuf, err: io::Error },
+
   #[error("cannot start daemon: {err}")]
   CannotStartDaemon { err: String },
 
@@ -43,6 +54,9 @@ pub enum OhNo {
   #[error("poll error: {err}")]
   PollError { err: io::Error },
 
+  #[error("cannot watch query files: {err}")]
+  CannotWatchQueries { err: notify::Error },
+
   #[error("cannot get already existing sessions: {err}")]
   CannotGetSessions { err: String },
 
@@ -58,42 +72,213 @@ pub enum OhNo {
   #[error("cannot start server: {err}")]
   CannotStartServer { err: io::Error },
 
+  #[error("{failures} language(s) failed to load")]
+  LanguagesFailed { failures: usize },
+
   #[error("cannot load grammar for language {lang}: {err}")]
   CannotLoadGrammar { lang: String, err: String },
 
+  #[error("cannot find symbol {symbol} in the grammar of language {lang}: {err}")]
+  NoGrammarSymbol {
+    lang: String,
+    symbol: String,
+    err: String,
+  },
+
   #[error("UNIX connection error: {err}")]
   UnixConnectionError { err: io::Error },
 
+  #[error("cannot read request from standard input: {err}")]
+  CannotReadStdinRequest { err: io::Error },
+
+  #[error("cannot read request from {path}: {err}")]
+  CannotReadRequestFile { path: PathBuf, err: io::Error },
+
   #[error("invalid request {req}: {err}")]
   InvalidRequest { req: String, err: String },
 
+  #[error("malformed {kind} request: {err}")]
+  MalformedRequest { kind: String, err: String },
+
+  #[error(
+    "the client speaks protocol version {version}, but the server speaks version {expected}; restart the server so \
+     that both run the same version of kak-tree-sitter"
+  )]
+  ProtocolMismatch {
+    version: ProtocolVersion,
+    expected: ProtocolVersion,
+  },
+
+  #[error(
+    "the server predates protocol versions, so it cannot understand this client; restart the server so that both run \
+     the same version of kak-tree-sitter"
+  )]
+  OutdatedServer,
+
   #[error("cannot connect to server; is it running?: {err}")]
   CannotConnectToServer { err: io::Error },
 
   #[error("cannot send request: {err}")]
   CannotSendRequest { err: String },
 
+  #[error("invalid response from server: {err}")]
+  InvalidResponse { err: String },
+
+  #[error("request rejected by the server: {reason}")]
+  RequestRejected { reason: String, exit_code: i32 },
+
+  #[error("the server did not handle the request within {timeout:?}; is it stuck?")]
+  ServerTimedOut { timeout: Duration },
+
+  #[error("request larger than {max_size} bytes")]
+  RequestTooLarge { max_size: usize },
+
+  #[error("request not received within {timeout:?}")]
+  RequestTimedOut { timeout: Duration },
+
+  #[error("no file descriptor passed with the request")]
+  MissingBufferFd,
+
+  #[error("cannot read buffer from file descriptor: {err}")]
+  CannotReadBufferFd { err: io::Error },
+
+  #[error("unknown session {session}")]
+  UnknownSession { session: String },
+
+  #[error("session {session} is gone; kak -p closed its input")]
+  SessionGone { session: String },
+
+  #[error("session {session} did not register with capability {capability}")]
+  MissingCapability { session: String, capability: String },
+
+  #[error("request handling panicked: {reason}")]
+  HandlerPanicked { reason: String },
+
   #[error("cannot parse buffer")]
   CannotParseBuffer,
 
   #[error("highlight error: {err}")]
   HighlightError { err: String },
 
+  #[error("language {lang} is not supported")]
+  UnsupportedLanguage { lang: String },
+
+  #[error("language {lang} has no highlights query")]
+  NoHighlightsQuery { lang: String },
+
+  #[error("cannot read file {file}: {err}")]
+  CannotReadFile { file: PathBuf, err: io::Error },
+
+  #[error(
+    "file {file} is too large: {size} bytes, more than highlight.max_buffer_bytes = {max_size}"
+  )]
+  FileTooLarge {
+    file: PathBuf,
+    size: u64,
+    max_size: u64,
+  },
+
   #[error("language error: {err}")]
   LangError {
     #[from]
     err: LanguageError,
   },
 
-  #[error("query error: {err}")]
-  QueryError {
-    #[from]
-    err: QueryError,
+  #[error("invalid {query}.scm query at line {line}: {err}")]
+  InvalidQuery {
+    query: String,
+    line: usize,
+    err: String,
+  },
+
+  #[error("predicate #{predicate} at line {line} of {query}.scm is not supported; it is ignored")]
+  UnsupportedPredicate {
+    query: String,
+    line: usize,
+    predicate: String,
   },
 
   #[error("text-objects not supported")]
   UnsupportedTextObjects,
 
+  #[error("indents not supported")]
+  UnsupportedIndents,
+
+  #[error("no comment tokens configured")]
+  UnsupportedComments,
+
   #[error("no such {pattern} text-object query")]
   UnknownTextObjectQuery { pattern: String },
+
+  #[error("no node spans the byte range {start}..{end}")]
+  InvalidByteRange { start: usize, end: usize },
+}
+
+impl OhNo {
+  /// Error of a request the server rejected for `reason`.
+  ///
+  /// Servers predating protocol versions reject the version sent with every request as an unknown field; that is
+  /// reported as such, rather than as a malformed request.
+  pub fn rejected(reason: String, exit_code: i32) -> Self {
+    if reason.contains("unknown field `version`") {
+      OhNo::OutdatedServer
+    } else {
+      OhNo::RequestRejected { reason, exit_code }
+    }
+  }
+
+  /// Exit status of the process failing with this error; see [`exit_code`].
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      OhNo::ServerNotRunning => exit_code::SERVER_NOT_RUNNING,
+
+      OhNo::CannotConnectToServer { err } if err.kind() == io::ErrorKind::NotFound => {
+        exit_code::SERVER_NOT_RUNNING
+      }
+
+      OhNo::CannotConnectToServer { .. }
+      | OhNo::CannotSendRequest { .. }
+      | OhNo::ServerTimedOut { .. }
+      | OhNo::OutdatedServer => exit_code::CONNECTION_FAILED,
+
+      OhNo::CannotReadStdinRequest { .. }
+      | OhNo::CannotReadRequestFile { .. }
+      | OhNo::InvalidRequest { .. }
+      | OhNo::MalformedRequest { .. }
+      | OhNo::ProtocolMismatch { .. }
+      | OhNo::RequestTooLarge { .. }
+      | OhNo::RequestTimedOut { .. }
+      | OhNo::MissingBufferFd
+      | OhNo::CannotReadBufferFd { .. }
+      | OhNo::MissingCapability { .. } => exit_code::INVALID_REQUEST,
+
+      OhNo::UnknownSession { .. } => exit_code::UNKNOWN_SESSION,
+
+      OhNo::RequestRejected { exit_code, .. } => *exit_code,
+
+      _ => exit_code::FAILURE,
+    }
+  }
+}
+
+/// Exit statuses of `kak-tree-sitter`, so that scripts can react to the different kinds of failures.
+///
+/// Requests rejected by the server exit with the status of the error the server rejected them with.
+pub mod exit_code {
+  /// Any failure not covered by the other statuses.
+  pub const FAILURE: i32 = 1;
+
+  // 2 is used by clap for invalid command lines
+
+  /// No server is running.
+  pub const SERVER_NOT_RUNNING: i32 = 3;
+
+  /// The server cannot be talked to; e.g. the connection is refused because it crashed, or it is stuck.
+  pub const CONNECTION_FAILED: i32 = 4;
+
+  /// The request is invalid; e.g. it cannot be parsed, or is too large.
+  pub const INVALID_REQUEST: i32 = 5;
+
+  /// The request is about a session the server does not know.
+  pub const UNKNOWN_SESSION: i32 = 6;
 }
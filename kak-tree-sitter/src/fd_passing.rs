@@ -0,0 +1,233 @@
+//! Passing file descriptors over UNIX sockets.
+//!
+//! A client can send the content of a buffer as a file descriptor (typically, the read end of a pipe) instead of
+//! writing it to the buffer FIFO; the descriptor is sent as `SCM_RIGHTS` ancillary data along with the first bytes of
+//! the request. [`std::os::unix::net::UnixStream`] does not expose ancillary data on stable Rust, so this is done with
+//! `sendmsg` / `recvmsg` directly.
+
+use std::{
+  fs::File,
+  io::{self, Read},
+  mem,
+  os::unix::{
+    io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    net::UnixStream,
+  },
+  ptr,
+  time::{Duration, Instant},
+};
+
+use crate::{buffer, error::OhNo};
+
+/// Control message buffer; large enough for a few file descriptors, and aligned as `cmsghdr`.
+type ControlBuf = [u64; 8];
+
+// received file descriptors must not leak into the `kak -p` processes spawned by the server
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// Send `bytes` along with the file descriptor `fd`, returning how many bytes were sent.
+///
+/// `bytes` must not be empty, as ancillary data cannot be sent alone on a stream socket. The rest of the bytes, if not
+/// all of them were sent, can be written normally.
+pub fn send_with_fd(stream: &UnixStream, bytes: &[u8], fd: RawFd) -> io::Result<usize> {
+  let mut iov = libc::iovec {
+    iov_base: bytes.as_ptr() as *mut libc::c_void,
+    iov_len: bytes.len(),
+  };
+  let mut control: ControlBuf = Default::default();
+
+  // SAFETY: the message only points to iov and control, which outlive it; the control message is written within the
+  // space computed by CMSG_SPACE, which fits in control
+  let sent = unsafe {
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+    libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+  };
+
+  if sent < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  Ok(sent as usize)
+}
+
+/// Receive bytes in `buf`, along with the file descriptor sent with them, if any.
+///
+/// Only the first file descriptor is kept; other ones are closed.
+pub fn recv_with_fd(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)> {
+  let mut iov = libc::iovec {
+    iov_base: buf.as_mut_ptr().cast(),
+    iov_len: buf.len(),
+  };
+  let mut control: ControlBuf = Default::default();
+
+  // SAFETY: the message only points to iov and control, which outlive it
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr().cast();
+  msg.msg_controllen = mem::size_of_val(&control) as _;
+
+  // SAFETY: see above
+  let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, RECV_FLAGS) };
+  if received < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  let mut fds = Vec::new();
+
+  // SAFETY: control messages are read as laid out by the kernel, within msg_controllen; the file descriptors they
+  // carry were just installed for this process, so they are owned here
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+        let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+
+        for i in 0..len / mem::size_of::<RawFd>() {
+          fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+        }
+      }
+
+      cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+    }
+  }
+
+  Ok((received as usize, fds.into_iter().next()))
+}
+
+/// Read the whole content of a file descriptor, until its write end is closed.
+///
+/// The content must be entirely read within `timeout`, if any, so that a client keeping a pipe open cannot hang the
+/// server. Bytes that are not valid UTF-8 are replaced; see [`buffer::decode`].
+pub fn read_to_string(fd: OwnedFd, timeout: Option<Duration>) -> Result<String, OhNo> {
+  // a timeout too long to be represented is as good as none
+  let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+  let mut file = File::from(fd);
+  let mut content = Vec::new();
+  let mut chunk = [0; 64 * 1024];
+
+  loop {
+    let remaining = poll_timeout(deadline);
+    let mut pollfd = libc::pollfd {
+      fd: file.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    };
+
+    // SAFETY: a single, valid pollfd is passed
+    let ready = unsafe { libc::poll(&mut pollfd, 1, remaining) };
+    match ready {
+      // poll only times out with a deadline
+      0 => {
+        return Err(OhNo::RequestTimedOut {
+          timeout: timeout.unwrap_or_default(),
+        })
+      }
+      n if n < 0 => {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+          continue;
+        }
+
+        return Err(OhNo::CannotReadBufferFd { err });
+      }
+      _ => (),
+    }
+
+    match file.read(&mut chunk) {
+      Ok(0) => break,
+      Ok(n) => content.extend_from_slice(&chunk[..n]),
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+      Err(err) => return Err(OhNo::CannotReadBufferFd { err }),
+    }
+  }
+
+  Ok(buffer::decode(&content))
+}
+
+/// Timeout of `poll` until `deadline`, in milliseconds.
+///
+/// A negative timeout waits forever, so timeouts too long for a `c_int` are clamped rather than wrapped around.
+fn poll_timeout(deadline: Option<Instant>) -> libc::c_int {
+  deadline.map_or(-1, |deadline| {
+    let remaining = deadline
+      .saturating_duration_since(Instant::now())
+      .as_millis();
+    remaining.min(libc::c_int::MAX as u128) as libc::c_int
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::Write,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    time::{Duration, Instant},
+  };
+
+  use super::{poll_timeout, read_to_string, recv_with_fd, send_with_fd};
+
+  #[test]
+  fn pass_fd() {
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let (pipe_read, mut pipe_write) = UnixStream::pair().unwrap();
+
+    assert_eq!(
+      send_with_fd(&sender, b"req", pipe_read.as_raw_fd()).unwrap(),
+      3
+    );
+    drop(pipe_read);
+
+    let mut buf = [0; 16];
+    let (received, fd) = recv_with_fd(&receiver, &mut buf).unwrap();
+    assert_eq!(&buf[..received], b"req");
+
+    // the received file descriptor is a duplicate; the content written on the other end is read through it
+    pipe_write.write_all(b"fn main() {}").unwrap();
+    drop(pipe_write);
+    let content = read_to_string(fd.unwrap(), Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(content, "fn main() {}");
+
+    // no file descriptor is sent with regular writes
+    (&sender).write_all(b"more").unwrap();
+    let (received, fd) = recv_with_fd(&receiver, &mut buf).unwrap();
+    assert_eq!((&buf[..received], fd.is_none()), (&b"more"[..], true));
+  }
+
+  #[test]
+  fn read_timeout() {
+    let (pipe_read, _pipe_write) = UnixStream::pair().unwrap();
+    let res = read_to_string(pipe_read.into(), Some(Duration::from_millis(50)));
+    assert!(res.is_err());
+  }
+
+  #[test]
+  fn long_read_timeout() {
+    assert_eq!(poll_timeout(None), -1);
+
+    let month = Instant::now() + Duration::from_secs(30 * 24 * 3600);
+    assert_eq!(poll_timeout(Some(month)), libc::c_int::MAX);
+
+    // the timeout is too long for a deadline, so there is none
+    let (pipe_read, mut pipe_write) = UnixStream::pair().unwrap();
+    pipe_write.write_all(b"fn main() {}").unwrap();
+    drop(pipe_write);
+    let content = read_to_string(pipe_read.into(), Some(Duration::MAX)).unwrap();
+    assert_eq!(content, "fn main() {}");
+  }
+}
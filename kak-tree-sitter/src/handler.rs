@@ -1,15 +1,27 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+  collections::{hash_map::Entry, HashMap, HashSet},
+  fs,
+  path::Path,
+  time::Instant,
+};
 
-use kak_tree_sitter_config::Config;
+use kak_tree_sitter_config::{Config, OnStale};
+use tree_sitter::{Parser, Query};
 
 use crate::{
-  buffer::BufferId,
+  buffer::{self, BufferId},
   error::OhNo,
+  highlighting::{face_name, flatten_highlights, overlay_spans, Highlighter, KakHighlightRange},
+  indent::IndentStyle,
   languages::{Language, Languages},
-  response::Response,
-  selection::Sel,
+  metrics::ParseMetrics,
+  navigation::Direction,
+  request::{HighlightItem, Request, BATCH_DELIMITER},
+  response::{did_you_mean, highlights_script, BufferFailure, BufferHighlights, Response},
+  selection::{Pos, Sel},
   text_objects,
-  tree_sitter_state::TreeState,
+  tree_cache::TreeCache,
+  tree_sitter_state::{CursorPool, HighlightCapture, TreeState},
 };
 
 /// Type responsible for handling requests.
@@ -18,60 +30,553 @@ use crate::{
 /// that can be reused, for instance).
 pub struct Handler {
   /// Tree-sitter trees associated with a [`BufferId`].
-  trees: HashMap<BufferId, TreeState>,
+  trees: TreeCache,
+
+  /// Parsers, one per language, reused across requests.
+  parsers: HashMap<String, Parser>,
 
   /// Known languages.
   langs: Languages,
+
+  /// Unsupported languages we already warned about.
+  warned_langs: HashSet<String>,
+
+  /// Query cursors, reused across requests.
+  cursors: CursorPool,
+
+  /// Highlighter, reused across requests.
+  highlighter: Highlighter,
+
+  /// Buffers successfully highlighted, used to report the highlighting status only when it changes.
+  highlighted: HashSet<BufferId>,
+
+  /// Parse metrics, per language.
+  metrics: HashMap<String, ParseMetrics>,
+
+  /// Size, in bytes, past which buffers are not highlighted.
+  max_buffer_bytes: u64,
+
+  /// What to do with stale highlight requests.
+  on_stale: OnStale,
+
+  /// Prefix of the faces of the ranges computed outside of highlighting; e.g. syntax errors.
+  face_prefix: String,
+
+  /// Maximum number of selections returned by a single request.
+  max_selections: usize,
+
+  /// Sessions for which highlighting is paused; see [`Request::SetEnabled`].
+  paused: HashSet<String>,
 }
 
 impl Handler {
   pub fn new(config: &Config) -> Result<Self, OhNo> {
-    let trees = HashMap::default();
+    let trees = TreeCache::new(config.server.max_trees);
+    let parsers = HashMap::default();
     let langs = Languages::load_from_dir(config)?;
+    let warned_langs = HashSet::default();
+    let cursors = CursorPool::default();
+    let highlighter = Highlighter::new();
+    let highlighted = HashSet::default();
+    let metrics = HashMap::default();
+    let max_buffer_bytes = config.highlight.max_buffer_bytes;
+    let on_stale = config.highlight.on_stale;
+    let face_prefix = config.highlight.face_prefix.clone();
+    let max_selections = config.server.max_selections;
+    let paused = HashSet::default();
 
-    Ok(Self { trees, langs })
+    Ok(Self {
+      trees,
+      parsers,
+      langs,
+      warned_langs,
+      cursors,
+      highlighter,
+      highlighted,
+      metrics,
+      max_buffer_bytes,
+      on_stale,
+      face_prefix,
+      max_selections,
+      paused,
+    })
+  }
+
+  /// Reload the configuration, grammars and queries.
+  ///
+  /// The handler is left untouched on failure. Grammars are shared libraries, which cannot be loaded again while they
+  /// are loaded, so if a grammar file changed, everything relying on the current languages is dropped first; a failure
+  /// then leaves the handler without languages.
+  pub fn reload(&mut self, config: &Config) -> Result<(), OhNo> {
+    // sessions paused highlighting themselves, regardless of the configuration; and Kakoune still shows the status of
+    // highlighted buffers, which must be cleared if they cannot be highlighted anymore
+    let paused = self.paused.clone();
+    let highlighted = self.highlighted.clone();
+
+    let handler = Self::new(config)?;
+    if !self.langs.grammars_changed() {
+      *self = Self {
+        paused,
+        highlighted,
+        ..handler
+      };
+      return Ok(());
+    }
+
+    log::info!("grammars changed; loading them again");
+    drop(handler);
+    self.trees = TreeCache::new(config.server.max_trees);
+    self.parsers.clear();
+    self.highlighter = Highlighter::new();
+    self.langs.unload();
+
+    *self = Self {
+      paused,
+      highlighted,
+      ..Self::new(config)?
+    };
+    Ok(())
+  }
+
+  /// Parse metrics, per language.
+  pub fn parse_metrics(&self) -> &HashMap<String, ParseMetrics> {
+    &self.metrics
+  }
+
+  /// Drop the tree kept for a buffer, so that it is parsed from scratch the next time it is needed.
+  fn drop_tree(&mut self, buffer_id: &BufferId) {
+    if self.trees.remove(buffer_id).is_some() {
+      log::debug!("dropped the tree of buffer {buffer_id:?}");
+    }
+  }
+
+  /// Forget everything about a buffer, so that it is parsed from scratch and its status reported again.
+  fn forget_buffer(&mut self, buffer_id: &BufferId) {
+    self.drop_tree(buffer_id);
+    self.highlighted.remove(buffer_id);
+  }
+
+  /// Forget about a session that exited: the trees of its buffers, whether they are highlighted, and whether
+  /// highlighting is paused for it.
+  pub fn drop_session(&mut self, session_name: &str) {
+    self.trees.remove_session(session_name);
+    self
+      .highlighted
+      .retain(|buffer_id| buffer_id.session() != session_name);
+    self.paused.remove(session_name);
+    log::debug!("dropped the state of session {session_name}");
+  }
+
+  /// Drop the trees of all the buffers of a session; they are parsed from scratch the next time they are needed.
+  pub fn drop_trees(&mut self, session_name: &str) {
+    self.trees.remove_session(session_name);
+    log::debug!("dropped the trees of session {session_name}");
+  }
+
+  /// Get the metrics of a given language, creating them the first time the language is seen.
+  fn lang_metrics<'a>(
+    metrics: &'a mut HashMap<String, ParseMetrics>,
+    lang_name: &str,
+  ) -> &'a mut ParseMetrics {
+    // avoid allocating the key on every request
+    if !metrics.contains_key(lang_name) {
+      metrics.insert(lang_name.to_owned(), ParseMetrics::default());
+    }
+
+    metrics.get_mut(lang_name).unwrap()
+  }
+
+  /// Get the parser for a given language, creating it the first time the language is seen.
+  fn parser<'a>(
+    parsers: &'a mut HashMap<String, Parser>,
+    lang_name: &str,
+    lang: &Language,
+  ) -> Result<&'a mut Parser, OhNo> {
+    match parsers.entry(lang_name.to_owned()) {
+      Entry::Vacant(entry) => {
+        let mut parser = Parser::new();
+        parser.set_language(lang.lang())?;
+        Ok(entry.insert(parser))
+      }
+
+      Entry::Occupied(entry) => Ok(entry.into_mut()),
+    }
   }
 
   /// Ensure we have a parsed tree for this buffer id and buffer content.
   fn compute_tree<'a>(
-    trees: &'a mut HashMap<BufferId, TreeState>,
+    trees: &'a mut TreeCache,
+    parsers: &mut HashMap<String, Parser>,
+    metrics: &mut HashMap<String, ParseMetrics>,
+    lang_name: &str,
     lang: &Language,
     buffer_id: BufferId,
     buf: &str,
   ) -> Result<&'a mut TreeState, OhNo> {
-    match trees.entry(buffer_id) {
-      Entry::Vacant(entry) => {
-        // first time we see this buffer; full parse
-        let tree_state = TreeState::new(lang, buf)?;
-        Ok(entry.insert(tree_state))
+    let parser = Self::parser(parsers, lang_name, lang)?;
+    let metrics = Self::lang_metrics(metrics, lang_name);
+    Self::parse_buffer(trees, parser, metrics, lang_name, buffer_id, buf)
+  }
+
+  /// Parse a buffer with the given parser, reusing its tree if it did not change since the last parse.
+  ///
+  /// If the buffer cannot be parsed, the tree previously parsed for it, if any, is dropped, so that a stale tree is
+  /// never used. So is a tree parsed with another language — e.g. when the filetype of the buffer changed.
+  fn parse_buffer<'a>(
+    trees: &'a mut TreeCache,
+    parser: &mut Parser,
+    metrics: &mut ParseMetrics,
+    lang_name: &str,
+    buffer_id: BufferId,
+    buf: &str,
+  ) -> Result<&'a mut TreeState, OhNo> {
+    if let Some(old_lang_name) = trees
+      .get(&buffer_id)
+      .map(|tree_state| tree_state.lang_name().to_owned())
+      .filter(|old_lang_name| old_lang_name != lang_name)
+    {
+      log::debug!("language of buffer {buffer_id:?} changed from {old_lang_name} to {lang_name}; dropping its tree");
+      trees.remove(&buffer_id);
+    }
+
+    match trees
+      .get(&buffer_id)
+      .map(|tree_state| tree_state.is_parsed_from(parser, buf))
+    {
+      None => {
+        // first time we see this buffer (or its tree was evicted); full parse
+        let start = Instant::now();
+        let tree_state = TreeState::new(lang_name, parser, buf)?;
+        metrics.record_parse(buf.len(), start.elapsed(), false);
+        Ok(trees.insert(buffer_id, tree_state))
+      }
+
+      Some(true) => {
+        // the buffer did not change since the last parse
+        metrics.record_cache_hit();
+        Ok(trees.get_mut(&buffer_id).unwrap())
       }
 
-      Entry::Occupied(mut entry) => {
+      Some(false) => {
         // TODO(#26): we already have a parsed buffer; we want an incremental update instead of fully reparsing everything
-        let tree_state = TreeState::new(lang, buf)?;
-        entry.insert(tree_state);
-        Ok(entry.into_mut())
+        let start = Instant::now();
+        match TreeState::new(lang_name, parser, buf) {
+          Ok(tree_state) => {
+            metrics.record_parse(buf.len(), start.elapsed(), true);
+            Ok(trees.insert(buffer_id, tree_state))
+          }
+
+          Err(err) => {
+            log::debug!("cannot parse buffer {buffer_id:?}; dropping its tree");
+            trees.remove(&buffer_id);
+            Err(err)
+          }
+        }
+      }
+    }
+  }
+
+  /// Handle a request from a given session.
+  ///
+  /// `buf` is the content of the buffer the request is about, and is ignored by requests that do not need it (see
+  /// [`Request::needs_buffer`]). Requests needing a tree never depend on a previous request: a buffer that was never
+  /// parsed, or whose tree was evicted, is parsed from `buf` on demand.
+  pub fn handle_request(
+    &mut self,
+    session_name: &str,
+    req: &Request,
+    buf: &str,
+  ) -> Result<Response, OhNo> {
+    match req {
+      Request::TryEnableHighlight { lang, buffer, .. } => {
+        self.handle_try_enable_highlight(session_name, lang, buffer.as_deref())
+      }
+
+      Request::Highlight {
+        buffer,
+        lang,
+        timestamp,
+        force,
+        output,
+        syntax_errors,
+        ..
+      } => {
+        let buffer_id = BufferId::new(session_name, buffer);
+        if *force {
+          self.forget_buffer(&buffer_id);
+        }
+
+        if let Some(output) = output {
+          return Ok(self.write_highlights(
+            buffer_id,
+            lang,
+            *timestamp,
+            buf,
+            *syntax_errors,
+            output,
+          ));
+        }
+
+        let resp = self.handle_highlight(buffer_id.clone(), lang, *timestamp, buf, *syntax_errors);
+        self.track_highlight_status(buffer_id, resp)
+      }
+
+      Request::Reparse { .. } => self.handle_request(session_name, &req.expand_reparse(), buf),
+
+      Request::HighlightBatch { items, .. } => {
+        Ok(self.handle_highlight_batch(session_name, items, buf))
+      }
+
+      Request::TextObjects {
+        buffer,
+        lang,
+        pattern,
+        selections,
+        mode,
+        ..
+      } => self.handle_text_objects(
+        BufferId::new(session_name, buffer),
+        lang,
+        buf,
+        pattern,
+        &Sel::parse_many(selections),
+        mode,
+      ),
+
+      Request::Indent {
+        buffer,
+        lang,
+        line,
+        style,
+        ..
+      } => self.handle_indent(BufferId::new(session_name, buffer), lang, buf, *line, style),
+
+      Request::ToggleComment {
+        buffer,
+        lang,
+        selections,
+        ..
+      } => self.handle_toggle_comment(
+        BufferId::new(session_name, buffer),
+        lang,
+        buf,
+        &Sel::parse_many(selections),
+      ),
+
+      Request::SelectSiblings {
+        buffer,
+        lang,
+        selections,
+        named_only,
+        ..
+      } => self.handle_select_siblings(
+        BufferId::new(session_name, buffer),
+        lang,
+        buf,
+        &Sel::parse_many(selections),
+        *named_only,
+      ),
+
+      Request::NavigateNode {
+        buffer,
+        lang,
+        selections,
+        kind,
+        direction,
+        wrap,
+        ..
+      } => self.handle_navigate_node(
+        BufferId::new(session_name, buffer),
+        lang,
+        buf,
+        &Sel::parse_many(selections),
+        kind,
+        *direction,
+        *wrap,
+      ),
+
+      Request::DumpTree {
+        buffer,
+        lang,
+        range,
+        ..
+      } => self.handle_dump_tree(BufferId::new(session_name, buffer), lang, buf, *range),
+
+      Request::RunQuery {
+        buffer,
+        lang,
+        query,
+        ..
+      } => self.handle_run_query(BufferId::new(session_name, buffer), lang, buf, query),
+
+      Request::SelectMatching {
+        buffer,
+        lang,
+        query,
+        ..
+      } => self.handle_select_matching(BufferId::new(session_name, buffer), lang, buf, query),
+
+      Request::ProseRanges {
+        buffer,
+        lang,
+        timestamp,
+        ..
+      } => self.handle_prose_ranges(BufferId::new(session_name, buffer), lang, *timestamp, buf),
+
+      Request::SyntaxErrors {
+        buffer,
+        lang,
+        timestamp,
+        ..
+      } => self.handle_syntax_errors(BufferId::new(session_name, buffer), lang, *timestamp, buf),
+
+      Request::NodeInfo {
+        buffer,
+        lang,
+        position,
+        ..
+      } => self.handle_node_info(BufferId::new(session_name, buffer), lang, buf, *position),
+
+      Request::Injections { buffer, lang, .. } => {
+        self.handle_injections(BufferId::new(session_name, buffer), lang, buf)
       }
+
+      Request::CommentTokens { lang, .. } => Ok(self.handle_comment_tokens(lang)),
+
+      Request::SetEnabled { enabled, .. } => Ok(self.handle_set_enabled(session_name, *enabled)),
+
+      #[cfg(feature = "panic-request")]
+      Request::Panic { .. } => panic!("panic requested by session {session_name}"),
     }
   }
 
+  /// Handle a request from a given session, passing early responses to `emit` when part of the result can be sent
+  /// before the rest is computed.
+  ///
+  /// Only highlight requests are handled progressively (see [`Handler::handle_highlight_progressive`]); other requests
+  /// are handled as with [`Handler::handle_request`].
+  pub fn handle_request_progressive(
+    &mut self,
+    session_name: &str,
+    req: &Request,
+    buf: &str,
+    emit: &mut dyn FnMut(Response),
+  ) -> Result<Response, OhNo> {
+    match req {
+      Request::Highlight {
+        buffer,
+        lang,
+        timestamp,
+        force,
+        output: None,
+        syntax_errors,
+        ..
+      } => {
+        let buffer_id = BufferId::new(session_name, buffer);
+        if *force {
+          self.forget_buffer(&buffer_id);
+        }
+
+        let resp = self.handle_highlight_progressive(
+          buffer_id.clone(),
+          lang,
+          *timestamp,
+          buf,
+          *syntax_errors,
+          emit,
+        );
+        self.track_highlight_status(buffer_id, resp)
+      }
+
+      Request::Reparse { .. } => {
+        self.handle_request_progressive(session_name, &req.expand_reparse(), buf, emit)
+      }
+
+      _ => self.handle_request(session_name, req, buf),
+    }
+  }
+
+  /// Comment tokens of a language; see [`comment_tokens`].
+  ///
+  /// [`comment_tokens`]: crate::comment::comment_tokens
+  pub fn handle_comment_tokens(&self, lang_name: &str) -> Response {
+    log::debug!("comment tokens for lang {lang_name}");
+
+    match self.langs.get(lang_name) {
+      Some(lang) => Response::CommentTokens {
+        tokens: lang.comment.clone(),
+      },
+      None => self.unsupported_language(lang_name),
+    }
+  }
+
+  /// Pause highlighting for a session, or resume it.
+  pub fn handle_set_enabled(&mut self, session_name: &str, enabled: bool) -> Response {
+    log::info!(
+      "{} highlighting for session {session_name}",
+      if enabled { "resuming" } else { "pausing" }
+    );
+
+    if enabled {
+      self.paused.remove(session_name);
+    } else {
+      self.paused.insert(session_name.to_owned());
+    }
+
+    Response::HighlightingEnabled { enabled }
+  }
+
   pub fn handle_try_enable_highlight(
     &mut self,
     session_name: impl AsRef<str>,
     lang_name: &str,
+    buffer: Option<&str>,
   ) -> Result<Response, OhNo> {
     let session_name = session_name.as_ref();
 
     log::info!("try enable highlight for language {lang_name}, session {session_name}");
 
-    let lang = self.langs.get(lang_name);
-    let supported = lang.is_some();
+    // the buffer might have been closed and opened again; its status must be reported again
+    if let Some(buffer) = buffer {
+      self
+        .highlighted
+        .remove(&BufferId::new(session_name, buffer));
+    }
+
+    let lang = self
+      .langs
+      .resolve(lang_name, buffer.unwrap_or_default())
+      .map(|(_, lang)| lang);
+    let supported = lang.map_or(false, Language::supports_highlighting);
     let remove_default_highlighter = lang
       .map(|lang| lang.remove_default_highlighter)
       .unwrap_or_default();
 
-    if !supported {
+    if lang.is_some() && !supported {
+      // the language is known, but without a highlights query; other features still work, so we do not warn the user
+      log::info!("language {lang_name} has no highlights query; keeping the default highlighter");
+    } else if !supported {
       log::warn!("language {lang_name} is not supported");
+
+      // warn the user only once per language; an empty language means no filetype, so we stay silent
+      if !lang_name.is_empty() && self.warned_langs.insert(lang_name.to_owned()) {
+        return Ok(Response::UnsupportedLanguage {
+          lang: lang_name.to_owned(),
+          suggestions: self.langs.suggestions(lang_name),
+        });
+      }
+    }
+
+    // the file is checked before Kakoune starts streaming its content; it would not be highlighted anyway
+    if let Some(size) = buffer
+      .filter(|_| supported)
+      .and_then(|buffer| self.file_too_large(buffer))
+    {
+      log::warn!(
+        "file {} is too large to be highlighted: {size} bytes",
+        buffer.unwrap_or_default()
+      );
+      return Ok(self.buffer_too_large(size));
     }
 
     Ok(Response::FiletypeSupported {
@@ -80,28 +585,424 @@ impl Handler {
     })
   }
 
+  /// Size of the file of a buffer, if larger than [`HighlightConfig::max_buffer_bytes`].
+  ///
+  /// Only absolute paths are checked; other buffers (e.g. scratch buffers) have no file to look at.
+  ///
+  /// [`HighlightConfig::max_buffer_bytes`]: kak_tree_sitter_config::HighlightConfig::max_buffer_bytes
+  fn file_too_large(&self, buffer: &str) -> Option<u64> {
+    let path = Path::new(buffer);
+    if !path.is_absolute() {
+      return None;
+    }
+
+    let size = fs::metadata(path).ok()?.len();
+    (size > self.max_buffer_bytes).then_some(size)
+  }
+
+  fn buffer_too_large(&self, size: u64) -> Response {
+    Response::BufferTooLarge {
+      size,
+      max_size: self.max_buffer_bytes,
+    }
+  }
+
+  /// Status response for requests about a language that is not supported, suggesting close known languages.
+  fn unsupported_language(&self, lang_name: &str) -> Response {
+    let suggestions = self.langs.suggestions(lang_name);
+    log::warn!("language {lang_name} is not supported");
+
+    Response::status(format!(
+      "unsupported language: {lang_name}{}",
+      did_you_mean(&suggestions)
+    ))
+  }
+
+  /// Highlight a buffer, along with its syntax errors if `syntax_errors` is set.
   pub fn handle_highlight(
     &mut self,
     buffer_id: BufferId,
     lang_name: &str,
     timestamp: u64,
     buf: &str,
+    syntax_errors: bool,
+  ) -> Result<Response, OhNo> {
+    self.highlight(buffer_id, lang_name, timestamp, buf, syntax_errors, None)
+  }
+
+  /// Highlight a buffer progressively, so that buffers with many injections (e.g. big Markdown documents) are
+  /// highlighted before all of their injections are resolved.
+  ///
+  /// The highlights of the buffer’s own language are passed to `emit` as soon as they are known, replacing the previous
+  /// ones; the returned response then adds the highlights of the injected languages to them. Buffers without
+  /// injections are highlighted in a single response, as with [`Handler::handle_highlight`].
+  pub fn handle_highlight_progressive(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    timestamp: u64,
+    buf: &str,
+    syntax_errors: bool,
+    emit: &mut dyn FnMut(Response),
+  ) -> Result<Response, OhNo> {
+    self.highlight(
+      buffer_id,
+      lang_name,
+      timestamp,
+      buf,
+      syntax_errors,
+      Some(emit),
+    )
+  }
+
+  /// Highlight a buffer, and write a Kakoune script setting its highlights to `output` instead of sending them.
+  ///
+  /// The highlighting status of the buffer is left untouched, as its highlights are not sent to the session. Failures
+  /// are reported to the session.
+  fn write_highlights(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    timestamp: u64,
+    buf: &str,
+    syntax_errors: bool,
+    output: &Path,
+  ) -> Response {
+    let script =
+      match self.handle_highlight(buffer_id.clone(), lang_name, timestamp, buf, syntax_errors) {
+        Ok(Response::Highlights {
+          timestamp, ranges, ..
+        }) => highlights_script(buffer_id.buffer(), timestamp, &ranges),
+
+        // e.g. the language is not supported
+        Ok(resp) => return resp,
+
+        Err(err) => return Response::status(format!("kak-tree-sitter: {err}")),
+      };
+
+    if let Err(err) = fs::write(output, script) {
+      let err = OhNo::CannotWriteFile {
+        file: output.to_owned(),
+        err,
+      };
+      log::error!("{err}");
+      return Response::status(format!("kak-tree-sitter: {err}"));
+    }
+
+    Response::status(format!(
+      "highlights of {buffer} written to {output}",
+      buffer = buffer_id.buffer(),
+      output = output.display()
+    ))
+  }
+
+  fn highlight(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    timestamp: u64,
+    buf: &str,
+    syntax_errors: bool,
+    emit: Option<&mut dyn FnMut(Response)>,
   ) -> Result<Response, OhNo> {
     log::debug!("highlight for buffer {buffer_id:?}, lang {lang_name}, timestamp {timestamp}");
 
-    let Some(lang) = self.langs.get(lang_name) else {
+    if self.paused.contains(buffer_id.session()) {
+      log::debug!("highlighting is paused for session {}", buffer_id.session());
+      return Ok(Response::HighlightsPaused);
+    }
+
+    if let Some(current) = self
+      .trees
+      .get(&buffer_id)
+      .map(TreeState::timestamp)
+      .filter(|current| timestamp < *current)
+    {
+      if self.on_stale == OnStale::Reparse {
+        log::debug!("buffer {buffer_id:?} was already highlighted at timestamp {current}; asking for its content");
+        return Ok(Response::RefreshBuffer {
+          buffer: buffer_id.buffer().to_owned(),
+        });
+      }
+
+      log::debug!("buffer {buffer_id:?} was already highlighted at timestamp {current}; ignoring stale request");
+      return Ok(Response::StaleHighlights { timestamp, current });
+    }
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      // the buffer might have been highlighted with another language before its filetype changed
+      self.trees.remove(&buffer_id);
+      self.highlighted.remove(&buffer_id);
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let Some(query) = lang.highlight_query() else {
       return Ok(Response::status(format!(
-        "unsupported language: {lang_name}"
+        "no highlights query for language: {lang_name}"
       )));
     };
 
-    let tree_state = Self::compute_tree(&mut self.trees, lang, buffer_id, buf)?;
+    let size = buf.len() as u64;
+    if size > self.max_buffer_bytes {
+      log::warn!("buffer {buffer_id:?} is too large to be highlighted: {size} bytes");
+      self.trees.remove(&buffer_id);
+      self.highlighted.remove(&buffer_id);
+      return Ok(self.buffer_too_large(size));
+    }
+
+    // an empty buffer has nothing to highlight; send empty ranges anyway so that old highlights are removed
+    if buf.is_empty() {
+      log::debug!("empty buffer {buffer_id:?}; clearing highlights");
+      self.trees.remove(&buffer_id);
+      return Ok(Response::Highlights {
+        timestamp,
+        ranges: Vec::new(),
+        activated: false,
+      });
+    }
+
+    let tree_state = match Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id.clone(),
+      buf,
+    ) {
+      Ok(tree_state) => tree_state,
+
+      Err(OhNo::CannotParseBuffer) => {
+        log::debug!("cannot parse buffer; clearing highlights");
+        return Ok(Response::Highlights {
+          timestamp,
+          ranges: Vec::new(),
+          activated: false,
+        });
+      }
+
+      Err(err) => return Err(err),
+    };
+    tree_state.set_timestamp(timestamp);
+
+    if let Some(ranges) = tree_state.highlights(syntax_errors) {
+      log::debug!("buffer {buffer_id:?} did not change; reusing its highlights");
+      return Ok(Response::Highlights {
+        timestamp,
+        ranges: ranges.to_vec(),
+        activated: false,
+      });
+    }
+
+    // syntax errors take precedence over every capture
+    let error_spans = if syntax_errors {
+      tree_state.syntax_error_spans(buf)
+    } else {
+      Vec::new()
+    };
+    let error_face = face_name(&self.face_prefix, "syntax_error");
+    let overlay_errors = |spans| {
+      if syntax_errors {
+        overlay_spans(spans, error_spans.iter().cloned(), &error_face)
+      } else {
+        spans
+      }
+    };
+
+    let tree = tree_state.tree();
+    let highlighter = &mut self.highlighter;
+    let langs = &self.langs;
+    let injection_callback =
+      |lang2: &str| langs.get(lang2).and_then(|lang2| lang2.hl_config.as_ref());
+
+    let Some(emit) = emit else {
+      let spans = highlighter.highlight(tree, buf, query, injection_callback)?;
+      let ranges = KakHighlightRange::from_spans(buf, overlay_errors(spans));
+      tree_state.set_highlights(ranges.clone(), syntax_errors);
 
-    let ranges = tree_state.highlight(lang, buf, |lang2| {
-      self.langs.get(lang2).map(|lang2| &lang2.hl_config)
+      return Ok(Response::Highlights {
+        timestamp,
+        ranges,
+        activated: false,
+      });
+    };
+
+    let (base_spans, has_injections) = highlighter.highlight_base(tree, buf, query, |lang2| {
+      injection_callback(lang2).is_some()
     })?;
+    let ranges = KakHighlightRange::from_spans(buf, overlay_errors(base_spans.clone()));
+    if !has_injections {
+      tree_state.set_highlights(ranges.clone(), syntax_errors);
+      return Ok(Response::Highlights {
+        timestamp,
+        ranges,
+        activated: false,
+      });
+    }
+
+    // the base highlights are sent right away, so the status is tracked here rather than by the caller
+    let base: HashSet<_> = ranges.iter().cloned().collect();
+    emit(Response::Highlights {
+      timestamp,
+      ranges,
+      activated: self.highlighted.insert(buffer_id.clone()),
+    });
+
+    // the base spans are reused if injections can be highlighted on their own; otherwise, the whole buffer is
+    // highlighted again, this time with its injections
+    let mut cursor = self.cursors.acquire();
+    let injections = tree_state.standalone_injections(&query.config.query, buf, &mut cursor);
+    self.cursors.release(cursor);
+
+    let spans = match injections {
+      Some(injections) => {
+        let injections = injections
+          .into_iter()
+          .filter_map(|(region, lang2)| injection_callback(lang2).map(|config| (region, config)));
+        highlighter
+          .highlight_injections(buf, query, injections, injection_callback)
+          .map(|injected| flatten_highlights(base_spans.into_iter().chain(injected).collect()))
+      }
+
+      None => highlighter.highlight(tree, buf, query, injection_callback),
+    };
+
+    let ranges = match spans {
+      Ok(spans) => KakHighlightRange::from_spans(buf, overlay_errors(spans)),
+
+      Err(err) => {
+        // the buffer is highlighted already, only without its injections
+        log::error!("cannot highlight injections of buffer {buffer_id:?}: {err}");
+        return Ok(Response::HighlightsAdded { ranges: Vec::new() });
+      }
+    };
+    tree_state.set_highlights(ranges.clone(), syntax_errors);
+
+    // injected highlights take precedence over the base ones they overlap; Kakoune would merge the faces of overlapping
+    // ranges, so base ranges that do not survive the injections require replacing all the ranges instead of adding some
+    let full: HashSet<_> = ranges.iter().collect();
+    if base.iter().all(|range| full.contains(range)) {
+      Ok(Response::HighlightsAdded {
+        ranges: ranges
+          .into_iter()
+          .filter(|range| !base.contains(range))
+          .collect(),
+      })
+    } else {
+      Ok(Response::Highlights {
+        timestamp,
+        ranges,
+        activated: false,
+      })
+    }
+  }
+
+  /// Mark a buffer as highlighted (or not), depending on the result of highlighting it.
+  ///
+  /// The status is only reported to Kakoune when it changes; a failure for a buffer that was not highlighted is left
+  /// as-is.
+  fn track_highlight_status(
+    &mut self,
+    buffer_id: BufferId,
+    resp: Result<Response, OhNo>,
+  ) -> Result<Response, OhNo> {
+    match resp {
+      Ok(Response::Highlights {
+        timestamp, ranges, ..
+      }) => {
+        let activated = self.highlighted.insert(buffer_id);
+        Ok(Response::Highlights {
+          timestamp,
+          ranges,
+          activated,
+        })
+      }
 
-    Ok(Response::Highlights { timestamp, ranges })
+      Err(err) if self.highlighted.remove(&buffer_id) => {
+        log::error!("cannot highlight buffer {buffer_id:?}: {err}");
+        Ok(Response::HighlightFailed)
+      }
+
+      resp => resp,
+    }
+  }
+
+  /// Highlight several buffers, whose contents are concatenated in `buf`, each one followed by [`BATCH_DELIMITER`].
+  ///
+  /// Buffers failing to be highlighted are logged and reported in the failures of the response, without failing the
+  /// whole batch.
+  pub fn handle_highlight_batch(
+    &mut self,
+    session_name: &str,
+    items: &[HighlightItem],
+    buf: &str,
+  ) -> Response {
+    log::debug!("highlight batch of {} buffers", items.len());
+
+    let mut highlights = Vec::with_capacity(items.len());
+    let mut failures = Vec::new();
+
+    for (item, content) in items.iter().zip(buf.split(BATCH_DELIMITER)) {
+      let buffer_id = BufferId::new(session_name, &item.buffer);
+
+      match self.handle_highlight(
+        buffer_id.clone(),
+        &item.lang,
+        item.timestamp,
+        content,
+        item.syntax_errors,
+      ) {
+        Ok(Response::Highlights {
+          timestamp, ranges, ..
+        }) => highlights.push(BufferHighlights {
+          buffer: item.buffer.clone(),
+          timestamp,
+          ranges,
+          activated: self.highlighted.insert(buffer_id),
+        }),
+
+        // a more recent version of the buffer is highlighted already; the next batch sends its current content anyway
+        Ok(
+          Response::StaleHighlights { .. }
+          | Response::RefreshBuffer { .. }
+          | Response::HighlightsPaused,
+        ) => (),
+
+        Ok(Response::BufferTooLarge { size, max_size }) => {
+          let reason = format!("buffer too large ({size} bytes, more than {max_size})");
+          failures.push(BufferFailure {
+            buffer: item.buffer.clone(),
+            reason,
+          });
+        }
+
+        Ok(_) => {
+          let reason = format!(
+            "language {} is unsupported or has no highlights query",
+            item.lang
+          );
+          log::warn!("cannot highlight buffer {}: {reason}", item.buffer);
+          failures.push(BufferFailure {
+            buffer: item.buffer.clone(),
+            reason,
+          });
+        }
+
+        Err(err) => {
+          log::error!("cannot highlight buffer {}: {err}", item.buffer);
+          self.highlighted.remove(&buffer_id);
+          failures.push(BufferFailure {
+            buffer: item.buffer.clone(),
+            reason: err.to_string(),
+          });
+        }
+      }
+    }
+
+    Response::HighlightBatch {
+      highlights,
+      failures,
+    }
   }
 
   pub fn handle_text_objects(
@@ -115,15 +1016,529 @@ impl Handler {
   ) -> Result<Response, OhNo> {
     log::debug!("text-objects {pattern} for buffer {buffer_id:?}, lang {lang_name}");
 
-    let Some(lang) = self.langs.get(lang_name) else {
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let mut cursor = self.cursors.acquire();
+    let sels = tree_state.text_objects(lang, buf, pattern, selections, mode, &mut cursor);
+    self.cursors.release(cursor);
+
+    Ok(Response::Selections { sels: sels? })
+  }
+
+  pub fn handle_toggle_comment(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    sels: &[Sel],
+  ) -> Result<Response, OhNo> {
+    log::debug!("toggle comment for buffer {buffer_id:?}, lang {lang_name}, selections {sels:?}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    let edits = tree_state.toggle_comment(lang, buf, sels)?;
+    Ok(Response::Edits { edits })
+  }
+
+  pub fn handle_select_siblings(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    sels: &[Sel],
+    named_only: bool,
+  ) -> Result<Response, OhNo> {
+    log::debug!("select siblings for buffer {buffer_id:?}, lang {lang_name}, selections {sels:?}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    Ok(Response::Selections {
+      sels: tree_state.siblings(sels, named_only),
+    })
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub fn handle_navigate_node(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    sels: &[Sel],
+    kind: &str,
+    direction: Direction,
+    wrap: bool,
+  ) -> Result<Response, OhNo> {
+    log::debug!(
+      "navigate to {direction:?} {kind} node for buffer {buffer_id:?}, lang {lang_name}, selections {sels:?}"
+    );
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    let sels = tree_state.navigate(sels, kind, direction, wrap);
+    if sels.is_empty() {
+      let place = match direction {
+        Direction::Next => "after",
+        Direction::Prev => "before",
+      };
       return Ok(Response::status(format!(
-        "unsupported language: {lang_name}"
+        "no {kind} node found {place} the selections"
       )));
+    }
+
+    Ok(Response::Selections { sels })
+  }
+
+  pub fn handle_indent(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    line: usize,
+    style: &IndentStyle,
+  ) -> Result<Response, OhNo> {
+    log::debug!("indent line {line} for buffer {buffer_id:?}, lang {lang_name}, style {style:?}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    let mut cursor = self.cursors.acquire();
+    let level = tree_state.indent_level(lang, buf, line, &mut cursor);
+    self.cursors.release(cursor);
+
+    let style = style
+      .clone()
+      .with_unit(&lang.indent_unit, lang.significant_indent);
+    Ok(Response::Indent {
+      line,
+      indent: style.indentation(level?),
+    })
+  }
+
+  pub fn handle_dump_tree(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    range: Option<(usize, usize)>,
+  ) -> Result<Response, OhNo> {
+    log::debug!("dump tree for buffer {buffer_id:?}, lang {lang_name}, range {range:?}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let sexp = tree_state.sexp(range).ok_or(OhNo::InvalidByteRange {
+      start: range.map(|r| r.0).unwrap_or_default(),
+      end: range.map(|r| r.1).unwrap_or_default(),
+    })?;
+
+    Ok(Response::TreeDump { sexp })
+  }
+
+  pub fn handle_node_info(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    pos: Pos,
+  ) -> Result<Response, OhNo> {
+    log::debug!("node info for buffer {buffer_id:?}, lang {lang_name}, position {pos:?}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
     };
 
-    let tree_state = Self::compute_tree(&mut self.trees, lang, buffer_id, buf)?;
-    let sels = tree_state.text_objects(lang, buf, pattern, selections, mode)?;
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    match tree_state.node_info(pos) {
+      Some(info) => Ok(Response::NodeInfo { info }),
+      None => Ok(Response::status(format!(
+        "no node at {line}.{col}",
+        line = pos.line,
+        col = pos.col
+      ))),
+    }
+  }
+
+  /// List the regions of a buffer injected with another language, along with the language highlighting them; see
+  /// [`TreeState::injections`].
+  pub fn handle_injections(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+  ) -> Result<Response, OhNo> {
+    log::debug!("injections for buffer {buffer_id:?}, lang {lang_name}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    // injections are part of the highlights query
+    let Some(hl_config) = &lang.hl_config else {
+      return Ok(Response::status(format!(
+        "no highlights query for language: {lang_name}"
+      )));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+
+    // same languages as the ones injection highlighting picks
+    let langs = &self.langs;
+    let resolve = |lang2: &str| {
+      langs
+        .lookup(lang2)
+        .filter(|(_, lang2)| lang2.hl_config.is_some())
+        .map(|(lang2_name, _)| lang2_name.to_owned())
+    };
+
+    let mut cursor = self.cursors.acquire();
+    let injections = tree_state.injections(&hl_config.query, buf, &mut cursor, resolve);
+    self.cursors.release(cursor);
+
+    Ok(Response::Injections { injections })
+  }
+
+  /// Get the captures of the highlights query on the file at `path`, along with their faces; see
+  /// [`TreeState::highlight_captures`].
+  ///
+  /// This is meant to debug queries, so the file is parsed on its own; trees kept for buffers and parse metrics are left
+  /// untouched.
+  pub fn dump_captures(
+    &mut self,
+    lang_name: &str,
+    path: &Path,
+  ) -> Result<Vec<HighlightCapture>, OhNo> {
+    log::debug!("dump captures of {}, lang {lang_name}", path.display());
+
+    let resolved = self
+      .langs
+      .resolve(lang_name, path.to_str().unwrap_or_default());
+    let Some((lang_name, lang)) = resolved else {
+      return Err(OhNo::UnsupportedLanguage {
+        lang: lang_name.to_owned(),
+      });
+    };
+
+    let query = lang
+      .highlight_query()
+      .ok_or_else(|| OhNo::NoHighlightsQuery {
+        lang: lang_name.to_owned(),
+      })?;
+
+    let size = fs::metadata(path)
+      .map_err(|err| OhNo::CannotReadFile {
+        file: path.to_owned(),
+        err,
+      })?
+      .len();
+    if size > self.max_buffer_bytes {
+      return Err(OhNo::FileTooLarge {
+        file: path.to_owned(),
+        size,
+        max_size: self.max_buffer_bytes,
+      });
+    }
+
+    let buf = fs::read(path).map_err(|err| OhNo::CannotReadFile {
+      file: path.to_owned(),
+      err,
+    })?;
+    let buf = buffer::decode(&buf);
+
+    let parser = Self::parser(&mut self.parsers, lang_name, lang)?;
+    let tree_state = TreeState::new(lang_name, parser, &buf)?;
+    let mut cursor = self.cursors.acquire();
+    let captures = tree_state.highlight_captures(query, &buf, &mut cursor);
+    self.cursors.release(cursor);
+
+    Ok(captures)
+  }
+
+  pub fn handle_run_query(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    query: &str,
+  ) -> Result<Response, OhNo> {
+    log::debug!("run query for buffer {buffer_id:?}, lang {lang_name}: {query}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let query = match Self::user_query(lang, query) {
+      Ok(query) => query,
+      Err(resp) => return Ok(resp),
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let mut cursor = self.cursors.acquire();
+    let captures = tree_state.captures(&query, buf, &mut cursor);
+    self.cursors.release(cursor);
+
+    Ok(Response::QueryCaptures { captures })
+  }
+
+  pub fn handle_select_matching(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    buf: &str,
+    query: &str,
+  ) -> Result<Response, OhNo> {
+    log::debug!("select matching for buffer {buffer_id:?}, lang {lang_name}: {query}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let query = match Self::user_query(lang, query) {
+      Ok(query) => query,
+      Err(resp) => return Ok(resp),
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let mut cursor = self.cursors.acquire();
+    let captures = tree_state.captures(&query, buf, &mut cursor);
+    self.cursors.release(cursor);
+
+    // several captures can select the same node
+    let mut sels: Vec<_> = captures.into_iter().map(|capture| capture.sel).collect();
+    sels.sort_by_key(|sel| (sel.anchor, sel.cursor));
+    sels.dedup();
+
+    if sels.is_empty() {
+      return Ok(Response::status("no node matches the query"));
+    }
+
+    if sels.len() > self.max_selections {
+      log::warn!(
+        "query matched {count} nodes; only the first {max} are selected",
+        count = sels.len(),
+        max = self.max_selections
+      );
+      sels.truncate(self.max_selections);
+    }
 
     Ok(Response::Selections { sels })
   }
+
+  /// Compile a user-provided query.
+  ///
+  /// Such a query is likely to be invalid while being written, so errors are returned as a status response for the
+  /// user to see.
+  fn user_query(lang: &Language, query: &str) -> Result<Query, Response> {
+    Query::new(lang.lang(), query).map_err(|err| {
+      Response::status(format!(
+        "invalid query (offset {offset}): {err}",
+        offset = err.offset
+      ))
+    })
+  }
+
+  pub fn handle_prose_ranges(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    timestamp: u64,
+    buf: &str,
+  ) -> Result<Response, OhNo> {
+    log::debug!("prose ranges for buffer {buffer_id:?}, lang {lang_name}, timestamp {timestamp}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let mut cursor = self.cursors.acquire();
+    let ranges = tree_state.prose_ranges(lang, buf, &mut cursor);
+    self.cursors.release(cursor);
+
+    Ok(Response::ProseRanges {
+      timestamp,
+      ranges,
+      face: face_name(&self.face_prefix, "spell"),
+    })
+  }
+
+  pub fn handle_syntax_errors(
+    &mut self,
+    buffer_id: BufferId,
+    lang_name: &str,
+    timestamp: u64,
+    buf: &str,
+  ) -> Result<Response, OhNo> {
+    log::debug!("syntax errors for buffer {buffer_id:?}, lang {lang_name}, timestamp {timestamp}");
+
+    let Some((lang_name, lang)) = self.langs.resolve(lang_name, buffer_id.buffer()) else {
+      return Ok(self.unsupported_language(lang_name));
+    };
+
+    let tree_state = Self::compute_tree(
+      &mut self.trees,
+      &mut self.parsers,
+      &mut self.metrics,
+      lang_name,
+      lang,
+      buffer_id,
+      buf,
+    )?;
+    let ranges = tree_state.syntax_errors();
+
+    Ok(Response::SyntaxErrors {
+      timestamp,
+      ranges,
+      face: face_name(&self.face_prefix, "syntax_error"),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::Handler;
+  use crate::{buffer::BufferId, error::OhNo, metrics::ParseMetrics, tree_cache::TreeCache};
+
+  #[test]
+  fn parse_failure_drops_stale_tree() {
+    let mut trees = TreeCache::default();
+    let mut metrics = ParseMetrics::default();
+    let buffer_id = BufferId::new("session", "/tmp/a.rs");
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    Handler::parse_buffer(
+      &mut trees,
+      &mut parser,
+      &mut metrics,
+      "rust",
+      buffer_id.clone(),
+      "fn main() {}",
+    )
+    .unwrap();
+    assert!(trees.get(&buffer_id).is_some());
+
+    // a parser without a language always fails to parse
+    let res = Handler::parse_buffer(
+      &mut trees,
+      &mut Parser::new(),
+      &mut metrics,
+      "rust",
+      buffer_id.clone(),
+      "fn main() { 1 }",
+    );
+    assert!(matches!(res, Err(OhNo::CannotParseBuffer)));
+    assert!(trees.get(&buffer_id).is_none());
+    assert_eq!(metrics.parses, 1);
+  }
 }
@@ -1,12 +1,282 @@
-//! Convert from tree-sitter-highlight events to Kakoune ranges highlighter.
+//! Highlighting of parsed buffers, and conversion from tree-sitter-highlight events to Kakoune ranges highlighter.
+//!
+//! A [`Highlighter`] runs a [`HighlightQuery`] on the tree of a buffer, and yields the highlighted byte spans of the
+//! buffer along with their face. Those spans are then converted to [`KakHighlightRange`], as expected by Kakoune.
 
-use tree_sitter_highlight::{Highlight, HighlightEvent};
+use std::{collections::BinaryHeap, ops::Range};
+
+use serde::Serialize;
+use tree_sitter::{Node, Tree};
+use tree_sitter_highlight::{Highlight, HighlightConfiguration, HighlightEvent};
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::error::OhNo;
+
+/// Query to highlight a buffer with.
+#[derive(Clone, Copy)]
+pub struct HighlightQuery<'a> {
+  pub config: &'a HighlightConfiguration,
+
+  /// Names of the highlights the configuration was configured with.
+  pub names: &'a [String],
+
+  /// Kakoune faces of the highlights, by index in `names`; see [`face_name`].
+  ///
+  /// They are computed once per query, as every highlighted span of every buffer needs one.
+  pub faces: &'a [String],
+
+  /// Face of the text that no highlight covers.
+  pub unknown_face: &'a str,
+
+  /// Faces of the captures of the query, by capture index; captures whose group is not configured (or is disabled) have
+  /// none. See [`capture_group`].
+  pub capture_faces: &'a [Option<String>],
+
+  /// Priorities of the highlights, by index in `names`; where highlights are nested, the highest priority wins.
+  ///
+  /// Missing priorities are lower than any other.
+  pub priorities: &'a [u32],
+
+  /// Whether anonymous nodes (keywords, operators, punctuation, etc.) are highlighted.
+  pub anonymous_nodes: bool,
+}
+
+/// Byte span of a buffer, along with the face of its highlight (e.g. `ts_keyword_control`).
+pub type HighlightSpan<'a> = (Range<usize>, &'a str);
+
+/// Highlighter of buffers, reused across buffers and requests.
+pub struct Highlighter {
+  // TODO: for now, we don’t support custom highligthing, and hence have to use tree-sitter-highlight; see
+  // #26 for further information
+  highlighter: tree_sitter_highlight::Highlighter,
+}
+
+impl Default for Highlighter {
+  fn default() -> Self {
+    Self {
+      highlighter: tree_sitter_highlight::Highlighter::new(),
+    }
+  }
+}
+
+impl Highlighter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Highlight a buffer, given its tree.
+  ///
+  /// Languages injected in the buffer are highlighted with the configuration returned by `injection_callback`, if any.
+  /// The highlighted spans are sorted and disjoint; see [`flatten_highlights`].
+  pub fn highlight<'a>(
+    &mut self,
+    tree: &Tree,
+    buf: &str,
+    query: HighlightQuery<'a>,
+    mut injection_callback: impl FnMut(&str) -> Option<&'a HighlightConfiguration>,
+  ) -> Result<Vec<HighlightSpan<'a>>, OhNo> {
+    let events = self
+      .highlighter
+      .highlight(query.config, buf.as_bytes(), None, |lang| {
+        injection_callback(lang)
+      })
+      .map_err(|err| OhNo::HighlightError {
+        err: err.to_string(),
+      })?
+      .flatten();
+
+    let spans = if query.anonymous_nodes {
+      event_spans(query.faces, query.unknown_face, query.priorities, events)
+    } else {
+      let events = without_anonymous_nodes(tree.root_node(), events.collect());
+      event_spans(
+        query.faces,
+        query.unknown_face,
+        query.priorities,
+        events.into_iter(),
+      )
+    };
+
+    Ok(flatten_highlights(spans))
+  }
+
+  /// Highlight a buffer with its own language only, leaving injections out.
+  ///
+  /// Also return whether the buffer has injections of languages for which `can_highlight` holds.
+  pub fn highlight_base<'a>(
+    &mut self,
+    tree: &Tree,
+    buf: &str,
+    query: HighlightQuery<'a>,
+    mut can_highlight: impl FnMut(&str) -> bool,
+  ) -> Result<(Vec<HighlightSpan<'a>>, bool), OhNo> {
+    let mut has_injections = false;
+    let spans = self.highlight(tree, buf, query, |lang| {
+      has_injections |= can_highlight(lang);
+      None
+    })?;
+
+    Ok((spans, has_injections))
+  }
+
+  /// Highlight regions of a buffer injected with other languages, each with the configuration of its language; see
+  /// [`TreeState::standalone_injections`](crate::tree_sitter_state::TreeState::standalone_injections).
+  ///
+  /// Languages injected in those regions are highlighted with the configuration returned by `injection_callback`, if
+  /// any. Text that no highlight covers is left out, so that the spans can be laid over the ones of
+  /// [`Highlighter::highlight_base`], as when highlighting the whole buffer at once.
+  pub fn highlight_injections<'a>(
+    &mut self,
+    buf: &str,
+    query: HighlightQuery<'a>,
+    injections: impl IntoIterator<Item = (Range<usize>, &'a HighlightConfiguration)>,
+    mut injection_callback: impl FnMut(&str) -> Option<&'a HighlightConfiguration>,
+  ) -> Result<Vec<HighlightSpan<'a>>, OhNo> {
+    let mut spans = Vec::new();
+
+    for (region, config) in injections {
+      let events = self
+        .highlighter
+        .highlight(config, buf[region.clone()].as_bytes(), None, |lang| {
+          injection_callback(lang)
+        })
+        .map_err(|err| OhNo::HighlightError {
+          err: err.to_string(),
+        })?
+        .flatten();
+
+      let region_spans = event_spans(query.faces, query.unknown_face, query.priorities, events);
+      spans.extend(
+        region_spans
+          .into_iter()
+          .filter(|(_, face)| *face != query.unknown_face)
+          .map(|(span, face)| (span.start + region.start..span.end + region.start, face)),
+      );
+    }
+
+    Ok(spans)
+  }
+}
+
+/// Kakoune face of a highlight group, given the configured prefix; e.g. `ts_keyword_control` for `keyword.control`.
+///
+/// The text that no highlight covers has the `unknown` face; e.g. `ts_unknown`.
+pub fn face_name(prefix: &str, group: &str) -> String {
+  format!("{prefix}{}", group.replace('.', "_"))
+}
+
+/// Group a capture is highlighted with, among `groups`, if any.
+///
+/// This is how tree-sitter-highlight resolves captures: the group with the most parts (e.g. `keyword.control` rather
+/// than `keyword`), all of them found in the name of the capture, wins.
+pub fn capture_group<'a>(capture_name: &str, groups: &'a [String]) -> Option<&'a str> {
+  let capture_parts: Vec<_> = capture_name.split('.').collect();
+
+  groups
+    .iter()
+    .filter(|group| group.split('.').all(|part| capture_parts.contains(&part)))
+    .fold(None, |best: Option<&String>, group| match best {
+      Some(best) if best.split('.').count() >= group.split('.').count() => Some(best),
+      _ => Some(group),
+    })
+    .map(String::as_str)
+}
+
+/// Byte spans of highlight events, along with the face of their highlight, given by index in `faces`; spans without
+/// highlights get `unknown_face`.
+///
+/// Where highlights are nested, a span gets the one with the highest priority (see [`HighlightQuery::priorities`]), or
+/// the innermost one on equal priorities.
+fn event_spans<'a>(
+  faces: &'a [String],
+  unknown_face: &'a str,
+  priorities: &[u32],
+  hl_events: impl Iterator<Item = HighlightEvent>,
+) -> Vec<HighlightSpan<'a>> {
+  let mut spans = Vec::new();
+  let mut active: Vec<usize> = Vec::new();
+
+  // iterate on the highlight event
+  for event in hl_events {
+    match event {
+      HighlightEvent::Source { start, end } => {
+        // max_by_key keeps the last of the maximum elements; i.e. the innermost highlight
+        let face = active
+          .iter()
+          .max_by_key(|&&idx| priorities.get(idx).copied().unwrap_or_default())
+          .map_or(unknown_face, |&idx| faces[idx].as_str());
+        spans.push((start..end, face));
+      }
+
+      HighlightEvent::HighlightStart(Highlight(idx)) => {
+        if idx >= faces.len() {
+          log::error!(
+            "unrecognized highlight group index: {idx} (len: {len}), faces = {faces:?}",
+            len = faces.len()
+          );
+        } else {
+          active.push(idx);
+        }
+      }
+
+      HighlightEvent::HighlightEnd => {
+        active.pop();
+      }
+    }
+  }
+
+  spans
+}
+
+/// Remove the highlights of anonymous nodes (e.g. keywords, operators and punctuation), keeping their text.
+///
+/// Only the nodes of `root` are considered; anonymous nodes of injected languages are still highlighted.
+fn without_anonymous_nodes(root: Node, events: Vec<HighlightEvent>) -> Vec<HighlightEvent> {
+  // the innermost node of a span might be an anonymous token wrapped by a named node with the same span (e.g. `true` in
+  // a boolean literal); the span is only anonymous if none of those nodes is named
+  let is_anonymous = |start, end| {
+    let same_span = |node: &Node| node.start_byte() == start && node.end_byte() == end;
+    let Some(mut node) = root.descendant_for_byte_range(start, end).filter(same_span) else {
+      return false;
+    };
+
+    loop {
+      if node.is_named() {
+        return false;
+      }
+
+      match node.parent().filter(same_span) {
+        Some(parent) => node = parent,
+        None => return true,
+      }
+    }
+  };
+
+  let mut kept = Vec::with_capacity(events.len());
+  let mut i = 0;
+  while i < events.len() {
+    // a capture of a single node starts a highlight, has the text of the node, and ends the highlight
+    if let [HighlightEvent::HighlightStart(_), HighlightEvent::Source { start, end }, HighlightEvent::HighlightEnd, ..] =
+      events[i..]
+    {
+      if is_anonymous(start, end) {
+        kept.push(HighlightEvent::Source { start, end });
+        i += 3;
+        continue;
+      }
+    }
+
+    kept.push(events[i]);
+    i += 1;
+  }
+
+  kept
+}
+
 /// A convenient representation of a single highlight range for Kakoune.
 ///
 /// `:doc highlighters`, `ranges`, for further documentation.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct KakHighlightRange {
   line_start: usize,
   col_byte_start: usize,
@@ -33,60 +303,43 @@ impl KakHighlightRange {
   }
 
   /// Given an iterator of [`HighlightEvent`], generate a list of Kakoune highlights.
+  ///
+  /// Ranges are sorted and never overlap, so that each byte gets a single face: where captures are nested, the innermost
+  /// one wins. A node captured by several patterns is already resolved by tree-sitter-highlight, which keeps the first
+  /// pattern of the query. See [`flatten_highlights`].
   pub fn from_iter(
     source: &str,
+    face_prefix: &str,
     hl_names: &[String],
     hl_events: impl Iterator<Item = HighlightEvent>,
   ) -> Vec<Self> {
-    let mut kak_hls = Vec::new();
-    let mut faces: Vec<&str> = Vec::new();
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
-
-    // iterate on the highlight event
-    for event in hl_events {
-      match event {
-        HighlightEvent::Source { start, end } => {
-          if start == end {
-            continue;
-          }
-
-          mapper.advance(start);
-          let line_start = mapper.line();
-          let col_byte_start = mapper.col_byte();
-
-          mapper.advance(end - 1);
-          let line_end = mapper.line();
-          let col_byte_end = mapper.col_byte();
-
-          let face = faces.last().copied().unwrap_or("unknown");
-
-          kak_hls.push(KakHighlightRange::new(
-            line_start,
-            col_byte_start,
-            line_end,
-            col_byte_end,
-            format!("ts_{}", face.replace('.', "_")),
-          ));
-        }
-
-        HighlightEvent::HighlightStart(Highlight(idx)) => {
-          if idx >= hl_names.len() {
-            log::error!(
-              "unrecognized highlight group index: {idx} (len: {len}), groups = {hl_names:?}",
-              len = hl_names.len()
-            );
-          } else {
-            faces.push(&hl_names[idx]);
-          }
-        }
-
-        HighlightEvent::HighlightEnd => {
-          faces.pop();
-        }
-      }
-    }
+    let faces: Vec<_> = hl_names
+      .iter()
+      .map(|name| face_name(face_prefix, name))
+      .collect();
+    let unknown_face = face_name(face_prefix, "unknown");
+    let spans = event_spans(&faces, &unknown_face, &[], hl_events);
+    Self::from_spans(source, flatten_highlights(spans))
+  }
 
-    kak_hls
+  /// Convert sorted, disjoint byte spans of `source` to Kakoune highlights, as returned by [`Highlighter::highlight`].
+  pub fn from_spans(source: &str, spans: Vec<HighlightSpan>) -> Vec<Self> {
+    // the mapper only goes forward, which is fine as spans are sorted
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    spans
+      .into_iter()
+      .map(|(span, face)| {
+        mapper.advance(span.start);
+        let line_start = mapper.line();
+        let col_byte_start = mapper.col_byte();
+
+        mapper.advance(span.end - 1);
+        let line_end = mapper.line();
+        let col_byte_end = mapper.col_byte();
+
+        KakHighlightRange::new(line_start, col_byte_start, line_end, col_byte_end, face)
+      })
+      .collect()
   }
 
   /// Display as a string recognized by the `ranges` Kakoune highlighter.
@@ -102,6 +355,67 @@ impl KakHighlightRange {
   }
 }
 
+/// Highlight `overlay` spans with `face` over sorted, disjoint `spans`, e.g. to show syntax errors; the result is sorted
+/// and disjoint as well.
+pub fn overlay_spans<'a>(
+  mut spans: Vec<HighlightSpan<'a>>,
+  overlay: impl IntoIterator<Item = Range<usize>>,
+  face: &'a str,
+) -> Vec<HighlightSpan<'a>> {
+  // the spans given last win
+  spans.extend(overlay.into_iter().map(|span| (span, face)));
+  flatten_highlights(spans)
+}
+
+/// Flatten byte spans of highlights into sorted, disjoint spans, as expected by the `ranges` Kakoune highlighter.
+///
+/// Where spans overlap, the one given last wins; that is the order in which nested captures start, so the innermost
+/// capture wins. Empty spans are dropped, and adjacent spans with the same face are merged.
+pub fn flatten_highlights<F>(hls: Vec<(Range<usize>, F)>) -> Vec<(Range<usize>, F)>
+where
+  F: Clone + PartialEq,
+{
+  // the winning span can only change at the bounds of spans
+  let mut bounds: Vec<_> = hls
+    .iter()
+    .flat_map(|(span, _)| [span.start, span.end])
+    .collect();
+  bounds.sort_unstable();
+  bounds.dedup();
+
+  // indices of the spans by start; the stable sort keeps the given order of spans starting at the same byte
+  let mut by_start: Vec<_> = (0..hls.len()).filter(|&i| !hls[i].0.is_empty()).collect();
+  by_start.sort_by_key(|&i| hls[i].0.start);
+  let mut by_start = by_start.into_iter().peekable();
+
+  // spans covering the current byte, the winning one on top; ended spans are only removed when they reach the top
+  let mut covering = BinaryHeap::new();
+  let mut flattened: Vec<(Range<usize>, F)> = Vec::new();
+
+  for segment in bounds.windows(2) {
+    let (start, end) = (segment[0], segment[1]);
+
+    while let Some(i) = by_start.next_if(|&i| hls[i].0.start <= start) {
+      covering.push(i);
+    }
+    while covering.peek().map_or(false, |&i| hls[i].0.end <= start) {
+      covering.pop();
+    }
+
+    let Some(&i) = covering.peek() else {
+      continue;
+    };
+    let face = &hls[i].1;
+
+    match flattened.last_mut() {
+      Some((last, last_face)) if last.end == start && last_face == face => last.end = end,
+      _ => flattened.push((start..end, face.clone())),
+    }
+  }
+
+  flattened
+}
+
 /// Map byte indices to line and column.
 #[derive(Debug)]
 struct ByteLineColMapper<C> {
@@ -144,7 +458,7 @@ where
       }
 
       if let Some(grapheme) = self.chars.next() {
-        let bytes = grapheme.as_bytes().len();
+        let bytes = grapheme.len();
         self.byte_idx += bytes;
 
         if Self::should_change_line(grapheme) {
@@ -162,10 +476,245 @@ where
 
 #[cfg(test)]
 mod tests {
-  use tree_sitter_highlight::{Highlight, HighlightConfiguration, HighlightEvent, Highlighter};
+  use tree_sitter::{Parser, Tree};
+  use tree_sitter_highlight::{Highlight, HighlightConfiguration, HighlightEvent};
   use unicode_segmentation::UnicodeSegmentation;
 
-  use super::ByteLineColMapper;
+  use super::{
+    capture_group, face_name, flatten_highlights, overlay_spans, ByteLineColMapper, HighlightQuery,
+    Highlighter, KakHighlightRange,
+  };
+
+  fn parse(source: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    parser.parse(source, None).unwrap()
+  }
+
+  /// Highlight `source` with `query`, returning the Kakoune ranges of the highlighted parts only.
+  fn kak_ranges(query: &str, source: &str) -> Vec<String> {
+    kak_ranges_with_priorities(query, source, &[])
+  }
+
+  /// Same as [`kak_ranges`], with the priorities of the `function`, `variable`, `punctuation.bracket` and `keyword`
+  /// groups.
+  fn kak_ranges_with_priorities(query: &str, source: &str, priorities: &[u32]) -> Vec<String> {
+    let hl_names = ["function", "variable", "punctuation.bracket", "keyword"].map(String::from);
+    let mut hl_conf =
+      HighlightConfiguration::new(tree_sitter_rust::language(), query, "", "").unwrap();
+    hl_conf.configure(&hl_names);
+
+    let faces: Vec<_> = hl_names.iter().map(|name| face_name("ts_", name)).collect();
+    let query = HighlightQuery {
+      config: &hl_conf,
+      names: &hl_names,
+      faces: &faces,
+      unknown_face: "ts_unknown",
+      capture_faces: &[],
+      priorities,
+      anonymous_nodes: true,
+    };
+    let spans = Highlighter::new()
+      .highlight(&parse(source), source, query, |_| None)
+      .unwrap();
+
+    KakHighlightRange::from_spans(source, spans)
+      .iter()
+      .map(KakHighlightRange::to_kak_range_str)
+      .filter(|range| !range.ends_with("|ts_unknown"))
+      .collect()
+  }
+
+  #[test]
+  fn overlapping_captures() {
+    let source = "fn f() { foo(bar); }";
+
+    // both patterns capture foo; the first one in the query wins
+    let specific_first =
+      "(call_expression function: (identifier) @function)\n(identifier) @variable\n";
+    assert_eq!(
+      kak_ranges(specific_first, source),
+      [
+        "1.4,1.4|ts_variable",
+        "1.10,1.12|ts_function",
+        "1.14,1.16|ts_variable"
+      ]
+    );
+
+    let generic_first =
+      "(identifier) @variable\n(call_expression function: (identifier) @function)\n";
+    assert_eq!(
+      kak_ranges(generic_first, source),
+      [
+        "1.4,1.4|ts_variable",
+        "1.10,1.12|ts_variable",
+        "1.14,1.16|ts_variable"
+      ]
+    );
+  }
+
+  #[test]
+  fn nested_captures() {
+    let source = "fn f() { foo(bar); }";
+
+    // the innermost capture wins, and the outer one only covers what is left; ranges never overlap, but adjacent ones
+    // with the same face are merged
+    let query = "(call_expression) @function\n(arguments (identifier) @variable)\n[\"(\" \")\"] @punctuation.bracket\n";
+    assert_eq!(
+      kak_ranges(query, source),
+      [
+        "1.5,1.6|ts_punctuation_bracket",
+        "1.10,1.12|ts_function",
+        "1.13,1.13|ts_punctuation_bracket",
+        "1.14,1.16|ts_variable",
+        "1.17,1.17|ts_punctuation_bracket"
+      ]
+    );
+  }
+
+  #[test]
+  fn nested_captures_priorities() {
+    let source = "fn f() { foo(bar); }";
+    let query = "(call_expression) @function\n(arguments (identifier) @variable)\n";
+
+    // bar is captured by both patterns; the innermost capture wins on equal priorities, the highest one otherwise
+    assert_eq!(
+      kak_ranges_with_priorities(query, source, &[100, 100, 100, 100]),
+      [
+        "1.10,1.13|ts_function",
+        "1.14,1.16|ts_variable",
+        "1.17,1.17|ts_function"
+      ]
+    );
+    assert_eq!(
+      kak_ranges_with_priorities(query, source, &[110, 100, 100, 100]),
+      ["1.10,1.17|ts_function"]
+    );
+    assert_eq!(
+      kak_ranges_with_priorities(query, source, &[100, 90, 100, 100]),
+      ["1.10,1.17|ts_function"]
+    );
+  }
+
+  #[test]
+  fn anonymous_nodes() {
+    let source = "fn add(a: i32) -> i32 { a + 1 } fn t() -> bool { true || false }";
+    let hl_names = [
+      "constant.builtin",
+      "function",
+      "keyword",
+      "operator",
+      "type.builtin",
+    ]
+    .map(str::to_owned);
+    let mut hl_conf = HighlightConfiguration::new(
+      tree_sitter_rust::language(),
+      tree_sitter_rust::HIGHLIGHT_QUERY,
+      "",
+      "",
+    )
+    .unwrap();
+    hl_conf.configure(&hl_names);
+
+    let tree = parse(source);
+    let mut highlighter = Highlighter::new();
+    let faces: Vec<_> = hl_names.iter().map(|name| face_name("ts_", name)).collect();
+    let mut spans = |anonymous_nodes| {
+      let query = HighlightQuery {
+        config: &hl_conf,
+        names: &hl_names,
+        faces: &faces,
+        unknown_face: "ts_unknown",
+        capture_faces: &[],
+        priorities: &[],
+        anonymous_nodes,
+      };
+      let spans = highlighter
+        .highlight(&tree, source, query, |_| None)
+        .unwrap();
+      spans
+        .into_iter()
+        .filter(|(_, face)| *face != "ts_unknown")
+        .collect::<Vec<_>>()
+    };
+
+    assert!(spans(true).contains(&(0..2, "ts_keyword")));
+
+    // `fn`, `+` and `||` are anonymous; `i32` is a named primitive type, and `true` and `false` are anonymous tokens
+    // wrapped by named boolean literals
+    assert_eq!(
+      spans(false),
+      [
+        (3..6, "ts_function"),
+        (10..13, "ts_type_builtin"),
+        (18..21, "ts_type_builtin"),
+        (28..29, "ts_constant_builtin"),
+        (35..36, "ts_function"),
+        (42..46, "ts_type_builtin"),
+        (49..53, "ts_constant_builtin"),
+        (57..62, "ts_constant_builtin")
+      ]
+    );
+  }
+
+  #[test]
+  fn flatten_nested_highlights() {
+    // the innermost span is given last, and splits the outer one
+    assert_eq!(
+      flatten_highlights(vec![(0..10, "outer"), (3..5, "inner"), (4..5, "innermost")]),
+      [
+        (0..3, "outer"),
+        (3..4, "inner"),
+        (4..5, "innermost"),
+        (5..10, "outer")
+      ]
+    );
+
+    // spans out of order are sorted; overlapping spans are cut where the last one starts
+    assert_eq!(
+      flatten_highlights(vec![(6..8, "b"), (0..4, "a"), (2..7, "c")]),
+      [(0..2, "a"), (2..7, "c"), (7..8, "b")]
+    );
+
+    // a span entirely covered by a later one disappears
+    assert_eq!(
+      flatten_highlights(vec![(2..3, "hidden"), (0..5, "wide")]),
+      [(0..5, "wide")]
+    );
+  }
+
+  #[test]
+  fn overlay_syntax_errors() {
+    // overlaid spans win over the ones they overlap, whatever their position
+    assert_eq!(
+      overlay_spans(
+        vec![(0..4, "keyword"), (5..9, "function")],
+        [3..6, 10..11],
+        "error"
+      ),
+      [
+        (0..3, "keyword"),
+        (3..6, "error"),
+        (6..9, "function"),
+        (10..11, "error")
+      ]
+    );
+  }
+
+  #[test]
+  fn flatten_adjacent_highlights() {
+    assert_eq!(
+      flatten_highlights(vec![(0..2, "a"), (2..4, "a"), (4..6, "b"), (7..8, "b")]),
+      [(0..4, "a"), (4..6, "b"), (7..8, "b")]
+    );
+
+    // empty spans are dropped, and do not prevent merging
+    assert_eq!(
+      flatten_highlights(vec![(0..2, "a"), (2..2, "b"), (2..3, "a")]),
+      [(0..3, "a")]
+    );
+    assert!(flatten_highlights::<&str>(Vec::new()).is_empty());
+  }
 
   #[test]
   fn idempotent_mapper() {
@@ -301,7 +850,7 @@ mod tests {
     .unwrap();
     hl_conf.configure(&hl_names);
 
-    let mut hl = Highlighter::new();
+    let mut hl = tree_sitter_highlight::Highlighter::new();
     let events: Vec<_> = hl
       .highlight(&hl_conf, source.as_bytes(), None, |_| None)
       .unwrap()
@@ -386,4 +935,21 @@ mod tests {
       ]
     ));
   }
+
+  #[test]
+  fn capture_groups() {
+    let groups = ["keyword", "keyword.control", "function.macro", "function"].map(str::to_owned);
+
+    // the most specific group wins, whatever the order of the parts of the capture
+    assert_eq!(
+      capture_group("keyword.control.rust", &groups),
+      Some("keyword.control")
+    );
+    assert_eq!(capture_group("keyword.storage", &groups), Some("keyword"));
+    assert_eq!(
+      capture_group("macro.function", &groups),
+      Some("function.macro")
+    );
+    assert_eq!(capture_group("variable", &groups), None);
+  }
 }
@@ -0,0 +1,166 @@
+//! Indentation support.
+//!
+//! Indent levels are computed from the `indents.scm` query of a language, and turned into actual indentation according
+//! to the indentation settings of the buffer (see [`IndentStyle`]), unless the language has its own.
+
+use kak_tree_sitter_config::IndentConfig;
+use serde::{Deserialize, Serialize};
+
+/// Capture names increasing the indent level of the lines spanned by the captured node.
+pub const INDENT_CAPTURES: &[&str] = &["indent", "indent.always"];
+
+/// Capture names decreasing the indent level of the line they start.
+pub const OUTDENT_CAPTURES: &[&str] = &["outdent", "outdent.always"];
+
+/// Maximum width of an indentation, in columns.
+pub const MAX_INDENT_WIDTH: usize = 1024;
+
+/// Indentation settings of a buffer, typically forwarded from Kakoune’s options.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndentStyle {
+  /// Width of a tab character; `%opt{tabstop}` in Kakoune.
+  pub tabstop: usize,
+
+  /// Width of an indent level; `%opt{indentwidth}` in Kakoune, where `0` means one tab per level.
+  pub indentwidth: usize,
+
+  /// Whether to indent with spaces instead of tabs.
+  ///
+  /// If not provided, spaces are used unless `indentwidth` is `0`, as Kakoune does.
+  #[serde(default)]
+  pub expandtab: Option<bool>,
+}
+
+impl IndentStyle {
+  pub fn new(tabstop: usize, indentwidth: usize) -> Self {
+    Self {
+      tabstop,
+      indentwidth,
+      expandtab: None,
+    }
+  }
+
+  pub fn with_expandtab(self, expandtab: bool) -> Self {
+    Self {
+      expandtab: Some(expandtab),
+      ..self
+    }
+  }
+
+  /// Override the settings of the buffer with the indentation unit of its language, where it sets them.
+  ///
+  /// If `significant` is set, tabs and spaces are never mixed: when indenting with tabs, each level is a tab.
+  pub fn with_unit(mut self, unit: &IndentConfig, significant: bool) -> Self {
+    if let Some(width) = unit.width {
+      self.indentwidth = width;
+    }
+    if let Some(use_tabs) = unit.use_tabs {
+      self.expandtab = Some(!use_tabs);
+    }
+
+    if significant && !self.expandtab.unwrap_or(self.indentwidth != 0) {
+      self.indentwidth = 0;
+    }
+
+    self
+  }
+
+  /// Indentation for the given indent level.
+  ///
+  /// The indentation is at most [`MAX_INDENT_WIDTH`] columns wide, whatever the settings the request came with.
+  pub fn indentation(&self, level: usize) -> String {
+    let tabstop = self.tabstop.max(1);
+    let width = if self.indentwidth == 0 {
+      level.saturating_mul(tabstop)
+    } else {
+      level.saturating_mul(self.indentwidth)
+    }
+    .min(MAX_INDENT_WIDTH);
+
+    if self.expandtab.unwrap_or(self.indentwidth != 0) {
+      " ".repeat(width)
+    } else {
+      "\t".repeat(width / tabstop) + &" ".repeat(width % tabstop)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use kak_tree_sitter_config::IndentConfig;
+
+  use super::{IndentStyle, MAX_INDENT_WIDTH};
+
+  #[test]
+  fn indentation() {
+    assert_eq!(IndentStyle::new(8, 4).indentation(0), "");
+    assert_eq!(IndentStyle::new(8, 4).indentation(2), "        ");
+    assert_eq!(IndentStyle::new(8, 0).indentation(2), "\t\t");
+    assert_eq!(
+      IndentStyle::new(8, 4).with_expandtab(false).indentation(3),
+      "\t    "
+    );
+    assert_eq!(
+      IndentStyle::new(4, 0).with_expandtab(true).indentation(1),
+      "    "
+    );
+
+    // absurd settings cannot make the indentation arbitrarily large
+    assert_eq!(
+      IndentStyle::new(8, usize::MAX).indentation(2).len(),
+      MAX_INDENT_WIDTH
+    );
+    assert_eq!(
+      IndentStyle::new(usize::MAX, 0).indentation(2),
+      " ".repeat(MAX_INDENT_WIDTH)
+    );
+  }
+
+  #[test]
+  fn indent_unit() {
+    let unit = IndentConfig {
+      width: Some(2),
+      use_tabs: None,
+    };
+    assert_eq!(
+      IndentStyle::new(8, 4)
+        .with_unit(&unit, false)
+        .indentation(2),
+      "    "
+    );
+
+    // the buffer settings apply where the language does not set anything
+    assert_eq!(
+      IndentStyle::new(8, 4)
+        .with_unit(&IndentConfig::default(), false)
+        .indentation(1),
+      "    "
+    );
+
+    // tabs are only mixed with spaces if indentation is free-form
+    let unit = IndentConfig {
+      width: None,
+      use_tabs: Some(true),
+    };
+    assert_eq!(
+      IndentStyle::new(8, 4)
+        .with_unit(&unit, false)
+        .indentation(3),
+      "\t    "
+    );
+    assert_eq!(
+      IndentStyle::new(8, 4).with_unit(&unit, true).indentation(3),
+      "\t\t\t"
+    );
+
+    // spaces are kept as they are
+    let unit = IndentConfig {
+      width: Some(4),
+      use_tabs: Some(false),
+    };
+    assert_eq!(
+      IndentStyle::new(8, 0).with_unit(&unit, true).indentation(1),
+      "    "
+    );
+  }
+}
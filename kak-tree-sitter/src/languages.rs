@@ -2,22 +2,60 @@
 //!
 //! Languages have different objects (grammars, queries, etc.) living at runtime and must be loaded beforehand.
 
-use std::{collections::HashMap, path::Path};
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
 
-use kak_tree_sitter_config::{Config, LanguagesConfig};
+use kak_tree_sitter_config::{
+  CommentConfig, Config, FallbackConfig, IndentConfig, LanguageConfig, LanguagesConfig,
+};
 use libloading::Symbol;
+use serde::Serialize;
 use tree_sitter::Query;
 use tree_sitter_highlight::HighlightConfiguration;
 
-use crate::{error::OhNo, queries::Queries};
+use crate::{
+  comment,
+  error::OhNo,
+  highlighting::{capture_group, face_name, HighlightQuery},
+  queries::{Queries, QueryFile, QuerySource},
+};
+
+pub use crate::queries::QUERY_NAMES;
 
 pub struct Language {
-  pub hl_config: HighlightConfiguration,
+  // highlight configuration, if the language has a highlights query; other features only need the tree
+  pub hl_config: Option<HighlightConfiguration>,
   pub hl_names: Vec<String>,
+  // priorities of the highlight groups, in the same order as hl_names
+  pub hl_priorities: Vec<u32>,
+  // Kakoune faces of the highlight groups, in the same order as hl_names
+  pub hl_faces: Vec<String>,
+  // face of the text no highlight group covers
+  pub hl_unknown_face: String,
+  // faces of the captures of the highlights query, by capture index; None for captures that are not highlighted
+  pub hl_capture_faces: Vec<Option<String>>,
   // whether we should remove the default highlighter when highlighting a buffer with this language
   pub remove_default_highlighter: bool,
   // query to use for text objects, if supported by the language
   pub textobject_query: Option<Query>,
+  // query to use for indentation, if supported by the language
+  pub indent_query: Option<Query>,
+  // comment tokens, used to comment and uncomment code
+  pub comment: CommentConfig,
+  // whether anonymous nodes (keywords, operators, punctuation, etc.) are highlighted
+  pub highlight_anonymous_nodes: bool,
+  // indentation unit, overriding the indentation settings of buffers
+  pub indent_unit: IndentConfig,
+  // whether indentation is part of the syntax of the language
+  pub significant_indent: bool,
+
+  // grammar file the language was loaded from, along with its modification time then
+  grammar_path: PathBuf,
+  grammar_modified: Option<SystemTime>,
 
   // NOTE: we need to keep that alive *probably*; better be safe than sorry
   ts_lang: tree_sitter::Language,
@@ -28,17 +66,76 @@ impl Language {
   pub fn lang(&self) -> tree_sitter::Language {
     self.ts_lang
   }
+
+  /// Whether the language can be highlighted; i.e. it has a highlights query.
+  pub fn supports_highlighting(&self) -> bool {
+    self.hl_config.is_some()
+  }
+
+  /// Query to highlight buffers of this language with, if it has a highlights query.
+  pub fn highlight_query(&self) -> Option<HighlightQuery<'_>> {
+    self.hl_config.as_ref().map(|config| HighlightQuery {
+      config,
+      names: &self.hl_names,
+      priorities: &self.hl_priorities,
+      faces: &self.hl_faces,
+      unknown_face: &self.hl_unknown_face,
+      capture_faces: &self.hl_capture_faces,
+      anonymous_nodes: self.highlight_anonymous_nodes,
+    })
+  }
+}
+
+/// Availability of the grammar and queries of a configured language.
+#[derive(Debug, Serialize)]
+pub struct LanguageStatus {
+  pub name: String,
+
+  /// Path the grammar is loaded from.
+  pub grammar_path: Option<PathBuf>,
+
+  /// Whether the grammar loads.
+  pub grammar_loads: bool,
+
+  /// Reason the grammar does not load, if so.
+  pub grammar_error: Option<String>,
+
+  /// Directory the queries are loaded from.
+  pub queries_dir: Option<PathBuf>,
+
+  /// Query files present in the queries directory; see [`QUERY_NAMES`].
+  pub queries: Vec<&'static str>,
+}
+
+/// Outcome of loading a configured language; see [`Languages::check`].
+#[derive(Debug)]
+pub enum LanguageCheck {
+  /// The grammar and all the queries load.
+  Ok,
+
+  /// The grammar is not installed.
+  NotInstalled,
+
+  /// The grammar or one of the queries fails to load.
+  Failed(OhNo),
 }
 
 pub struct Languages {
   /// Map a `kts_lang` to the tree-sitter [`Language`] and its queries.
   langs: HashMap<String, Language>,
+
+  /// Other names of the languages, lowercased; see [`LanguagesConfig::aliases`].
+  aliases: HashMap<String, String>,
+
+  /// Languages to use for buffers whose language is not supported.
+  fallback: FallbackConfig,
 }
 
 impl Languages {
   /// Load a grammar.
   fn load_grammar(
     lang: &str,
+    lang_config: &LanguageConfig,
     path: &Path,
   ) -> Result<(libloading::Library, tree_sitter::Language), OhNo> {
     let lib = unsafe { libloading::Library::new(path) };
@@ -46,13 +143,14 @@ impl Languages {
       lang: lang.to_owned(),
       err: err.to_string(),
     })?;
-    let fn_sym = format!("tree_sitter_{}", lang.replace(['.', '-'], "_"));
+    let fn_sym = lang_config.grammar.symbol(lang);
 
     let sym: Result<Symbol<fn() -> tree_sitter::Language>, _> =
       unsafe { lib.get(fn_sym.as_bytes()) };
-    let sym = sym.map_err(|err| OhNo::CannotLoadGrammar {
+    let sym = sym.map_err(|err| OhNo::NoGrammarSymbol {
       lang: lang.to_owned(),
-      err: format!("cannot find language: {err}"),
+      symbol: fn_sym,
+      err: err.to_string(),
     })?;
     let sym = sym();
 
@@ -72,7 +170,7 @@ impl Languages {
       if let Some(grammar_path) = LanguagesConfig::get_grammar_path(lang_config, lang_name) {
         log::info!("  grammar path: {}", grammar_path.display());
 
-        let (ts_lib, ts_lang) = match Self::load_grammar(lang_name, &grammar_path) {
+        let (ts_lib, ts_lang) = match Self::load_grammar(lang_name, lang_config, &grammar_path) {
           Ok(x) => x,
           Err(err) => {
             log::warn!("{err}");
@@ -80,48 +178,372 @@ impl Languages {
           }
         };
 
-        if let Some(queries_dir) = LanguagesConfig::get_queries_dir(lang_config, lang_name) {
-          log::info!("  queries directory: {}", queries_dir.display());
-
-          let queries = Queries::load_from_dir(queries_dir);
-          let mut hl_config = HighlightConfiguration::new(
-            ts_lang,
-            queries.highlights.as_deref().unwrap_or(""),
-            queries.injections.as_deref().unwrap_or(""),
-            queries.locals.as_deref().unwrap_or(""),
-          )
-          .map_err(|err| OhNo::HighlightError {
-            err: err.to_string(),
-          })?;
-
-          let hl_names: Vec<_> = config.highlight.groups.iter().cloned().collect();
-          hl_config.configure(&hl_names);
-
-          let remove_default_highlighter = lang_config.remove_default_highlighter.into();
-
-          let textobject_query = queries
-            .text_objects
-            .as_deref()
-            .map(|q| Query::new(ts_lang, q).map(Some))
-            .unwrap_or_else(|| Ok(None))?;
-
-          let lang = Language {
-            hl_config,
-            hl_names,
-            remove_default_highlighter,
-            textobject_query,
-            ts_lang,
-            _ts_lib: ts_lib,
-          };
-          langs.insert(lang_name.to_owned(), lang);
-        }
+        let lang = Self::load_language(
+          config,
+          lang_name,
+          lang_config,
+          &grammar_path,
+          ts_lib,
+          ts_lang,
+        )?;
+        langs.insert(lang_name.to_owned(), lang);
       }
     }
 
-    Ok(Self { langs })
+    let aliases = config
+      .languages
+      .aliases
+      .iter()
+      .map(|(alias, lang_name)| (alias.to_lowercase(), lang_name.clone()))
+      .collect();
+
+    Ok(Self {
+      langs,
+      aliases,
+      fallback: config.fallback.clone(),
+    })
+  }
+
+  /// Load the queries of a language whose grammar is loaded.
+  fn load_language(
+    config: &Config,
+    lang_name: &str,
+    lang_config: &LanguageConfig,
+    grammar_path: &Path,
+    ts_lib: libloading::Library,
+    ts_lang: tree_sitter::Language,
+  ) -> Result<Language, OhNo> {
+    // without queries, the language is still useful for features that only need the tree (e.g. dumping it)
+    let queries_dirs = config.languages.get_queries_dirs(lang_name);
+    let mut queries = if queries_dirs.is_empty() {
+      log::warn!("  no queries directory for {lang_name}");
+      Queries::default()
+    } else {
+      for queries_dir in &queries_dirs {
+        log::info!("  queries directory: {}", queries_dir.display());
+      }
+      Queries::load_from_dirs(&queries_dirs, lang_name, |lang| {
+        config.languages.get_queries_dirs(lang)
+      })
+    };
+    queries.add_extra_highlights(&lang_config.queries.extra_highlights)?;
+
+    // disabled groups are not recognized, and their captures are disabled below, so they are not highlighted
+    let hl_names: Vec<_> = config
+      .highlight
+      .enabled_groups()
+      .map(str::to_owned)
+      .collect();
+    let hl_priorities = hl_names
+      .iter()
+      .map(|name| config.highlight.priority(name))
+      .collect();
+    let face_prefix = &config.highlight.face_prefix;
+    let hl_faces = hl_names
+      .iter()
+      .map(|name| face_name(face_prefix, name))
+      .collect();
+    let hl_unknown_face = face_name(face_prefix, "unknown");
+
+    let hl_config = match &queries.highlights {
+      Some(highlights) => {
+        let injections = queries.injections.iter().flat_map(QueryFile::files);
+        let locals = queries.locals.iter().flat_map(QueryFile::files);
+
+        // the three queries are compiled as a single one, in that order
+        let source = QuerySource::new(injections.chain(locals).chain(highlights.files()));
+        let mut hl_config = HighlightConfiguration::new(
+          ts_lang,
+          &highlights.content(),
+          &queries
+            .injections
+            .as_ref()
+            .map(QueryFile::content)
+            .unwrap_or_default(),
+          &queries
+            .locals
+            .as_ref()
+            .map(QueryFile::content)
+            .unwrap_or_default(),
+        )
+        .map_err(|err| source.query_error(&err))?;
+        source.check(&hl_config.query);
+        hl_config.configure(&hl_names);
+
+        // a capture of a disabled subgroup would otherwise be highlighted as its parent group, if that one is enabled
+        let disabled: Vec<_> = hl_config
+          .query
+          .capture_names()
+          .iter()
+          .filter(|name| config.highlight.is_disabled(name))
+          .cloned()
+          .collect();
+        for name in disabled {
+          hl_config.query.disable_capture(&name);
+        }
+
+        Some(hl_config)
+      }
+
+      None => {
+        log::warn!("  no highlights query for {lang_name}; highlighting is disabled");
+        None
+      }
+    };
+
+    // resolved once per compiled query, rather than for every capture
+    let hl_capture_faces = hl_config.as_ref().map_or_else(Vec::new, |hl_config| {
+      hl_config
+        .query
+        .capture_names()
+        .iter()
+        .map(|name| capture_group(name, &hl_names).map(|group| face_name(face_prefix, group)))
+        .collect()
+    });
+
+    let remove_default_highlighter = lang_config.remove_default_highlighter.into();
+
+    let textobject_query = queries
+      .text_objects
+      .as_ref()
+      .map(|q| QuerySource::new(q.files()).compile(ts_lang))
+      .transpose()?;
+
+    let indent_query = queries
+      .indents
+      .as_ref()
+      .map(|q| QuerySource::new(q.files()).compile(ts_lang))
+      .transpose()?;
+
+    let lang = Language {
+      hl_config,
+      hl_names,
+      hl_priorities,
+      hl_faces,
+      hl_unknown_face,
+      hl_capture_faces,
+      remove_default_highlighter,
+      textobject_query,
+      indent_query,
+      comment: comment::comment_tokens(ts_lang, &lang_config.comment),
+      highlight_anonymous_nodes: lang_config.highlights_anonymous_nodes(&config.highlight),
+      indent_unit: lang_config.indent_unit(&config.indent),
+      significant_indent: lang_config.significant_indent,
+      grammar_path: grammar_path.to_owned(),
+      grammar_modified: modified(grammar_path),
+      ts_lang,
+      _ts_lib: ts_lib,
+    };
+
+    Ok(lang)
+  }
+
+  /// Load the grammar and queries of every configured language, sorted by name, without keeping them.
+  ///
+  /// This goes through the same steps as [`Languages::load_from_dir`], but every failure is reported instead of
+  /// stopping at the first one.
+  pub fn check(config: &Config) -> Vec<(String, LanguageCheck)> {
+    let mut checks: Vec<_> = config
+      .languages
+      .language
+      .iter()
+      .map(|(lang_name, lang_config)| {
+        let check = match LanguagesConfig::get_grammar_path(lang_config, lang_name) {
+          Some(path) if path.exists() => Self::load_grammar(lang_name, lang_config, &path)
+            .and_then(|(ts_lib, ts_lang)| {
+              Self::load_language(config, lang_name, lang_config, &path, ts_lib, ts_lang)
+            })
+            .map_or_else(LanguageCheck::Failed, |_| LanguageCheck::Ok),
+          _ => LanguageCheck::NotInstalled,
+        };
+
+        (lang_name.clone(), check)
+      })
+      .collect();
+
+    checks.sort_by(|a, b| a.0.cmp(&b.0));
+    checks
+  }
+
+  /// Check the grammar and queries of every configured language, sorted by name.
+  ///
+  /// Contrary to [`Languages::load_from_dir`], queries are not parsed; this is only about what is installed.
+  pub fn status(config: &Config) -> Vec<LanguageStatus> {
+    let mut statuses: Vec<_> = config
+      .languages
+      .language
+      .iter()
+      .map(|(lang_name, lang_config)| {
+        let grammar_path = LanguagesConfig::get_grammar_path(lang_config, lang_name);
+        let grammar_error = match &grammar_path {
+          Some(path) => Self::load_grammar(lang_name, lang_config, path)
+            .err()
+            .map(|err| err.to_string()),
+          None => Some("no grammar path".to_owned()),
+        };
+
+        let queries_dir = LanguagesConfig::get_queries_dir(lang_config, lang_name);
+        let queries = Queries::available_in_dirs(&config.languages.get_queries_dirs(lang_name));
+
+        LanguageStatus {
+          name: lang_name.clone(),
+          grammar_path,
+          grammar_loads: grammar_error.is_none(),
+          grammar_error,
+          queries_dir,
+          queries,
+        }
+      })
+      .collect();
+
+    statuses.sort_by(|a, b| a.name.cmp(&b.name));
+    statuses
   }
 
+  /// Whether the grammar file of a loaded language changed since it was loaded.
+  pub fn grammars_changed(&self) -> bool {
+    self
+      .langs
+      .values()
+      .any(|lang| modified(&lang.grammar_path) != lang.grammar_modified)
+  }
+
+  /// Drop all the languages, unloading their grammars.
+  ///
+  /// Nothing relying on them (trees, parsers, etc.) must be alive anymore.
+  pub fn unload(&mut self) {
+    self.langs.clear();
+  }
+
+  /// Get a language by its name or one of its aliases; see [`Languages::lookup`].
   pub fn get(&self, filetype: impl AsRef<str>) -> Option<&Language> {
-    self.langs.get(filetype.as_ref())
+    self.lookup(filetype.as_ref()).map(|(_, lang)| lang)
+  }
+
+  /// Get a language by its name or one of its aliases, along with its name.
+  ///
+  /// Names are normalized first — surrounding whitespace is removed, and case is ignored — so that filetypes that do not
+  /// exactly match the name of a language (e.g. `JavaScript` or `js`) still find it.
+  pub fn lookup<'a>(&'a self, name: &str) -> Option<(&'a str, &'a Language)> {
+    if let Some((name, lang)) = self.langs.get_key_value(name) {
+      return Some((name, lang));
+    }
+
+    let normalized = name.trim().to_lowercase();
+    let target = self.aliases.get(&normalized).unwrap_or(&normalized);
+    let (target, lang) = self.langs.get_key_value(target)?;
+    log::debug!("using language {target} for {name}");
+
+    Some((target, lang))
+  }
+
+  /// Get a language, or the fallback language of `buffer` if the language is not supported.
+  ///
+  /// The name of the language actually used is returned along with it.
+  pub fn resolve<'a>(&'a self, lang: &str, buffer: &str) -> Option<(&'a str, &'a Language)> {
+    if let Some(found) = self.lookup(lang) {
+      return Some(found);
+    }
+
+    let fallback = self.fallback.language_for(buffer)?;
+    let language = self.langs.get(fallback)?;
+    log::debug!("using fallback language {fallback} instead of {lang} for buffer {buffer}");
+
+    Some((fallback, language))
+  }
+
+  /// Known languages close to `name`, from the closest to the farthest; used to suggest alternatives to the user.
+  pub fn suggestions(&self, name: &str) -> Vec<String> {
+    close_matches(name, self.langs.keys().map(String::as_str))
+      .into_iter()
+      .map(str::to_owned)
+      .collect()
+  }
+}
+
+/// Modification time of a file, if it can be known.
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Maximum number of suggestions returned by [`close_matches`].
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Candidates close to `name`, sorted by edit distance (and then alphabetically).
+///
+/// A candidate is close if it contains `name` (or the other way around, ignoring single characters), or if it is at most
+/// a third of the length of `name` (rounded up) of edits away from it.
+fn close_matches<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+  if name.is_empty() {
+    return Vec::new();
+  }
+
+  let name = name.to_lowercase();
+  let mut matches: Vec<_> = candidates
+    .filter_map(|candidate| {
+      let lower = candidate.to_lowercase();
+      let dist = edit_distance(&name, &lower);
+      let contains = lower.contains(&name) || name.contains(&lower);
+      let close = (contains && name.len().min(lower.len()) > 1) || dist <= (name.len() + 2) / 3;
+
+      close.then_some((dist, candidate))
+    })
+    .collect();
+
+  matches.sort_unstable();
+  matches
+    .into_iter()
+    .take(MAX_SUGGESTIONS)
+    .map(|(_, candidate)| candidate)
+    .collect()
+}
+
+/// Levenshtein distance between two strings, in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<_> = b.chars().collect();
+  let mut row: Vec<_> = (0..=b.len()).collect();
+
+  for (i, ca) in a.chars().enumerate() {
+    let mut prev_diag = row[0];
+    row[0] = i + 1;
+
+    for (j, cb) in b.iter().enumerate() {
+      let prev_row = row[j + 1];
+      row[j + 1] = if ca == *cb {
+        prev_diag
+      } else {
+        1 + prev_diag.min(prev_row).min(row[j])
+      };
+      prev_diag = prev_row;
+    }
+  }
+
+  row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{close_matches, edit_distance};
+
+  #[test]
+  fn distance() {
+    assert_eq!(edit_distance("rust", "rust"), 0);
+    assert_eq!(edit_distance("rsut", "rust"), 2);
+    assert_eq!(edit_distance("bash", "sh"), 2);
+    assert_eq!(edit_distance("", "toml"), 4);
+  }
+
+  #[test]
+  fn suggestions() {
+    let langs = ["bash", "c", "cpp", "javascript", "rust", "typescript"];
+
+    assert_eq!(close_matches("rsut", langs.into_iter()), ["rust"]);
+    assert_eq!(close_matches("sh", langs.into_iter()), ["bash"]);
+    assert_eq!(close_matches("js", langs.into_iter()), Vec::<&str>::new());
+    assert_eq!(
+      close_matches("script", langs.into_iter()),
+      ["javascript", "typescript"]
+    );
+    assert_eq!(close_matches("", langs.into_iter()), Vec::<&str>::new());
   }
 }
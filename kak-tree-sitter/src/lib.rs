@@ -0,0 +1,53 @@
+//! Server between Kakoune and tree-sitter.
+//!
+//! This library is what the `kak-tree-sitter` binary is built on. It can be used to:
+//!
+//! - Build and serialize [`Request`] and [`UnixRequest`] in a type-safe way, instead of writing JSON by hand, to talk
+//!   to a running server.
+//! - Handle requests in-process with a [`Handler`], which returns structured [`Response`]s, without running a server.
+//!
+//! ```no_run
+//! use kak_tree_sitter::{Handler, Request, Response};
+//! use kak_tree_sitter_config::Config;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = Config::load_default_user()?;
+//! let mut handler = Handler::new(&config)?;
+//!
+//! let req = Request::highlight("client0", "main.rs", "rust", 0);
+//! if let Response::Highlights { ranges, .. } = handler.handle_request("session", &req, "fn main() {}")? {
+//!   println!("{} highlight ranges", ranges.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod buffer;
+pub mod cli;
+pub mod comment;
+pub mod edit;
+pub mod error;
+mod fd_passing;
+pub mod handler;
+pub mod highlighting;
+pub mod indent;
+pub mod languages;
+mod log_files;
+pub mod logging;
+pub mod metrics;
+pub mod navigation;
+mod queries;
+mod query_watcher;
+pub mod rc;
+pub mod request;
+pub mod response;
+pub mod selection;
+pub mod server;
+mod session;
+pub mod text_objects;
+mod tree_cache;
+pub mod tree_sitter_state;
+
+pub use handler::Handler;
+pub use request::{HighlightItem, Request, UnixRequest};
+pub use response::Response;
@@ -0,0 +1,111 @@
+//! Files the standard output and error of a daemonized server are redirected to.
+//!
+//! A long-running server can write a lot to those files, so they are rotated once they grow past a maximum size: the
+//! file is renamed with a `.1` suffix — replacing the previous rotated file, if any — and a new, empty file takes its
+//! place. Files are also rotated when the server starts, so that the logs of the previous run are kept.
+
+use std::{
+  fs::{self, File},
+  io,
+  os::fd::{AsRawFd, RawFd},
+  path::{Path, PathBuf},
+};
+
+use crate::error::OhNo;
+
+/// Standard output and error files.
+#[derive(Debug)]
+pub struct LogFiles {
+  stdout: PathBuf,
+  stderr: PathBuf,
+}
+
+impl LogFiles {
+  pub fn new(runtime_dir: &Path) -> Self {
+    Self {
+      stdout: runtime_dir.join("stdout.txt"),
+      stderr: runtime_dir.join("stderr.txt"),
+    }
+  }
+
+  /// Create the files, rotating the existing ones, and return them as standard output and error.
+  pub fn create(&self) -> Result<(File, File), OhNo> {
+    Ok((Self::rotate(&self.stdout)?, Self::rotate(&self.stderr)?))
+  }
+
+  /// Rotate the files that are larger than `max_size` bytes, redirecting standard output and error to the new files.
+  pub fn rotate_if_larger(&self, max_size: u64) {
+    for (path, fd) in [
+      (&self.stdout, libc::STDOUT_FILENO),
+      (&self.stderr, libc::STDERR_FILENO),
+    ] {
+      let size = fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+      if size > max_size {
+        if let Err(err) = Self::rotate(path).and_then(|file| Self::redirect(&file, path, fd)) {
+          log::error!("{err}");
+        }
+      }
+    }
+  }
+
+  /// Move the file to its `.1` path, if it exists, and create a new one.
+  fn rotate(path: &Path) -> Result<File, OhNo> {
+    if path.exists() {
+      let mut rotated = path.as_os_str().to_owned();
+      rotated.push(".1");
+
+      fs::rename(path, rotated).map_err(|err| OhNo::CannotRotateFile {
+        file: path.to_owned(),
+        err,
+      })?;
+    }
+
+    File::create(path).map_err(|err| OhNo::CannotCreateFile {
+      file: path.to_owned(),
+      err,
+    })
+  }
+
+  /// Make `fd` refer to `file`, the new file at `path`.
+  fn redirect(file: &File, path: &Path, fd: RawFd) -> Result<(), OhNo> {
+    // SAFETY: both file descriptors are valid; the previous file behind fd is closed, and fd stays open even when file
+    // is dropped
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+      return Err(OhNo::CannotRotateFile {
+        file: path.to_owned(),
+        err: io::Error::last_os_error(),
+      });
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::LogFiles;
+
+  #[test]
+  fn rotation() {
+    let dir = std::env::temp_dir().join(format!("kts-log-files-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("stdout.txt"), "previous run").unwrap();
+
+    let log_files = LogFiles::new(&dir);
+    log_files.create().unwrap();
+
+    assert_eq!(fs::read_to_string(dir.join("stdout.txt")).unwrap(), "");
+    assert_eq!(
+      fs::read_to_string(dir.join("stdout.txt.1")).unwrap(),
+      "previous run"
+    );
+    assert!(dir.join("stderr.txt").exists());
+    assert!(!dir.join("stderr.txt.1").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
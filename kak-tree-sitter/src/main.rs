@@ -1,77 +1,388 @@
-mod buffer;
-mod cli;
-mod error;
-mod handler;
-mod highlighting;
-mod languages;
-mod logging;
-mod queries;
-mod rc;
-mod request;
-mod response;
-mod selection;
-mod server;
-mod session;
-mod text_objects;
-mod tree_sitter_state;
+use std::{
+  fs,
+  io::{self, Read},
+  os::fd::AsRawFd,
+  path::Path,
+  time::Duration,
+};
 
 use clap::Parser;
-use cli::Cli;
-use error::OhNo;
-use kak_tree_sitter_config::Config;
-use logging::Verbosity;
-use request::UnixRequest;
-use server::Server;
-
-use crate::logging::KakouneLogger;
+use kak_tree_sitter::{
+  cli::{Cli, Cmd, LegacyArgs, RequestCmd, ServerCmd, StartArgs},
+  error::OhNo,
+  languages::{LanguageCheck, LanguageStatus, Languages, QUERY_NAMES},
+  logging::{KakouneLogger, Verbosity},
+  metrics::ParseMetrics,
+  rc,
+  request::{UnixRequest, PROTOCOL_VERSION},
+  response::UnixResponse,
+  server::{Server, ServerPaths},
+};
+use kak_tree_sitter_config::{Config, LanguagesConfig};
 
 fn main() {
   if let Err(err) = start() {
-    log::error!("{err}");
-    std::process::exit(1);
+    eprintln!("kak-tree-sitter: {err}");
+    std::process::exit(err.exit_code());
   }
 }
 
 fn start() -> Result<(), OhNo> {
   let cli = Cli::parse();
 
-  if let Some(level) = Verbosity::from_count(cli.verbose).to_level() {
-    if cli.kakoune {
+  if cli.check_config {
+    init_logging(cli.verbose, false)?;
+    return check_config();
+  }
+
+  if cli.print_config {
+    init_logging(cli.verbose, false)?;
+    return print_config();
+  }
+
+  let timeout = cli.timeout();
+  let paths = ServerPaths::new(cli.socket);
+
+  match cli.cmd {
+    Some(Cmd::Server { cmd }) => match cmd {
+      ServerCmd::Start(args) => {
+        init_logging(cli.verbose, args.kakoune)?;
+        print_rc(&args)?;
+        start_server(&paths, &args)
+      }
+
+      ServerCmd::Stop => {
+        init_logging(cli.verbose, false)?;
+        Server::send_request(&paths, UnixRequest::Shutdown, timeout)
+      }
+
+      ServerCmd::Status => {
+        init_logging(cli.verbose, false)?;
+        server_status(&paths, timeout)
+      }
+    },
+
+    Some(Cmd::Request {
+      req: RequestCmd::DumpCaptures { lang, path },
+    }) => {
+      init_logging(cli.verbose, false)?;
+      dump_captures(&paths, timeout, lang, &path)
+    }
+
+    Some(Cmd::Request { req }) => {
+      init_logging(cli.verbose, false)?;
+
+      if req.passes_stdin() {
+        Server::send_request_with_fd(&paths, req.into(), io::stdin().as_raw_fd(), timeout)
+      } else {
+        Server::send_request_or_start(&paths, req.into(), timeout)
+      }
+    }
+
+    Some(Cmd::Languages { json }) => {
+      init_logging(cli.verbose, false)?;
+      list_languages(json)
+    }
+
+    None => start_legacy(cli.verbose, &paths, timeout, cli.legacy),
+  }
+}
+
+/// Compatibility shim for the flat flags used prior to subcommands.
+fn start_legacy(
+  verbose: u8,
+  paths: &ServerPaths,
+  timeout: Option<Duration>,
+  legacy: LegacyArgs,
+) -> Result<(), OhNo> {
+  let args = legacy.start_args();
+
+  init_logging(verbose, args.kakoune)?;
+  print_rc(&args)?;
+
+  if legacy.server {
+    return start_server(paths, &args);
+  }
+
+  // otherwise, regular client
+  let request = match (legacy.request, legacy.request_file) {
+    (Some(request), _) if request == "-" => Some(read_stdin_request()?),
+    (Some(request), _) => Some(request),
+    (None, Some(path)) if path.as_os_str() == "-" => Some(read_stdin_request()?),
+    (None, Some(path)) => {
+      Some(fs::read_to_string(&path).map_err(|err| OhNo::CannotReadRequestFile { path, err })?)
+    }
+    (None, None) => None,
+  };
+
+  if let Some(request) = request {
+    let req = UnixRequest::from_json(&request)?;
+    let req = if let Some(session) = legacy.session {
+      req.with_session(session)
+    } else {
+      req
+    };
+
+    return Server::send_request_or_start(paths, req, timeout);
+  }
+
+  Err(OhNo::NothingToDo)
+}
+
+/// Read a request from the standard input, so that large requests do not have to be quoted for the shell.
+fn read_stdin_request() -> Result<String, OhNo> {
+  let mut request = String::new();
+  io::stdin()
+    .read_to_string(&mut request)
+    .map_err(|err| OhNo::CannotReadStdinRequest { err })?;
+
+  Ok(request)
+}
+
+fn init_logging(verbose: u8, kakoune: bool) -> Result<(), OhNo> {
+  if let Some(level) = Verbosity::from_count(verbose).to_level() {
+    if kakoune {
       KakouneLogger::new(level).register()?;
     } else {
       simple_logger::init_with_level(level)?;
     }
   }
 
-  if cli.kakoune {
+  Ok(())
+}
+
+fn print_rc(args: &StartArgs) -> Result<(), OhNo> {
+  if args.kakoune {
+    // faces are named after the configuration, which is the one the server loads as well
+    let config = Config::load_default_user()?;
     println!("{}", rc::static_kak());
+    println!("{}", rc::faces_kak(&config.highlight.face_prefix));
+    println!("{}", rc::default_faces_kak(&config.highlight));
   }
 
-  if cli.with_text_objects {
+  if args.with_text_objects {
     println!("{}", rc::text_objects_kak());
   }
 
-  if cli.server {
-    let config = Config::load_default_user()?;
-    log::trace!("running with configuration:\n{config:#?}");
-    return Server::bootstrap(&config, &cli);
-  }
-
-  if let Some(request) = cli.request {
-    // otherwise, regular client
-    let req =
-      serde_json::from_str::<UnixRequest>(&request).map_err(|err| OhNo::InvalidRequest {
-        req: request,
-        err: err.to_string(),
-      })?;
-    let req = if let Some(session) = cli.session {
-      req.with_session(session)
+  Ok(())
+}
+
+fn start_server(paths: &ServerPaths, args: &StartArgs) -> Result<(), OhNo> {
+  let config = Config::load_default_user()?;
+  log::trace!("running with configuration:\n{config:#?}");
+  Server::bootstrap(&config, paths, args)
+}
+
+fn server_status(paths: &ServerPaths, timeout: Option<Duration>) -> Result<(), OhNo> {
+  match Server::running_pid(paths) {
+    Some(pid) => {
+      println!("kak-tree-sitter is running (PID {pid})");
+
+      // the server is running, so failing to get more details is not an error
+      match Server::request_status(paths, timeout) {
+        Ok(UnixResponse::Status {
+          protocol_version,
+          parse_metrics,
+        }) => {
+          if protocol_version.major == PROTOCOL_VERSION.major {
+            println!("protocol version {protocol_version}");
+          } else {
+            println!(
+              "protocol version {protocol_version}, but this client speaks version {PROTOCOL_VERSION}; restart the \
+               server so that both run the same version of kak-tree-sitter"
+            );
+          }
+
+          for (lang, metrics) in parse_metrics {
+            println!("{lang}: {}", display_parse_metrics(&metrics));
+          }
+        }
+
+        Ok(UnixResponse::Error { reason, exit_code }) => log::warn!(
+          "cannot get the status of the server: {}",
+          OhNo::rejected(reason, exit_code)
+        ),
+
+        Ok(resp) => log::warn!("unexpected answer to a status request: {resp:?}"),
+
+        Err(err) => log::warn!("cannot get the status of the server: {err}"),
+      }
+
+      Ok(())
+    }
+
+    None => Err(OhNo::ServerNotRunning),
+  }
+}
+
+/// Print the captures of the highlights query on a file as JSON, to be piped into e.g. `jq`.
+fn dump_captures(
+  paths: &ServerPaths,
+  timeout: Option<Duration>,
+  lang: String,
+  path: &Path,
+) -> Result<(), OhNo> {
+  // the file is read by the server, which might run in another directory
+  let path = std::env::current_dir()
+    .map(|dir| dir.join(path))
+    .unwrap_or_else(|_| path.to_owned());
+
+  match Server::request_answer(paths, &UnixRequest::DumpCaptures { lang, path }, timeout)? {
+    UnixResponse::Captures { captures } => {
+      println!(
+        "{}",
+        serde_json::to_string_pretty(&captures).unwrap_or_default()
+      );
+      Ok(())
+    }
+
+    UnixResponse::Error { reason, exit_code } => Err(OhNo::rejected(reason, exit_code)),
+
+    resp => Err(OhNo::InvalidResponse {
+      err: format!("unexpected answer: {resp:?}"),
+    }),
+  }
+}
+
+fn display_parse_metrics(metrics: &ParseMetrics) -> String {
+  let cache_hits = metrics
+    .cache_hit_rate()
+    .map(|rate| format!("{:.0}% cache hits", rate * 100.))
+    .unwrap_or_else(|| "no reparse".to_owned());
+
+  format!(
+    "{} parses, {:?} on average, {cache_hits}, {} bytes parsed",
+    metrics.parses,
+    metrics.average_parse_time(),
+    metrics.bytes
+  )
+}
+
+fn list_languages(json: bool) -> Result<(), OhNo> {
+  let config = Config::load_default_user()?;
+  let statuses = Languages::status(&config);
+
+  if json {
+    println!(
+      "{}",
+      serde_json::to_string_pretty(&statuses).unwrap_or_default()
+    );
+  } else {
+    display_languages(&statuses);
+  }
+
+  Ok(())
+}
+
+fn display_languages(statuses: &[LanguageStatus]) {
+  let lang_width = statuses
+    .iter()
+    .map(|status| status.name.len())
+    .chain(Some("language".len()))
+    .max()
+    .unwrap_or_default();
+
+  let mut header = format!("{:lang_width$}  grammar", "language");
+  for query in QUERY_NAMES {
+    header.push_str(&format!("  {query}"));
+  }
+  println!("{header}");
+
+  let mark = |present: bool| if present { "yes" } else { "-" };
+  for status in statuses {
+    let grammar = if status.grammar_loads {
+      "yes"
+    } else if is_installed(status) {
+      "error"
     } else {
-      req
+      "-"
     };
 
-    return Server::send_request(req);
+    let mut line = format!("{:lang_width$}  {grammar:7}", status.name);
+    for query in QUERY_NAMES {
+      line.push_str(&format!(
+        "  {:width$}",
+        mark(status.queries.contains(&query)),
+        width = query.len()
+      ));
+    }
+    println!("{}", line.trim_end());
   }
 
-  Err(OhNo::NothingToDo)
+  // errors are too long to fit in the table; missing grammars are not worth reporting
+  for status in statuses {
+    if let Some(err) = status
+      .grammar_error
+      .as_ref()
+      .filter(|_| is_installed(status))
+    {
+      println!("{err}");
+    }
+  }
+}
+
+fn check_config() -> Result<(), OhNo> {
+  let config = Config::load_default_user()?;
+  let mut failures = 0;
+
+  for (lang, check) in Languages::check(&config) {
+    match check {
+      LanguageCheck::Ok => println!("{lang}: OK"),
+      LanguageCheck::NotInstalled => println!("{lang}: not installed"),
+      LanguageCheck::Failed(err) => {
+        failures += 1;
+        println!("{lang}: error: {err}");
+      }
+    }
+  }
+
+  if failures > 0 {
+    return Err(OhNo::LanguagesFailed { failures });
+  }
+
+  Ok(())
+}
+
+fn print_config() -> Result<(), OhNo> {
+  let config = Config::load_default_user()?;
+  println!("{}", config.to_toml()?);
+
+  let mut langs: Vec<_> = config.languages.language.iter().collect();
+  langs.sort_by(|a, b| a.0.cmp(b.0));
+
+  println!("# resolved paths");
+  for (lang, lang_config) in langs {
+    println!("#\n# {lang}");
+
+    match LanguagesConfig::get_grammar_path(lang_config, lang) {
+      Some(path) => println!("#   grammar: {}", resolved_path(&path)),
+      None => println!("#   grammar: unknown"),
+    }
+
+    for dir in config.languages.get_queries_dirs(lang) {
+      println!("#   queries: {}", resolved_path(&dir));
+    }
+  }
+
+  Ok(())
+}
+
+/// Absolute version of a path, telling whether it exists.
+fn resolved_path(path: &Path) -> String {
+  let path = std::env::current_dir()
+    .map(|dir| dir.join(path))
+    .unwrap_or_else(|_| path.to_owned());
+
+  if path.exists() {
+    path.display().to_string()
+  } else {
+    format!("{} (not found)", path.display())
+  }
+}
+
+fn is_installed(status: &LanguageStatus) -> bool {
+  status
+    .grammar_path
+    .as_deref()
+    .map_or(false, |path| path.exists())
 }
@@ -0,0 +1,77 @@
+//! Parse metrics, reported by `kak-tree-sitter server status`.
+//!
+//! Metrics are kept per language, and are reset when the server reloads.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Parse statistics of a language.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ParseMetrics {
+  /// Number of times a buffer was parsed.
+  pub parses: u64,
+
+  /// Total time spent parsing, in microseconds.
+  pub parse_time_us: u64,
+
+  /// Total number of bytes parsed.
+  pub bytes: u64,
+
+  /// Number of requests about a buffer that was already parsed.
+  pub reparses: u64,
+
+  /// Number of those requests that reused the previous tree, as the buffer did not change.
+  pub cache_hits: u64,
+}
+
+impl ParseMetrics {
+  /// Record a parse of `bytes` bytes that took `time`; `reparse` is `true` if the buffer was already parsed before.
+  pub fn record_parse(&mut self, bytes: usize, time: Duration, reparse: bool) {
+    self.parses += 1;
+    self.parse_time_us += time.as_micros() as u64;
+    self.bytes += bytes as u64;
+    self.reparses += reparse as u64;
+  }
+
+  /// Record a request that reused the tree of a buffer.
+  pub fn record_cache_hit(&mut self) {
+    self.reparses += 1;
+    self.cache_hits += 1;
+  }
+
+  /// Average time of a parse.
+  pub fn average_parse_time(&self) -> Duration {
+    Duration::from_micros(self.parse_time_us.checked_div(self.parses).unwrap_or(0))
+  }
+
+  /// Ratio of requests about already parsed buffers that reused the tree, if any.
+  pub fn cache_hit_rate(&self) -> Option<f64> {
+    (self.reparses > 0).then(|| self.cache_hits as f64 / self.reparses as f64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::ParseMetrics;
+
+  #[test]
+  fn metrics() {
+    let mut metrics = ParseMetrics::default();
+    assert_eq!(metrics.average_parse_time(), Duration::ZERO);
+    assert_eq!(metrics.cache_hit_rate(), None);
+
+    metrics.record_parse(100, Duration::from_micros(300), false);
+    metrics.record_parse(50, Duration::from_micros(100), true);
+    metrics.record_cache_hit();
+    metrics.record_cache_hit();
+    metrics.record_cache_hit();
+
+    assert_eq!(metrics.parses, 2);
+    assert_eq!(metrics.bytes, 150);
+    assert_eq!(metrics.average_parse_time(), Duration::from_micros(200));
+    assert_eq!(metrics.cache_hit_rate(), Some(0.75));
+  }
+}
@@ -0,0 +1,34 @@
+//! Navigation between nodes of a given kind.
+//!
+//! Kinds are the node kinds of the grammar (e.g. `function_item` in Rust); as for text-objects patterns, we do not
+//! provide a type for them, as they vary between grammars.
+
+use serde::{Deserialize, Serialize};
+
+/// Direction to look for a node in, relative to the selections.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  /// Look for the first node starting after the selection.
+  Next,
+
+  /// Look for the last node starting before the selection.
+  Prev,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Direction;
+
+  #[test]
+  fn deser() {
+    assert_eq!(
+      serde_json::from_str::<Direction>("\"next\"").unwrap(),
+      Direction::Next
+    );
+    assert_eq!(
+      serde_json::from_str::<Direction>("\"prev\"").unwrap(),
+      Direction::Prev
+    );
+  }
+}
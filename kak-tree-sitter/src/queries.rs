@@ -1,29 +1,519 @@
 //! Supported queries.
 
-use std::{fs, path::Path};
+use std::{fs, path::PathBuf};
 
-#[derive(Debug)]
+use tree_sitter::{Language, Query, QueryError, QueryErrorKind};
+
+use crate::error::OhNo;
+
+/// Query files known to kak-tree-sitter, without their `.scm` extension.
+///
+/// Not all of them are used yet (e.g. `folds`), but they are part of the usual runtime files of a language.
+pub const QUERY_NAMES: [&str; 6] = [
+  "highlights",
+  "injections",
+  "locals",
+  "folds",
+  "indents",
+  "textobjects",
+];
+
+#[derive(Debug, Default)]
 pub struct Queries {
-  pub highlights: Option<String>,
-  pub injections: Option<String>,
-  pub locals: Option<String>,
-  pub text_objects: Option<String>,
+  pub highlights: Option<QueryFile>,
+  pub injections: Option<QueryFile>,
+  pub locals: Option<QueryFile>,
+  pub text_objects: Option<QueryFile>,
+  pub indents: Option<QueryFile>,
 }
 
 impl Queries {
-  pub fn load_from_dir(dir: impl AsRef<Path>) -> Self {
-    let dir = dir.as_ref();
+  /// Load the queries of the `lang` language from its queries directories.
+  ///
+  /// Each query file is looked up in `dirs` in order, and the first one found wins. `queries_dirs` maps a language to
+  /// its queries directories, for the languages that query files inherit from; see [`QueryFile`].
+  pub fn load_from_dirs(
+    dirs: &[PathBuf],
+    lang: &str,
+    queries_dirs: impl Fn(&str) -> Vec<PathBuf>,
+  ) -> Self {
+    let load = |name| QueryFile::load(dirs, lang, name, &queries_dirs);
 
-    let highlights = fs::read_to_string(dir.join("highlights.scm")).ok();
-    let injections = fs::read_to_string(dir.join("injections.scm")).ok();
-    let locals = fs::read_to_string(dir.join("locals.scm")).ok();
-    let text_objects = fs::read_to_string(dir.join("textobjects.scm")).ok();
+    let highlights = load("highlights");
+    let injections = load("injections");
+    let locals = load("locals");
+    let text_objects = load("textobjects");
+    let indents = load("indents");
 
     Queries {
       highlights,
       injections,
       locals,
       text_objects,
+      indents,
+    }
+  }
+
+  /// Compile the `paths` query files along with the highlights query, before it, so that their captures take
+  /// precedence on the same nodes (tree-sitter-highlight keeps the first pattern capturing a node).
+  ///
+  /// They make up the highlights query on their own if the language has none.
+  pub fn add_extra_highlights(&mut self, paths: &[PathBuf]) -> Result<(), OhNo> {
+    if paths.is_empty() {
+      return Ok(());
+    }
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+      let mut content = fs::read_to_string(path).map_err(|err| OhNo::CannotReadFile {
+        file: path.to_owned(),
+        err,
+      })?;
+      log::info!("  extra highlights: {}", path.display());
+
+      if !content.ends_with('\n') {
+        content.push('\n');
+      }
+
+      // named after their path, so that errors point at them; see QuerySource
+      let name = path.with_extension("").display().to_string();
+      files.push((name, content));
+    }
+
+    let highlights = self.highlights.get_or_insert_with(QueryFile::default);
+    files.append(&mut highlights.files);
+    highlights.files = files;
+
+    Ok(())
+  }
+
+  /// Names of the known query files (see [`QUERY_NAMES`]) present in any of the directories.
+  pub fn available_in_dirs(dirs: &[PathBuf]) -> Vec<&'static str> {
+    QUERY_NAMES
+      .into_iter()
+      .filter(|name| find_file(dirs, name).is_some())
+      .collect()
+  }
+}
+
+/// A query file, along with the query files it inherits.
+///
+/// As with nvim-treesitter, a query file can start with a `; inherits: lang1,lang2` comment; the same query files of
+/// those languages are then prepended to it, recursively. A language within parentheses — e.g. `; inherits: (jsx)` — is
+/// only inherited when the file is not itself inherited. A language is inherited only once.
+#[derive(Debug, Default)]
+pub struct QueryFile {
+  /// Name and content of each file, inherited ones first.
+  ///
+  /// Inherited files are named after their language; e.g. `javascript/highlights`.
+  files: Vec<(String, String)>,
+}
+
+impl QueryFile {
+  fn load(
+    dirs: &[PathBuf],
+    lang: &str,
+    name: &str,
+    queries_dirs: &dyn Fn(&str) -> Vec<PathBuf>,
+  ) -> Option<Self> {
+    let mut query = Self::default();
+    let mut inherited = vec![lang.to_owned()];
+    query.load_with_parents(dirs, None, name, &mut inherited, queries_dirs)?;
+
+    Some(query)
+  }
+
+  /// Load a file after the files it inherits; `lang` is set when the file is inherited.
+  ///
+  /// Return [`None`] if the file does not exist in any of the directories.
+  fn load_with_parents(
+    &mut self,
+    dirs: &[PathBuf],
+    lang: Option<&str>,
+    name: &str,
+    inherited: &mut Vec<String>,
+    queries_dirs: &dyn Fn(&str) -> Vec<PathBuf>,
+  ) -> Option<()> {
+    let mut content = fs::read_to_string(find_file(dirs, name)?).ok()?;
+
+    for (parent, optional) in inherited_langs(&content) {
+      if (optional && lang.is_some()) || inherited.iter().any(|inherited| inherited == parent) {
+        continue;
+      }
+      inherited.push(parent.to_owned());
+
+      let parent_dirs = queries_dirs(parent);
+      if parent_dirs.is_empty() {
+        log::warn!("  cannot inherit {name}.scm of {parent}: unknown language");
+        continue;
+      }
+
+      if self
+        .load_with_parents(&parent_dirs, Some(parent), name, inherited, queries_dirs)
+        .is_none()
+      {
+        log::warn!("  cannot inherit {name}.scm of {parent}: not found");
+      }
     }
+
+    // a trailing comment must not swallow the beginning of the next file
+    if !content.ends_with('\n') {
+      content.push('\n');
+    }
+
+    let file_name = match lang {
+      Some(lang) => format!("{lang}/{name}"),
+      None => name.to_owned(),
+    };
+    self.files.push((file_name, content));
+
+    Some(())
+  }
+
+  /// Content of the query, inherited files included.
+  pub fn content(&self) -> String {
+    self
+      .files
+      .iter()
+      .map(|(_, content)| content.as_str())
+      .collect()
+  }
+
+  /// Name and content of each file, inherited ones first; see [`QuerySource`].
+  pub fn files(&self) -> impl Iterator<Item = (&str, &str)> {
+    self
+      .files
+      .iter()
+      .map(|(name, content)| (name.as_str(), content.as_str()))
+  }
+}
+
+/// Path of the `name` query file in the first directory that has one.
+fn find_file(dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+  dirs
+    .iter()
+    .map(|dir| dir.join(format!("{name}.scm")))
+    .find(|path| path.is_file())
+}
+
+/// Languages a query file inherits, from the `; inherits:` comments at its top, along with whether they are optional
+/// (i.e. within parentheses).
+fn inherited_langs(content: &str) -> Vec<(&str, bool)> {
+  content
+    .lines()
+    .take_while(|line| line.starts_with(';'))
+    .filter_map(|line| {
+      line
+        .trim_start_matches(';')
+        .trim()
+        .strip_prefix("inherits:")
+    })
+    .flat_map(|langs| langs.split(','))
+    .map(str::trim)
+    .filter(|lang| !lang.is_empty())
+    .map(|lang| {
+      match lang
+        .strip_prefix('(')
+        .and_then(|lang| lang.strip_suffix(')'))
+      {
+        Some(lang) => (lang, true),
+        None => (lang, false),
+      }
+    })
+    .collect()
+}
+
+/// Query files compiled together into a single [`Query`].
+///
+/// tree-sitter reports problems relative to the concatenation of the files (e.g. highlights are compiled along with
+/// injections and locals); this is used to point at the faulty file and line instead.
+pub struct QuerySource<'a> {
+  files: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> QuerySource<'a> {
+  pub fn new(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+    Self {
+      files: files.into_iter().collect(),
+    }
+  }
+
+  /// Compile the files, warning about predicates the runtime ignores.
+  pub fn compile(&self, lang: Language) -> Result<Query, OhNo> {
+    let query = Query::new(lang, &self.concat()).map_err(|err| self.query_error(&err))?;
+    self.check(&query);
+    Ok(query)
+  }
+
+  /// Log the predicates of an already compiled query that the runtime ignores.
+  pub fn check(&self, query: &Query) {
+    for err in self.unsupported_predicates(query) {
+      log::warn!("  {err}");
+    }
+  }
+
+  /// Map a compilation error to the file and line it comes from.
+  pub fn query_error(&self, err: &QueryError) -> OhNo {
+    let source = self.concat();
+    let offset: usize = source
+      .split_inclusive('\n')
+      .take(err.row)
+      .map(str::len)
+      .sum();
+    let (query, line) = self.locate(offset);
+
+    let kind = match err.kind {
+      QueryErrorKind::Field => "invalid field name ",
+      QueryErrorKind::NodeType => "invalid node type ",
+      QueryErrorKind::Capture => "invalid capture name ",
+      QueryErrorKind::Predicate => "invalid predicate: ",
+      QueryErrorKind::Structure => "impossible pattern: ",
+      QueryErrorKind::Syntax => "invalid syntax: ",
+      QueryErrorKind::Language => "",
+    };
+
+    OhNo::InvalidQuery {
+      query: query.to_owned(),
+      line,
+      err: format!("{kind}{}", err.message),
+    }
+  }
+
+  /// Predicates that are not evaluated by this tree-sitter runtime.
+  ///
+  /// Only `#eq?`, `#match?` (and their negations), `#set!`, `#is?` and `#is-not?` are known. Other predicates, such as
+  /// `#any-of?` from more recent runtimes, are accepted by the compiler but ignored, so their patterns match more
+  /// nodes than intended.
+  pub fn unsupported_predicates(&self, query: &Query) -> Vec<OhNo> {
+    let source = self.concat();
+
+    (0..query.pattern_count())
+      .flat_map(|pattern| {
+        let start = query.start_byte_for_pattern(pattern);
+        query
+          .general_predicates(pattern)
+          .iter()
+          .map(move |predicate| (start, predicate))
+      })
+      .map(|(start, predicate)| {
+        // the runtime only knows where the pattern starts; the predicate might be a couple of lines below
+        let offset = source[start..]
+          .find(&format!("#{}", predicate.operator))
+          .map_or(start, |i| start + i);
+        let (query, line) = self.locate(offset);
+
+        OhNo::UnsupportedPredicate {
+          query: query.to_owned(),
+          line,
+          predicate: predicate.operator.to_string(),
+        }
+      })
+      .collect()
+  }
+
+  fn concat(&self) -> String {
+    self.files.iter().map(|(_, content)| *content).collect()
+  }
+
+  /// File name and line (1-based) of a byte offset in the concatenated files.
+  fn locate(&self, mut offset: usize) -> (&'a str, usize) {
+    let mut last = ("", 1);
+
+    for &(name, content) in self.files.iter().filter(|(_, content)| !content.is_empty()) {
+      if offset < content.len() {
+        return (name, content[..offset].matches('\n').count() + 1);
+      }
+
+      offset -= content.len();
+      last = (name, content.matches('\n').count() + 1);
+    }
+
+    last
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::{Queries, QueryFile, QuerySource};
+  use crate::error::OhNo;
+
+  #[test]
+  fn unsupported_predicates() {
+    let highlights = "(string_literal) @string\n\n((identifier) @constant\n  (#any-of? @constant \"FOO\" \"BAR\"))\n";
+    let injections =
+      "((line_comment) @injection.content\n  (#set! injection.language \"comment\"))\n";
+    let source = QuerySource::new([
+      ("injections", injections),
+      ("locals", ""),
+      ("highlights", highlights),
+    ]);
+    let query = source.compile(tree_sitter_rust::language()).unwrap();
+
+    let errs = source.unsupported_predicates(&query);
+    assert!(
+      matches!(
+        errs.as_slice(),
+        [OhNo::UnsupportedPredicate { query, line: 4, predicate }] if query == "highlights" && predicate == "any-of?"
+      ),
+      "{errs:?}"
+    );
+  }
+
+  #[test]
+  fn invalid_query() {
+    let textobjects =
+      "(function_item\n  body: (_) @function.inside) @function.around\n(function_item nope: (_))\n";
+    let source = QuerySource::new([("textobjects", textobjects)]);
+    let err = source.compile(tree_sitter_rust::language()).unwrap_err();
+
+    assert!(
+      matches!(
+        err,
+        OhNo::InvalidQuery {
+          ref query,
+          line: 3,
+          ..
+        } if query == "textobjects"
+      ),
+      "{err}"
+    );
+    assert!(err.to_string().contains("invalid field name nope"), "{err}");
+  }
+
+  #[test]
+  fn inherited_queries() {
+    let dir = std::env::temp_dir().join(format!("kts-inherited-queries-{}", std::process::id()));
+    for (lang, highlights) in [
+      // optional languages are not inherited by inherited files
+      (
+        "base",
+        "; inherits: (extra)\n(identifier) @variable ; no newline",
+      ),
+      ("extra", "(integer_literal) @number\n"),
+      // a language is only inherited once, which prevents cycles
+      (
+        "derived",
+        "; inherits: base\n; inherits: base,derived\n(string_literal) @string\n",
+      ),
+    ] {
+      fs::create_dir_all(dir.join(lang)).unwrap();
+      fs::write(dir.join(lang).join("highlights.scm"), highlights).unwrap();
+    }
+
+    let queries_dirs = |lang: &str| vec![dir.join(lang)];
+    let query = QueryFile::load(
+      &queries_dirs("derived"),
+      "derived",
+      "highlights",
+      &queries_dirs,
+    )
+    .unwrap();
+    assert_eq!(
+      query.files().map(|(name, _)| name).collect::<Vec<_>>(),
+      ["base/highlights", "highlights"]
+    );
+    assert!(query
+      .content()
+      .starts_with("; inherits: (extra)\n(identifier) @variable ; no newline\n; inherits: base\n"));
+    QuerySource::new(query.files())
+      .compile(tree_sitter_rust::language())
+      .unwrap();
+
+    // errors point at the inherited file
+    fs::write(
+      dir.join("base").join("highlights.scm"),
+      "(identifier) @variable\n(nope) @nope\n",
+    )
+    .unwrap();
+    let query = QueryFile::load(
+      &queries_dirs("derived"),
+      "derived",
+      "highlights",
+      &queries_dirs,
+    )
+    .unwrap();
+    let err = QuerySource::new(query.files())
+      .compile(tree_sitter_rust::language())
+      .unwrap_err();
+    assert!(
+      matches!(err, OhNo::InvalidQuery { ref query, line: 2, .. } if query == "base/highlights"),
+      "{err}"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn overridden_queries() {
+    let dir = std::env::temp_dir().join(format!("kts-overridden-queries-{}", std::process::id()));
+    let (user, packaged) = (dir.join("user"), dir.join("packaged"));
+    fs::create_dir_all(&user).unwrap();
+    fs::create_dir_all(&packaged).unwrap();
+    fs::write(user.join("highlights.scm"), "(identifier) @variable\n").unwrap();
+    fs::write(packaged.join("highlights.scm"), "(identifier) @function\n").unwrap();
+    fs::write(packaged.join("locals.scm"), "(block) @local.scope\n").unwrap();
+
+    // the first directory having a query file wins, file by file
+    let dirs = [user, packaged];
+    let queries = Queries::load_from_dirs(&dirs, "rust", |_| Vec::new());
+    assert_eq!(
+      queries.highlights.unwrap().content(),
+      "(identifier) @variable\n"
+    );
+    assert_eq!(queries.locals.unwrap().content(), "(block) @local.scope\n");
+    assert!(queries.injections.is_none());
+    assert_eq!(Queries::available_in_dirs(&dirs), ["highlights", "locals"]);
+
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn extra_highlights() {
+    let dir = std::env::temp_dir().join(format!("kts-extra-highlights-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let extra = dir.join("extra.scm");
+    fs::write(&extra, "(identifier) @constant").unwrap();
+
+    // extra files come first, so that their captures win
+    let mut queries = Queries {
+      highlights: Some(QueryFile {
+        files: vec![(
+          "highlights".to_owned(),
+          "(identifier) @variable\n".to_owned(),
+        )],
+      }),
+      ..Queries::default()
+    };
+    queries
+      .add_extra_highlights(std::slice::from_ref(&extra))
+      .unwrap();
+    let highlights = queries.highlights.unwrap();
+    assert_eq!(
+      highlights.content(),
+      "(identifier) @constant\n(identifier) @variable\n"
+    );
+    assert_eq!(
+      highlights.files().next().map(|(name, _)| name.to_owned()),
+      Some(dir.join("extra").display().to_string())
+    );
+
+    // they make up the highlights query of languages without one
+    let mut queries = Queries::default();
+    queries.add_extra_highlights(&[extra]).unwrap();
+    assert_eq!(
+      queries.highlights.unwrap().content(),
+      "(identifier) @constant\n"
+    );
+
+    // a missing file is an error, as it was explicitly configured
+    let mut queries = Queries::default();
+    assert!(matches!(
+      queries.add_extra_highlights(&[dir.join("nope.scm")]),
+      Err(OhNo::CannotReadFile { .. })
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
   }
 }
@@ -0,0 +1,218 @@
+//! Watching query files, so that languages are reloaded when their queries change.
+//!
+//! This is meant for query authors iterating on queries, and is only enabled with `--watch-queries`. Query directories
+//! are watched rather than query files, as editors often save a file by replacing it. Watching relies on the `notify`
+//! crate, which uses whatever the platform provides (inotify on Linux, FSEvents on macOS, kqueue on BSDs).
+
+use std::{
+  path::PathBuf,
+  sync::{
+    mpsc::{channel, Receiver},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use kak_tree_sitter_config::Config;
+use mio::Waker;
+use notify::{
+  event::{AccessKind, AccessMode, ModifyKind},
+  Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use crate::error::OhNo;
+
+/// Watched query directories, along with the changes to their query files not reported yet.
+pub struct QueryWatcher {
+  /// Watches the directories for as long as it lives.
+  _watcher: RecommendedWatcher,
+
+  /// Events of the watched directories, sent from the thread of the watcher.
+  events: Receiver<notify::Result<Event>>,
+
+  /// Changed query files, not reported yet.
+  changed: Vec<PathBuf>,
+
+  /// Last time a query file changed.
+  last_change: Option<Instant>,
+}
+
+impl QueryWatcher {
+  /// Time without changes after which changes are considered settled.
+  ///
+  /// Saving a file often comes with several events, and query authors might save several files at once; languages are
+  /// reloaded only once for all of them.
+  const DEBOUNCE: Duration = Duration::from_millis(200);
+
+  /// Watch the query directories of the configured languages, along with the directories of their extra highlights
+  /// files; directories that do not exist are skipped.
+  ///
+  /// `waker` is woken up whenever something happens in the watched directories; see [`QueryWatcher::read_changes`].
+  pub fn new(config: &Config, waker: Arc<Waker>) -> Result<Self, OhNo> {
+    let mut dirs: Vec<_> = config
+      .languages
+      .language
+      .iter()
+      .flat_map(|(lang_name, lang_config)| {
+        let extra_dirs = lang_config
+          .queries
+          .extra_highlights
+          .iter()
+          .filter_map(|path| path.parent().map(PathBuf::from));
+        config
+          .languages
+          .get_queries_dirs(lang_name)
+          .into_iter()
+          .chain(extra_dirs)
+      })
+      .filter(|dir| dir.is_dir())
+      .collect();
+
+    // several extra files might live in the same directory
+    dirs.sort();
+    dirs.dedup();
+
+    Self::watch_dirs(dirs, waker)
+  }
+
+  fn watch_dirs(dirs: impl IntoIterator<Item = PathBuf>, waker: Arc<Waker>) -> Result<Self, OhNo> {
+    let (sender, events) = channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+      if sender.send(event).is_ok() {
+        if let Err(err) = waker.wake() {
+          log::error!("cannot wake the server up: {err}");
+        }
+      }
+    })
+    .map_err(|err| OhNo::CannotWatchQueries { err })?;
+
+    for dir in dirs {
+      if let Err(err) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        log::warn!("cannot watch queries in {dir}: {err}", dir = dir.display());
+        continue;
+      }
+
+      log::debug!("watching queries in {}", dir.display());
+    }
+
+    Ok(Self {
+      _watcher: watcher,
+      events,
+      changed: Vec::new(),
+      last_change: None,
+    })
+  }
+
+  /// Read the pending changes of the watched directories.
+  pub fn read_changes(&mut self) {
+    while let Ok(event) = self.events.try_recv() {
+      let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+          log::error!("cannot read query file changes: {err}");
+          continue;
+        }
+      };
+
+      if event.need_rescan() {
+        log::warn!("too many query file changes at once; some of them might be missed");
+        continue;
+      }
+
+      // changes that replace the content of a query file
+      let replaced = matches!(
+        event.kind,
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+          | EventKind::Create(_)
+          | EventKind::Modify(ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Name(_))
+          | EventKind::Remove(_)
+      );
+      if !replaced {
+        continue;
+      }
+
+      for path in event.paths {
+        if path.extension().map_or(false, |ext| ext == "scm") {
+          log::debug!("query file {} changed", path.display());
+          self.last_change = Some(Instant::now());
+
+          if !self.changed.contains(&path) {
+            self.changed.push(path);
+          }
+        }
+      }
+    }
+  }
+
+  /// How long to wait for more changes before the pending ones are settled, if there are any.
+  pub fn timeout(&self) -> Option<Duration> {
+    self
+      .last_change
+      .map(|last_change| (last_change + Self::DEBOUNCE).saturating_duration_since(Instant::now()))
+  }
+
+  /// Changed query files, once changes have settled; each file is reported once, however many times it changed.
+  pub fn settled_changes(&mut self) -> Option<Vec<PathBuf>> {
+    if self.timeout()? > Duration::ZERO {
+      return None;
+    }
+
+    self.last_change = None;
+    Some(std::mem::take(&mut self.changed))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use mio::{Poll, Token, Waker};
+
+  use super::QueryWatcher;
+
+  #[test]
+  fn settled_changes() {
+    let dir = std::env::temp_dir().join(format!("kts-query-watcher-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let poll = Poll::new().unwrap();
+    let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+    let mut watcher = QueryWatcher::watch_dirs([dir.clone()], waker).unwrap();
+    watcher.read_changes();
+    assert_eq!(watcher.timeout(), None);
+
+    // only query files are reported, once, and not before changes have settled
+    fs::write(dir.join("highlights.scm"), "(identifier) @variable").unwrap();
+    fs::write(dir.join("highlights.scm"), "(identifier) @function").unwrap();
+    fs::write(dir.join("notes.txt"), "").unwrap();
+
+    // events come from another thread, whenever it gets to them
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while watcher.timeout().is_none() {
+      assert!(Instant::now() < deadline, "no change was reported");
+      thread::sleep(Duration::from_millis(10));
+      watcher.read_changes();
+    }
+    assert_eq!(watcher.settled_changes(), None);
+
+    // more events might come before changes settle, postponing them
+    let changed = loop {
+      assert!(Instant::now() < deadline, "changes did not settle");
+      if let Some(changed) = watcher.settled_changes() {
+        break changed;
+      }
+      thread::sleep(watcher.timeout().unwrap_or_default());
+      watcher.read_changes();
+    };
+    assert_eq!(changed, vec![dir.join("highlights.scm")]);
+    assert_eq!(watcher.timeout(), None);
+
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
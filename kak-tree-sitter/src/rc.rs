@@ -1,11 +1,128 @@
 //! rc file used by Kakoune to inject kak-tree-sitter commands.
 
+use kak_tree_sitter_config::HighlightConfig;
+
+use crate::highlighting::face_name;
+
+/// Kakoune faces the highlight groups are mapped to by `kak-tree-sitter-apply-default-faces`.
+///
+/// Subgroups not listed keep inheriting from their parent (see `rc/faces.kak`).
+const DEFAULT_FACES: &[(&str, &str)] = &[
+  ("attribute", "attribute"),
+  ("comment", "comment"),
+  ("constant", "value"),
+  ("constant.character.escape", "meta"),
+  ("constructor", "type"),
+  ("diff.delta", "yellow"),
+  ("diff.minus", "red"),
+  ("diff.plus", "green"),
+  ("error", "Error"),
+  ("function", "function"),
+  ("function.builtin", "builtin"),
+  ("function.macro", "meta"),
+  ("include", "meta"),
+  ("keyword", "keyword"),
+  ("keyword.directive", "meta"),
+  ("label", "meta"),
+  ("markup.bold", "+b"),
+  ("markup.heading", "title"),
+  ("markup.italic", "+i"),
+  ("markup.link", "link"),
+  ("markup.list", "bullet"),
+  ("markup.quote", "comment"),
+  ("markup.raw", "mono"),
+  ("markup.raw.block", "block"),
+  ("markup.strikethrough", "+s"),
+  ("namespace", "module"),
+  ("operator", "operator"),
+  ("string", "string"),
+  ("string.escape", "meta"),
+  ("tag", "keyword"),
+  ("type", "type"),
+  ("variable", "variable"),
+  ("variable.builtin", "builtin"),
+];
+
 /// Main RC file.
 pub fn static_kak() -> &'static str {
   include_str!("../rc/static.kak")
 }
 
+/// Face declarations, named with the configured prefix.
+///
+/// Face names in the file all start with `ts_`, right after a space; other names containing it (e.g. the
+/// `kts_syntax_errors` option) are left as is.
+pub fn faces_kak(face_prefix: &str) -> String {
+  include_str!("../rc/faces.kak").replace(" ts_", &format!(" {face_prefix}"))
+}
+
+/// `kak-tree-sitter-apply-default-faces`, mapping the faces highlights are emitted with to the faces of the current
+/// colorscheme.
+///
+/// Only the groups that are highlighted — i.e. enabled, or with enabled subgroups — are mapped, with the configured
+/// prefix, so that the command follows the faces actually emitted.
+pub fn default_faces_kak(config: &HighlightConfig) -> String {
+  let enabled: Vec<_> = config.enabled_groups().collect();
+  let set_faces: String = DEFAULT_FACES
+    .iter()
+    .filter(|(group, _)| {
+      enabled.iter().any(|enabled| {
+        enabled == group
+          || enabled
+            .strip_prefix(group)
+            .map_or(false, |sub| sub.starts_with('.'))
+      })
+    })
+    .map(|(group, kak_face)| {
+      format!(
+        "  set-face global {} {kak_face}\n",
+        face_name(&config.face_prefix, group)
+      )
+    })
+    .collect();
+
+  format!(
+    "# Map the faces of the highlights to the faces of the current colorscheme, for colorschemes that are not\n\
+     # tree-sitter aware.\n\
+     define-command kak-tree-sitter-apply-default-faces -docstring 'Map tree-sitter faces to the faces of the colorscheme' %{{\n\
+     {set_faces}}}"
+  )
+}
+
 /// Text-objects related file.
 pub fn text_objects_kak() -> &'static str {
   include_str!("../rc/text-objects.kak")
 }
+
+#[cfg(test)]
+mod tests {
+  use kak_tree_sitter_config::Config;
+
+  use super::{default_faces_kak, faces_kak};
+
+  #[test]
+  fn prefixed_faces() {
+    let faces = faces_kak("tree_sitter_");
+    assert!(faces
+      .contains("set-face global tree_sitter_comment_line                 tree_sitter_comment\n"));
+    assert!(faces.contains("%opt{kts_syntax_errors}"));
+    assert!(!faces.contains(" ts_"));
+  }
+
+  #[test]
+  fn default_faces() {
+    let mut config = Config::load_default_config().unwrap().highlight;
+    config.face_prefix = "tree_sitter_".to_owned();
+    config.disabled.insert("diff".to_owned());
+    let faces = default_faces_kak(&config);
+
+    assert!(faces.contains("  set-face global tree_sitter_keyword keyword\n"));
+    assert!(faces.contains("  set-face global tree_sitter_function_builtin builtin\n"));
+
+    // parents of highlighted groups are mapped even though they are not groups themselves
+    assert!(faces.contains("  set-face global tree_sitter_markup_list bullet\n"));
+
+    // disabled groups are not highlighted, so they are not mapped either
+    assert!(!faces.contains("diff"));
+  }
+}
@@ -1,19 +1,57 @@
 //! Requests that can be sent to the server from Kakoune.
 
-use std::fmt::Debug;
+use std::{
+  borrow::Cow,
+  fmt::{self, Debug, Display},
+  path::PathBuf,
+};
 
-use serde::{Deserialize, Serialize};
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::text_objects;
+use crate::{
+  error::OhNo, indent::IndentStyle, navigation::Direction, selection::Pos, text_objects,
+};
+
+/// Delimiter following the content of each buffer written to the buffer FIFO — including each buffer of a
+/// [`Request::HighlightBatch`] — so that buffers written back to back are told apart.
+///
+/// This is the ASCII record separator, which should not be present in regular text buffers.
+pub const BATCH_DELIMITER: &str = "\u{1e}";
+
+/// Version of the protocol spoken on the UNIX socket.
+///
+/// Clients send it along with their requests (see [`UnixRequest::to_json`]), so that a server and a client of different
+/// versions of KTS can tell whether they understand each other — e.g. after updating KTS while an older server is still
+/// running. The major version changes when requests or responses change in a way older peers cannot understand; the
+/// minor version when they only gain new kinds of requests or optional fields.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+/// Version of the protocol spoken on the UNIX socket; see [`PROTOCOL_VERSION`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ProtocolVersion {
+  pub major: u32,
+  pub minor: u32,
+}
+
+impl Display for ProtocolVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}.{}", self.major, self.minor)
+  }
+}
 
 /// Unidentified request (i.e. not linked to a given session).
 #[derive(Debug, Deserialize, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
 pub enum UnixRequest {
   /// Inform KTS that a session exists and that we should be sending back the Kakoune commands to get KTS features.
   RegisterSession {
     name: String,
     client: Option<String>,
+
+    /// Optional features supported by the session.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<Capability>,
   },
 
   /// Inform KTS that a session has exited.
@@ -24,16 +62,182 @@ pub enum UnixRequest {
 
   /// Ask KTS to shutdown.
   Shutdown,
+
+  /// Ask KTS about its status.
+  ///
+  /// Unlike other requests, KTS answers on the same connection, with a [`UnixResponse::Status`].
+  ///
+  /// [`UnixResponse::Status`]: crate::response::UnixResponse::Status
+  Status,
+
+  /// Ask KTS to highlight a buffer whose content is read from a file descriptor, such as the read end of a pipe.
+  ///
+  /// The file descriptor is passed along with the request (`SCM_RIGHTS`), and read until its write end is closed; the
+  /// content does not go through the buffer FIFO. The session must have registered with [`Capability::FdPassing`].
+  /// The response is sent to the session, as with [`Request::Highlight`], `force` included.
+  Highlight {
+    session: String,
+    client: String,
+    buffer: String,
+    lang: String,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    force: bool,
+  },
+
+  /// Ask KTS about the captures of the highlights query on a file, along with the face each of them gets.
+  ///
+  /// This is meant for query authors. As [`UnixRequest::Status`], KTS answers on the same connection, with a
+  /// [`UnixResponse::Captures`]. The file is read by KTS, so its path should be absolute.
+  ///
+  /// [`UnixResponse::Captures`]: crate::response::UnixResponse::Captures
+  DumpCaptures { lang: String, path: PathBuf },
+}
+
+/// Optional feature a session supports, advertised when registering with [`UnixRequest::RegisterSession`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+  /// The session can send the content of buffers as file descriptors, with [`UnixRequest::Highlight`].
+  FdPassing,
+
+  /// A capability unknown to this version of KTS; it is ignored.
+  #[serde(other)]
+  #[value(skip)]
+  Unknown,
 }
 
 impl UnixRequest {
+  /// Parse a request from JSON; see [`Request::from_json`].
+  ///
+  /// The request might come with the protocol version it was sent with, as [`UnixRequest::to_json`] does. A request of
+  /// another major version is rejected, as it cannot be understood reliably — except for [`UnixRequest::Status`], so
+  /// that any client can tell which version the server speaks. Requests without a version, e.g. written by hand, are
+  /// assumed to be of the current version.
+  ///
+  /// The request might also come with a string `id`, which is not part of the request itself; see
+  /// [`UnixRequest::to_json_with_id`].
+  pub fn from_json(json: &str) -> Result<Self, OhNo> {
+    let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str(json) else {
+      // let the parser tell what is wrong
+      return from_json(json);
+    };
+    let version = fields.remove("version");
+    let id = fields.remove("id");
+    if version.is_none() && id.is_none() {
+      return from_json(json);
+    }
+
+    if id.map_or(false, |id| !id.is_string()) {
+      return Err(OhNo::InvalidRequest {
+        req: json.to_owned(),
+        err: "the id of a request must be a string".to_owned(),
+      });
+    }
+
+    let req = from_json(&serde_json::Value::Object(fields).to_string());
+    let Some(version) = version else {
+      return req;
+    };
+
+    let version: ProtocolVersion =
+      serde_json::from_value(version).map_err(|err| OhNo::InvalidRequest {
+        req: json.to_owned(),
+        err: format!("invalid protocol version: {err}"),
+      })?;
+
+    if version.major != PROTOCOL_VERSION.major && !matches!(req, Ok(UnixRequest::Status)) {
+      return Err(OhNo::ProtocolMismatch {
+        version,
+        expected: PROTOCOL_VERSION,
+      });
+    }
+
+    if version.minor > PROTOCOL_VERSION.minor {
+      log::warn!(
+        "request of protocol version {version}, newer than the version of the server ({PROTOCOL_VERSION}); it might \
+         not be understood"
+      );
+    }
+
+    req
+  }
+
+  /// Serialize the request as JSON, along with the [`PROTOCOL_VERSION`], as expected on the UNIX socket.
+  pub fn to_json(&self) -> String {
+    self.to_json_fields(None)
+  }
+
+  /// Serialize the request as JSON, as [`UnixRequest::to_json`], along with an `id` the answer of the server echoes.
+  ///
+  /// This lets clients sending several requests tell the answers apart, and trace a request in the logs of the server.
+  /// Most requests are not answered, except when they are rejected; see [`UnixResponse::to_json`].
+  ///
+  /// [`UnixResponse::to_json`]: crate::response::UnixResponse::to_json
+  pub fn to_json_with_id(&self, id: &str) -> String {
+    self.to_json_fields(Some(id))
+  }
+
+  fn to_json_fields(&self, id: Option<&str>) -> String {
+    let mut value = serialize(self, serde_json::to_value);
+    if let Some(fields) = value.as_object_mut() {
+      fields.insert(
+        "version".to_owned(),
+        serde_json::to_value(PROTOCOL_VERSION).unwrap_or_default(),
+      );
+
+      if let Some(id) = id {
+        fields.insert("id".to_owned(), id.into());
+      }
+    }
+
+    value.to_string()
+  }
+
+  /// Register a session, sending back the initial commands to the given client, if any.
+  pub fn register_session(name: impl Into<String>, client: impl Into<Option<String>>) -> Self {
+    UnixRequest::RegisterSession {
+      name: name.into(),
+      client: client.into(),
+      capabilities: Vec::new(),
+    }
+  }
+
+  /// Inform the server that a session has exited.
+  pub fn session_exit(name: impl Into<String>) -> Self {
+    UnixRequest::SessionExit { name: name.into() }
+  }
+
   /// Add a session name to a [`UnidentifiedRequest`], replacing it if one was already provided.
   pub fn with_session(self, name: impl Into<String>) -> Self {
     let name = name.into();
 
     match self {
-      UnixRequest::RegisterSession { client, .. } => UnixRequest::RegisterSession { client, name },
+      UnixRequest::RegisterSession {
+        client,
+        capabilities,
+        ..
+      } => UnixRequest::RegisterSession {
+        name,
+        client,
+        capabilities,
+      },
       UnixRequest::SessionExit { .. } => UnixRequest::SessionExit { name },
+      UnixRequest::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+        ..
+      } => UnixRequest::Highlight {
+        session: name,
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+      },
       _ => self,
     }
   }
@@ -42,24 +246,67 @@ impl UnixRequest {
 /// Request payload.
 ///
 /// Request payload are parameterized with the « origin » at which requests are expected.
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
 pub enum Request {
   /// Try enabling highlighting for a given filetype.
   ///
   /// This request starts a “highlighting session.” The response will not replay with « supports highlighting » or
   /// « does not support highlighting », but instead will insert the Kakoune commands to ask for highlights only if the
   /// filetype is supported.
-  TryEnableHighlight { lang: String, client: String },
+  ///
+  /// The buffer, if provided, is used to pick a fallback language if the filetype is not supported.
+  TryEnableHighlight {
+    lang: String,
+    client: String,
+    #[serde(default)]
+    buffer: Option<String>,
+  },
 
   /// Ask to highlight the given buffer.
   ///
-  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in. If
+  /// `force` is set, the tree kept for the buffer is dropped and the buffer is parsed from scratch; e.g. when the tree
+  /// is suspected to be stale.
+  ///
+  /// If `output` is set, the highlights are not sent to the session; instead, a Kakoune script setting them is written
+  /// to that path, to be sourced later on.
+  ///
+  /// If `syntax_errors` is set, the syntax errors of the buffer are highlighted as well, over the other highlights.
   Highlight {
     client: String,
     buffer: String,
     lang: String,
     timestamp: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    force: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    syntax_errors: bool,
+  },
+
+  /// Ask to highlight several buffers at once.
+  ///
+  /// The content of each buffer is streamed right after in the buffer FIFO, in the same order as `items`, each one
+  /// followed by [`BATCH_DELIMITER`]. Buffers are highlighted independently: a failure for one of them does not prevent
+  /// the others from being highlighted.
+  HighlightBatch {
+    client: String,
+    items: Vec<HighlightItem>,
+  },
+
+  /// Ask to parse the given buffer from scratch and highlight it.
+  ///
+  /// This is a shorthand for a [`Request::Highlight`] with `force` set; see [`Request::expand_reparse`]. The content of
+  /// the buffer is streamed right after in the same command FIFO file the request was sent in.
+  Reparse {
+    client: String,
+    buffer: String,
+    lang: String,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    syntax_errors: bool,
   },
 
   /// TODO
@@ -71,21 +318,630 @@ pub enum Request {
     selections: String,
     mode: text_objects::OperationMode,
   },
+
+  /// Ask to indent a line (1-based) of the given buffer.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  Indent {
+    client: String,
+    buffer: String,
+    lang: String,
+    line: usize,
+    #[serde(flatten)]
+    style: IndentStyle,
+  },
+
+  /// Ask to comment the lines spanned by the given selections, or to uncomment them if they are already commented.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  ToggleComment {
+    client: String,
+    buffer: String,
+    lang: String,
+    selections: String,
+  },
+
+  /// Ask to select the siblings of the nodes spanning the given selections that are of the same kind (e.g. all the
+  /// arguments of a call).
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in. If
+  /// `named_only` is `true`, anonymous nodes (e.g. punctuation) are ignored.
+  SelectSiblings {
+    client: String,
+    buffer: String,
+    lang: String,
+    selections: String,
+    #[serde(default)]
+    named_only: bool,
+  },
+
+  /// Ask to move each selection to the next (or previous) node of the given kind (e.g. `function_item`).
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in. If `wrap`
+  /// is `true`, the search wraps around the end (or start) of the buffer.
+  NavigateNode {
+    client: String,
+    buffer: String,
+    lang: String,
+    selections: String,
+    kind: String,
+    direction: Direction,
+    #[serde(default)]
+    wrap: bool,
+  },
+
+  /// Ask to dump the tree of the given buffer as an S-expression.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in. If
+  /// `range` is provided, only the smallest node spanning the given byte range is dumped.
+  DumpTree {
+    client: String,
+    buffer: String,
+    lang: String,
+    #[serde(default)]
+    range: Option<(usize, usize)>,
+  },
+
+  /// Ask to run a user-provided query on the given buffer, and return its captures.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  RunQuery {
+    client: String,
+    buffer: String,
+    lang: String,
+    query: String,
+  },
+
+  /// Ask to select all the nodes captured by a user-provided query on the given buffer.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in. At most
+  /// `server.max_selections` selections are returned.
+  SelectMatching {
+    client: String,
+    buffer: String,
+    lang: String,
+    query: String,
+  },
+
+  /// Ask for the prose ranges (i.e. comments and strings) of the given buffer.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  ProseRanges {
+    client: String,
+    buffer: String,
+    lang: String,
+    timestamp: u64,
+  },
+
+  /// Ask for the syntax errors (i.e. `ERROR` and `MISSING` nodes) of the given buffer.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  SyntaxErrors {
+    client: String,
+    buffer: String,
+    lang: String,
+    timestamp: u64,
+  },
+
+  /// Ask about the named node under a position of the given buffer (e.g. the cursor): its kind, range and ancestors.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  NodeInfo {
+    client: String,
+    buffer: String,
+    lang: String,
+    position: Pos,
+  },
+
+  /// Ask for the regions of the given buffer injected with another language, along with the language highlighting
+  /// them; they are not highlighted.
+  ///
+  /// The content of the buffer is streamed right after in the same command FIFO file the request was sent in.
+  Injections {
+    client: String,
+    buffer: String,
+    lang: String,
+  },
+
+  /// Ask for the comment tokens of a language, to set the comment options of Kakoune for the current buffer of the
+  /// client; see [`comment_tokens`].
+  ///
+  /// No buffer content follows the request.
+  ///
+  /// [`comment_tokens`]: crate::comment::comment_tokens
+  CommentTokens { client: String, lang: String },
+
+  /// Ask to pause highlighting for the session, or to resume it.
+  ///
+  /// While paused, highlight requests are acknowledged, but no highlights are sent, and those of the buffers are
+  /// removed. Once resumed, the next highlight request of a buffer sends all of its highlights again.
+  SetEnabled { client: String, enabled: bool },
+
+  /// Make the handler panic, to test that panics are isolated; only available with the `panic-request` feature.
+  #[cfg(feature = "panic-request")]
+  #[doc(hidden)]
+  Panic { client: String },
+}
+
+/// A buffer to highlight as part of a [`Request::HighlightBatch`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HighlightItem {
+  pub buffer: String,
+  pub lang: String,
+  pub timestamp: u64,
+
+  /// Whether the syntax errors of the buffer are highlighted as well; see [`Request::Highlight`].
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub syntax_errors: bool,
+}
+
+impl HighlightItem {
+  pub fn new(buffer: impl Into<String>, lang: impl Into<String>, timestamp: u64) -> Self {
+    Self {
+      buffer: buffer.into(),
+      lang: lang.into(),
+      timestamp,
+      syntax_errors: false,
+    }
+  }
 }
 
 impl Request {
+  pub fn try_enable_highlight(lang: impl Into<String>, client: impl Into<String>) -> Self {
+    Request::TryEnableHighlight {
+      lang: lang.into(),
+      client: client.into(),
+      buffer: None,
+    }
+  }
+
+  pub fn highlight(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    timestamp: u64,
+  ) -> Self {
+    Request::Highlight {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      timestamp,
+      force: false,
+      output: None,
+      syntax_errors: false,
+    }
+  }
+
+  pub fn reparse(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    timestamp: u64,
+  ) -> Self {
+    Request::Reparse {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      timestamp,
+      syntax_errors: false,
+    }
+  }
+
+  pub fn highlight_batch(
+    client: impl Into<String>,
+    items: impl IntoIterator<Item = HighlightItem>,
+  ) -> Self {
+    Request::HighlightBatch {
+      client: client.into(),
+      items: items.into_iter().collect(),
+    }
+  }
+
+  pub fn text_objects(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    pattern: impl Into<String>,
+    selections: impl Into<String>,
+    mode: text_objects::OperationMode,
+  ) -> Self {
+    Request::TextObjects {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      pattern: pattern.into(),
+      selections: selections.into(),
+      mode,
+    }
+  }
+
+  pub fn indent(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    line: usize,
+    style: IndentStyle,
+  ) -> Self {
+    Request::Indent {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      line,
+      style,
+    }
+  }
+
+  pub fn toggle_comment(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    selections: impl Into<String>,
+  ) -> Self {
+    Request::ToggleComment {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      selections: selections.into(),
+    }
+  }
+
+  pub fn select_siblings(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    selections: impl Into<String>,
+    named_only: bool,
+  ) -> Self {
+    Request::SelectSiblings {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      selections: selections.into(),
+      named_only,
+    }
+  }
+
+  pub fn navigate_node(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    selections: impl Into<String>,
+    kind: impl Into<String>,
+    direction: Direction,
+    wrap: bool,
+  ) -> Self {
+    Request::NavigateNode {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      selections: selections.into(),
+      kind: kind.into(),
+      direction,
+      wrap,
+    }
+  }
+
+  /// Dump the whole tree; use [`Request::with_range`] to only dump a part of it.
+  pub fn dump_tree(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+  ) -> Self {
+    Request::DumpTree {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      range: None,
+    }
+  }
+
+  pub fn run_query(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    query: impl Into<String>,
+  ) -> Self {
+    Request::RunQuery {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      query: query.into(),
+    }
+  }
+
+  pub fn select_matching(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    query: impl Into<String>,
+  ) -> Self {
+    Request::SelectMatching {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      query: query.into(),
+    }
+  }
+
+  pub fn prose_ranges(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    timestamp: u64,
+  ) -> Self {
+    Request::ProseRanges {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      timestamp,
+    }
+  }
+
+  pub fn syntax_errors(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    timestamp: u64,
+  ) -> Self {
+    Request::SyntaxErrors {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      timestamp,
+    }
+  }
+
+  pub fn node_info(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+    position: Pos,
+  ) -> Self {
+    Request::NodeInfo {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+      position,
+    }
+  }
+
+  pub fn injections(
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    lang: impl Into<String>,
+  ) -> Self {
+    Request::Injections {
+      client: client.into(),
+      buffer: buffer.into(),
+      lang: lang.into(),
+    }
+  }
+
+  pub fn comment_tokens(client: impl Into<String>, lang: impl Into<String>) -> Self {
+    Request::CommentTokens {
+      client: client.into(),
+      lang: lang.into(),
+    }
+  }
+
+  pub fn set_enabled(client: impl Into<String>, enabled: bool) -> Self {
+    Request::SetEnabled {
+      client: client.into(),
+      enabled,
+    }
+  }
+
+  /// Make a [`Request::Highlight`] parse the buffer from scratch, ignoring the tree kept for it.
+  ///
+  /// Other requests are left untouched.
+  pub fn with_force(self) -> Self {
+    match self {
+      Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        output,
+        syntax_errors,
+        ..
+      } => Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force: true,
+        output,
+        syntax_errors,
+      },
+      _ => self,
+    }
+  }
+
+  /// Make a [`Request::Highlight`] write the highlights to a Kakoune script at `path`, instead of sending them.
+  ///
+  /// Other requests are left untouched.
+  pub fn with_output(self, path: impl Into<PathBuf>) -> Self {
+    match self {
+      Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+        syntax_errors,
+        ..
+      } => Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+        output: Some(path.into()),
+        syntax_errors,
+      },
+      _ => self,
+    }
+  }
+
+  /// The [`Request::Highlight`] a [`Request::Reparse`] is a shorthand for.
+  ///
+  /// Other requests are returned as is.
+  pub fn expand_reparse(&self) -> Cow<'_, Self> {
+    match self {
+      Request::Reparse {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        syntax_errors,
+      } => Cow::Owned(Request::Highlight {
+        client: client.clone(),
+        buffer: buffer.clone(),
+        lang: lang.clone(),
+        timestamp: *timestamp,
+        force: true,
+        output: None,
+        syntax_errors: *syntax_errors,
+      }),
+      _ => Cow::Borrowed(self),
+    }
+  }
+
+  /// Make a [`Request::Highlight`] highlight the syntax errors of the buffer as well.
+  ///
+  /// Other requests are left untouched.
+  pub fn with_syntax_errors(self) -> Self {
+    match self {
+      Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+        output,
+        ..
+      } => Request::Highlight {
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+        output,
+        syntax_errors: true,
+      },
+      _ => self,
+    }
+  }
+
+  /// Restrict a [`Request::DumpTree`] to the smallest node spanning the `start..end` byte range.
+  ///
+  /// Other requests are left untouched.
+  pub fn with_range(self, start: usize, end: usize) -> Self {
+    match self {
+      Request::DumpTree {
+        client,
+        buffer,
+        lang,
+        ..
+      } => Request::DumpTree {
+        client,
+        buffer,
+        lang,
+        range: Some((start, end)),
+      },
+      _ => self,
+    }
+  }
+
+  /// Parse a request from JSON, as received on the command FIFO.
+  ///
+  /// Unknown fields are rejected, so that typos are not silently ignored. Errors name the kind of request that was
+  /// tried, if any, along with the faulty field.
+  pub fn from_json(json: &str) -> Result<Self, OhNo> {
+    from_json(json)
+  }
+
+  /// Serialize the request as JSON, as expected on the command FIFO.
+  pub fn to_json(&self) -> String {
+    serialize(self, serde_json::to_string)
+  }
+
+  /// Whether the request is followed by the content of the buffer it is about.
+  pub fn needs_buffer(&self) -> bool {
+    match self {
+      Request::TryEnableHighlight { .. }
+      | Request::CommentTokens { .. }
+      | Request::SetEnabled { .. } => false,
+      #[cfg(feature = "panic-request")]
+      Request::Panic { .. } => false,
+      Request::HighlightBatch { items, .. } => !items.is_empty(),
+      _ => true,
+    }
+  }
+
   pub fn client_name(&self) -> Option<&str> {
     match self {
       Request::TryEnableHighlight { client, .. } => Some(client.as_str()),
       Request::Highlight { client, .. } => Some(client.as_str()),
+      Request::HighlightBatch { client, .. } => Some(client.as_str()),
+      Request::Reparse { client, .. } => Some(client.as_str()),
       Request::TextObjects { client, .. } => Some(client.as_str()),
+      Request::Indent { client, .. } => Some(client.as_str()),
+      Request::ToggleComment { client, .. } => Some(client.as_str()),
+      Request::SelectSiblings { client, .. } => Some(client.as_str()),
+      Request::NavigateNode { client, .. } => Some(client.as_str()),
+      Request::DumpTree { client, .. } => Some(client.as_str()),
+      Request::RunQuery { client, .. } => Some(client.as_str()),
+      Request::SelectMatching { client, .. } => Some(client.as_str()),
+      Request::ProseRanges { client, .. } => Some(client.as_str()),
+      Request::SyntaxErrors { client, .. } => Some(client.as_str()),
+      Request::NodeInfo { client, .. } => Some(client.as_str()),
+      Request::Injections { client, .. } => Some(client.as_str()),
+      Request::CommentTokens { client, .. } => Some(client.as_str()),
+      Request::SetEnabled { client, .. } => Some(client.as_str()),
+      #[cfg(feature = "panic-request")]
+      Request::Panic { client } => Some(client.as_str()),
     }
   }
 }
 
+/// String field of a JSON request, if the request is at least a JSON object.
+///
+/// This is useful to know more about a request that cannot be parsed; e.g. the client to report the error to.
+pub fn request_field(json: &str, field: &str) -> Option<String> {
+  let value: serde_json::Value = serde_json::from_str(json).ok()?;
+  value.get(field)?.as_str().map(str::to_owned)
+}
+
+/// Serialize a request or a response with `serialize` (e.g. [`serde_json::to_string`]).
+///
+/// This cannot fail, as requests and responses only have string keys and no custom `Serialize` implementation.
+pub(crate) fn serialize<T, R>(value: T, serialize: impl FnOnce(T) -> serde_json::Result<R>) -> R {
+  serialize(value)
+    .expect("requests and responses only have string keys and no custom Serialize implementation")
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, OhNo> {
+  // serde does not say which variant it tried to match, so we tell
+  serde_json::from_str(json).map_err(|err| match request_field(json, "type") {
+    Some(kind) => OhNo::MalformedRequest {
+      kind,
+      err: err.to_string(),
+    },
+    None => OhNo::InvalidRequest {
+      req: json.to_owned(),
+      err: err.to_string(),
+    },
+  })
+}
+
 #[cfg(test)]
 mod tests {
-  use super::Request;
+  use crate::error::OhNo;
+
+  use super::{HighlightItem, Request, UnixRequest, PROTOCOL_VERSION};
+  use crate::selection::Pos;
 
   #[test]
   fn serialization() {
@@ -94,6 +950,9 @@ mod tests {
       buffer: "/tmp/a.rs".to_owned(),
       lang: "rust".to_owned(),
       timestamp: 0,
+      force: false,
+      output: None,
+      syntax_errors: false,
     };
     let expected =
       r#"{"type":"highlight","client":"client0","buffer":"/tmp/a.rs","lang":"rust","timestamp":0}"#;
@@ -101,4 +960,138 @@ mod tests {
 
     assert_eq!(serialized.unwrap(), expected);
   }
+
+  #[test]
+  fn constructors() {
+    assert_eq!(
+      Request::highlight("client0", "/tmp/a.rs", "rust", 0).to_json(),
+      r#"{"type":"highlight","client":"client0","buffer":"/tmp/a.rs","lang":"rust","timestamp":0}"#
+    );
+    assert_eq!(
+      Request::highlight("client0", "/tmp/a.rs", "rust", 0)
+        .with_force()
+        .to_json(),
+      r#"{"type":"highlight","client":"client0","buffer":"/tmp/a.rs","lang":"rust","timestamp":0,"force":true}"#
+    );
+    assert_eq!(
+      Request::highlight("client0", "/tmp/a.rs", "rust", 0)
+        .with_output("/tmp/a.kak")
+        .to_json(),
+      r#"{"type":"highlight","client":"client0","buffer":"/tmp/a.rs","lang":"rust","timestamp":0,"output":"/tmp/a.kak"}"#
+    );
+    assert_eq!(
+      Request::dump_tree("client0", "/tmp/a.rs", "rust")
+        .with_range(1, 3)
+        .to_json(),
+      r#"{"type":"dump_tree","client":"client0","buffer":"/tmp/a.rs","lang":"rust","range":[1,3]}"#
+    );
+    assert_eq!(
+      Request::node_info("client0", "/tmp/a.rs", "rust", Pos { line: 2, col: 5 }).to_json(),
+      r#"{"type":"node_info","client":"client0","buffer":"/tmp/a.rs","lang":"rust","position":{"line":2,"col":5}}"#
+    );
+    assert_eq!(
+      Request::injections("client0", "/tmp/a.md", "markdown").to_json(),
+      r#"{"type":"injections","client":"client0","buffer":"/tmp/a.md","lang":"markdown"}"#
+    );
+    assert_eq!(
+      Request::comment_tokens("client0", "rust").to_json(),
+      r#"{"type":"comment_tokens","client":"client0","lang":"rust"}"#
+    );
+    assert_eq!(
+      Request::set_enabled("client0", false).to_json(),
+      r#"{"type":"set_enabled","client":"client0","enabled":false}"#
+    );
+    assert_eq!(
+      serde_json::to_string(&UnixRequest::register_session("foo", None)).unwrap(),
+      r#"{"type":"register_session","name":"foo","client":null}"#
+    );
+    assert_eq!(
+      serde_json::to_string(&UnixRequest::Status).unwrap(),
+      r#"{"type":"status"}"#
+    );
+  }
+
+  #[test]
+  fn needs_buffer() {
+    let req = Request::highlight_batch(
+      "client0",
+      [
+        HighlightItem::new("/tmp/a.rs", "rust", 0),
+        HighlightItem::new("/tmp/b.rs", "rust", 0),
+      ],
+    );
+    assert!(req.needs_buffer());
+
+    assert!(!Request::highlight_batch("client0", []).needs_buffer());
+    assert!(!Request::set_enabled("client0", true).needs_buffer());
+    assert!(!Request::comment_tokens("client0", "rust").needs_buffer());
+    assert!(Request::reparse("client0", "/tmp/a.rs", "rust", 0).needs_buffer());
+  }
+
+  #[test]
+  fn malformed_requests() {
+    let err = |json: &str| Request::from_json(json).unwrap_err().to_string();
+
+    assert_eq!(
+      err(
+        r#"{"type":"highlight","client":"client0","buffer":"a.rs","lang":"rust","timestamp":0,"sesion":"s"}"#
+      ),
+      "malformed highlight request: unknown field `sesion`"
+    );
+    assert_eq!(
+      err(r#"{"type":"highlight","client":"client0","buffer":"a.rs","lang":"rust"}"#),
+      "malformed highlight request: missing field `timestamp`"
+    );
+    assert!(err(r#"{"type":"hilight"}"#)
+      .starts_with("malformed hilight request: unknown variant `hilight`"));
+    assert!(err("not json").starts_with("invalid request not json"));
+
+    assert!(UnixRequest::from_json(r#"{"type":"session_exit","nam":"s"}"#).is_err());
+  }
+
+  #[test]
+  fn protocol_versions() {
+    let json = UnixRequest::session_exit("s").to_json();
+    assert!(
+      json.contains(&format!(
+        r#""version":{{"major":{},"minor":{}}}"#,
+        PROTOCOL_VERSION.major, PROTOCOL_VERSION.minor
+      )),
+      "{json}"
+    );
+    assert!(matches!(
+      UnixRequest::from_json(&json),
+      Ok(UnixRequest::SessionExit { name }) if name == "s"
+    ));
+
+    // requests written by hand do not need a version
+    assert!(UnixRequest::from_json(r#"{"type":"session_exit","name":"s"}"#).is_ok());
+
+    let err = |json: &str| UnixRequest::from_json(json).unwrap_err().to_string();
+    assert!(err(r#"{"type":"reload","version":{"major":99,"minor":0}}"#)
+      .starts_with("the client speaks protocol version 99.0, but the server speaks version"));
+    assert!(err(r#"{"type":"reload","version":"1.0"}"#).contains("invalid protocol version"));
+    assert!(
+      UnixRequest::from_json(r#"{"type":"status","version":{"major":99,"minor":0}}"#).is_ok()
+    );
+
+    // ids are not part of the requests themselves
+    let json = UnixRequest::Reload.to_json_with_id("42");
+    assert!(json.contains(r#""id":"42""#), "{json}");
+    assert!(matches!(
+      UnixRequest::from_json(&json),
+      Ok(UnixRequest::Reload)
+    ));
+    assert!(UnixRequest::from_json(r#"{"type":"reload","id":"42"}"#).is_ok());
+    assert!(err(r#"{"type":"reload","id":42}"#).contains("the id of a request must be a string"));
+
+    // servers predating versions reject them as unknown fields
+    assert!(matches!(
+      OhNo::rejected(
+        "malformed reload request: unknown field `version`, there are no fields".to_owned(),
+        1
+      ),
+      OhNo::OutdatedServer
+    ));
+  }
 }
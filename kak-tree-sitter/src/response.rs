@@ -1,13 +1,85 @@
 //! Response sent from the daemon to Kakoune, typically via the socket interface (kak -p, etc.).
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, iter, path::PathBuf};
 
 use itertools::Itertools;
+use kak_tree_sitter_config::CommentConfig;
+use serde::{Deserialize, Serialize};
 
-use crate::{highlighting::KakHighlightRange, selection::Sel};
+use crate::{
+  edit::TextEdit,
+  error::exit_code,
+  highlighting::KakHighlightRange,
+  metrics::ParseMetrics,
+  request::{serialize, ProtocolVersion, BATCH_DELIMITER},
+  selection::Sel,
+  tree_sitter_state::{HighlightCapture, Injection, NamedCapture, NodeInfo},
+};
+
+/// Value of `%opt{kts_status}` when highlighting is active for a buffer.
+pub const STATUS_ACTIVE: &str = "tree-sitter";
+
+/// Response sent by the daemon back to a client of the UNIX socket.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UnixResponse {
+  /// Status of the server, answering [`UnixRequest::Status`].
+  ///
+  /// [`UnixRequest::Status`]: crate::request::UnixRequest::Status
+  Status {
+    /// Version of the protocol the server speaks.
+    protocol_version: ProtocolVersion,
+
+    /// Parse metrics, per language.
+    parse_metrics: BTreeMap<String, ParseMetrics>,
+  },
+
+  /// Captures of the highlights query on a file, answering [`UnixRequest::DumpCaptures`].
+  ///
+  /// [`UnixRequest::DumpCaptures`]: crate::request::UnixRequest::DumpCaptures
+  Captures { captures: Vec<HighlightCapture> },
+
+  /// The request was rejected; e.g. it was invalid, too large, or not received in time.
+  Error {
+    reason: String,
+
+    /// Exit status clients should exit with; see [`exit_code`].
+    #[serde(default = "default_exit_code")]
+    exit_code: i32,
+  },
+}
+
+impl UnixResponse {
+  /// Serialize the response as JSON, along with the `id` of the request it answers, if it had one.
+  ///
+  /// See [`UnixRequest::to_json_with_id`].
+  ///
+  /// [`UnixRequest::to_json_with_id`]: crate::request::UnixRequest::to_json_with_id
+  pub fn to_json(&self, id: Option<&str>) -> String {
+    let mut value = serialize(self, serde_json::to_value);
+    if let (Some(fields), Some(id)) = (value.as_object_mut(), id) {
+      fields.insert("id".to_owned(), id.into());
+    }
+
+    value.to_string()
+  }
+}
+
+/// Exit status of rejected requests, for servers that do not tell it.
+fn default_exit_code() -> i32 {
+  exit_code::FAILURE
+}
 
 /// Response sent by the daemon to Kakoune.
-#[derive(Debug, Eq, PartialEq)]
+///
+/// Responses are turned into Kakoune commands with [`Response::to_kak_cmd`]. They can also be serialized (e.g. to JSON
+/// with [`Response::to_json`]) for front-ends other than Kakoune.
+///
+/// Requests about a buffer come with its content, so there is no “buffer not parsed” response: a buffer without a tree
+/// yet (e.g. text-objects requested before any highlighting) is parsed on demand, and the request is answered as
+/// usual. Only a buffer that cannot be parsed at all leads to a failure, such as [`Response::HighlightFailed`].
+#[derive(Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Response {
   /// Status change.
   StatusChanged { status: String },
@@ -32,18 +104,120 @@ pub enum Response {
     remove_default_highlighter: bool,
   },
 
+  /// A language is not supported.
+  ///
+  /// This response is only sent once per language, so that the user is not spammed. Known languages close to the
+  /// unsupported one are suggested, if any.
+  UnsupportedLanguage {
+    lang: String,
+    suggestions: Vec<String>,
+  },
+
   /// Highlights.
   ///
-  /// This response is generated when new highlights are asked.
+  /// This response is generated when new highlights are asked. `activated` is set the first time a buffer is
+  /// successfully highlighted, in which case `%opt{kts_status}` is set too.
   Highlights {
     timestamp: u64,
     ranges: Vec<KakHighlightRange>,
+    activated: bool,
+  },
+
+  /// Highlights added to the ones of the previous [`Response::Highlights`] of the buffer.
+  ///
+  /// Buffers with injections are highlighted progressively: the ranges of the injected languages are sent once resolved,
+  /// after the ones of the buffer’s own language. They are only added if they do not override any of those; otherwise,
+  /// all the ranges are sent again with [`Response::Highlights`], as Kakoune would merge the faces of overlapping ranges.
+  HighlightsAdded { ranges: Vec<KakHighlightRange> },
+
+  /// Highlighting failed for a buffer that was highlighted before; `%opt{kts_status}` is cleared.
+  HighlightFailed,
+
+  /// Highlights were asked for an older timestamp than the last highlighted one of the buffer; nothing is sent.
+  ///
+  /// Requests sent concurrently (e.g. from several clients of the UNIX socket) can be handled out of order; applying
+  /// the highlights of the older one would replace the more recent ones.
+  StaleHighlights { timestamp: u64, current: u64 },
+
+  /// Highlights were asked for an older timestamp than the last highlighted one of the buffer, and Kakoune is asked
+  /// for its current content instead (see `highlight.on_stale`).
+  ///
+  /// The buffer is then highlighted from scratch, so that the answer cannot be stale again.
+  RefreshBuffer { buffer: String },
+
+  /// Highlighting was paused or resumed for the session, answering a [`Request::SetEnabled`].
+  ///
+  /// Pausing removes the highlights of all the buffers; they are sent again by the next highlight request of each
+  /// buffer once resumed, e.g. when Kakoune is idle.
+  ///
+  /// [`Request::SetEnabled`]: crate::request::Request::SetEnabled
+  HighlightingEnabled { enabled: bool },
+
+  /// Highlights were asked while highlighting is paused for the session; nothing is sent.
+  HighlightsPaused,
+
+  /// The buffer is larger than `highlight.max_buffer_bytes`, and is not highlighted.
+  ///
+  /// Kakoune falls back to its own highlighting for the buffer. This is sent instead of enabling highlighting if the file
+  /// of the buffer is already too large, or when highlighting a buffer that has grown too large.
+  BufferTooLarge { size: u64, max_size: u64 },
+
+  /// Highlights of several buffers, answering a batch request.
+  ///
+  /// Buffers that could not be highlighted are reported in `failures`; they do not prevent the other buffers from being
+  /// highlighted.
+  HighlightBatch {
+    highlights: Vec<BufferHighlights>,
+    failures: Vec<BufferFailure>,
   },
 
   /// Selections.
   ///
   /// These selections are typically returned when the user asked to perform text-objects queries.
   Selections { sels: Vec<Sel> },
+
+  /// Indentation of a line, replacing its leading whitespace.
+  Indent { line: usize, indent: String },
+
+  /// Edits to apply to the buffer, sorted by position.
+  Edits { edits: Vec<TextEdit> },
+
+  /// Tree dump.
+  ///
+  /// The S-expression of a buffer’s tree, displayed in a scratch buffer.
+  TreeDump { sexp: String },
+
+  /// Named node under a position, displayed in an info box.
+  NodeInfo { info: NodeInfo },
+
+  /// Regions injected with another language, displayed in a scratch buffer.
+  Injections { injections: Vec<Injection> },
+
+  /// Comment tokens of a language, set as the comment options of the current buffer.
+  CommentTokens { tokens: CommentConfig },
+
+  /// Captures of a user-provided query, displayed in a scratch buffer.
+  QueryCaptures { captures: Vec<NamedCapture> },
+
+  /// Prose ranges.
+  ///
+  /// Regions of the buffer that are comments or strings, typically used to restrict spell checking. They are
+  /// highlighted with `face`; e.g. `ts_spell`.
+  ProseRanges {
+    timestamp: u64,
+    ranges: Vec<Sel>,
+    face: String,
+  },
+
+  /// Syntax errors.
+  ///
+  /// Regions of the buffer that tree-sitter could not parse, or where it expected a missing token. They are highlighted
+  /// with `face`; e.g. `ts_syntax_error`.
+  SyntaxErrors {
+    timestamp: u64,
+    ranges: Vec<Sel>,
+    face: String,
+  },
 }
 
 impl Response {
@@ -53,11 +227,14 @@ impl Response {
     }
   }
 
+  /// Serialize the response as JSON.
+  pub fn to_json(&self) -> String {
+    serialize(self, serde_json::to_string)
+  }
+
   pub fn to_kak_cmd(&self, client: Option<&str>) -> Option<String> {
     let kak_cmd = match self {
-      Response::StatusChanged { status, .. } => {
-        format!("info %{{{status}}}",)
-      }
+      Response::StatusChanged { status, .. } => kak_info(client, None, status),
 
       Response::Init {
         cmd_fifo_path,
@@ -66,16 +243,18 @@ impl Response {
       } => {
         let mut resp = format!(
           "set-option global kts_cmd_fifo_path {cmd}\n
-           set-option global kts_buf_fifo_path {buf}",
+           set-option global kts_buf_fifo_path {buf}\n
+           set-option global kts_batch_delimiter {delim}",
           cmd = cmd_fifo_path.display(),
           buf = buf_fifo_path.display(),
+          delim = kak_quote(BATCH_DELIMITER),
         );
 
         if *with_highlighting {
-          resp.push_str(&format!(
+          resp.push_str(
             "\nkak-tree-sitter-enable-highlighting\n
-               kak-tree-sitter-req-enable"
-          ));
+               kak-tree-sitter-req-enable",
+          );
         }
 
         resp
@@ -101,22 +280,269 @@ impl Response {
         }
       }
 
-      Response::Highlights { timestamp, ranges } => {
+      Response::UnsupportedLanguage { lang, suggestions } => {
+        let short_msg = format!(
+          "kak-tree-sitter: language {lang} is not supported{}",
+          did_you_mean(suggestions)
+        );
+        let msg = format!(
+          "{short_msg}; add it to your configuration and install its grammar and queries with: ktsctl manage -fci \
+           {lang}"
+        );
+
+        format!(
+          "echo -debug {msg}\necho {short_msg}",
+          msg = kak_quote(&msg),
+          short_msg = kak_quote(&short_msg)
+        )
+      }
+
+      Response::Highlights {
+        timestamp,
+        ranges,
+        activated,
+      } => highlights_with_status(*timestamp, ranges, *activated),
+
+      Response::HighlightsAdded { ranges } if ranges.is_empty() => String::new(),
+
+      Response::HighlightsAdded { ranges } => {
+        // contrary to setting the option, adding to it does not take a timestamp
         let ranges_str = ranges
           .iter()
           .map(KakHighlightRange::to_kak_range_str)
           .join(" ");
 
+        format!("set -add buffer kts_highlighter_ranges {ranges_str}")
+      }
+
+      Response::HighlightFailed => "set-option buffer kts_status ''".to_owned(),
+
+      Response::StaleHighlights { .. } | Response::HighlightsPaused => String::new(),
+
+      // the highlights go to the client of the stale request, whichever buffer it displays
+      Response::RefreshBuffer { buffer } => client
+        .map(|client| {
+          format!(
+            "evaluate-commands -buffer {buffer} {cmd}",
+            buffer = kak_quote(buffer),
+            cmd = kak_quote(&format!(
+              "kak-tree-sitter-req-refresh-buffer {}",
+              kak_quote(client)
+            ))
+          )
+        })
+        .unwrap_or_default(),
+
+      Response::HighlightingEnabled { enabled: true } => {
+        format!(
+          "set-option global kts_highlighting_paused false\n{}",
+          kak_info(client, None, "kak-tree-sitter: highlighting resumed")
+        )
+      }
+
+      Response::HighlightingEnabled { enabled: false } => [
+        "set-option global kts_highlighting_paused true",
+        "evaluate-commands -buffer * %{ set-option buffer kts_highlighter_ranges %val{timestamp} }",
+        &kak_info(client, None, "kak-tree-sitter: highlighting paused"),
+      ]
+      .join("\n"),
+
+      Response::BufferTooLarge { size, max_size } => {
+        let msg = format!(
+          "kak-tree-sitter: buffer too large to be highlighted ({size} bytes, more than highlight.max_buffer_bytes = \
+           {max_size}); using the default highlighter"
+        );
+
         format!(
-          "{range_specs} {timestamp} {ranges_str}",
-          range_specs = "set buffer kts_highlighter_ranges",
+          "kak-tree-sitter-highlight-disable\necho -debug {msg}\necho {msg}",
+          msg = kak_quote(&msg)
         )
       }
 
+      Response::HighlightBatch {
+        highlights,
+        failures,
+      } => {
+        let highlights = highlights.iter().map(|hl| {
+          format!(
+            "evaluate-commands -buffer {buffer} {cmd}",
+            buffer = kak_quote(&hl.buffer),
+            cmd = kak_quote(&highlights_with_status(
+              hl.timestamp,
+              &hl.ranges,
+              hl.activated
+            ))
+          )
+        });
+        let failures = failures.iter().map(|failure| {
+          let msg = format!(
+            "kak-tree-sitter: cannot highlight buffer {}: {}",
+            failure.buffer, failure.reason
+          );
+          format!("echo -debug {}", kak_quote(&msg))
+        });
+
+        highlights.chain(failures).join("\n")
+      }
+
       Response::Selections { sels } => {
         let sels_str = sels.iter().map(|sel| sel.to_kak_str()).join(" ");
         format!("select {sels_str}")
       }
+
+      Response::Indent { line, indent } => {
+        let mut cmd =
+          format!("select {line}.1,{line}.1\ntry %{{ execute-keys -draft 'xs^\\h+<ret>d' }}");
+
+        if !indent.is_empty() {
+          cmd.push_str(&format!(
+            "\nset-register dquote {indent}\nexecute-keys P",
+            indent = kak_quote(indent)
+          ));
+        }
+
+        format!(
+          "evaluate-commands -draft -save-regs '\"' {}",
+          kak_quote(&cmd)
+        )
+      }
+
+      Response::Edits { edits } if edits.is_empty() => String::new(),
+
+      Response::Edits { edits } => {
+        // apply the edits from the end of the buffer, so that they do not shift the positions of the next ones
+        let cmd = edits
+          .iter()
+          .rev()
+          .map(|edit| match edit {
+            TextEdit::Insert { pos, text } => format!(
+              "select {line}.{col},{line}.{col}\nset-register dquote {text}\nexecute-keys P",
+              line = pos.line,
+              col = pos.col,
+              text = kak_quote(text)
+            ),
+
+            TextEdit::Delete { sel } => format!("select {}\nexecute-keys d", sel.to_kak_str()),
+          })
+          .join("\n");
+
+        format!(
+          "evaluate-commands -draft -save-regs '\"' {}",
+          kak_quote(&cmd)
+        )
+      }
+
+      Response::TreeDump { sexp } => scratch_buffer("*tree-sitter-tree*", sexp),
+
+      Response::NodeInfo { info } => {
+        // breadcrumbs, from the root down to the node
+        let path = info
+          .ancestors
+          .iter()
+          .rev()
+          .chain(iter::once(&info.kind))
+          .join(" > ");
+        let content = format!(
+          "{path}\n{start}.{start_col},{end}.{end_col} (bytes {start_byte}..{end_byte})",
+          start = info.start.line,
+          start_col = info.start.col,
+          end = info.end.line,
+          end_col = info.end.col,
+          start_byte = info.bytes.0,
+          end_byte = info.bytes.1,
+        );
+
+        kak_info(client, Some(&info.kind), &content)
+      }
+
+      Response::CommentTokens { tokens } => {
+        let block = tokens.block.as_ref();
+        [
+          ("comment_line", tokens.line.as_deref()),
+          (
+            "comment_block_begin",
+            block.map(|block| block.start.as_str()),
+          ),
+          ("comment_block_end", block.map(|block| block.end.as_str())),
+        ]
+        .into_iter()
+        .filter_map(|(opt, token)| {
+          // the options are declared by comment.kak, which might not be loaded
+          let set = format!("set-option buffer {opt} {}", kak_quote(token?));
+          Some(format!("try {}", kak_quote(&set)))
+        })
+        .join("\n")
+      }
+
+      Response::Injections { injections } => {
+        let content = if injections.is_empty() {
+          "no injection".to_owned()
+        } else {
+          injections
+            .iter()
+            .map(|injection| {
+              let sel = Sel {
+                anchor: injection.start,
+                cursor: injection.end,
+              };
+              let resolved = match &injection.resolved {
+                Some(resolved) if *resolved == injection.lang => String::new(),
+                Some(resolved) => format!(" (highlighted as {resolved})"),
+                None => " (not highlighted)".to_owned(),
+              };
+
+              format!(
+                "{sel} (bytes {start}..{end}) {lang}{resolved}",
+                sel = sel.to_kak_str(),
+                start = injection.bytes.0,
+                end = injection.bytes.1,
+                lang = injection.lang,
+              )
+            })
+            .join("\n")
+        };
+
+        scratch_buffer("*tree-sitter-injections*", &content)
+      }
+
+      Response::QueryCaptures { captures } => {
+        let content = if captures.is_empty() {
+          "no capture".to_owned()
+        } else {
+          captures
+            .iter()
+            .map(|capture| format!("{} @{}", capture.sel.to_kak_str(), capture.name))
+            .join("\n")
+        };
+
+        scratch_buffer("*tree-sitter-query*", &content)
+      }
+
+      Response::ProseRanges {
+        timestamp,
+        ranges,
+        face,
+      } => {
+        let ranges_str = ranges
+          .iter()
+          .map(|sel| format!("{}|{face}", sel.to_kak_str()))
+          .join(" ");
+
+        format!("set buffer kts_prose_ranges {timestamp} {ranges_str}")
+      }
+
+      Response::SyntaxErrors {
+        timestamp,
+        ranges,
+        face,
+      } => {
+        let ranges_str = ranges
+          .iter()
+          .map(|sel| format!("{}|{face}", sel.to_kak_str()))
+          .join(" ");
+
+        format!("set buffer kts_syntax_errors {timestamp} {ranges_str}")
+      }
     };
 
     // empty command means no response
@@ -130,16 +556,167 @@ impl Response {
       String::new()
     };
 
-    Some(format!("eval -no-hooks {prefix}%{{{kak_cmd}}}"))
+    Some(format!(
+      "eval -no-hooks {prefix}{kak_cmd}",
+      kak_cmd = kak_quote(&kak_cmd)
+    ))
+  }
+}
+
+/// Quote a string as a single Kakoune argument.
+///
+/// Single-quoted strings are not subject to expansions nor to brace balancing, the only character to escape being the
+/// single quote itself (by doubling it).
+pub fn kak_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Kakoune command displaying `content` in an info box of `client`, titled `title` if any.
+///
+/// Info boxes are displayed by clients; without one, the content is written to the `*debug*` buffer instead, so that it
+/// is not lost.
+pub fn kak_info(client: Option<&str>, title: Option<&str>, content: &str) -> String {
+  match (client, title) {
+    (Some(_), Some(title)) => format!("info -title {} {}", kak_quote(title), kak_quote(content)),
+    (Some(_), None) => format!("info {}", kak_quote(content)),
+    (None, Some(title)) => format!("echo -debug {}", kak_quote(&format!("{title}: {content}"))),
+    (None, None) => format!("echo -debug {}", kak_quote(content)),
+  }
+}
+
+/// Kakoune command displaying `msg` in the status line of `client`, or writing it to the `*debug*` buffer without one.
+pub fn kak_echo(client: Option<&str>, msg: &str) -> String {
+  if client.is_some() {
+    format!("echo {}", kak_quote(msg))
+  } else {
+    format!("echo -debug {}", kak_quote(msg))
+  }
+}
+
+/// Kakoune command displaying a menu of `(label, command)` items in `client`.
+///
+/// There is nothing to choose from without a client nor without items, so the command is empty — i.e. no response.
+pub fn kak_menu<'a>(
+  client: Option<&str>,
+  items: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> String {
+  if client.is_none() {
+    log::debug!("no client to display a menu in");
+    return String::new();
+  }
+
+  let items = items
+    .into_iter()
+    .map(|(label, cmd)| format!("{} {}", kak_quote(label), kak_quote(cmd)))
+    .join(" ");
+
+  if items.is_empty() {
+    String::new()
+  } else {
+    format!("menu {items}")
+  }
+}
+
+/// Suggestion suffix for messages about unknown languages; empty if there is no suggestion.
+pub fn did_you_mean(suggestions: &[String]) -> String {
+  if suggestions.is_empty() {
+    String::new()
+  } else {
+    format!(" (did you mean {}?)", suggestions.join(", "))
   }
 }
 
+/// Kakoune command setting the highlight ranges of the current buffer.
+fn highlighter_ranges(timestamp: u64, ranges: &[KakHighlightRange]) -> String {
+  let ranges_str = ranges
+    .iter()
+    .map(KakHighlightRange::to_kak_range_str)
+    .join(" ");
+
+  format!(
+    "{range_specs} {timestamp} {ranges_str}",
+    range_specs = "set buffer kts_highlighter_ranges",
+  )
+}
+
+/// Set the highlights of a buffer, along with `%opt{kts_status}` if the buffer was not highlighted before.
+fn highlights_with_status(timestamp: u64, ranges: &[KakHighlightRange], activated: bool) -> String {
+  let mut cmd = highlighter_ranges(timestamp, ranges);
+
+  // only set the status when it changes, so that the mode line is not updated on every highlight
+  if activated {
+    cmd.push_str(&format!(
+      "\nset-option buffer kts_status {}",
+      kak_quote(STATUS_ACTIVE)
+    ));
+  }
+
+  cmd
+}
+
+/// Kakoune script setting the highlights of a buffer, meant to be sourced later on; see the `output` of
+/// [`Request::Highlight`].
+///
+/// The option and highlighter are set up the same way as when highlighting is enabled, so that the script does not
+/// depend on the buffer having been highlighted before.
+///
+/// [`Request::Highlight`]: crate::request::Request::Highlight
+pub fn highlights_script(buffer: &str, timestamp: u64, ranges: &[KakHighlightRange]) -> String {
+  let cmds = format!(
+    "try %{{ declare-option range-specs kts_highlighter_ranges }}\n\
+     add-highlighter -override buffer/kak-tree-sitter-highlighter ranges kts_highlighter_ranges\n\
+     {ranges}",
+    ranges = highlighter_ranges(timestamp, ranges)
+  );
+
+  format!(
+    "evaluate-commands -buffer {buffer} {cmds}\n",
+    buffer = kak_quote(buffer),
+    cmds = kak_quote(&cmds)
+  )
+}
+
+/// Kakoune commands replacing the content of a scratch buffer with the given content.
+fn scratch_buffer(name: &str, content: &str) -> String {
+  let fill = format!(
+    "set-register dquote {content}\nexecute-keys '%R'",
+    content = kak_quote(content)
+  );
+
+  format!(
+    "edit -scratch {name}\nevaluate-commands -save-regs '\"' {fill}",
+    fill = kak_quote(&fill)
+  )
+}
+
+/// Highlights of a single buffer in a [`Response::HighlightBatch`].
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct BufferHighlights {
+  pub buffer: String,
+  pub timestamp: u64,
+  pub ranges: Vec<KakHighlightRange>,
+
+  /// Whether the buffer was not highlighted before; see [`Response::Highlights`].
+  pub activated: bool,
+}
+
+/// Buffer that could not be highlighted in a [`Response::HighlightBatch`].
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct BufferFailure {
+  pub buffer: String,
+  pub reason: String,
+}
+
 /// Response that can be sent to a specific session.
 #[derive(Debug, Eq, PartialEq)]
 pub struct ConnectedResponse {
   pub session: String,
   pub client: Option<String>,
   pub resp: Response,
+
+  /// Buffer of the highlight request the response answers, along with the generation of that request; see
+  /// [`ConnectedResponse::with_generation`].
+  pub generation: Option<(String, u64)>,
 }
 
 impl ConnectedResponse {
@@ -155,6 +732,254 @@ impl ConnectedResponse {
       session,
       client,
       resp,
+      generation: None,
     }
   }
+
+  /// Mark the response as answering the `generation`-th highlight request of `buffer`.
+  ///
+  /// Highlights answering an older request than one of the responses queued along with them are superseded, and
+  /// dropped instead of being sent.
+  pub fn with_generation(self, buffer: impl Into<String>, generation: u64) -> Self {
+    Self {
+      generation: Some((buffer.into(), generation)),
+      ..self
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    edit::TextEdit,
+    highlighting::KakHighlightRange,
+    selection::{Pos, Sel},
+  };
+
+  use super::{
+    kak_echo, kak_info, kak_menu, kak_quote, BufferFailure, BufferHighlights, Response,
+    UnixResponse,
+  };
+
+  #[test]
+  fn quoting() {
+    assert_eq!(kak_quote("foo"), "'foo'");
+    assert_eq!(kak_quote("it's"), "'it''s'");
+    assert_eq!(kak_quote("%{ unbalanced"), "'%{ unbalanced'");
+  }
+
+  #[test]
+  fn kak_cmd_is_quoted() {
+    let resp = Response::status("hello, it's me");
+    assert_eq!(
+      resp.to_kak_cmd(Some("client0")),
+      Some("eval -no-hooks -try-client client0 'info ''hello, it''''s me'''".to_owned())
+    );
+  }
+
+  #[test]
+  fn ui_cmds() {
+    assert_eq!(
+      kak_info(Some("client0"), Some("it's"), "%{ content"),
+      "info -title 'it''s' '%{ content'"
+    );
+    assert_eq!(kak_info(Some("client0"), None, "hello"), "info 'hello'");
+    assert_eq!(
+      kak_info(None, Some("kind"), "hello"),
+      "echo -debug 'kind: hello'"
+    );
+    assert_eq!(kak_echo(Some("client0"), "it's"), "echo 'it''s'");
+    assert_eq!(kak_echo(None, "hello"), "echo -debug 'hello'");
+
+    assert_eq!(
+      kak_menu(
+        Some("client0"),
+        [("first", "exec gg"), ("it's", "echo 'hi'")]
+      ),
+      "menu 'first' 'exec gg' 'it''s' 'echo ''hi'''"
+    );
+    assert_eq!(kak_menu(Some("client0"), []), "");
+    assert_eq!(kak_menu(None, [("first", "exec gg")]), "");
+
+    // UI responses do not get lost without a client
+    assert_eq!(
+      Response::status("hello").to_kak_cmd(None),
+      Some("eval -no-hooks 'echo -debug ''hello'''".to_owned())
+    );
+  }
+
+  #[test]
+  fn unix_json() {
+    let resp = UnixResponse::Error {
+      reason: "nope".to_owned(),
+      exit_code: 1,
+    };
+    assert_eq!(
+      resp.to_json(None),
+      r#"{"exit_code":1,"reason":"nope","type":"error"}"#
+    );
+    assert_eq!(
+      resp.to_json(Some("42")),
+      r#"{"exit_code":1,"id":"42","reason":"nope","type":"error"}"#
+    );
+  }
+
+  #[test]
+  fn json() {
+    let resp = Response::Selections {
+      sels: vec![Sel {
+        anchor: Pos { line: 1, col: 1 },
+        cursor: Pos { line: 2, col: 3 },
+      }],
+    };
+    assert_eq!(
+      resp.to_json(),
+      r#"{"type":"selections","sels":[{"anchor":{"line":1,"col":1},"cursor":{"line":2,"col":3}}]}"#
+    );
+  }
+
+  #[test]
+  fn prose_ranges() {
+    let resp = Response::ProseRanges {
+      timestamp: 3,
+      ranges: vec![Sel {
+        anchor: Pos { line: 1, col: 1 },
+        cursor: Pos { line: 1, col: 12 },
+      }],
+      face: "ts_spell".to_owned(),
+    };
+    assert_eq!(
+      resp.to_kak_cmd(None),
+      Some("eval -no-hooks 'set buffer kts_prose_ranges 3 1.1,1.12|ts_spell'".to_owned())
+    );
+  }
+
+  #[test]
+  fn syntax_errors() {
+    let resp = Response::SyntaxErrors {
+      timestamp: 3,
+      ranges: vec![Sel {
+        anchor: Pos { line: 2, col: 5 },
+        cursor: Pos { line: 2, col: 5 },
+      }],
+      face: "ts_syntax_error".to_owned(),
+    };
+    assert_eq!(
+      resp.to_kak_cmd(None),
+      Some("eval -no-hooks 'set buffer kts_syntax_errors 3 2.5,2.5|ts_syntax_error'".to_owned())
+    );
+  }
+
+  #[test]
+  fn highlight_batch() {
+    let resp = Response::HighlightBatch {
+      highlights: vec![
+        BufferHighlights {
+          buffer: "/tmp/a.rs".to_owned(),
+          timestamp: 1,
+          ranges: vec![KakHighlightRange::new(1, 0, 1, 1, "ts_keyword")],
+          activated: true,
+        },
+        BufferHighlights {
+          buffer: "/tmp/it's.rs".to_owned(),
+          timestamp: 2,
+          ranges: Vec::new(),
+          activated: false,
+        },
+      ],
+      failures: vec![BufferFailure {
+        buffer: "/tmp/notes.txt".to_owned(),
+        reason: "language txt is not supported".to_owned(),
+      }],
+    };
+    let expected = [
+      "eval -no-hooks 'evaluate-commands -buffer ''/tmp/a.rs'' ''set buffer kts_highlighter_ranges 1 1.1,1.2|ts_keyword",
+      "set-option buffer kts_status ''''tree-sitter''''''",
+      "evaluate-commands -buffer ''/tmp/it''''s.rs'' ''set buffer kts_highlighter_ranges 2 ''",
+      "echo -debug ''kak-tree-sitter: cannot highlight buffer /tmp/notes.txt: language txt is not supported'''",
+    ]
+    .join("\n");
+    assert_eq!(resp.to_kak_cmd(None), Some(expected));
+  }
+
+  #[test]
+  fn unsupported_language() {
+    let resp = Response::UnsupportedLanguage {
+      lang: "rsut".to_owned(),
+      suggestions: vec!["rust".to_owned()],
+    };
+    let cmd = resp.to_kak_cmd(None).unwrap();
+
+    assert!(
+      cmd.contains("echo ''kak-tree-sitter: language rsut is not supported (did you mean rust?)''")
+    );
+    assert!(cmd.contains("ktsctl manage -fci rsut"));
+  }
+
+  #[test]
+  fn highlight_status() {
+    let resp = Response::Highlights {
+      timestamp: 1,
+      ranges: Vec::new(),
+      activated: true,
+    };
+    assert_eq!(
+      resp.to_kak_cmd(None),
+      Some(
+        "eval -no-hooks 'set buffer kts_highlighter_ranges 1 \nset-option buffer kts_status ''tree-sitter'''"
+          .to_owned()
+      )
+    );
+    assert_eq!(
+      Response::HighlightFailed.to_kak_cmd(None),
+      Some("eval -no-hooks 'set-option buffer kts_status '''''".to_owned())
+    );
+  }
+
+  #[test]
+  fn highlights_added() {
+    let resp = Response::HighlightsAdded {
+      ranges: vec![KakHighlightRange::new(1, 0, 1, 1, "ts_string")],
+    };
+    assert_eq!(
+      resp.to_kak_cmd(None),
+      Some("eval -no-hooks 'set -add buffer kts_highlighter_ranges 1.1,1.2|ts_string'".to_owned())
+    );
+
+    // nothing to add, nothing to send
+    assert_eq!(
+      Response::HighlightsAdded { ranges: Vec::new() }.to_kak_cmd(None),
+      None
+    );
+  }
+
+  #[test]
+  fn edits() {
+    let resp = Response::Edits {
+      edits: vec![
+        TextEdit::Insert {
+          pos: Pos { line: 1, col: 3 },
+          text: "// ".to_owned(),
+        },
+        TextEdit::Delete {
+          sel: Sel {
+            anchor: Pos { line: 2, col: 1 },
+            cursor: Pos { line: 2, col: 3 },
+          },
+        },
+      ],
+    };
+
+    // edits are applied from the last one
+    let expected = [
+      r#"eval -no-hooks 'evaluate-commands -draft -save-regs ''"'' ''select 2.1,2.3"#,
+      "execute-keys d",
+      "select 1.3,1.3",
+      "set-register dquote ''''// ''''",
+      "execute-keys P'''",
+    ]
+    .join("\n");
+    assert_eq!(resp.to_kak_cmd(None), Some(expected));
+    assert_eq!(Response::Edits { edits: Vec::new() }.to_kak_cmd(None), None);
+  }
 }
@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 use tree_sitter::Point;
 
 /// A single position in a buffer.
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+///
+/// Lines and columns are 1-based, and columns are byte offsets in their line, as in Kakoune (`select`, range-specs,
+/// etc.).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct Pos {
   pub line: usize,
   pub col: usize,
@@ -36,7 +39,7 @@ impl Pos {
 ///
 /// Note: there is no rule about anchors and cursors. One can come before the other; do not assume anything about their
 /// position.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Sel {
   pub anchor: Pos,
   pub cursor: Pos,
@@ -1,15 +1,20 @@
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet},
+  env,
   ffi::CString,
   fs::{self, File, OpenOptions},
   io::{self, Read, Write},
+  iter,
+  net::Shutdown,
   os::{
-    fd::AsRawFd,
+    fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
     unix::{
+      fs::{MetadataExt, PermissionsExt},
       net::UnixStream,
       prelude::{OpenOptionsExt, OsStrExt},
     },
   },
+  panic::{self, AssertUnwindSafe},
   path::{Path, PathBuf},
   process::{Command, Stdio},
   sync::{
@@ -18,20 +23,23 @@ use std::{
     Arc,
   },
   thread::{spawn, JoinHandle},
+  time::{Duration, Instant},
 };
 
-use kak_tree_sitter_config::Config;
+use kak_tree_sitter_config::{Config, ServerConfig};
 use mio::{net::UnixListener, unix::SourceFd, Events, Interest, Poll, Token, Waker};
 
 use crate::{
-  buffer::BufferId,
-  cli::Cli,
+  buffer::{self, BufferId},
+  cli::StartArgs,
   error::OhNo,
+  fd_passing,
   handler::Handler,
-  request::{Request, UnixRequest},
-  response::{ConnectedResponse, Response},
-  selection::Sel,
-  session::{Fifo, Session, SessionState, SessionTracker},
+  log_files::LogFiles,
+  query_watcher::QueryWatcher,
+  request::{request_field, Capability, Request, UnixRequest, PROTOCOL_VERSION},
+  response::{kak_quote, ConnectedResponse, Response, UnixResponse},
+  session::{Fifo, Session, SessionTracker},
 };
 
 /// Feedback provided after a request has finished. Mainly used to shutdown.
@@ -46,54 +54,32 @@ pub struct Server {
 }
 
 impl Server {
-  fn new(config: &Config, is_standalone: bool, with_highlighting: bool) -> Result<Self, OhNo> {
-    let server_state = ServerState::new(config, is_standalone, with_highlighting)?;
+  fn new(
+    config: &Config,
+    paths: &ServerPaths,
+    is_standalone: bool,
+    with_highlighting: bool,
+    watch_queries: bool,
+  ) -> Result<Self, OhNo> {
+    let server_state = ServerState::new(
+      config,
+      paths,
+      is_standalone,
+      with_highlighting,
+      watch_queries,
+    )?;
     Ok(Self { server_state })
   }
 
-  /// Bootstrap the server from the `config` and `cli`.
-  pub fn bootstrap(config: &Config, cli: &Cli) -> Result<(), OhNo> {
-    // find a runtime directory to write in
-    let runtime_dir = ServerState::runtime_dir()?;
+  /// Bootstrap the server from the `config` and start arguments.
+  pub fn bootstrap(config: &Config, paths: &ServerPaths, args: &StartArgs) -> Result<(), OhNo> {
+    let runtime_dir = &paths.runtime_dir;
     log::info!("running in {}", runtime_dir.display());
-
-    let pid_file = runtime_dir.join("pid");
-
-    // check whether a pid file exists and can be read
-    if let Ok(pid) = std::fs::read_to_string(&pid_file) {
-      let pid = pid.trim();
-      log::debug!("checking whether PID {pid} is still up…");
-
-      // if the contained pid corresponds to a running process, stop right away
-      // otherwise, remove the previous PID and socket files
-      if Command::new("ps")
-        .args(["-p", pid])
-        .output()
-        .is_ok_and(|o| o.status.success())
-      {
-        log::debug!("kak-tree-sitter already running; not starting a new server");
-        return Ok(());
-      } else {
-        log::debug!("removing previous PID file");
-        std::fs::remove_file(&pid_file).map_err(|err| OhNo::CannotStartDaemon {
-          err: format!(
-            "cannot remove previous PID file {path}: {err}",
-            path = pid_file.display()
-          ),
-        })?;
-
-        log::debug!("removing previous socket file");
-        let socket_file = runtime_dir.join("socket");
-        std::fs::remove_file(&socket_file).map_err(|err| OhNo::CannotStartDaemon {
-          err: format!(
-            "cannot remove previous socket file {path}: {err}",
-            path = socket_file.display()
-          ),
-        })?;
-      }
-    }
+    log::info!("listening on {}", paths.socket.display());
 
     // ensure that the runtime directory exists, along with commands and buffers subdirectory
+    create_runtime_dir(runtime_dir)?;
+
     let commands_dir = runtime_dir.join("commands");
     fs::create_dir_all(&commands_dir).map_err(|err| OhNo::CannotCreateDir {
       dir: commands_dir,
@@ -106,18 +92,40 @@ impl Server {
       err,
     })?;
 
-    if cli.daemonize {
+    // several clients might start a server at once (e.g. hooks of an editor opening many files); the one holding the lock
+    // decides whether to start, and only releases it once the server it started listens on its socket, so that the
+    // others see it running
+    let start_lock = StartLock::acquire(&paths.lock_file)?;
+
+    let pid_file = paths.pid_file.clone();
+
+    // if the PID file corresponds to a running process, stop right away; otherwise, remove the PID and socket files a
+    // previous server might have left behind, as binding the socket would fail
+    if Self::running_pid(paths).is_some() {
+      log::debug!("kak-tree-sitter already running; not starting a new server");
+      return Ok(());
+    }
+
+    for (what, path) in [("PID", &pid_file), ("socket", &paths.socket)] {
+      match fs::remove_file(path) {
+        Ok(()) => log::debug!("removed previous {what} file"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => {
+          return Err(OhNo::CannotStartDaemon {
+            err: format!(
+              "cannot remove previous {what} file {path}: {err}",
+              path = path.display()
+            ),
+          })
+        }
+      }
+    }
+
+    let log_files = args.daemonize.then(|| LogFiles::new(runtime_dir));
+
+    if let Some(log_files) = &log_files {
       // create stdout / stderr files
-      let stdout_path = runtime_dir.join("stdout.txt");
-      let stderr_path = runtime_dir.join("stderr.txt");
-      let stdout = File::create(&stdout_path).map_err(|err| OhNo::CannotCreateFile {
-        file: stdout_path,
-        err,
-      })?;
-      let stderr = File::create(&stderr_path).map_err(|err| OhNo::CannotCreateFile {
-        file: stderr_path,
-        err,
-      })?;
+      let (stdout, stderr) = log_files.create()?;
 
       daemonize::Daemonize::new()
         .stdout(stdout)
@@ -136,11 +144,31 @@ impl Server {
       })?;
     }
 
-    Server::new(config, !cli.kakoune, cli.with_highlighting)?.start()?;
+    let mut server = Server::new(
+      config,
+      paths,
+      !args.kakoune,
+      args.with_highlighting,
+      args.watch_queries,
+    )?;
+    server.server_state.log_files = log_files;
+    drop(start_lock);
+    server.start()?;
 
     Ok(())
   }
 
+  /// PID of the running server, if any.
+  pub fn running_pid(paths: &ServerPaths) -> Option<String> {
+    let pid = std::fs::read_to_string(&paths.pid_file).ok()?;
+    let pid = pid.trim();
+    log::debug!("checking whether PID {pid} is still up…");
+
+    let is_running =
+      matches!(Command::new("ps").args(["-p", pid]).output(), Ok(o) if o.status.success());
+    is_running.then(|| pid.to_owned())
+  }
+
   fn start(mut self) -> Result<(), OhNo> {
     // search for already existing sessions, and if so, register them ahead of time
     if let Err(err) = self.server_state.register_already_existing_sessions() {
@@ -150,39 +178,417 @@ impl Server {
     self.server_state.start()
   }
 
-  pub fn send_request(req: UnixRequest) -> Result<(), OhNo> {
-    // serialize the request
-    let serialized = serde_json::to_string(&req).map_err(|err| OhNo::CannotSendRequest {
-      err: err.to_string(),
-    })?;
+  /// Send a request; the server must handle it within `timeout`, if any.
+  pub fn send_request(
+    paths: &ServerPaths,
+    req: UnixRequest,
+    timeout: Option<Duration>,
+  ) -> Result<(), OhNo> {
+    let serialized = req.to_json();
 
     log::debug!("sending request {req:?}");
+    Self::send_serialized(paths, &serialized, None, timeout)
+  }
 
-    // connect and send the request to the daemon
-    UnixStream::connect(ServerState::runtime_dir()?.join("socket"))
-      .map_err(|err| OhNo::CannotConnectToServer { err })?
-      .write_all(serialized.as_bytes())
-      .map_err(|err| OhNo::CannotSendRequest {
+  /// Send a request along with a file descriptor, such as the content of the buffer of a [`UnixRequest::Highlight`].
+  pub fn send_request_with_fd(
+    paths: &ServerPaths,
+    req: UnixRequest,
+    fd: RawFd,
+    timeout: Option<Duration>,
+  ) -> Result<(), OhNo> {
+    let serialized = req.to_json();
+
+    log::debug!("sending request {req:?} with file descriptor {fd}");
+    Self::send_serialized(paths, &serialized, Some(fd), timeout)
+  }
+
+  /// Send a request, starting a server first if none is running.
+  ///
+  /// Only [`UnixRequest::RegisterSession`] starts a server, as it is the first request a session sends; the other
+  /// requests are pointless without a running server. The started server is daemonized, with highlighting enabled.
+  pub fn send_request_or_start(
+    paths: &ServerPaths,
+    req: UnixRequest,
+    timeout: Option<Duration>,
+  ) -> Result<(), OhNo> {
+    let serialized = req.to_json();
+
+    log::debug!("sending request {req:?}");
+
+    match Self::send_serialized(paths, &serialized, None, timeout) {
+      Err(OhNo::CannotConnectToServer { err })
+        if matches!(req, UnixRequest::RegisterSession { .. })
+          && matches!(
+            err.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+          ) =>
+      {
+        log::info!("server not running ({err}); starting it");
+        Self::autostart(paths)?;
+        Self::send_serialized(paths, &serialized, None, timeout)
+      }
+
+      res => res,
+    }
+  }
+
+  fn send_serialized(
+    paths: &ServerPaths,
+    serialized: &str,
+    fd: Option<RawFd>,
+    timeout: Option<Duration>,
+  ) -> Result<(), OhNo> {
+    // connect and send the request to the daemon; it reads the request until EOF, so we close our side afterwards
+    let mut stream = Self::connect(paths, timeout)?;
+    let bytes = serialized.as_bytes();
+
+    // the file descriptor, if any, goes with the first bytes of the request
+    let sent = match fd {
+      Some(fd) => fd_passing::send_with_fd(&stream, bytes, fd)
+        .and_then(|sent| stream.write_all(&bytes[sent..])),
+      None => stream.write_all(bytes),
+    }
+    .and_then(|_| stream.shutdown(Shutdown::Write));
+
+    // the server only answers to reject the request, possibly before reading all of it, so check that even if sending
+    // failed; being unable to read the answer is not an error, as the request was sent, unless the server is stuck
+    match Self::read_response(&mut stream, timeout) {
+      Ok(Some(UnixResponse::Error { reason, exit_code })) => {
+        return Err(OhNo::rejected(reason, exit_code))
+      }
+      Err(err @ OhNo::ServerTimedOut { .. }) => return Err(err),
+      _ => (),
+    }
+
+    sent.map_err(|err| send_error(err, timeout))
+  }
+
+  /// Connect to the server; reading and writing then time out after `timeout`, if any.
+  fn connect(paths: &ServerPaths, timeout: Option<Duration>) -> Result<UnixStream, OhNo> {
+    let stream =
+      UnixStream::connect(&paths.socket).map_err(|err| OhNo::CannotConnectToServer { err })?;
+    stream
+      .set_read_timeout(timeout)
+      .and_then(|_| stream.set_write_timeout(timeout))
+      .map_err(|err| OhNo::CannotConnectToServer { err })?;
+
+    Ok(stream)
+  }
+
+  /// Read the response of the server, if any; the server closes the connection without answering most requests.
+  fn read_response(
+    stream: &mut UnixStream,
+    timeout: Option<Duration>,
+  ) -> Result<Option<UnixResponse>, OhNo> {
+    let mut resp = Vec::new();
+
+    match stream.read_to_end(&mut resp) {
+      // the server resets the connection if it answers without reading the whole request; the answer is still there
+      Err(err) if err.kind() == io::ErrorKind::ConnectionReset && !resp.is_empty() => (),
+      Err(err) => {
+        return Err(match timed_out(&err, timeout) {
+          Some(timeout) => OhNo::ServerTimedOut { timeout },
+          None => OhNo::InvalidResponse {
+            err: err.to_string(),
+          },
+        })
+      }
+      Ok(_) => (),
+    }
+
+    if resp.is_empty() {
+      return Ok(None);
+    }
+
+    serde_json::from_slice(&resp)
+      .map(Some)
+      .map_err(|err| OhNo::InvalidResponse {
         err: err.to_string(),
       })
   }
+
+  /// Maximum time to wait for an automatically started server to listen on its socket.
+  const AUTOSTART_TIMEOUT: Duration = Duration::from_secs(3);
+
+  /// Start a daemonized server, and wait for it to accept connections.
+  fn autostart(paths: &ServerPaths) -> Result<(), OhNo> {
+    let exe = std::env::current_exe().map_err(|err| OhNo::CannotStartDaemon {
+      err: format!("cannot find the kak-tree-sitter executable: {err}"),
+    })?;
+
+    // the daemon forks, so this returns as soon as it is detached
+    let status = Command::new(exe)
+      .args([
+        "server",
+        "start",
+        "--daemonize",
+        "--with-highlighting",
+        "--socket",
+      ])
+      .arg(&paths.socket)
+      .stdin(Stdio::null())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .status()
+      .map_err(|err| OhNo::CannotStartDaemon {
+        err: err.to_string(),
+      })?;
+
+    if !status.success() {
+      return Err(OhNo::CannotStartDaemon {
+        err: format!("server exited with {status}"),
+      });
+    }
+
+    // wait for the server to accept connections, backing off exponentially, until the timeout is reached
+    let deadline = Instant::now() + Self::AUTOSTART_TIMEOUT;
+    let mut backoff = Duration::from_millis(10);
+
+    while UnixStream::connect(&paths.socket).is_err() {
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(OhNo::CannotStartDaemon {
+          err: format!(
+            "server not listening on {} after {:?}",
+            paths.socket.display(),
+            Self::AUTOSTART_TIMEOUT
+          ),
+        });
+      }
+
+      std::thread::sleep(backoff.min(deadline - now));
+      backoff = (backoff * 2).min(Duration::from_millis(500));
+    }
+
+    Ok(())
+  }
+
+  /// Ask the running server about its status.
+  pub fn request_status(
+    paths: &ServerPaths,
+    timeout: Option<Duration>,
+  ) -> Result<UnixResponse, OhNo> {
+    Self::request_answer(paths, &UnixRequest::Status, timeout)
+  }
+
+  /// Send a request the server answers on the same connection — e.g. [`UnixRequest::Status`] — and read its answer.
+  pub fn request_answer(
+    paths: &ServerPaths,
+    req: &UnixRequest,
+    timeout: Option<Duration>,
+  ) -> Result<UnixResponse, OhNo> {
+    let serialized = req.to_json();
+
+    let mut stream = Self::connect(paths, timeout)?;
+
+    // the server reads the request until EOF, so we have to close our side before reading the response
+    stream
+      .write_all(serialized.as_bytes())
+      .and_then(|_| stream.shutdown(Shutdown::Write))
+      .map_err(|err| send_error(err, timeout))?;
+
+    Self::read_response(&mut stream, timeout)?.ok_or_else(|| OhNo::InvalidResponse {
+      err: "no response".to_owned(),
+    })
+  }
+}
+
+/// Paths used to reach a server.
+///
+/// The socket and the PID file live in the runtime directory by default. If the socket path is overridden, the PID file
+/// lives right next to it instead, so that isolated servers can run alongside the default one.
+///
+/// Paths are absolute, as the server changes its working directory when it daemonizes.
+#[derive(Clone, Debug)]
+pub struct ServerPaths {
+  /// Directory of the FIFOs, response and log files of the server; see [`ServerState::runtime_dir`].
+  pub runtime_dir: PathBuf,
+
+  pub socket: PathBuf,
+  pub pid_file: PathBuf,
+
+  /// Lock held while a server starts; see [`StartLock`].
+  pub lock_file: PathBuf,
+}
+
+impl ServerPaths {
+  /// Paths of the default server, or of the server listening on `socket` if provided.
+  ///
+  /// The runtime directory of a server listening on another socket is a directory of its own next to the socket (e.g.
+  /// `kts.sock.d` for `kts.sock`), so that it shares nothing with the default server — sessions, buffers or log files —
+  /// and the directory the socket is put in is left as is. A relative socket path is relative to the current directory.
+  pub fn new(socket: Option<PathBuf>) -> Self {
+    let (runtime_dir, socket, pid_file, lock_file) = match socket {
+      Some(socket) => {
+        let socket = if socket.is_relative() {
+          env::current_dir().map_or(socket.clone(), |cwd| cwd.join(&socket))
+        } else {
+          socket
+        };
+        let mut runtime_dir = socket.clone().into_os_string();
+        runtime_dir.push(".d");
+        let runtime_dir = PathBuf::from(runtime_dir);
+        let pid_file = socket.with_extension("pid");
+        let lock_file = socket.with_extension("lock");
+        (runtime_dir, socket, pid_file, lock_file)
+      }
+
+      None => {
+        let runtime_dir = ServerState::runtime_dir();
+        let socket = runtime_dir.join("socket");
+        let pid_file = runtime_dir.join("pid");
+        let lock_file = runtime_dir.join("lock");
+        (runtime_dir, socket, pid_file, lock_file)
+      }
+    };
+
+    Self {
+      runtime_dir,
+      socket,
+      pid_file,
+      lock_file,
+    }
+  }
+}
+
+/// The timeout an I/O error of a client is about, if it is about one.
+fn timed_out(err: &io::Error, timeout: Option<Duration>) -> Option<Duration> {
+  timeout.filter(|_| {
+    matches!(
+      err.kind(),
+      io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+  })
+}
+
+/// Error of a client failing to send its request.
+fn send_error(err: io::Error, timeout: Option<Duration>) -> OhNo {
+  match timed_out(&err, timeout) {
+    Some(timeout) => OhNo::ServerTimedOut { timeout },
+    None => OhNo::CannotSendRequest {
+      err: err.to_string(),
+    },
+  }
+}
+
+/// Create the runtime directory if needed, and make it private.
+///
+/// The runtime directory might be under a directory shared by all users (e.g. `/tmp`), where another user could have
+/// created it first to connect to the server or read the buffers; such a directory is never used.
+fn create_runtime_dir(dir: &Path) -> Result<(), OhNo> {
+  fs::create_dir_all(dir).map_err(|err| OhNo::CannotCreateDir {
+    dir: dir.to_owned(),
+    err,
+  })?;
+
+  let metadata = fs::metadata(dir).map_err(|err| OhNo::CannotCreateDir {
+    dir: dir.to_owned(),
+    err,
+  })?;
+
+  // SAFETY: getuid always succeeds
+  let uid = unsafe { libc::getuid() };
+  if metadata.uid() != uid {
+    return Err(OhNo::RuntimeDirNotOwned {
+      dir: dir.to_owned(),
+      uid: metadata.uid(),
+    });
+  }
+
+  set_mode(dir, 0o700)
+}
+
+/// Set the permissions of a file, if they differ.
+fn set_mode(path: &Path, mode: u32) -> Result<(), OhNo> {
+  let metadata = fs::metadata(path).map_err(|err| OhNo::CannotSetPermissions {
+    path: path.to_owned(),
+    err,
+  })?;
+
+  if metadata.permissions().mode() & 0o777 == mode {
+    return Ok(());
+  }
+
+  fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|err| {
+    OhNo::CannotSetPermissions {
+      path: path.to_owned(),
+      err,
+    }
+  })
+}
+
+/// Exclusive lock on a file, released when dropped.
+///
+/// The lock file itself is never removed, as a client waiting on it might then lock a file that another client creates
+/// anew in the meantime.
+struct StartLock {
+  _file: File,
+}
+
+impl StartLock {
+  /// Lock `path`, waiting for the current holder to release the lock if any.
+  fn acquire(path: &Path) -> Result<Self, OhNo> {
+    let file = OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .write(true)
+      .open(path)
+      .map_err(|err| OhNo::CannotCreateFile {
+        file: path.to_owned(),
+        err,
+      })?;
+
+    // SAFETY: no pointer is involved; the lock is released when the file is closed
+    while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+      let err = io::Error::last_os_error();
+      if err.kind() != io::ErrorKind::Interrupted {
+        return Err(OhNo::CannotStartDaemon {
+          err: format!("cannot lock {path}: {err}", path = path.display()),
+        });
+      }
+    }
+
+    Ok(Self { _file: file })
+  }
 }
 
 /// Resources requiring a special drop implementation.
 #[derive(Clone, Debug)]
 pub struct ServerResources {
   pub runtime_dir: PathBuf,
+  pub paths: ServerPaths,
 }
 
 impl ServerResources {
-  fn new(runtime_dir: PathBuf) -> Self {
-    Self { runtime_dir }
+  fn new(runtime_dir: PathBuf, paths: ServerPaths) -> Self {
+    Self { runtime_dir, paths }
+  }
+
+  /// Remove the response files of a session (see [`ResponseQueue::max_inline_bytes`]).
+  ///
+  /// They are removed by the session once sourced; those of a session exiting in the meantime are left behind.
+  fn remove_response_files(&self, session: &str) {
+    let responses = self.runtime_dir.join("responses");
+    for dentry in responses.read_dir().into_iter().flatten().flatten() {
+      let path = dentry.path();
+      let is_session_file = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit_once('-'))
+        .map_or(false, |(name, _)| name == session);
+
+      if is_session_file {
+        let _ = fs::remove_file(path);
+      }
+    }
   }
 }
 
 impl Drop for ServerResources {
   fn drop(&mut self) {
-    let _ = std::fs::remove_dir_all(self.runtime_dir.join("pid"));
+    let _ = std::fs::remove_file(&self.paths.pid_file);
+    let _ = std::fs::remove_file(&self.paths.socket);
   }
 }
 
@@ -234,23 +640,61 @@ pub struct ServerState {
   shutdown: Arc<AtomicBool>,
   session_tracker: SessionTracker,
   token_provider: TokenProvider,
+
+  // sessions the response queue could not reach anymore; the waker is woken up whenever one is sent
+  gone_sessions: Receiver<String>,
+
+  // buffers read from file descriptors; the waker is woken up whenever one is sent
+  fd_buffers: Receiver<FdBuffer>,
+
+  // watched query files, if requested
+  query_watcher: Option<QueryWatcher>,
+
+  // standard output and error files, if daemonized
+  log_files: Option<LogFiles>,
+
+  // binary used to list and reach Kakoune sessions
+  kak_binary: PathBuf,
+
+  // last time a request was received, to shut down once idle
+  last_request: Instant,
 }
 
 impl ServerState {
-  pub fn new(config: &Config, is_standalone: bool, with_highlighting: bool) -> Result<Self, OhNo> {
-    let resources = ServerResources::new(Self::runtime_dir()?);
+  pub fn new(
+    config: &Config,
+    paths: &ServerPaths,
+    is_standalone: bool,
+    with_highlighting: bool,
+    watch_queries: bool,
+  ) -> Result<Self, OhNo> {
+    let resources = ServerResources::new(paths.runtime_dir.clone(), paths.clone());
     let mut poll = Poll::new().map_err(|err| OhNo::CannotStartPoll { err })?;
     let waker = Arc::new(
       Waker::new(poll.registry(), TokenProvider::WAKER_TOKEN)
         .map_err(|err| OhNo::CannotStartServer { err })?,
     );
-    let (resp_queue, resp_sender) = ResponseQueue::new();
+    let kak_binary = Self::kak_binary(config);
+    log::info!("reaching Kakoune with {}", kak_binary.display());
+
+    let (gone_sender, gone_sessions) = channel();
+    let (fd_buffer_sender, fd_buffers) = channel();
+    let (resp_queue, resp_sender) = ResponseQueue::new(
+      kak_binary.clone(),
+      resources.runtime_dir.join("responses"),
+      config.server.max_inline_response_bytes,
+      gone_sender,
+      waker.clone(),
+    );
     let mut unix_handler = UnixHandler::new(
       is_standalone,
       with_highlighting,
       resources.clone(),
-      ServerState::socket_path()?,
+      &paths.socket,
       resp_sender.clone(),
+      config.server.clone(),
+      fd_buffer_sender,
+      waker.clone(),
     )?;
     let fifo_handler = FifoHandler::new(config, resp_sender.clone())?;
     let shutdown = Arc::new(AtomicBool::new(false));
@@ -260,6 +704,7 @@ impl ServerState {
     // SIGINT handler; we just ask to shutdown the server
     {
       let shutdown = shutdown.clone();
+      let waker = waker.clone();
       ctrlc::set_handler(move || {
         log::warn!("received SIGINT");
         shutdown.store(true, Ordering::Relaxed);
@@ -270,6 +715,10 @@ impl ServerState {
 
     unix_handler.register_poll(&mut poll)?;
 
+    let query_watcher = watch_queries
+      .then(|| Self::watch_queries(config, waker.clone()))
+      .flatten();
+
     let _resp_queue_handle = resp_queue.run();
 
     Ok(ServerState {
@@ -282,24 +731,54 @@ impl ServerState {
       shutdown,
       session_tracker,
       token_provider,
+      gone_sessions,
+      fd_buffers,
+      query_watcher,
+      log_files: None,
+      kak_binary,
+      last_request: Instant::now(),
     })
   }
 
-  pub fn runtime_dir() -> Result<PathBuf, OhNo> {
-    let dir = dirs::runtime_dir()
-      .or_else(||
-        // macOS doesn’t implement XDG, yay…
-        std::env::var("TMPDIR").map(PathBuf::from).ok())
-      .ok_or_else(|| OhNo::NoRuntimeDir)?;
-    Ok(dir.join("kak-tree-sitter"))
+  /// Watch the query files of the configured languages.
+  ///
+  /// This is a convenience for query authors; the server is still useful without it, so failing to watch is not fatal.
+  fn watch_queries(config: &Config, waker: Arc<Waker>) -> Option<QueryWatcher> {
+    QueryWatcher::new(config, waker)
+      .map_err(|err| log::error!("{err}"))
+      .ok()
   }
 
-  pub fn socket_path() -> Result<PathBuf, OhNo> {
-    Ok(Self::runtime_dir()?.join("socket"))
+  /// `kak` binary to use, read once at startup: the `KAK_BINARY` environment variable takes precedence over the
+  /// configuration.
+  fn kak_binary(config: &Config) -> PathBuf {
+    std::env::var_os("KAK_BINARY")
+      .filter(|path| !path.is_empty())
+      .map(PathBuf::from)
+      .unwrap_or_else(|| config.server.kak_binary.clone())
+  }
+
+  /// Directory of the socket, FIFOs and other files of the default server.
+  ///
+  /// Minimal environments (e.g. cron, containers) might set neither `XDG_RUNTIME_DIR` nor `TMPDIR`; `/tmp` is then
+  /// used, with the UID in the name of the directory, as it is shared by all users.
+  pub fn runtime_dir() -> PathBuf {
+    if let Some(dir) = dirs::runtime_dir() {
+      return dir.join("kak-tree-sitter");
+    }
+
+    // macOS doesn’t implement XDG, yay…
+    if let Some(dir) = std::env::var_os("TMPDIR").filter(|dir| !dir.is_empty()) {
+      return PathBuf::from(dir).join("kak-tree-sitter");
+    }
+
+    // SAFETY: getuid always succeeds
+    let uid = unsafe { libc::getuid() };
+    PathBuf::from(format!("/tmp/kak-tree-sitter-{uid}"))
   }
 
   fn register_already_existing_sessions(&mut self) -> Result<(), OhNo> {
-    let sessions = Self::get_running_sessions();
+    let sessions = self.get_running_sessions();
 
     // we use command FIFOs for that
     let fifo_dir = self.resources.runtime_dir.join("commands");
@@ -322,10 +801,8 @@ impl ServerState {
           &mut self.token_provider,
           &mut self.session_tracker,
           &mut self.fifo_handler,
-          UnixRequest::RegisterSession {
-            name: name.clone(),
-            client: None,
-          },
+          UnixRequest::register_session(name.clone(), None),
+          None,
         ) {
           log::error!("cannot register already existing session '{name}': {err}");
         }
@@ -335,8 +812,8 @@ impl ServerState {
     Ok(())
   }
 
-  fn get_running_sessions() -> HashSet<String> {
-    Command::new("kak")
+  fn get_running_sessions(&self) -> HashSet<String> {
+    Command::new(&self.kak_binary)
       .arg("-l")
       .output()
       .map(|output| {
@@ -371,6 +848,8 @@ impl ServerState {
         path = buf_fifo.display()
       );
     }
+
+    self.resources.remove_response_files(session);
   }
 
   /// Start the server state and wait for events to be dispatched.
@@ -383,8 +862,17 @@ impl ServerState {
         break;
       }
 
+      // changed query files are only reloaded once changes settle
+      let timeout = self
+        .query_watcher
+        .as_ref()
+        .and_then(QueryWatcher::timeout)
+        .into_iter()
+        .chain(self.idle_timeout())
+        .min();
+
       log::debug!("waiting on poll…");
-      if let Err(err) = self.poll.poll(&mut events, None) {
+      if let Err(err) = self.poll.poll(&mut events, timeout) {
         if err.kind() == io::ErrorKind::Interrupted {
           log::warn!("mio interrupted");
         } else {
@@ -396,12 +884,12 @@ impl ServerState {
         log::trace!("mio event: {event:#?}");
 
         match event.token() {
-          TokenProvider::WAKER_TOKEN => {
-            log::debug!("waking up mio poll before shutting down");
-            break;
-          }
+          // shutdown, gone sessions, buffers read from file descriptors and query file changes are checked once all
+          // events are handled
+          TokenProvider::WAKER_TOKEN => log::debug!("mio poll woken up"),
 
           TokenProvider::UNIX_LISTENER_TOKEN if event.is_readable() => {
+            self.last_request = Instant::now();
             match self.unix_handler.accept(
               &mut self.poll,
               &mut self.token_provider,
@@ -418,11 +906,28 @@ impl ServerState {
             }
           }
 
-          tkn if event.is_readable() => self.fifo_handler.accept(&mut self.session_tracker, tkn)?,
+          // a writer closing a FIFO we already read from is only reported as read-closed; we still have to read the EOF
+          tkn if event.is_readable() || event.is_read_closed() => {
+            self.last_request = Instant::now();
+            self.fifo_handler.accept(&mut self.session_tracker, tkn)?
+          }
 
           _ => (),
         }
       }
+
+      self.reload_changed_queries();
+      self.handle_fd_buffers();
+      self.recycle_gone_sessions();
+
+      if self.idle_timeout() == Some(Duration::ZERO) {
+        log::info!("no session and no request for a while; shutting down");
+        self.shutdown.store(true, Ordering::Relaxed);
+      }
+
+      if let Some(log_files) = &self.log_files {
+        log_files.rotate_if_larger(self.unix_handler.limits.max_log_size);
+      }
     }
 
     log::info!("shutting down");
@@ -431,6 +936,83 @@ impl ServerState {
     Ok(())
   }
 
+  /// How long to wait for a request before shutting down, if the server has no sessions and an idle timeout.
+  fn idle_timeout(&self) -> Option<Duration> {
+    if !self.session_tracker.is_empty() {
+      return None;
+    }
+
+    let idle_timeout = self.unix_handler.limits.idle_timeout()?;
+    Some(idle_timeout.saturating_sub(self.last_request.elapsed()))
+  }
+
+  /// Recycle the sessions the response queue found gone — e.g. Kakoune was killed while highlights were sent to it — as
+  /// if they had exited.
+  fn recycle_gone_sessions(&mut self) {
+    while let Ok(session) = self.gone_sessions.try_recv() {
+      // several responses might have found the session gone
+      if self.session_tracker.by_name(&session).is_none() {
+        continue;
+      }
+
+      log::warn!("session {session} is gone");
+      if let Err(err) = self.unix_handler.recycle_session(
+        &mut self.poll,
+        &mut self.session_tracker,
+        &mut self.token_provider,
+        &mut self.fifo_handler,
+        &session,
+      ) {
+        log::error!("cannot recycle session {session}: {err}");
+      }
+      self.cleanup_session_data(&session);
+
+      if !self.unix_handler.is_standalone && self.session_tracker.is_empty() {
+        log::info!("last session is gone; stopping the server…");
+        self.shutdown.store(true, Ordering::Relaxed);
+      }
+    }
+  }
+
+  /// Handle the requests whose buffer was read from a file descriptor; see [`UnixHandler::read_fd_buffer`].
+  fn handle_fd_buffers(&mut self) {
+    while let Ok(FdBuffer { session, req, buf }) = self.fd_buffers.try_recv() {
+      // the session might have exited while its buffer was read
+      let Some(session) = self.session_tracker.by_name(&session) else {
+        log::warn!("session {session} exited before its buffer could be highlighted");
+        continue;
+      };
+
+      match buf {
+        Ok(buf) => self.fifo_handler.handle_with_buffer(session, &req, &buf),
+        Err(err) => log::error!(
+          "cannot read buffer of session {session_name}: {err}",
+          session_name = session.name()
+        ),
+      }
+    }
+  }
+
+  /// Reload languages if query files changed, once changes have settled.
+  fn reload_changed_queries(&mut self) {
+    let Some(query_watcher) = &mut self.query_watcher else {
+      return;
+    };
+
+    query_watcher.read_changes();
+    let Some(changed) = query_watcher.settled_changes() else {
+      return;
+    };
+
+    let files = changed
+      .iter()
+      .map(|path| path.display().to_string())
+      .collect::<Vec<_>>()
+      .join(", ");
+    log::info!("query files changed ({files}); reloading configuration, grammars and queries");
+    self.unix_handler.reload(&mut self.fifo_handler);
+  }
+
   /// Disconnect all sessions by sending them all a [`Response::Deinit`].
   fn disconnect_sessions(&self) {
     for session_name in self.session_tracker.sessions() {
@@ -451,18 +1033,40 @@ struct UnixHandler {
   resources: ServerResources,
   unix_listener: UnixListener,
   resp_sender: Sender<ConnectedResponse>,
+
+  /// Limits applied to requests; updated when reloading.
+  limits: ServerConfig,
+
+  /// Buffers read from file descriptors, sent back to the server along with their request.
+  fd_buffers: Sender<FdBuffer>,
+  waker: Arc<Waker>,
+}
+
+/// Request whose buffer was read from a file descriptor, off the main loop; see [`UnixHandler::read_fd_buffer`].
+#[derive(Debug)]
+struct FdBuffer {
+  session: String,
+  req: Request,
+  buf: Result<String, OhNo>,
 }
 
 impl UnixHandler {
+  #[allow(clippy::too_many_arguments)]
   fn new(
     is_standalone: bool,
     with_highlighting: bool,
     resources: ServerResources,
     socket_path: impl AsRef<Path>,
     resp_sender: Sender<ConnectedResponse>,
+    limits: ServerConfig,
+    fd_buffers: Sender<FdBuffer>,
+    waker: Arc<Waker>,
   ) -> Result<Self, OhNo> {
     let unix_listener =
-      UnixListener::bind(socket_path).map_err(|err| OhNo::CannotStartServer { err })?;
+      UnixListener::bind(&socket_path).map_err(|err| OhNo::CannotStartServer { err })?;
+
+    // only the user may connect; the socket might not be in the runtime directory
+    set_mode(socket_path.as_ref(), 0o600)?;
 
     Ok(Self {
       is_standalone,
@@ -470,6 +1074,9 @@ impl UnixHandler {
       resources,
       unix_listener,
       resp_sender,
+      limits,
+      fd_buffers,
+      waker,
     })
   }
 
@@ -485,6 +1092,10 @@ impl UnixHandler {
       .map_err(|err| OhNo::CannotStartPoll { err })
   }
 
+  /// Accept all pending connections.
+  ///
+  /// Events are edge-triggered, so every pending connection must be accepted before polling again; otherwise, a
+  /// connection arriving along with another one would wait for the next client to be served.
   fn accept(
     &mut self,
     poll: &mut Poll,
@@ -492,30 +1103,160 @@ impl UnixHandler {
     session_tracker: &mut SessionTracker,
     fifo_handler: &mut FifoHandler,
   ) -> Result<Feedback, OhNo> {
-    let (mut client, _) = self
-      .unix_listener
-      .accept()
-      .map_err(|err| OhNo::UnixConnectionError { err })?;
+    loop {
+      let client = match self.unix_listener.accept() {
+        Ok((client, _)) => client,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Feedback::Ok),
+        Err(err) => return Err(OhNo::UnixConnectionError { err }),
+      };
+
+      match self.accept_client(poll, token_provider, session_tracker, fifo_handler, client) {
+        Ok(Feedback::ShouldExit) => return Ok(Feedback::ShouldExit),
+        Ok(Feedback::Ok) => (),
+        Err(err) => log::error!("{err}"),
+      }
+    }
+  }
 
+  fn accept_client(
+    &mut self,
+    poll: &mut Poll,
+    token_provider: &mut TokenProvider,
+    session_tracker: &mut SessionTracker,
+    fifo_handler: &mut FifoHandler,
+    client: mio::net::UnixStream,
+  ) -> Result<Feedback, OhNo> {
     log::info!("client connected: {client:?}");
 
-    // read the request and parse it
-    let mut req_str = String::new();
+    // the accepted stream is non-blocking, but the client might not have written its request yet; read it in blocking
+    // mode instead, with a timeout so that a stalled client cannot hang the server
+    //
+    // SAFETY: the file descriptor is moved out of the mio stream, so it is owned by the std stream only
+    let mut client = unsafe { UnixStream::from_raw_fd(client.into_raw_fd()) };
+    let timeout = self.limits.request_timeout();
     client
-      .read_to_string(&mut req_str)
-      .map_err(|err| OhNo::InvalidRequest {
-        req: req_str.clone(),
-        err: err.to_string(),
-      })?;
-    log::info!("UNIX socket request: {req_str}");
+      .set_nonblocking(false)
+      .and_then(|_| client.set_read_timeout(timeout))
+      .and_then(|_| client.set_write_timeout(timeout))
+      .map_err(|err| OhNo::UnixConnectionError { err })?;
 
-    let req =
-      serde_json::from_str::<UnixRequest>(&req_str).map_err(|err| OhNo::InvalidRequest {
-        req: req_str,
-        err: err.to_string(),
-      })?;
+    let read = self.read_request(&mut client);
+
+    // the id of the request is echoed in the answer, even if the request cannot be parsed
+    let id = match &read {
+      Ok(Some((req_str, _))) => request_field(req_str, "id"),
+      _ => None,
+    };
+    let req = read.and_then(|read| {
+      read
+        .map(|(req_str, fd)| UnixRequest::from_json(&req_str).map(|req| (req, fd)))
+        .transpose()
+    });
+
+    let res = match req {
+      Ok(Some((UnixRequest::Status, _))) => {
+        // besides errors, the status is the only answer sent to the client itself
+        let resp = fifo_handler.status().to_json(id.as_deref());
+        client
+          .write_all(resp.as_bytes())
+          .map_err(|err| OhNo::UnixConnectionError { err })?;
+
+        return Ok(Feedback::Ok);
+      }
+
+      Ok(Some((UnixRequest::DumpCaptures { lang, path }, _))) => fifo_handler
+        .handler
+        .dump_captures(&lang, &path)
+        .and_then(|captures| {
+          let resp = UnixResponse::Captures { captures }.to_json(id.as_deref());
+          client
+            .write_all(resp.as_bytes())
+            .map_err(|err| OhNo::UnixConnectionError { err })?;
+          Ok(Feedback::Ok)
+        }),
+
+      Ok(Some((req, fd))) => {
+        self.process_req(poll, token_provider, session_tracker, fifo_handler, req, fd)
+      }
+
+      // clients probing whether the server is up connect without sending anything
+      Ok(None) => return Ok(Feedback::Ok),
+
+      Err(err) => Err(err),
+    };
+
+    if let Err(err) = &res {
+      // let the client know why its request is dropped; it might have gone already, so failing to do so is fine
+      let resp = UnixResponse::Error {
+        reason: err.to_string(),
+        exit_code: err.exit_code(),
+      };
+      if let Err(write_err) = client.write_all(resp.to_json(id.as_deref()).as_bytes()) {
+        log::debug!("cannot send error to client: {write_err}");
+      }
+    }
 
-    self.process_req(poll, token_provider, session_tracker, fifo_handler, req)
+    res
+  }
+
+  /// Read the JSON request of a client, enforcing the configured limits, along with the file descriptor passed with it,
+  /// if any.
+  ///
+  /// Return [`None`] if the client did not send anything.
+  fn read_request(
+    &self,
+    client: &mut UnixStream,
+  ) -> Result<Option<(String, Option<OwnedFd>)>, OhNo> {
+    let max_size = self.limits.max_request_size;
+    let timeout = self.limits.request_timeout();
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+    // read one more byte than allowed, to tell a request of the maximum size apart from a larger one; a file
+    // descriptor can only come with the first bytes
+    let mut req_bytes = vec![0; (max_size + 1).min(4096)];
+    let mut fd = None;
+    let read = fd_passing::recv_with_fd(client, &mut req_bytes).and_then(|(size, passed_fd)| {
+      req_bytes.truncate(size);
+      fd = passed_fd;
+
+      if size == 0 {
+        return Ok(0);
+      }
+
+      read_until_deadline(client, &mut req_bytes, max_size + 1, deadline).map(|_| req_bytes.len())
+    });
+    let req_str = String::from_utf8_lossy(&req_bytes).into_owned();
+
+    match read {
+      Ok(size) if size > max_size => return Err(OhNo::RequestTooLarge { max_size }),
+      Ok(_) => (),
+
+      Err(err) => match timeout {
+        Some(timeout)
+          if matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+          ) =>
+        {
+          return Err(OhNo::RequestTimedOut { timeout });
+        }
+
+        _ => {
+          return Err(OhNo::InvalidRequest {
+            req: req_str,
+            err: err.to_string(),
+          });
+        }
+      },
+    }
+
+    if req_str.is_empty() {
+      return Ok(None);
+    }
+
+    log::info!("UNIX socket request: {req_str}");
+
+    Ok(Some((req_str, fd)))
   }
 
   fn process_req(
@@ -525,13 +1266,23 @@ impl UnixHandler {
     session_tracker: &mut SessionTracker,
     fifo_handler: &mut FifoHandler,
     req: UnixRequest,
+    fd: Option<OwnedFd>,
   ) -> Result<Feedback, OhNo> {
     match req {
-      UnixRequest::RegisterSession { name, client } => {
-        log::info!("registering session {name}");
+      UnixRequest::RegisterSession {
+        name,
+        client,
+        capabilities,
+      } => {
+        log::info!("registering session {name} with capabilities {capabilities:?}");
 
-        let (cmd_fifo_path, buf_fifo_path) =
-          self.track_session(poll, token_provider, session_tracker, name.clone())?;
+        let (cmd_fifo_path, buf_fifo_path) = self.track_session(
+          poll,
+          token_provider,
+          session_tracker,
+          name.clone(),
+          capabilities,
+        )?;
 
         let resp = Response::Init {
           cmd_fifo_path,
@@ -551,7 +1302,7 @@ impl UnixHandler {
       }
 
       UnixRequest::SessionExit { name } => {
-        self.recycle_session(poll, session_tracker, token_provider, name)?;
+        self.recycle_session(poll, session_tracker, token_provider, fifo_handler, name)?;
 
         // only shutdown if were started with an initial session (non standalone)
         let feedback = if !self.is_standalone && session_tracker.is_empty() {
@@ -565,6 +1316,39 @@ impl UnixHandler {
       }
 
       UnixRequest::Shutdown => return Ok(Feedback::ShouldExit),
+
+      UnixRequest::Status => log::warn!("status requested without a client to answer to"),
+
+      UnixRequest::DumpCaptures { .. } => {
+        log::warn!("captures requested without a client to answer to")
+      }
+
+      UnixRequest::Highlight {
+        session,
+        client,
+        buffer,
+        lang,
+        timestamp,
+        force,
+      } => {
+        let fd = fd.ok_or(OhNo::MissingBufferFd)?;
+        let Some(session) = session_tracker.by_name(&session) else {
+          return Err(OhNo::UnknownSession { session });
+        };
+
+        if !session.has_capability(Capability::FdPassing) {
+          return Err(OhNo::MissingCapability {
+            session: session.name().to_owned(),
+            capability: "fd_passing".to_owned(),
+          });
+        }
+
+        let mut req = Request::highlight(client, buffer, lang, timestamp);
+        if force {
+          req = req.with_force();
+        }
+        self.read_fd_buffer(session.name().to_owned(), req, fd);
+      }
     }
 
     Ok(Feedback::Ok)
@@ -576,6 +1360,7 @@ impl UnixHandler {
     token_provider: &mut TokenProvider,
     session_tracker: &mut SessionTracker,
     session_name: impl Into<String>,
+    capabilities: Vec<Capability>,
   ) -> Result<(PathBuf, PathBuf), OhNo> {
     let session_name = session_name.into();
 
@@ -605,7 +1390,7 @@ impl UnixHandler {
 
     session_tracker.track(
       session_name.clone(),
-      Session::new(session_name.clone(), cmd_token, buf_token),
+      Session::new(session_name.clone(), cmd_token, buf_token).with_capabilities(capabilities),
       Fifo::Cmd {
         session_name: session_name.clone(),
         file: cmd_fifo_file,
@@ -614,7 +1399,7 @@ impl UnixHandler {
       Fifo::Buf {
         session_name,
         file: buf_fifo_file,
-        buffer: String::new(),
+        buffer: Vec::new(),
       },
     );
 
@@ -670,17 +1455,40 @@ impl UnixHandler {
     Ok(fifo)
   }
 
+  /// Read the buffer of a request from a file descriptor in the background, sending it back to the server once read.
+  ///
+  /// The writer of the file descriptor might take its time — or never close it, if the request timeout is disabled — so
+  /// the buffer is not read on the main loop, which would block every other session in the meantime.
+  fn read_fd_buffer(&self, session: String, req: Request, fd: OwnedFd) {
+    let timeout = self.limits.request_timeout();
+    let fd_buffers = self.fd_buffers.clone();
+    let waker = self.waker.clone();
+
+    spawn(move || {
+      let buf = fd_passing::read_to_string(fd, timeout);
+      if fd_buffers.send(FdBuffer { session, req, buf }).is_ok() {
+        if let Err(err) = waker.wake() {
+          log::error!("cannot wake the server up: {err}");
+        }
+      }
+    });
+  }
+
   /// Recycle a session by removing the session from the session tracker and recycling the token in the token provider.
+  ///
+  /// Everything the handler kept for the session is dropped too.
   fn recycle_session(
     &mut self,
     poll: &mut Poll,
     session_tracker: &mut SessionTracker,
     token_provider: &mut TokenProvider,
+    fifo_handler: &mut FifoHandler,
     session_name: impl AsRef<str>,
   ) -> Result<(), OhNo> {
     let session_name = session_name.as_ref();
 
     log::info!("recycling session {session_name}");
+    fifo_handler.drop_session(session_name);
     if let Some((session, cmd_fifo, buf_fifo)) = session_tracker.untrack(session_name) {
       if let Some(cmd_fifo) = cmd_fifo {
         poll
@@ -698,6 +1506,7 @@ impl UnixHandler {
 
       token_provider.recycle(session.cmd_token());
       token_provider.recycle(session.buf_token());
+      self.resources.remove_response_files(session_name);
     }
 
     Ok(())
@@ -712,9 +1521,10 @@ impl UnixHandler {
       }
     };
 
-    match FifoHandler::new(&config, self.resp_sender.clone()) {
-      Ok(new_fifo_handler) => *fifo_handler = new_fifo_handler,
-      Err(err) => log::error!("refreshing grammars/queries failed: {err}"),
+    self.limits = config.server.clone();
+
+    if let Err(err) = fifo_handler.handler.reload(&config) {
+      log::error!("refreshing grammars/queries failed: {err}");
     }
   }
 }
@@ -722,6 +1532,10 @@ impl UnixHandler {
 struct FifoHandler {
   handler: Handler,
   resp_sender: Sender<ConnectedResponse>,
+
+  /// Generation of the latest highlight request of each buffer, so that the response queue can drop the highlights of
+  /// older ones still queued.
+  generations: HashMap<BufferId, u64>,
 }
 
 impl FifoHandler {
@@ -731,9 +1545,33 @@ impl FifoHandler {
     Ok(Self {
       handler,
       resp_sender,
+      generations: HashMap::new(),
     })
   }
 
+  /// Forget about a session that exited, along with the generations of its buffers.
+  fn drop_session(&mut self, session_name: &str) {
+    self.handler.drop_session(session_name);
+    self
+      .generations
+      .retain(|buffer_id, _| buffer_id.session() != session_name);
+  }
+
+  /// Status of the server, as known by the handler.
+  fn status(&self) -> UnixResponse {
+    let parse_metrics = self
+      .handler
+      .parse_metrics()
+      .iter()
+      .map(|(lang, metrics)| (lang.clone(), metrics.clone()))
+      .collect();
+
+    UnixResponse::Status {
+      protocol_version: PROTOCOL_VERSION,
+      parse_metrics,
+    }
+  }
+
   /// Dispatch FIFO reads.
   pub fn accept(&mut self, session_tracker: &mut SessionTracker, token: Token) -> Result<(), OhNo> {
     if let Some((session, fifo)) = session_tracker.by_token(token) {
@@ -772,12 +1610,45 @@ impl FifoHandler {
 
     log::info!("FIFO request: {buffer}");
 
-    let req = serde_json::from_str::<Request>(buffer).map_err(|err| OhNo::InvalidRequest {
-      req: buffer.clone(),
-      err: err.to_string(),
-    });
+    // several requests might have been written before the FIFO is read; they are handled in order
+    let reqs = std::mem::take(buffer);
+    let mut jsons = serde_json::Deserializer::from_str(&reqs).into_iter::<serde_json::Value>();
+    loop {
+      let start = jsons.byte_offset();
+      match jsons.next() {
+        Some(Ok(_)) => {
+          let json = reqs[start..jsons.byte_offset()].trim();
+          self.process_fifo_req(session, json);
+        }
 
-    buffer.clear();
+        // not even JSON, so there is no telling where the next request starts; the rest is dropped
+        Some(Err(err)) => {
+          log::error!(
+            "cannot read requests from the command FIFO of session {session_name}: {err}; dropping {rest:?}",
+            session_name = session.name(),
+            rest = reqs[start..].trim(),
+          );
+          break;
+        }
+
+        None => break,
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Handle a single JSON request read from the command FIFO.
+  ///
+  /// A request that cannot be parsed is reported, but does not prevent the next ones from being handled.
+  fn process_fifo_req(&mut self, session: &mut Session, json: &str) {
+    let req = Request::from_json(json);
+
+    // the request cannot tell us its client, but the JSON might
+    let client = req
+      .is_err()
+      .then(|| request_field(json, "client"))
+      .flatten();
 
     match req {
       Ok(req) => match self.process_cmd(session, &req) {
@@ -799,11 +1670,19 @@ impl FifoHandler {
       },
 
       Err(err) => {
-        log::error!("malformed request: {err}");
+        log::error!("{err}");
+
+        // hand-written requests (e.g. in user scripts) are easier to fix if the error shows up in Kakoune
+        if let Some(client) = client {
+          let resp = Response::status(format!("kak-tree-sitter: {err}"));
+          let conn_resp = ConnectedResponse::new(session.name(), Some(client), resp);
+
+          if let Err(err) = self.resp_sender.send(conn_resp) {
+            log::error!("failure while sending response: {err}");
+          }
+        }
       }
     }
-
-    Ok(())
   }
 
   fn process_cmd(
@@ -811,64 +1690,31 @@ impl FifoHandler {
     session: &mut Session,
     req: &Request,
   ) -> Result<Option<Response>, OhNo> {
-    match req {
-      Request::TryEnableHighlight { lang, .. } => self
-        .handler
-        .handle_try_enable_highlight(session.name(), lang)
-        .map(Option::Some),
-
-      Request::Highlight {
-        client,
-        buffer,
-        lang,
-        timestamp,
-      } => {
-        // we do not send the highlight immediately; instead, we change the state machine
-        *session.state_mut() = SessionState::HighlightingWaiting {
-          client: client.clone(),
-          buffer: buffer.clone(),
-          lang: lang.clone(),
-          timestamp: *timestamp,
-        };
-
-        Ok(None)
-      }
-
-      Request::TextObjects {
-        client,
-        buffer,
-        lang,
-        pattern,
-        selections,
-        mode,
-      } => {
-        let selections = Sel::parse_many(selections);
-        *session.state_mut() = SessionState::TextObjectsWaiting {
-          client: client.clone(),
-          buffer: buffer.clone(),
-          lang: lang.clone(),
-          pattern: pattern.clone(),
-          selections,
-          mode: mode.clone(),
-        };
-
-        Ok(None)
-      }
+    if req.needs_buffer() {
+      // we do not handle the request immediately; instead, we wait for the buffer content, which is streamed after the
+      // buffers of the requests already waiting
+      session.state_mut().wait_buffer(req.clone());
+      return Ok(None);
     }
+
+    isolate(&mut self.handler, session.name(), |handler| {
+      handler.handle_request(session.name(), req, "")
+    })
+    .map(Option::Some)
   }
 
   fn accept_buf(
     &mut self,
     session: &mut Session,
     file: &mut File,
-    buffer: &mut String,
+    buffer: &mut Vec<u8>,
   ) -> Result<(), OhNo> {
     log::debug!(
       "reading buffer FIFO for session {session_name}…",
       session_name = session.name()
     );
 
-    if let Err(err) = file.read_to_string(buffer) {
+    if let Err(err) = file.read_to_end(buffer) {
       if err.kind() == io::ErrorKind::WouldBlock {
         log::debug!("buffer FIFO is not ready");
         return Ok(());
@@ -880,67 +1726,89 @@ impl FifoHandler {
       }
     };
 
-    let res = self.process_buf(session, buffer);
-    buffer.clear();
+    if !session.state().is_waiting() {
+      log::debug!("dropping buffer content no request is waiting for");
+      buffer.clear();
+      return Ok(());
+    }
 
-    res
+    // several buffers might have been written before the FIFO is read; each request gets its own, in order, and the
+    // ones whose buffer is not complete yet (e.g. batches streaming several buffers) keep waiting
+    let (taken, consumed) = session.state_mut().take_buffers(buffer);
+    if taken.is_empty() {
+      log::debug!("waiting for more buffer content");
+    }
+
+    for (req, span) in taken {
+      self.handle_with_buffer(session, &req, &buffer::decode(&buffer[span]));
+    }
+    buffer.drain(..consumed);
+
+    Ok(())
   }
 
-  fn process_buf(&mut self, session: &mut Session, buf: &str) -> Result<(), OhNo> {
-    match session.state() {
-      SessionState::HighlightingWaiting {
-        client,
-        buffer,
-        lang,
-        timestamp,
-      } => {
-        let client = client.clone();
-        let buffer_id = BufferId::new(session.name(), buffer);
-        let resp = self
-          .handler
-          .handle_highlight(buffer_id, lang, *timestamp, buf);
+  /// Handle a request along with the content of its buffer, sending the responses back to the Kakoune session.
+  ///
+  /// The state of the session is left untouched, as the buffer might not come from the buffer FIFO.
+  fn handle_with_buffer(&mut self, session: &Session, req: &Request, buf: &str) {
+    let client = req.client_name().map(str::to_owned);
+    let generation = self.next_generation(session, req);
+    let with_generation = |conn_resp: ConnectedResponse| match &generation {
+      Some((buffer, generation)) => conn_resp.with_generation(buffer, *generation),
+      None => conn_resp,
+    };
 
-        self.finish_cmd(session, Some(&client), resp);
+    // highlights of the buffer’s own language are sent before its injections are resolved
+    let resp_sender = &self.resp_sender;
+    let mut emit = |resp| {
+      let conn_resp = with_generation(ConnectedResponse::new(session.name(), client.clone(), resp));
+      if let Err(err) = resp_sender.send(conn_resp) {
+        log::error!("failure while sending response: {err}");
       }
+    };
+    let resp = isolate(&mut self.handler, session.name(), |handler| {
+      handler.handle_request_progressive(session.name(), req, buf, &mut emit)
+    });
 
-      SessionState::TextObjectsWaiting {
-        client,
-        buffer,
-        lang,
-        pattern,
-        selections,
-        mode,
-      } => {
-        let client = client.clone();
-        let buffer_id = BufferId::new(session.name(), buffer);
-        let resp = self
-          .handler
-          .handle_text_objects(buffer_id, lang, buf, pattern, selections, mode);
+    self.send_resp(session, client.as_deref(), resp, generation);
+  }
 
-        self.finish_cmd(session, Some(&client), resp);
-      }
+  /// Bump the generation of the buffer a highlight request is about, returning the buffer along with it.
+  ///
+  /// Other requests (including highlights written to a file) do not send highlights, and have no generation.
+  fn next_generation(&mut self, session: &Session, req: &Request) -> Option<(String, u64)> {
+    let req = req.expand_reparse();
+    let Request::Highlight {
+      buffer,
+      output: None,
+      ..
+    } = req.as_ref()
+    else {
+      return None;
+    };
 
-      // keep this branch so that we have exhaustiveness
-      SessionState::Idle => (),
-    }
+    let generation = self
+      .generations
+      .entry(BufferId::new(session.name(), buffer))
+      .or_default();
+    *generation += 1;
 
-    Ok(())
+    Some((buffer.clone(), *generation))
   }
 
   /// Inspect the result of a command and eventually send a response back to the Kakoune session.
-  fn finish_cmd(
+  fn send_resp(
     &mut self,
-    session: &mut Session,
+    session: &Session,
     client: Option<&str>,
     resp: Result<Response, OhNo>,
+    generation: Option<(String, u64)>,
   ) {
-    // switch back to idle, as we have read the FIFO
-    session.state_mut().idle();
-
     match resp {
       Ok(resp) => {
-        let conn_resp =
+        let mut conn_resp =
           ConnectedResponse::new(session.name().to_owned(), client.map(str::to_owned), resp);
+        conn_resp.generation = generation;
 
         if let Err(err) = self.resp_sender.send(conn_resp) {
           log::error!("failure while sending response: {err}");
@@ -957,64 +1825,436 @@ impl FifoHandler {
   }
 }
 
+/// Read from a client until it closes its write end, up to `limit` bytes in total, before `deadline`, if any.
+///
+/// The read timeout of the client is shortened before each read, so that a client sending its request a few bytes at a
+/// time cannot hold the server past the deadline.
+fn read_until_deadline(
+  client: &mut UnixStream,
+  buf: &mut Vec<u8>,
+  limit: usize,
+  deadline: Option<Instant>,
+) -> io::Result<()> {
+  let mut chunk = [0; 4096];
+
+  while buf.len() < limit {
+    if let Some(deadline) = deadline {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+      }
+
+      client.set_read_timeout(Some(remaining))?;
+    }
+
+    let len = (limit - buf.len()).min(chunk.len());
+    match client.read(&mut chunk[..len]) {
+      Ok(0) => break,
+      Ok(n) => buf.extend_from_slice(&chunk[..n]),
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+      Err(err) => return Err(err),
+    }
+  }
+
+  Ok(())
+}
+
+/// Handle a request of a session, turning a panic into a failure response for that session only.
+///
+/// A malformed request must not take down the server, and with it every other session. Unwinding is safe for the
+/// handler: a cursor taken from its pool is simply not given back, and another one is created when needed. A tree might
+/// be left half-updated, though; the trees of the session are dropped, and parsed again from scratch when needed.
+fn isolate(
+  handler: &mut Handler,
+  session_name: &str,
+  handle: impl FnOnce(&mut Handler) -> Result<Response, OhNo>,
+) -> Result<Response, OhNo> {
+  panic::catch_unwind(AssertUnwindSafe(|| handle(handler))).unwrap_or_else(|payload| {
+    handler.drop_trees(session_name);
+
+    let reason = payload
+      .downcast_ref::<&str>()
+      .map(|reason| reason.to_string())
+      .or_else(|| payload.downcast_ref::<String>().cloned())
+      .unwrap_or_else(|| "unknown reason".to_owned());
+    let err = OhNo::HandlerPanicked { reason };
+
+    log::error!("command failed for session {session_name}: {err}");
+    Ok(Response::status(format!("kak-tree-sitter: {err}")))
+  })
+}
+
+/// Drop the highlights superseded by more recent ones of the same buffer, queued in the meantime.
+///
+/// Highlights replace all the highlights of their buffer, so only the ones answering the latest highlight request of a
+/// buffer are worth sending. Highlights activating a buffer are kept, as they also set its highlighting status.
+fn drop_superseded(conn_resps: Vec<ConnectedResponse>) -> Vec<ConnectedResponse> {
+  let mut latest: HashMap<(&str, &str), u64> = HashMap::new();
+  for conn_resp in &conn_resps {
+    if let Some((buffer, generation)) = &conn_resp.generation {
+      let entry = latest.entry((&conn_resp.session, buffer)).or_default();
+      *entry = (*entry).max(*generation);
+    }
+  }
+
+  let superseded: Vec<bool> = conn_resps
+    .iter()
+    .map(|conn_resp| {
+      let Some((buffer, generation)) = &conn_resp.generation else {
+        return false;
+      };
+
+      let is_highlights = matches!(
+        conn_resp.resp,
+        Response::Highlights {
+          activated: false,
+          ..
+        } | Response::HighlightsAdded { .. }
+      );
+      is_highlights && latest[&(conn_resp.session.as_str(), buffer.as_str())] > *generation
+    })
+    .collect();
+
+  conn_resps
+    .into_iter()
+    .zip(superseded)
+    .filter_map(|(conn_resp, superseded)| {
+      if superseded {
+        log::debug!("dropping superseded highlights: {conn_resp:?}");
+      }
+      (!superseded).then_some(conn_resp)
+    })
+    .collect()
+}
+
+/// Group the Kakoune commands of responses per session, in the order they were queued.
+///
+/// An error stops the commands sent with the same `kak -p`, so when several are, each one is run on its own, reporting
+/// its error in the `*debug*` buffer as Kakoune would have.
+fn batch_kak_cmds(
+  conn_resps: impl IntoIterator<Item = ConnectedResponse>,
+) -> Vec<(String, String)> {
+  let mut batches: Vec<(String, Vec<String>)> = Vec::new();
+
+  for conn_resp in conn_resps {
+    let Some(cmd) = conn_resp.resp.to_kak_cmd(conn_resp.client.as_deref()) else {
+      continue;
+    };
+
+    match batches
+      .iter_mut()
+      .find(|(session, _)| *session == conn_resp.session)
+    {
+      Some((_, cmds)) => cmds.push(cmd),
+      None => batches.push((conn_resp.session, vec![cmd])),
+    }
+  }
+
+  batches
+    .into_iter()
+    .map(|(session, cmds)| {
+      if cmds.len() == 1 {
+        return (session, cmds.concat());
+      }
+
+      let data = cmds
+        .iter()
+        .map(|cmd| {
+          format!(
+            "try {} catch %{{ echo -debug %val{{error}} }}",
+            kak_quote(cmd)
+          )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+      (session, data)
+    })
+    .collect()
+}
+
+/// Kakoune commands sourcing a file of commands, and removing it afterwards.
+///
+/// The file is removed even if sourcing it fails; the error is reported in the `*debug*` buffer.
+fn source_kak_cmd(path: &Path) -> String {
+  let path = path.to_string_lossy();
+  let source = kak_quote(&format!("source {}", kak_quote(&path)));
+  let sh_path = format!("'{}'", path.replace('\'', "'\\''"));
+
+  format!("try {source} catch %{{ echo -debug %val{{error}} }}\nnop %sh{{ rm -f {sh_path} }}")
+}
+
 /// Response queue, responsible in sending responses to Kakoune session.
 struct ResponseQueue {
   receiver: Receiver<ConnectedResponse>,
+  kak_binary: PathBuf,
+
+  /// Directory of the commands too large to be sent inline; see [`ResponseQueue::max_inline_bytes`].
+  responses_dir: PathBuf,
+
+  /// Size past which commands are written to a file, sourced by the session, rather than sent to `kak -p`.
+  ///
+  /// Highlighting a large buffer yields commands with tens of thousands of ranges, which Kakoune fails to handle as a
+  /// single command line.
+  max_inline_bytes: usize,
+
+  /// Number of response files written so far, to name them uniquely.
+  written_files: u64,
+
+  /// Sessions whose `kak -p` closed its input, reported to the server so that it recycles them.
+  gone_sessions: Sender<String>,
+
+  /// Waker of the poll of the server, woken up when a session is gone.
+  waker: Arc<Waker>,
 }
 
 impl ResponseQueue {
-  fn new() -> (Self, Sender<ConnectedResponse>) {
+  fn new(
+    kak_binary: PathBuf,
+    responses_dir: PathBuf,
+    max_inline_bytes: usize,
+    gone_sessions: Sender<String>,
+    waker: Arc<Waker>,
+  ) -> (Self, Sender<ConnectedResponse>) {
     let (sender, receiver) = channel();
-    (Self { receiver }, sender)
+    (
+      Self {
+        receiver,
+        kak_binary,
+        responses_dir,
+        max_inline_bytes,
+        written_files: 0,
+        gone_sessions,
+        waker,
+      },
+      sender,
+    )
   }
 
   /// Run the response queue by dequeuing connected responses as they arrive in a dedicated thread.
-  fn run(self) -> JoinHandle<()> {
+  ///
+  /// Responses are sent one `kak -p` at a time, so that the commands sent to a session never interleave. Responses
+  /// queued in the meantime are then sent together, with a single `kak -p` per session.
+  fn run(mut self) -> JoinHandle<()> {
     spawn(move || {
-      for conn_resp in self.receiver {
-        Self::send(conn_resp);
+      while let Ok(conn_resp) = self.receiver.recv() {
+        let pending = iter::once(conn_resp)
+          .chain(self.receiver.try_iter())
+          .collect();
+
+        for (session, data) in batch_kak_cmds(drop_superseded(pending)) {
+          let data = self.inline_or_source(&session, data);
+          match self.send_via_kak_p(&session, &data) {
+            Ok(()) => (),
+
+            Err(err @ OhNo::SessionGone { .. }) => {
+              log::warn!("{err}");
+
+              // the server only stops listening when shutting down
+              if self.gone_sessions.send(session).is_ok() {
+                if let Err(err) = self.waker.wake() {
+                  log::error!("cannot report gone session: {err}");
+                }
+              }
+            }
+
+            Err(err) => log::error!("error while sending connected response: {err}"),
+          }
+        }
       }
     })
   }
 
-  fn send(conn_resp: ConnectedResponse) {
-    let resp = conn_resp.resp.to_kak_cmd(conn_resp.client.as_deref());
+  /// Commands to send to a session: `data` itself, or commands sourcing it from a file if it is too large.
+  ///
+  /// If the file cannot be written, `data` is sent as-is.
+  fn inline_or_source(&mut self, session: &str, data: String) -> String {
+    if data.len() <= self.max_inline_bytes {
+      return data;
+    }
 
-    if let Some(data) = resp {
-      if let Err(err) = Self::send_via_kak_p(&conn_resp.session, &data) {
-        log::error!("error while sending connected response: {err}");
-      }
+    self.written_files += 1;
+    let path = self
+      .responses_dir
+      .join(format!("{session}-{}.kak", self.written_files));
+
+    if let Err(err) = fs::create_dir_all(&self.responses_dir).and_then(|_| fs::write(&path, &data))
+    {
+      log::warn!(
+        "cannot write response file {path}: {err}; sending it inline",
+        path = path.display()
+      );
+      return data;
     }
+
+    log::debug!(
+      "sending {len} bytes to session {session} with {path}",
+      len = data.len(),
+      path = path.display()
+    );
+    source_kak_cmd(&path)
   }
 
-  fn send_via_kak_p(session: &str, data: &str) -> Result<(), OhNo> {
-    let mut child = std::process::Command::new("kak")
+  fn send_via_kak_p(&self, session: &str, data: &str) -> Result<(), OhNo> {
+    let mut child = std::process::Command::new(&self.kak_binary)
       .args(["-p", session])
       .stdin(Stdio::piped())
       .spawn()
       .map_err(|err| OhNo::CannotSendRequest {
         err: err.to_string(),
       })?;
-    let child_stdin = child
-      .stdin
-      .as_mut()
-      .ok_or_else(|| OhNo::CannotSendRequest {
-        err: "cannot pipe data to kak -p".to_owned(),
-      })?;
-
-    child_stdin
-      .write_all(data.as_bytes())
-      .map_err(|err| OhNo::CannotSendRequest {
-        err: err.to_string(),
-      })?;
-
-    child_stdin.flush().map_err(|err| OhNo::CannotSendRequest {
-      err: err.to_string(),
+    let mut child_stdin = child.stdin.take().ok_or_else(|| OhNo::CannotSendRequest {
+      err: "cannot pipe data to kak -p".to_owned(),
     })?;
+    let written = child_stdin
+      .write_all(data.as_bytes())
+      .and_then(|_| child_stdin.flush());
+    drop(child_stdin);
 
+    // waited for even if writing failed, so that it does not linger as a zombie
     child.wait().map_err(|err| OhNo::CannotSendRequest {
       err: format!("error while waiting on kak -p: {err}"),
     })?;
-    Ok(())
+
+    // kak -p exits without reading its input when the session does not exist anymore; e.g. Kakoune was killed
+    written.map_err(|err| match err.kind() {
+      io::ErrorKind::BrokenPipe => OhNo::SessionGone {
+        session: session.to_owned(),
+      },
+      _ => OhNo::CannotSendRequest {
+        err: err.to_string(),
+      },
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::response::{ConnectedResponse, Response};
+
+  use std::{env, path::Path};
+
+  use super::{batch_kak_cmds, drop_superseded, source_kak_cmd, ServerPaths};
+
+  #[test]
+  fn batched_kak_cmds() {
+    let status = |session: &str, status: &str| {
+      ConnectedResponse::new(
+        session,
+        Some("client0".to_owned()),
+        Response::status(status),
+      )
+    };
+
+    // a lone command is sent as-is
+    let batches = batch_kak_cmds([status("a", "hello")]);
+    assert_eq!(
+      batches,
+      [(
+        "a".to_owned(),
+        "eval -no-hooks -try-client client0 'info ''hello'''".to_owned()
+      )]
+    );
+
+    // commands are grouped per session, in order, and isolated from each other's errors
+    let batches = batch_kak_cmds([status("a", "1"), status("b", "2"), status("a", "3")]);
+    assert_eq!(
+      batches
+        .iter()
+        .map(|(session, _)| session.as_str())
+        .collect::<Vec<_>>(),
+      ["a", "b"]
+    );
+    let lines: Vec<_> = batches[0].1.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines
+      .iter()
+      .all(|line| line.starts_with("try 'eval -no-hooks")
+        && line.ends_with("' catch %{ echo -debug %val{error} }")));
+    assert!(lines[0].contains("1") && lines[1].contains("3"));
+  }
+
+  #[test]
+  fn dropped_superseded_highlights() {
+    let highlights = |session: &str, buffer: &str, generation, activated| {
+      ConnectedResponse::new(
+        session,
+        None,
+        Response::Highlights {
+          timestamp: generation,
+          ranges: Vec::new(),
+          activated,
+        },
+      )
+      .with_generation(buffer, generation)
+    };
+    let generations = |conn_resps: Vec<ConnectedResponse>| {
+      conn_resps
+        .iter()
+        .map(|conn_resp| conn_resp.generation.clone().unwrap())
+        .collect::<Vec<_>>()
+    };
+
+    // only the highlights of the latest request of each buffer of each session are kept
+    let kept = drop_superseded(vec![
+      highlights("a", "main.rs", 1, false),
+      highlights("a", "lib.rs", 1, false),
+      highlights("b", "main.rs", 2, false),
+      highlights("a", "main.rs", 2, false),
+      highlights("b", "main.rs", 1, false),
+    ]);
+    assert_eq!(
+      kept
+        .iter()
+        .map(|conn_resp| conn_resp.session.as_str())
+        .collect::<Vec<_>>(),
+      ["a", "b", "a"]
+    );
+    assert_eq!(
+      generations(kept),
+      [
+        ("lib.rs".to_owned(), 1),
+        ("main.rs".to_owned(), 2),
+        ("main.rs".to_owned(), 2)
+      ]
+    );
+
+    // highlights activating a buffer are kept, as they also set its status
+    let kept = drop_superseded(vec![
+      highlights("a", "main.rs", 1, true),
+      highlights("a", "main.rs", 2, false),
+    ]);
+    assert_eq!(kept.len(), 2);
+
+    // responses without generation are never dropped
+    let kept = drop_superseded(vec![
+      ConnectedResponse::new("a", None, Response::status("hello")),
+      highlights("a", "main.rs", 1, false),
+    ]);
+    assert_eq!(kept.len(), 2);
+  }
+
+  #[test]
+  fn sourced_kak_cmd() {
+    assert_eq!(
+      source_kak_cmd(Path::new("/tmp/kts/it's-1.kak")),
+      "try 'source ''/tmp/kts/it''''s-1.kak''' catch %{ echo -debug %val{error} }\n\
+       nop %sh{ rm -f '/tmp/kts/it'\\''s-1.kak' }"
+    );
+  }
+
+  #[test]
+  fn socket_paths() {
+    let paths = ServerPaths::new(Some("/tmp/kts.sock".into()));
+    assert_eq!(paths.socket, Path::new("/tmp/kts.sock"));
+    assert_eq!(paths.runtime_dir, Path::new("/tmp/kts.sock.d"));
+    assert_eq!(paths.pid_file, Path::new("/tmp/kts.pid"));
+    assert_eq!(paths.lock_file, Path::new("/tmp/kts.lock"));
+
+    // the server does not run in the current directory once daemonized
+    let paths = ServerPaths::new(Some("kts.sock".into()));
+    let cwd = env::current_dir().unwrap();
+    assert_eq!(paths.socket, cwd.join("kts.sock"));
+    assert_eq!(paths.runtime_dir, cwd.join("kts.sock.d"));
+    assert_eq!(paths.pid_file, cwd.join("kts.pid"));
   }
 }
@@ -1,8 +1,12 @@
-use std::{collections::HashMap, fs::File};
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  fs::File,
+  ops::Range,
+};
 
 use mio::Token;
 
-use crate::{selection::Sel, text_objects};
+use crate::request::{Capability, Request, BATCH_DELIMITER};
 
 /// Session tracker,
 ///
@@ -52,6 +56,10 @@ impl SessionTracker {
     })
   }
 
+  pub fn by_name(&mut self, session_name: &str) -> Option<&mut Session> {
+    self.sessions.get_mut(session_name)
+  }
+
   pub fn sessions(&self) -> impl Iterator<Item = &str> {
     self.sessions.keys().map(String::as_str)
   }
@@ -64,18 +72,29 @@ pub struct Session {
   state: SessionState,
   cmd_token: Token,
   buf_token: Token,
+  capabilities: HashSet<Capability>,
 }
 
 impl Session {
   pub fn new(name: impl Into<String>, cmd_token: Token, buf_token: Token) -> Self {
     Self {
       name: name.into(),
-      state: SessionState::Idle,
+      state: SessionState::default(),
       cmd_token,
       buf_token,
+      capabilities: HashSet::new(),
     }
   }
 
+  pub fn with_capabilities(mut self, capabilities: impl IntoIterator<Item = Capability>) -> Self {
+    self.capabilities.extend(capabilities);
+    self
+  }
+
+  pub fn has_capability(&self, capability: Capability) -> bool {
+    self.capabilities.contains(&capability)
+  }
+
   pub fn name(&self) -> &str {
     &self.name
   }
@@ -111,7 +130,10 @@ pub enum Fifo {
   Buf {
     session_name: String,
     file: File,
-    buffer: String,
+    /// Raw content read so far; buffers are not necessarily valid UTF-8, see [`buffer::decode`].
+    ///
+    /// [`buffer::decode`]: crate::buffer::decode
+    buffer: Vec<u8>,
   },
 }
 
@@ -131,33 +153,113 @@ impl Fifo {
   }
 }
 
-/// State machine used in sessions.
-#[derive(Debug)]
-pub enum SessionState {
-  /// The session is idle.
-  Idle,
+/// State of a session: the requests waiting for the content of their buffer.
+#[derive(Debug, Default)]
+pub struct SessionState {
+  /// Requests waiting for their buffer, in the order they were sent; buffers are streamed in the same order.
+  pending: VecDeque<Request>,
+}
 
-  /// The session requested highlighting and we are waiting for the buffer content.
-  HighlightingWaiting {
-    client: String,
-    buffer: String,
-    lang: String,
-    timestamp: u64,
-  },
+impl SessionState {
+  /// Wait for the buffer of a request, after the buffers of the requests already waiting.
+  pub fn wait_buffer(&mut self, req: Request) {
+    self.pending.push_back(req);
+  }
 
-  /// The session requested text-objects and we are waiting for the buffer content.
-  TextObjectsWaiting {
-    client: String,
-    buffer: String,
-    lang: String,
-    pattern: String,
-    selections: Vec<Sel>,
-    mode: text_objects::OperationMode,
-  },
+  pub fn is_waiting(&self) -> bool {
+    !self.pending.is_empty()
+  }
+
+  /// Split the content read from the buffer FIFO between the waiting requests, in order.
+  ///
+  /// Each buffer is followed by [`BATCH_DELIMITER`] (a batch by one per item), so that buffers written back to back
+  /// are told apart. The last waiting request also accepts a buffer without delimiter, as written by older front-ends.
+  ///
+  /// The requests whose buffer is complete are returned along with the span of their buffer in `buf`, followed by the
+  /// number of bytes of `buf` consumed.
+  pub fn take_buffers(&mut self, buf: &[u8]) -> (Vec<(Request, Range<usize>)>, usize) {
+    let delim = BATCH_DELIMITER.as_bytes();
+    let delim_ends = |from: usize| {
+      buf[from..]
+        .windows(delim.len())
+        .enumerate()
+        .filter(|(_, bytes)| *bytes == delim)
+        .map(move |(i, _)| from + i + delim.len())
+    };
+
+    let mut taken = Vec::new();
+    let mut start = 0;
+    while let Some(req) = self.pending.front() {
+      let end = match req {
+        Request::HighlightBatch { items, .. } => {
+          delim_ends(start).nth(items.len() - 1).map(|end| (end, end))
+        }
+        _ => delim_ends(start)
+          .next()
+          .map(|end| (end - delim.len(), end))
+          .or_else(|| {
+            (self.pending.len() == 1 && start < buf.len()).then_some((buf.len(), buf.len()))
+          }),
+      };
+      let Some((content_end, consumed)) = end else {
+        break;
+      };
+
+      taken.extend(
+        self
+          .pending
+          .pop_front()
+          .map(|req| (req, start..content_end)),
+      );
+      start = consumed;
+    }
+
+    (taken, start)
+  }
 }
 
-impl SessionState {
-  pub fn idle(&mut self) {
-    *self = SessionState::Idle
+#[cfg(test)]
+mod tests {
+  use crate::request::{HighlightItem, Request, BATCH_DELIMITER};
+
+  use super::SessionState;
+
+  #[test]
+  fn take_buffers() {
+    let highlight = |buffer| Request::highlight("client0", buffer, "rust", 1);
+    let mut state = SessionState::default();
+    state.wait_buffer(highlight("a"));
+    state.wait_buffer(Request::highlight_batch(
+      "client0",
+      vec![
+        HighlightItem::new("b", "rust", 1),
+        HighlightItem::new("c", "rust", 1),
+      ],
+    ));
+    state.wait_buffer(highlight("d"));
+
+    // each request gets its own buffer, even when several are read at once
+    let buf = format!("a{BATCH_DELIMITER}b{BATCH_DELIMITER}c{BATCH_DELIMITER}d");
+    let buf = buf.as_bytes();
+    let (taken, consumed) = state.take_buffers(&buf[..buf.len() - 3]);
+    assert_eq!(taken.len(), 1);
+    assert_eq!(&buf[taken[0].1.clone()], b"a");
+    assert_eq!(consumed, 2);
+
+    // a buffer without delimiter is only accepted by the last request
+    let (taken, consumed) = state.take_buffers(&buf[2..]);
+    let bufs: Vec<_> = taken
+      .iter()
+      .map(|(_, span)| &buf[2..][span.clone()])
+      .collect();
+    assert_eq!(
+      bufs,
+      [
+        format!("b{BATCH_DELIMITER}c{BATCH_DELIMITER}").as_bytes(),
+        b"d"
+      ]
+    );
+    assert_eq!(consumed, buf.len() - 2);
+    assert!(!state.is_waiting());
   }
 }
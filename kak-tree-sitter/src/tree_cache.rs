@@ -0,0 +1,151 @@
+//! Trees of the buffers, kept across requests.
+//!
+//! A session opening many buffers accumulates as many trees. The number of trees kept per session can be limited (see
+//! `server.max_trees` in the configuration); the least recently used trees of the session are then evicted. An evicted
+//! tree is simply parsed again the next time its buffer is needed.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::{buffer::BufferId, tree_sitter_state::TreeState};
+
+/// Trees associated with a [`BufferId`], with their last access.
+#[derive(Default)]
+pub struct TreeCache {
+  trees: HashMap<BufferId, CachedTree>,
+
+  /// Maximum number of trees per session, if limited.
+  max_trees: Option<usize>,
+
+  /// Incremented on every access; a logical clock is enough to order accesses, and cannot go backwards.
+  clock: u64,
+}
+
+struct CachedTree {
+  state: TreeState,
+  last_access: u64,
+}
+
+impl TreeCache {
+  pub fn new(max_trees: Option<usize>) -> Self {
+    Self {
+      max_trees,
+      ..Self::default()
+    }
+  }
+
+  /// Tree of a buffer, if any; contrary to [`TreeCache::get_mut`], this does not count as an access.
+  pub fn get(&self, buffer_id: &BufferId) -> Option<&TreeState> {
+    self.trees.get(buffer_id).map(|cached| &cached.state)
+  }
+
+  /// Tree of a buffer, if any; this counts as an access.
+  pub fn get_mut(&mut self, buffer_id: &BufferId) -> Option<&mut TreeState> {
+    let last_access = self.tick();
+    self.trees.get_mut(buffer_id).map(|cached| {
+      cached.last_access = last_access;
+      &mut cached.state
+    })
+  }
+
+  /// Insert or replace the tree of a buffer, evicting the least recently used trees of its session if the limit is
+  /// exceeded.
+  pub fn insert(&mut self, buffer_id: BufferId, state: TreeState) -> &mut TreeState {
+    if !self.trees.contains_key(&buffer_id) {
+      self.make_room(buffer_id.session());
+    }
+
+    let last_access = self.tick();
+    let cached = CachedTree { state, last_access };
+    let cached = match self.trees.entry(buffer_id) {
+      Entry::Occupied(mut entry) => {
+        entry.insert(cached);
+        entry.into_mut()
+      }
+      Entry::Vacant(entry) => entry.insert(cached),
+    };
+
+    &mut cached.state
+  }
+
+  pub fn remove(&mut self, buffer_id: &BufferId) -> Option<TreeState> {
+    self.trees.remove(buffer_id).map(|cached| cached.state)
+  }
+
+  /// Remove the trees of all the buffers of a session.
+  pub fn remove_session(&mut self, session: &str) {
+    self
+      .trees
+      .retain(|buffer_id, _| buffer_id.session() != session);
+  }
+
+  fn tick(&mut self) -> u64 {
+    self.clock += 1;
+    self.clock
+  }
+
+  /// Evict the least recently used trees of a session, so that a new one can be inserted without exceeding the limit.
+  fn make_room(&mut self, session: &str) {
+    let Some(max_trees) = self.max_trees else {
+      return;
+    };
+
+    let mut session_trees: Vec<_> = self
+      .trees
+      .iter()
+      .filter(|(buffer_id, _)| buffer_id.session() == session)
+      .map(|(buffer_id, cached)| (cached.last_access, buffer_id.clone()))
+      .collect();
+
+    // keep room for the tree about to be inserted
+    let excess = (session_trees.len() + 1).saturating_sub(max_trees);
+    if excess == 0 {
+      return;
+    }
+
+    session_trees.sort_unstable_by_key(|(last_access, _)| *last_access);
+    for (_, buffer_id) in session_trees.into_iter().take(excess) {
+      log::debug!("evicting the tree of buffer {buffer_id:?}");
+      self.trees.remove(&buffer_id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::TreeCache;
+  use crate::{buffer::BufferId, tree_sitter_state::TreeState};
+
+  fn tree() -> TreeState {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    TreeState::new("rust", &mut parser, "fn main() {}").unwrap()
+  }
+
+  #[test]
+  fn lru_eviction() {
+    let mut cache = TreeCache::new(Some(2));
+    let a = BufferId::new("session", "a.rs");
+    let b = BufferId::new("session", "b.rs");
+    let c = BufferId::new("session", "c.rs");
+    let other = BufferId::new("other", "a.rs");
+
+    cache.insert(a.clone(), tree());
+    cache.insert(b.clone(), tree());
+    cache.insert(other.clone(), tree());
+
+    // a is now more recently used than b
+    assert!(cache.get_mut(&a).is_some());
+    cache.insert(c.clone(), tree());
+
+    assert!(cache.get(&a).is_some());
+    assert!(cache.get(&b).is_none(), "b is the least recently used tree");
+    assert!(cache.get(&c).is_some());
+    assert!(cache.get(&other).is_some(), "limits are per session");
+
+    // replacing a tree does not evict anything
+    cache.insert(c.clone(), tree());
+    assert!(cache.get(&a).is_some());
+  }
+}
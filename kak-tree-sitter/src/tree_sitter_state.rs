@@ -1,65 +1,657 @@
-//! Tree-sitter state (i.e. highlighting, tree walking, etc.)
+//! Tree-sitter state (i.e. tree walking, queries, etc.)
 
-use tree_sitter::{Parser, QueryCapture, QueryCursor};
+use std::{
+  collections::{hash_map::DefaultHasher, HashSet},
+  hash::{Hash, Hasher},
+  ops::Range,
+};
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Parser, Point, Query, QueryCapture, QueryCursor, QueryMatch};
 
 use crate::{
+  comment,
+  edit::TextEdit,
   error::OhNo,
-  highlighting::KakHighlightRange,
+  highlighting::{HighlightQuery, KakHighlightRange},
+  indent::{INDENT_CAPTURES, OUTDENT_CAPTURES},
   languages::Language,
+  navigation::Direction,
   selection::{ObjectFlags, Pos, Sel, SelectMode},
   text_objects,
 };
 
+/// A capture returned by a query, along with its name.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct NamedCapture {
+  pub name: String,
+  pub sel: Sel,
+}
+
+impl NamedCapture {
+  fn new(name: impl Into<String>, node: &Node) -> Self {
+    Self {
+      name: name.into(),
+      sel: node_sel(node),
+    }
+  }
+}
+
+/// A capture of the highlights query, along with the face it is highlighted with; see
+/// [`TreeState::highlight_captures`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HighlightCapture {
+  /// Name of the capture, as written in the query; e.g. `keyword.control.rust`.
+  pub name: String,
+
+  /// Byte range of the captured node, its end excluded.
+  pub bytes: (usize, usize),
+
+  /// Positions of the first and last characters of the captured node.
+  pub start: Pos,
+  pub end: Pos,
+
+  /// Face of the capture, if it is highlighted; its group might not be configured or be disabled, or it might capture
+  /// an anonymous node while those are not highlighted.
+  pub face: Option<String>,
+}
+
+/// Named node under a position, along with the kinds of its ancestors; see [`TreeState::node_info`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct NodeInfo {
+  /// Kind of the node, as named by the grammar; e.g. `function_item`.
+  pub kind: String,
+
+  /// Byte range of the node, its end excluded.
+  pub bytes: (usize, usize),
+
+  /// Positions of the first and last characters of the node.
+  pub start: Pos,
+  pub end: Pos,
+
+  /// Kinds of the named ancestors of the node, from its parent to the root.
+  pub ancestors: Vec<String>,
+}
+
+/// Region of a buffer injected with another language, as detected by the injections query; see
+/// [`TreeState::injections`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Injection {
+  /// Language of the region, as named by the query; e.g. the info string of a fenced code block.
+  pub lang: String,
+
+  /// Language the region is highlighted with, if any; the language might not be supported, or have no highlights
+  /// query.
+  pub resolved: Option<String>,
+
+  /// Byte range of the region, its end excluded.
+  pub bytes: (usize, usize),
+
+  /// Positions of the first and last characters of the region.
+  pub start: Pos,
+  pub end: Pos,
+}
+
+/// Node injected with another language by a match of an injections query, along with the name of that language.
+///
+/// The language is either the text of the `@injection.language` capture, or set with `(#set! injection.language …)`.
+fn injection<'a, 'tree>(
+  query: &'a Query,
+  m: &QueryMatch<'_, 'tree>,
+  buf: &'a str,
+) -> Option<(Node<'tree>, &'a str)> {
+  let names = query.capture_names();
+  let is_capture = |capture: &QueryCapture, name: &str| names[capture.index as usize] == name;
+
+  let content = m
+    .captures
+    .iter()
+    .find(|capture| is_capture(capture, "injection.content"))?
+    .node;
+  let lang = m
+    .captures
+    .iter()
+    .find(|capture| is_capture(capture, "injection.language"))
+    .and_then(|capture| capture.node.utf8_text(buf.as_bytes()).ok())
+    .or_else(|| {
+      query
+        .property_settings(m.pattern_index)
+        .iter()
+        .find(|prop| &*prop.key == "injection.language")
+        .and_then(|prop| prop.value.as_deref())
+    })?;
+
+  Some((content, lang))
+}
+
+/// Selection of a node, both ends included.
+fn node_sel(node: &Node) -> Sel {
+  let anchor = Pos::from(node.start_position());
+  let mut cursor = Pos::from(node.end_position());
+  cursor.col = cursor.col.saturating_sub(1).max(1);
+
+  Sel { anchor, cursor }
+}
+
+/// Whether a capture name denotes prose (comments, strings and explicit `@spell` captures).
+fn is_prose_capture(name: &str) -> bool {
+  ["comment", "string", "spell"].iter().any(|group| {
+    name == *group
+      || name
+        .strip_prefix(group)
+        .map(|rest| rest.starts_with('.'))
+        .unwrap_or_default()
+  })
+}
+
+/// Maximum number of idle cursors kept by a [`CursorPool`].
+const MAX_POOLED_CURSORS: usize = 4;
+
+/// Pool of [`QueryCursor`], reused across queries to avoid allocating a new cursor for every request.
+#[derive(Default)]
+pub struct CursorPool {
+  cursors: Vec<QueryCursor>,
+}
+
+impl CursorPool {
+  /// Take a cursor from the pool, or create a new one if the pool is empty.
+  pub fn acquire(&mut self) -> QueryCursor {
+    self.cursors.pop().unwrap_or_else(QueryCursor::new)
+  }
+
+  /// Give a cursor back to the pool.
+  ///
+  /// The settings of the cursor (match limit, ranges) are reset, so that they do not leak to the next user.
+  pub fn release(&mut self, mut cursor: QueryCursor) {
+    if self.cursors.len() >= MAX_POOLED_CURSORS {
+      return;
+    }
+
+    cursor.set_match_limit(u32::MAX);
+    cursor
+      .set_byte_range(0..usize::MAX)
+      .set_point_range(Point::new(0, 0)..Point::new(usize::MAX, usize::MAX));
+    self.cursors.push(cursor);
+  }
+}
+
 /// State around a tree.
 ///
 /// A tree-sitter tree represents a parsed buffer in a given state. It can be walked with queries and updated.
 pub struct TreeState {
-  // this will be useful for #26
-  _parser: Parser,
   tree: tree_sitter::Tree,
 
-  // TODO: for now, we don’t support custom highligthing, and hence have to use tree-sitter-highlight; see
-  // #26 for further information
-  highlighter: tree_sitter_highlight::Highlighter,
+  /// Name of the language the tree was parsed with.
+  ///
+  /// Several languages can share a grammar, so the language of the tree itself is not enough to tell them apart.
+  lang_name: String,
+
+  /// Hash of the content the tree was parsed from, to detect unchanged buffers.
+  content_hash: u64,
+
+  /// Highlights of the tree, once computed.
+  ///
+  /// Kakoune asks for highlights whenever it is idle, even if the buffer did not change since; those are then reused,
+  /// as long as they were computed with syntax errors or not the same way, as the second element tells.
+  highlights: Option<(Vec<KakHighlightRange>, bool)>,
+
+  /// Timestamp of the buffer last highlighted with the tree.
+  ///
+  /// Highlight requests about an older timestamp are stale: their highlights would replace more recent ones.
+  timestamp: u64,
 }
 
 impl TreeState {
-  pub fn new(lang: &Language, buf: &str) -> Result<Self, OhNo> {
-    let mut parser = Parser::new();
-    parser.set_language(lang.lang())?;
+  /// Parse a buffer with the given parser.
+  ///
+  /// The parser is expected to already be set with the language of the buffer, named `lang_name`.
+  pub fn new(lang_name: &str, parser: &mut Parser, buf: &str) -> Result<Self, OhNo> {
+    // a previous parse might have been interrupted; ensure we start from a clean state
+    parser.reset();
 
     let tree = parser
       .parse(buf.as_bytes(), None)
       .ok_or(OhNo::CannotParseBuffer)?;
 
-    let highlighter = tree_sitter_highlight::Highlighter::new();
+    let content_hash = Self::hash_content(buf);
 
     Ok(Self {
-      _parser: parser,
       tree,
-      highlighter,
+      lang_name: lang_name.to_owned(),
+      content_hash,
+      highlights: None,
+      timestamp: 0,
     })
   }
 
-  pub fn highlight<'a>(
-    &'a mut self,
-    lang: &'a Language,
+  fn hash_content(buf: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buf.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  /// Whether the tree was parsed from this very content, with the language of `parser`, in which case it can be reused
+  /// as-is.
+  pub fn is_parsed_from(&self, parser: &Parser, buf: &str) -> bool {
+    parser.language() == Some(self.tree.language()) && self.content_hash == Self::hash_content(buf)
+  }
+
+  pub fn lang_name(&self) -> &str {
+    &self.lang_name
+  }
+
+  pub fn tree(&self) -> &tree_sitter::Tree {
+    &self.tree
+  }
+
+  /// Highlights previously computed for the tree, with syntax errors overlaid or not, if any.
+  pub fn highlights(&self, syntax_errors: bool) -> Option<&[KakHighlightRange]> {
+    self
+      .highlights
+      .as_ref()
+      .filter(|(_, with_errors)| *with_errors == syntax_errors)
+      .map(|(ranges, _)| ranges.as_slice())
+  }
+
+  pub fn set_highlights(&mut self, ranges: Vec<KakHighlightRange>, syntax_errors: bool) {
+    self.highlights = Some((ranges, syntax_errors));
+  }
+
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+
+  pub fn set_timestamp(&mut self, timestamp: u64) {
+    self.timestamp = timestamp;
+  }
+
+  /// Get the S-expression of the tree.
+  ///
+  /// If `range` is provided, only the smallest node spanning the byte range is returned. Return [`None`] if no such
+  /// node exists.
+  pub fn sexp(&self, range: Option<(usize, usize)>) -> Option<String> {
+    let root = self.tree.root_node();
+    let node = match range {
+      Some((start, end)) => root.descendant_for_byte_range(start, end)?,
+      None => root,
+    };
+
+    Some(node.to_sexp())
+  }
+
+  /// Get the smallest named node spanning the character at `pos`, along with its ancestors.
+  ///
+  /// Positions past the end of their line (e.g. the cursor on a newline) fall back to the node spanning the line end.
+  pub fn node_info(&self, pos: Pos) -> Option<NodeInfo> {
+    let point = Point::new(pos.line.saturating_sub(1), pos.col.saturating_sub(1));
+    let node = self
+      .tree
+      .root_node()
+      .named_descendant_for_point_range(point, point)?;
+    let sel = node_sel(&node);
+
+    let mut ancestors = Vec::new();
+    let mut parent = node.parent();
+    while let Some(node) = parent {
+      if node.is_named() {
+        ancestors.push(node.kind().to_owned());
+      }
+      parent = node.parent();
+    }
+
+    Some(NodeInfo {
+      kind: node.kind().to_owned(),
+      bytes: (node.start_byte(), node.end_byte()),
+      start: sel.anchor,
+      end: sel.cursor,
+      ancestors,
+    })
+  }
+
+  /// Run a query on the tree and return all of its captures.
+  pub fn captures(&self, query: &Query, buf: &str, cursor: &mut QueryCursor) -> Vec<NamedCapture> {
+    let names = query.capture_names();
+    cursor
+      .captures(query, self.tree.root_node(), buf.as_bytes())
+      .flat_map(|(cm, idx)| cm.captures.get(idx).copied())
+      .map(|capture| NamedCapture::new(&names[capture.index as usize], &capture.node))
+      .collect()
+  }
+
+  /// Get the captures of the highlights query, in the order the query yields them.
+  ///
+  /// Contrary to highlighting, nested captures are all kept, and injected languages are ignored; this is meant to debug
+  /// queries.
+  pub fn highlight_captures(
+    &self,
+    query: HighlightQuery,
+    buf: &str,
+    cursor: &mut QueryCursor,
+  ) -> Vec<HighlightCapture> {
+    let hl_query = &query.config.query;
+    let names = hl_query.capture_names();
+
+    cursor
+      .captures(hl_query, self.tree.root_node(), buf.as_bytes())
+      .flat_map(|(cm, idx)| cm.captures.get(idx).copied())
+      .map(|capture| {
+        let name = &names[capture.index as usize];
+        let node = capture.node;
+        let sel = node_sel(&node);
+        let face = (query.anonymous_nodes || node.is_named())
+          .then(|| query.capture_faces.get(capture.index as usize).cloned())
+          .flatten()
+          .flatten();
+
+        HighlightCapture {
+          name: name.clone(),
+          bytes: (node.start_byte(), node.end_byte()),
+          start: sel.anchor,
+          end: sel.cursor,
+          face,
+        }
+      })
+      .collect()
+  }
+
+  /// Get the regions injected with another language, in the order the injections query yields them.
+  ///
+  /// Regions are detected as when highlighting: the language is either the text of the `@injection.language` capture,
+  /// or set with `(#set! injection.language …)`. `resolve` maps that language to the one actually highlighting the
+  /// region, if any. Injections combining several regions (`injection.combined`) are not detected.
+  pub fn injections(
+    &self,
+    query: &Query,
+    buf: &str,
+    cursor: &mut QueryCursor,
+    resolve: impl Fn(&str) -> Option<String>,
+  ) -> Vec<Injection> {
+    cursor
+      .matches(query, self.tree.root_node(), buf.as_bytes())
+      .filter_map(|m| {
+        let (content, lang) = injection(query, &m, buf)?;
+        let sel = node_sel(&content);
+
+        Some(Injection {
+          lang: lang.to_owned(),
+          resolved: resolve(lang),
+          bytes: (content.start_byte(), content.end_byte()),
+          start: sel.anchor,
+          end: sel.cursor,
+        })
+      })
+      .collect()
+  }
+
+  /// Get the regions injected with another language that can be highlighted on their own, along with their language
+  /// as named by the query; see [`TreeState::injections`].
+  ///
+  /// Return [`None`] if some regions cannot, because they are combined (`injection.combined`) or leave out the children
+  /// of the injected node (no `injection.include-children`); only highlighting the whole buffer at once gets those right.
+  pub fn standalone_injections<'a>(
+    &self,
+    query: &'a Query,
     buf: &'a str,
-    injection_callback: impl FnMut(&str) -> Option<&'a tree_sitter_highlight::HighlightConfiguration>
-      + 'a,
-  ) -> Result<Vec<KakHighlightRange>, OhNo> {
-    let events = self
-      .highlighter
-      .highlight(&lang.hl_config, buf.as_bytes(), None, injection_callback)
-      .map_err(|err| OhNo::HighlightError {
-        err: err.to_string(),
-      })?;
-
-    Ok(KakHighlightRange::from_iter(
-      buf,
-      &lang.hl_names,
-      events.flatten(),
-    ))
+    cursor: &mut QueryCursor,
+  ) -> Option<Vec<(Range<usize>, &'a str)>> {
+    let has_prop = |pattern_index, key| {
+      query
+        .property_settings(pattern_index)
+        .iter()
+        .any(|prop| &*prop.key == key)
+    };
+    if (0..query.pattern_count()).any(|i| has_prop(i, "injection.combined")) {
+      return None;
+    }
+
+    cursor
+      .matches(query, self.tree.root_node(), buf.as_bytes())
+      .filter_map(|m| {
+        let (content, lang) = injection(query, &m, buf)?;
+        let standalone =
+          has_prop(m.pattern_index, "injection.include-children") || content.child_count() == 0;
+        Some(standalone.then(|| (content.byte_range(), lang)))
+      })
+      .collect()
+  }
+
+  /// Get the prose ranges of the tree; i.e. the regions captured as comments or strings by the highlight query.
+  ///
+  /// Languages without a highlights query have no prose ranges.
+  pub fn prose_ranges(&self, lang: &Language, buf: &str, cursor: &mut QueryCursor) -> Vec<Sel> {
+    let Some(hl_config) = &lang.hl_config else {
+      return Vec::new();
+    };
+
+    self
+      .captures(&hl_config.query, buf, cursor)
+      .into_iter()
+      .filter(|capture| is_prose_capture(&capture.name))
+      .map(|capture| capture.sel)
+      .collect()
+  }
+
+  /// Get the syntax errors of the tree; i.e. the ranges of its `ERROR` and `MISSING` nodes, sorted by position.
+  ///
+  /// Errors nested in other errors are not reported on their own. Missing nodes have no width; they are reported on the
+  /// character right after where they are expected.
+  pub fn syntax_errors(&self) -> Vec<Sel> {
+    let mut errors: Vec<_> = self
+      .error_nodes()
+      .iter()
+      .map(|node| {
+        if node.is_missing() {
+          let pos = Pos::from(node.start_position());
+          Sel {
+            anchor: pos,
+            cursor: pos,
+          }
+        } else {
+          node_sel(node)
+        }
+      })
+      .collect();
+
+    errors.sort_by_key(|sel| (sel.anchor, sel.cursor));
+    errors
+  }
+
+  /// Get the byte spans of the syntax errors of the tree, as [`TreeState::syntax_errors`]; i.e. missing nodes span the
+  /// character right after where they are expected, if any.
+  pub fn syntax_error_spans(&self, buf: &str) -> Vec<Range<usize>> {
+    self
+      .error_nodes()
+      .iter()
+      .filter_map(|node| {
+        let start = node.start_byte();
+        if !node.is_missing() {
+          return Some(start..node.end_byte());
+        }
+
+        let len = buf.get(start..)?.chars().next()?.len_utf8();
+        Some(start..start + len)
+      })
+      .collect()
+  }
+
+  /// `ERROR` and `MISSING` nodes of the tree, outermost ones only.
+  fn error_nodes(&self) -> Vec<Node<'_>> {
+    let mut errors = Vec::new();
+    let mut nodes = vec![self.tree.root_node()];
+
+    while let Some(node) = nodes.pop() {
+      if node.is_missing() || node.is_error() {
+        errors.push(node);
+      } else if node.has_error() {
+        nodes.extend(node.children(&mut node.walk()));
+      }
+    }
+
+    errors
+  }
+
+  /// Get the indent level of a line (1-based), according to the indents query of the language.
+  ///
+  /// Every line starting an indent capture that spans `line` adds a level, and an outdent capture starting `line` (e.g.
+  /// a closing brace) removes one.
+  pub fn indent_level(
+    &self,
+    lang: &Language,
+    buf: &str,
+    line: usize,
+    cursor: &mut QueryCursor,
+  ) -> Result<usize, OhNo> {
+    let query = lang.indent_query.as_ref().ok_or(OhNo::UnsupportedIndents)?;
+    let names = query.capture_names();
+    let row = line.saturating_sub(1);
+
+    // outdent captures only apply to the first token of the line
+    let first_col = buf
+      .lines()
+      .nth(row)
+      .and_then(|l| l.find(|c: char| !c.is_whitespace()));
+
+    cursor.set_point_range(Point::new(row, 0)..Point::new(row + 1, 0));
+
+    let mut indent_rows = HashSet::new();
+    let mut outdent = false;
+
+    for (cm, idx) in cursor.captures(query, self.tree.root_node(), buf.as_bytes()) {
+      let Some(capture) = cm.captures.get(idx) else {
+        continue;
+      };
+      let name = names[capture.index as usize].as_str();
+      let start = capture.node.start_position();
+      let end = capture.node.end_position();
+
+      if INDENT_CAPTURES.contains(&name)
+        && start.row < row
+        && (end.row > row || (end.row == row && end.column > 0))
+      {
+        indent_rows.insert(start.row);
+      } else if OUTDENT_CAPTURES.contains(&name)
+        && start.row == row
+        && Some(start.column) == first_col
+      {
+        outdent = true;
+      }
+    }
+
+    Ok(indent_rows.len().saturating_sub(outdent as usize))
+  }
+
+  /// Edits commenting the lines spanned by the selections, or uncommenting them if they are already commented.
+  pub fn toggle_comment(
+    &self,
+    lang: &Language,
+    buf: &str,
+    sels: &[Sel],
+  ) -> Result<Vec<TextEdit>, OhNo> {
+    comment::toggle_comment(self.tree.root_node(), buf, &lang.comment, sels)
+  }
+
+  /// Select the siblings of the nodes spanning the selections that are of the same kind; i.e. the children of the same
+  /// parent with the same kind.
+  ///
+  /// If `named_only` is `true`, anonymous nodes are ignored. A node without parent (i.e. the root) is its only sibling.
+  /// Each node is selected once, even if several selections lead to it.
+  pub fn siblings(&self, sels: &[Sel], named_only: bool) -> Vec<Sel> {
+    let root = self.tree.root_node();
+    let mut seen = HashSet::new();
+    let mut siblings = Vec::new();
+
+    for sel in sels {
+      let start = sel.anchor.min(sel.cursor);
+      let end = sel.anchor.max(sel.cursor);
+      let start = Point::new(start.line.saturating_sub(1), start.col.saturating_sub(1));
+      let end = Point::new(end.line.saturating_sub(1), end.col);
+
+      let node = if named_only {
+        root.named_descendant_for_point_range(start, end)
+      } else {
+        root.descendant_for_point_range(start, end)
+      };
+      let Some(node) = node else {
+        continue;
+      };
+
+      let Some(parent) = node.parent() else {
+        if seen.insert(node.id()) {
+          siblings.push(node_sel(&node));
+        }
+        continue;
+      };
+
+      let mut cursor = parent.walk();
+      for sibling in parent.children(&mut cursor) {
+        if sibling.kind_id() == node.kind_id()
+          && (!named_only || sibling.is_named())
+          && seen.insert(sibling.id())
+        {
+          siblings.push(node_sel(&sibling));
+        }
+      }
+    }
+
+    siblings
+  }
+
+  /// Move each selection to the next (or previous) node of the given kind, selecting it.
+  ///
+  /// Nodes are looked for relative to the start of the selections. If `wrap` is `true`, the search wraps around the end
+  /// (or start) of the buffer. Selections without such a node are dropped.
+  pub fn navigate(&self, sels: &[Sel], kind: &str, direction: Direction, wrap: bool) -> Vec<Sel> {
+    let nodes = self.nodes_of_kind(kind);
+
+    sels
+      .iter()
+      .flat_map(|sel| {
+        let start = sel.anchor.min(sel.cursor);
+        let node = match direction {
+          Direction::Next => nodes
+            .iter()
+            .find(|node| Pos::from(node.start_position()) > start)
+            .or_else(|| nodes.first().filter(|_| wrap)),
+
+          Direction::Prev => nodes
+            .iter()
+            .rev()
+            .find(|node| Pos::from(node.start_position()) < start)
+            .or_else(|| nodes.last().filter(|_| wrap)),
+        };
+
+        node.map(node_sel)
+      })
+      .collect()
+  }
+
+  /// All the nodes of a given kind, in buffer order.
+  fn nodes_of_kind(&self, kind: &str) -> Vec<Node<'_>> {
+    let mut nodes = Vec::new();
+    let mut cursor = self.tree.walk();
+
+    // pre-order traversal, so that nodes are sorted by start position
+    'walk: loop {
+      if cursor.node().kind() == kind {
+        nodes.push(cursor.node());
+      }
+
+      if cursor.goto_first_child() || cursor.goto_next_sibling() {
+        continue;
+      }
+
+      loop {
+        if !cursor.goto_parent() {
+          break 'walk;
+        }
+
+        if cursor.goto_next_sibling() {
+          break;
+        }
+      }
+    }
+
+    nodes
   }
 
   /// Get the text-objects for the given pattern.
@@ -73,24 +665,24 @@ impl TreeState {
     pattern: &str,
     selections: &[Sel],
     mode: &text_objects::OperationMode,
+    cursor: &mut QueryCursor,
   ) -> Result<Vec<Sel>, OhNo> {
     // first, check whether the language supports text-objects, and also check whether it has the text-object type in
     // its capture names
     let query = lang
       .textobject_query
       .as_ref()
-      .ok_or_else(|| OhNo::UnsupportedTextObjects)?;
+      .ok_or(OhNo::UnsupportedTextObjects)?;
 
     // get captures for the given pattern; this is a function because the pattern might be dynamically recomputed (e.g.
     // object mode)
-    let get_captures = |pattern| {
+    let mut get_captures = |pattern| {
       let capture_index =
         query
           .capture_index_for_name(pattern)
           .ok_or(OhNo::UnknownTextObjectQuery {
             pattern: pattern.to_owned(),
           })?;
-      let mut cursor = QueryCursor::new();
       let captures: Vec<_> = cursor
         .captures(query, self.tree.root_node(), buf.as_bytes())
         .flat_map(|(cm, _)| cm.captures.iter().cloned())
@@ -358,3 +950,151 @@ impl TreeState {
     candidates.last().cloned()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Query, QueryCursor};
+
+  use super::{is_prose_capture, CursorPool, TreeState};
+  use crate::{navigation::Direction, selection::Sel};
+
+  #[test]
+  fn prose_captures() {
+    assert!(is_prose_capture("comment"));
+    assert!(is_prose_capture("comment.line"));
+    assert!(is_prose_capture("string.special"));
+    assert!(is_prose_capture("spell"));
+    assert!(!is_prose_capture("stringly"));
+    assert!(!is_prose_capture("keyword"));
+    assert!(!is_prose_capture("punctuation.comment"));
+  }
+
+  #[test]
+  fn siblings() {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let buf = "fn main() {\n  f(a, b, 3);\n}\n";
+    let tree_state = TreeState::new("rust", &mut parser, buf).unwrap();
+    let siblings = |sels: &str, named_only| {
+      tree_state
+        .siblings(&Sel::parse_many(sels), named_only)
+        .iter()
+        .map(Sel::to_kak_str)
+        .collect::<Vec<_>>()
+    };
+
+    // the literal is not an identifier, so it is not selected
+    assert_eq!(siblings("2.5,2.5", false), ["2.5,2.5", "2.8,2.8"]);
+    assert_eq!(siblings("2.6,2.6", false), ["2.6,2.6", "2.9,2.9"]);
+
+    // the comma is anonymous; asking for named nodes only selects the whole argument list
+    assert_eq!(siblings("2.6,2.6", true), ["2.4,2.12"]);
+
+    // both selections lead to the same nodes
+    assert_eq!(siblings("2.5,2.5 2.8,2.8", true), ["2.5,2.5", "2.8,2.8"]);
+  }
+
+  #[test]
+  fn navigate() {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let buf = "fn a() {}\nmod m {\n  fn b() {}\n}\nfn c() {}\n";
+    let tree_state = TreeState::new("rust", &mut parser, buf).unwrap();
+    let navigate = |sels: &str, direction, wrap| {
+      tree_state
+        .navigate(&Sel::parse_many(sels), "function_item", direction, wrap)
+        .iter()
+        .map(Sel::to_kak_str)
+        .collect::<Vec<_>>()
+    };
+
+    // nested nodes are found too
+    assert_eq!(navigate("1.1,1.9", Direction::Next, false), ["3.3,3.11"]);
+    assert_eq!(navigate("3.3,3.11", Direction::Next, false), ["5.1,5.9"]);
+    assert_eq!(navigate("5.1,5.1", Direction::Prev, false), ["3.3,3.11"]);
+
+    // selections without a node are dropped, unless the search wraps around
+    assert!(navigate("5.1,5.1", Direction::Next, false).is_empty());
+    assert_eq!(navigate("5.1,5.1", Direction::Next, true), ["1.1,1.9"]);
+    assert_eq!(navigate("1.1,1.1", Direction::Prev, true), ["5.1,5.9"]);
+  }
+
+  #[test]
+  fn syntax_errors() {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let mut errors = |buf| {
+      TreeState::new("rust", &mut parser, buf)
+        .unwrap()
+        .syntax_errors()
+        .iter()
+        .map(Sel::to_kak_str)
+        .collect::<Vec<_>>()
+    };
+
+    assert!(errors("fn main() {}\n").is_empty());
+
+    // a stray token, and a missing semicolon
+    assert_eq!(
+      errors("fn main() {\n  let x = 1 $;\n  let y = 2\n}\n"),
+      ["2.13,2.13", "3.12,3.12"]
+    );
+  }
+
+  #[test]
+  fn standalone_injections() {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let buf = "fn main() {\n  println!(\"{}\", 1);\n}\n";
+    let tree_state = TreeState::new("rust", &mut parser, buf).unwrap();
+    let injections = |query| {
+      let query = Query::new(tree_sitter_rust::language(), query).unwrap();
+      tree_state
+        .standalone_injections(&query, buf, &mut QueryCursor::new())
+        .map(|injections| {
+          injections
+            .into_iter()
+            .map(|(region, lang)| (region, lang.to_owned()))
+            .collect::<Vec<_>>()
+        })
+    };
+
+    assert_eq!(
+      injections(
+        r#"((macro_invocation (token_tree) @injection.content)
+            (#set! injection.language "rust")
+            (#set! injection.include-children))"#
+      ),
+      Some(vec![(22..31, "rust".to_owned())])
+    );
+
+    // the children of the token tree would be left out, and combined regions highlighted as one
+    assert_eq!(
+      injections(
+        r#"((macro_invocation (token_tree) @injection.content)
+            (#set! injection.language "rust"))"#
+      ),
+      None
+    );
+    assert_eq!(
+      injections(
+        r#"((macro_invocation (token_tree) @injection.content)
+            (#set! injection.language "rust")
+            (#set! injection.include-children)
+            (#set! injection.combined))"#
+      ),
+      None
+    );
+  }
+
+  #[test]
+  fn cursor_pool_resets_cursors() {
+    let mut pool = CursorPool::default();
+
+    let mut cursor = pool.acquire();
+    cursor.set_match_limit(1);
+    pool.release(cursor);
+
+    assert_eq!(pool.acquire().match_limit(), u32::MAX);
+  }
+}
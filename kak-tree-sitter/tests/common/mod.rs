@@ -0,0 +1,76 @@
+//! Helpers shared by integration tests.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  process::Command,
+};
+
+/// Install the Rust grammar and its highlight query in `data_dir`, laid out as `ktsctl` does.
+pub fn install_rust(data_dir: &Path) {
+  let data_dir = data_dir.join("kak-tree-sitter");
+  fs::create_dir_all(data_dir.join("grammars")).unwrap();
+  fs::copy(rust_grammar(), data_dir.join("grammars/rust.so")).unwrap();
+  fs::create_dir_all(data_dir.join("queries/rust")).unwrap();
+  fs::write(
+    data_dir.join("queries/rust/highlights.scm"),
+    tree_sitter_rust::HIGHLIGHT_QUERY,
+  )
+  .unwrap();
+}
+
+/// Build the Rust grammar as a shared object, from the sources of the tree-sitter-rust crate.
+///
+/// The shared object is cached in the target directory.
+pub fn rust_grammar() -> PathBuf {
+  let so = Path::new(env!("CARGO_TARGET_TMPDIR")).join("tree-sitter-rust.so");
+  if so.exists() {
+    return so;
+  }
+
+  let metadata = Command::new(env!("CARGO"))
+    .args(["metadata", "--format-version", "1", "--manifest-path"])
+    .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+    .output()
+    .unwrap();
+  let metadata: serde_json::Value = serde_json::from_slice(&metadata.stdout).unwrap();
+  let manifest_path = metadata["packages"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|pkg| pkg["name"] == "tree-sitter-rust")
+    .and_then(|pkg| pkg["manifest_path"].as_str())
+    .expect("tree-sitter-rust sources");
+  let src = Path::new(manifest_path).parent().unwrap().join("src");
+
+  // build to a temporary path first, so that concurrent tests never see a partially written shared object
+  let tmp = so.with_extension(format!("{}.so", std::process::id()));
+  let status = Command::new("cc")
+    .args(["-shared", "-fPIC", "-O2", "-I"])
+    .arg(&src)
+    .arg(src.join("parser.c"))
+    .arg(src.join("scanner.c"))
+    .arg("-o")
+    .arg(&tmp)
+    .status()
+    .unwrap();
+  assert!(status.success(), "cannot build the Rust grammar");
+  fs::rename(&tmp, &so).unwrap();
+
+  so
+}
+
+/// Compare `actual` to the golden file `name`; set `KTS_UPDATE_GOLDEN=1` to update the golden file instead.
+pub fn assert_golden(name: &str, actual: &str) {
+  let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/golden")
+    .join(name);
+
+  if std::env::var("KTS_UPDATE_GOLDEN").as_deref() == Ok("1") {
+    fs::write(&path, format!("{actual}\n")).unwrap();
+    return;
+  }
+
+  let expected = fs::read_to_string(&path).unwrap();
+  assert_eq!(actual, expected.trim_end());
+}
@@ -0,0 +1,1229 @@
+//! End-to-end tests, running a server on a temporary socket.
+//!
+//! The server is isolated from the user’s environment: XDG directories point to a temporary directory, and `kak` is
+//! replaced by a stub, passed with `KAK_BINARY`, that records the commands the server sends to Kakoune.
+
+use std::{
+  fs,
+  io::{Read, Write},
+  net::Shutdown,
+  os::unix::{
+    fs::PermissionsExt,
+    net::{UnixListener, UnixStream},
+  },
+  path::{Path, PathBuf},
+  process::{Child, Command, Stdio},
+  thread::{self, sleep},
+  time::{Duration, Instant},
+};
+
+use kak_tree_sitter::{
+  error::exit_code,
+  indent::IndentStyle,
+  request::{Capability, BATCH_DELIMITER, PROTOCOL_VERSION},
+  response::UnixResponse,
+  server::{Server, ServerPaths},
+  HighlightItem, Request, UnixRequest,
+};
+
+mod common;
+
+const SESSION: &str = "kts-test";
+const CLIENT: &str = "client0";
+
+/// Stub replacing `kak`; `kak -p` invocations have their stdin recorded to `$KTS_TEST_KAK_STDIN`, each one followed by
+/// [`RECORD_SEPARATOR`], as commands can span several lines.
+const FAKE_KAK: &str = r#"#!/bin/sh
+if [ "$1" = "-p" ]; then
+  { cat; printf '\0'; } >> "$KTS_TEST_KAK_STDIN"
+fi
+"#;
+
+const RECORD_SEPARATOR: char = '\0';
+
+/// A server running in an isolated environment; it is killed when dropped.
+struct TestServer {
+  dir: PathBuf,
+  socket: PathBuf,
+  kak_stdin: PathBuf,
+  fake_kak: PathBuf,
+
+  /// Server process, if started by the test itself.
+  child: Option<Child>,
+}
+
+impl TestServer {
+  fn start(name: &str) -> Self {
+    Self::isolated(name).run()
+  }
+
+  /// Start the server in the isolated environment.
+  fn run(self) -> Self {
+    self.run_with(&[])
+  }
+
+  /// Start the server in the isolated environment, with additional arguments to `server start`.
+  fn run_with(mut self, args: &[&str]) -> Self {
+    let child = self
+      .command()
+      .args(["server", "start"])
+      .args(args)
+      .stdout(Stdio::null())
+      .spawn()
+      .unwrap();
+    self.child = Some(child);
+    wait_for("the server socket", || self.socket.exists());
+
+    self
+  }
+
+  /// Write the user configuration.
+  fn configure(&self, config: &str) {
+    let dir = self.dir.join("config/kak-tree-sitter");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("config.toml"), config).unwrap();
+  }
+
+  /// Prepare the isolated environment, without starting a server.
+  fn isolated(name: &str) -> Self {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+
+    // not named kak, so that only KAK_BINARY can lead to it
+    let bin_dir = dir.join("bin");
+    fs::create_dir_all(&bin_dir).unwrap();
+    let fake_kak = bin_dir.join("fake-kak");
+    fs::write(&fake_kak, FAKE_KAK).unwrap();
+    fs::set_permissions(&fake_kak, fs::Permissions::from_mode(0o755)).unwrap();
+
+    common::install_rust(&dir.join("data"));
+
+    for sub in ["config", "isolated", "runtime"] {
+      fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+
+    // the runtime directory of the server lives next to its socket, not in the default one
+    let socket = dir.join("isolated/kts.sock");
+    let kak_stdin = dir.join("kak-stdin");
+
+    Self {
+      dir,
+      socket,
+      kak_stdin,
+      fake_kak,
+      child: None,
+    }
+  }
+
+  /// `kak-tree-sitter` command running in the isolated environment.
+  fn command(&self) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_kak-tree-sitter"));
+    cmd
+      .env("KAK_TREE_SITTER_SOCKET", &self.socket)
+      .env("KTS_TEST_KAK_STDIN", &self.kak_stdin)
+      .env("KAK_BINARY", &self.fake_kak)
+      .env("XDG_CONFIG_HOME", self.dir.join("config"))
+      .env("XDG_DATA_HOME", self.dir.join("data"))
+      .env("XDG_RUNTIME_DIR", self.dir.join("runtime"));
+    cmd
+  }
+
+  fn send(&self, req: &UnixRequest) {
+    let mut stream = UnixStream::connect(&self.socket).unwrap();
+    stream.write_all(req.to_json().as_bytes()).unwrap();
+  }
+
+  /// Send a request to the UNIX socket and return the answer of the server, if any.
+  ///
+  /// Only status requests and rejected requests are answered; the effects of the others show in the commands sent to
+  /// Kakoune (see [`TestServer::wait_kak_cmd`]).
+  fn request(&self, req: &UnixRequest) -> Option<UnixResponse> {
+    let mut stream = UnixStream::connect(&self.socket).unwrap();
+    stream.write_all(req.to_json().as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    (!resp.is_empty()).then(|| serde_json::from_str(&resp).unwrap())
+  }
+
+  fn runtime_dir(&self) -> PathBuf {
+    self.socket.with_extension("sock.d")
+  }
+
+  fn session_fifo(&self, kind: &str) -> PathBuf {
+    self.runtime_dir().join(kind).join(SESSION)
+  }
+
+  fn register_session(&self) {
+    self.send(&UnixRequest::register_session(
+      SESSION,
+      Some(CLIENT.to_owned()),
+    ));
+    wait_for("the session FIFOs", || {
+      self.session_fifo("commands").exists() && self.session_fifo("buffers").exists()
+    });
+  }
+
+  /// Send a request with the content of a buffer, the same way Kakoune does.
+  fn send_with_buffer(&self, req: &Request, buf: impl AsRef<[u8]>) {
+    fs::write(self.session_fifo("commands"), req.to_json()).unwrap();
+
+    // leave some time to the server to read the command before streaming the buffer
+    sleep(Duration::from_millis(200));
+    fs::write(self.session_fifo("buffers"), buf).unwrap();
+  }
+
+  /// Wait for the server to send a command to Kakoune containing `needle`, and return it.
+  fn wait_kak_cmd(&self, needle: &str) -> String {
+    let mut found = None;
+    wait_for(needle, || {
+      let recorded = fs::read_to_string(&self.kak_stdin).unwrap_or_default();
+
+      // the last record might still be being written
+      found = recorded
+        .rsplit_once(RECORD_SEPARATOR)
+        .and_then(|(complete, _)| {
+          complete
+            .split(RECORD_SEPARATOR)
+            .find(|cmd| cmd.contains(needle))
+        })
+        .map(str::to_owned);
+      found.is_some()
+    });
+
+    found.unwrap()
+  }
+}
+
+impl Drop for TestServer {
+  fn drop(&mut self) {
+    // the server might not be running if the test failed early
+    let _ = UnixStream::connect(&self.socket).and_then(|mut stream| {
+      stream.write_all(
+        serde_json::to_string(&UnixRequest::Shutdown)
+          .unwrap()
+          .as_bytes(),
+      )
+    });
+
+    let Some(child) = &mut self.child else {
+      return;
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+      if let Ok(Some(_)) = child.try_wait() {
+        return;
+      }
+
+      sleep(Duration::from_millis(50));
+    }
+
+    let _ = child.kill();
+  }
+}
+
+fn wait_for(what: &str, mut pred: impl FnMut() -> bool) {
+  let deadline = Instant::now() + Duration::from_secs(10);
+
+  while !pred() {
+    assert!(Instant::now() < deadline, "timed out waiting for {what}");
+    sleep(Duration::from_millis(50));
+  }
+}
+
+#[test]
+fn highlight() {
+  let server = TestServer::start("highlight");
+  server.register_session();
+
+  server.send_with_buffer(
+    &Request::highlight(CLIENT, "/tmp/highlight.rs", "rust", 1),
+    include_str!("fixtures/highlight.rs"),
+  );
+
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  common::assert_golden("highlight.rs.kak", &cmd);
+}
+
+#[test]
+fn highlight_invalid_utf8() {
+  let server = TestServer::start("highlight-invalid-utf8");
+  server.register_session();
+
+  // a latin-1 é; what follows it on the line keeps its position
+  server.send_with_buffer(
+    &Request::highlight(CLIENT, "/tmp/latin1.rs", "rust", 1),
+    b"fn caf() { \"\xe9\"; } fn main() {}\n",
+  );
+
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  assert!(cmd.contains("1.22,1.25|ts_function"), "{cmd}");
+}
+
+#[test]
+fn highlight_batch() {
+  let server = TestServer::start("highlight-batch");
+  server.register_session();
+
+  let req = Request::highlight_batch(
+    CLIENT,
+    [
+      HighlightItem::new("/tmp/highlight.rs", "rust", 1),
+      HighlightItem::new("/tmp/notes.txt", "unsupported", 1),
+    ],
+  );
+
+  // each buffer is written separately, as Kakoune does; the server must wait for all of them
+  fs::write(server.session_fifo("commands"), req.to_json()).unwrap();
+  sleep(Duration::from_millis(200));
+  for content in [include_str!("fixtures/highlight.rs"), "some notes\n"] {
+    fs::write(server.session_fifo("buffers"), content).unwrap();
+    fs::write(server.session_fifo("buffers"), BATCH_DELIMITER).unwrap();
+    sleep(Duration::from_millis(100));
+  }
+
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  assert!(cmd.contains("evaluate-commands -buffer ''/tmp/highlight.rs''"));
+  assert!(!cmd.contains("evaluate-commands -buffer ''/tmp/notes.txt''"));
+  assert!(cmd.contains("cannot highlight buffer /tmp/notes.txt"));
+}
+
+#[test]
+fn tree_without_queries() {
+  let server = TestServer::start("tree-without-queries");
+  server.register_session();
+
+  // the grammar alone is enough for features that only need the tree
+  fs::remove_dir_all(server.dir.join("data/kak-tree-sitter/queries/rust")).unwrap();
+  server.send(&UnixRequest::Reload);
+  sleep(Duration::from_millis(200));
+
+  server.send_with_buffer(
+    &Request::dump_tree(CLIENT, "/tmp/highlight.rs", "rust"),
+    include_str!("fixtures/highlight.rs"),
+  );
+
+  server.wait_kak_cmd("(source_file (line_comment) (function_item");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watch_queries() {
+  let server = TestServer::isolated("watch-queries").run_with(&["--watch-queries"]);
+  server.register_session();
+
+  // saving a query file is enough for the next request to use it
+  fs::write(
+    server
+      .dir
+      .join("data/kak-tree-sitter/queries/rust/highlights.scm"),
+    "(line_comment) @comment\n",
+  )
+  .unwrap();
+  sleep(Duration::from_millis(1000));
+
+  server.send_with_buffer(
+    &Request::highlight(CLIENT, "/tmp/highlight.rs", "rust", 1),
+    include_str!("fixtures/highlight.rs"),
+  );
+
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  assert!(cmd.contains("|ts_comment"), "{cmd}");
+  assert!(!cmd.contains("|ts_keyword"), "{cmd}");
+}
+
+#[test]
+fn indent() {
+  let server = TestServer::start("indent");
+  server.register_session();
+
+  fs::write(
+    server
+      .dir
+      .join("data/kak-tree-sitter/queries/rust/indents.scm"),
+    "(block) @indent\n\"}\" @outdent\n",
+  )
+  .unwrap();
+  server.send(&UnixRequest::Reload);
+  sleep(Duration::from_millis(200));
+
+  // the closing brace of the inner block is outdented back to the level of the outer block
+  server.send_with_buffer(
+    &Request::indent(CLIENT, "/tmp/indent.rs", "rust", 3, IndentStyle::new(8, 4)),
+    "fn main() {\nif true {\n}\n}\n",
+  );
+
+  let cmd = server.wait_kak_cmd("set-register dquote");
+  assert!(cmd.contains("set-register dquote ''''    ''''"), "{cmd}");
+}
+
+#[cfg(feature = "panic-request")]
+#[test]
+fn handler_panic() {
+  let server = TestServer::start("handler-panic");
+  server.register_session();
+
+  fs::write(
+    server
+      .dir
+      .join("data/kak-tree-sitter/queries/rust/indents.scm"),
+    "(block) @indent\n",
+  )
+  .unwrap();
+  server.send(&UnixRequest::Reload);
+  sleep(Duration::from_millis(200));
+
+  // the tree of the buffer is parsed before the handler panics
+  let buf = "fn main() {\nlet a = 1;\n}\n";
+  server.send_with_buffer(
+    &Request::indent(CLIENT, "/tmp/panic.rs", "rust", 2, IndentStyle::new(8, 4)),
+    buf,
+  );
+  server.wait_kak_cmd("set-register dquote");
+
+  fs::write(
+    server.session_fifo("commands"),
+    format!(r#"{{"type":"panic","client":"{CLIENT}"}}"#),
+  )
+  .unwrap();
+  let cmd = server.wait_kak_cmd("request handling panicked");
+  assert!(cmd.contains("info "), "{cmd}");
+
+  // the server survives, and keeps handling requests, even absurd ones
+  server.send_with_buffer(
+    &Request::indent(
+      CLIENT,
+      "/tmp/panic.rs",
+      "rust",
+      2,
+      IndentStyle::new(8, usize::MAX),
+    ),
+    buf,
+  );
+  server.wait_kak_cmd(&format!("set-register dquote ''''{}''''", " ".repeat(1024)));
+
+  server.send_with_buffer(
+    &Request::indent(CLIENT, "/tmp/panic.rs", "rust", 2, IndentStyle::new(8, 4)),
+    buf,
+  );
+  let cmd = server.wait_kak_cmd("set-register dquote");
+  assert!(cmd.contains("set-register dquote ''''    ''''"), "{cmd}");
+}
+
+#[test]
+fn malformed_request() {
+  let server = TestServer::start("malformed-request");
+  server.register_session();
+
+  // a typo in a hand-written request is reported to the client it names
+  fs::write(
+    server.session_fifo("commands"),
+    format!(r#"{{"type":"highlight","client":"{CLIENT}","buffer":"/tmp/a.rs","lang":"rust","timestamp":1,"sesion":"{SESSION}"}}"#),
+  )
+  .unwrap();
+  let cmd = server.wait_kak_cmd("malformed highlight request");
+  assert!(cmd.contains("unknown field `sesion`"), "{cmd}");
+  assert!(cmd.contains(&format!("-try-client {CLIENT}")), "{cmd}");
+}
+
+#[test]
+fn several_fifo_requests() {
+  let server = TestServer::start("several-fifo-requests");
+  server.register_session();
+
+  // requests written at once are all handled, including the ones following a request that cannot be parsed
+  let paused = Request::set_enabled(CLIENT, false).to_json();
+  let resumed = Request::set_enabled(CLIENT, true).to_json();
+  let malformed = format!(r#"{{"type":"set_enabled","client":"{CLIENT}","enabld":true}}"#);
+  fs::write(
+    server.session_fifo("commands"),
+    format!("{paused}\n{malformed}{resumed}"),
+  )
+  .unwrap();
+  server.wait_kak_cmd("highlighting paused");
+  let cmd = server.wait_kak_cmd("malformed set_enabled request");
+  assert!(cmd.contains("missing field `enabled`"), "{cmd}");
+  server.wait_kak_cmd("highlighting resumed");
+
+  // there is no telling where the next request starts after something that is not JSON, so the rest is dropped…
+  let enable = format!(r#"{{"type":"try_enable_highlight","lang":"rust","client":"{CLIENT}"}}"#);
+  fs::write(
+    server.session_fifo("commands"),
+    format!("{enable} not json {paused}"),
+  )
+  .unwrap();
+  server.wait_kak_cmd("kak-tree-sitter-highlight-enable");
+
+  // … but the FIFO still works
+  fs::write(server.session_fifo("commands"), &paused).unwrap();
+  wait_for("highlighting to be paused again", || {
+    let recorded = fs::read_to_string(&server.kak_stdin).unwrap_or_default();
+    recorded.matches("highlighting paused").count() == 2
+  });
+}
+
+#[test]
+fn several_buffers_at_once() {
+  let server = TestServer::start("several-buffers-at-once");
+  server.register_session();
+
+  // both requests are read at once, and so are both buffers; each request must get its own buffer
+  let code = Request::highlight(CLIENT, "/tmp/code.rs", "rust", 1).to_json();
+  let comment = Request::highlight(CLIENT, "/tmp/comment.rs", "rust", 1).to_json();
+  fs::write(
+    server.session_fifo("commands"),
+    format!("{code}\n{comment}"),
+  )
+  .unwrap();
+  sleep(Duration::from_millis(200));
+  fs::write(
+    server.session_fifo("buffers"),
+    format!("fn main() {{}}\n{BATCH_DELIMITER}// notes\n{BATCH_DELIMITER}"),
+  )
+  .unwrap();
+
+  // both responses might be batched in the same command, each one on its own line along with its ranges
+  let cmd = server.wait_kak_cmd("|ts_keyword");
+  let line = cmd
+    .lines()
+    .find(|line| line.contains("|ts_keyword"))
+    .unwrap();
+  assert!(!line.contains("|ts_comment"), "{cmd}");
+  let cmd = server.wait_kak_cmd("|ts_comment");
+  let line = cmd
+    .lines()
+    .find(|line| line.contains("|ts_comment"))
+    .unwrap();
+  assert!(!line.contains("|ts_keyword"), "{cmd}");
+}
+
+#[test]
+fn highlight_from_fd() {
+  let server = TestServer::start("highlight-from-fd");
+
+  // the buffer is piped to the request command, whose standard input is passed to the server
+  let highlight = |args: &[&str]| {
+    let mut client = server
+      .command()
+      .args([
+        "-v",
+        "request",
+        "highlight",
+        "--session",
+        SESSION,
+        "--client",
+        CLIENT,
+      ])
+      .args([
+        "--buffer",
+        "/tmp/fd.rs",
+        "--lang",
+        "rust",
+        "--timestamp",
+        "1",
+      ])
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .unwrap();
+    client
+      .stdin
+      .take()
+      .unwrap()
+      .write_all(include_str!("fixtures/highlight.rs").as_bytes())
+      .unwrap();
+    client.wait_with_output().unwrap()
+  };
+
+  // sessions must opt in
+  server.register_session();
+  let output = highlight(&[]);
+  assert!(!output.status.success());
+  let logs = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+  assert!(
+    logs.contains("did not register with capability fd_passing"),
+    "{logs}"
+  );
+
+  server.send(&UnixRequest::RegisterSession {
+    name: SESSION.to_owned(),
+    client: Some(CLIENT.to_owned()),
+    capabilities: vec![Capability::FdPassing],
+  });
+  sleep(Duration::from_millis(200));
+  let output = highlight(&[]);
+  assert!(output.status.success(), "{output:?}");
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  assert!(cmd.contains("|ts_function_macro"), "{cmd}");
+
+  // the buffer did not change, but its tree is not reused
+  let output = highlight(&["--force"]);
+  assert!(output.status.success(), "{output:?}");
+  let paths = ServerPaths::new(Some(server.socket.clone()));
+  wait_for("the buffer to be parsed again", || {
+    matches!(
+      Server::request_status(&paths, Some(Duration::from_secs(5))),
+      Ok(UnixResponse::Status { parse_metrics, .. }) if parse_metrics["rust"].parses == 2
+    )
+  });
+}
+
+#[test]
+fn slow_fd_writer() {
+  let server = TestServer::isolated("slow-fd-writer");
+  server.configure("[server]\nrequest_timeout_ms = 0\n");
+  let server = server.run();
+  server.send(&UnixRequest::RegisterSession {
+    name: SESSION.to_owned(),
+    client: Some(CLIENT.to_owned()),
+    capabilities: vec![Capability::FdPassing],
+  });
+  sleep(Duration::from_millis(200));
+
+  let mut client = server
+    .command()
+    .args([
+      "request",
+      "highlight",
+      "--session",
+      SESSION,
+      "--client",
+      CLIENT,
+    ])
+    .args([
+      "--buffer",
+      "/tmp/slow.rs",
+      "--lang",
+      "rust",
+      "--timestamp",
+      "1",
+    ])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+  let mut stdin = client.stdin.take().unwrap();
+  stdin.write_all(b"fn main() {\n").unwrap();
+  sleep(Duration::from_millis(200));
+
+  // the buffer is not entirely written yet, but the server still answers other requests
+  let paths = ServerPaths::new(Some(server.socket.clone()));
+  let status = Server::request_status(&paths, Some(Duration::from_secs(2)));
+  assert!(
+    matches!(status, Ok(UnixResponse::Status { .. })),
+    "{status:?}"
+  );
+
+  stdin.write_all(b"  println!(\"hello\");\n}\n").unwrap();
+  drop(stdin);
+  client.wait().unwrap();
+  let cmd = server.wait_kak_cmd("kts_highlighter_ranges");
+  assert!(cmd.contains("|ts_function_macro"), "{cmd}");
+}
+
+#[test]
+fn rejected_requests() {
+  let server = TestServer::start("rejected-requests");
+
+  assert!(matches!(
+    server.request(&UnixRequest::Status),
+    Some(UnixResponse::Status { .. })
+  ));
+  assert_eq!(
+    server.request(&UnixRequest::register_session(SESSION, None)),
+    None
+  );
+
+  // failing to handle a request is reported back to the client, not only its malformed requests
+  let highlight = UnixRequest::Highlight {
+    session: SESSION.to_owned(),
+    client: CLIENT.to_owned(),
+    buffer: "/tmp/a.rs".to_owned(),
+    lang: "rust".to_owned(),
+    timestamp: 1,
+    force: false,
+  };
+  assert_eq!(
+    server.request(&highlight),
+    Some(UnixResponse::Error {
+      reason: "no file descriptor passed with the request".to_owned(),
+      exit_code: exit_code::INVALID_REQUEST,
+    })
+  );
+}
+
+#[test]
+fn protocol_version() {
+  let server = TestServer::start("protocol-version");
+
+  let request = |json: &str| {
+    let mut stream = UnixStream::connect(&server.socket).unwrap();
+    stream.write_all(json.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    (!resp.is_empty()).then(|| serde_json::from_str::<UnixResponse>(&resp).unwrap())
+  };
+
+  // requests of another major version are rejected with a clear message
+  assert_eq!(
+    request(r#"{"type":"session_exit","name":"s","version":{"major":99,"minor":0}}"#),
+    Some(UnixResponse::Error {
+      reason: format!(
+        "the client speaks protocol version 99.0, but the server speaks version {PROTOCOL_VERSION}; restart the \
+         server so that both run the same version of kak-tree-sitter"
+      ),
+      exit_code: exit_code::INVALID_REQUEST,
+    })
+  );
+
+  // but anyone can ask which version the server speaks; newer minor versions are fine
+  assert_eq!(
+    request(r#"{"type":"status","version":{"major":99,"minor":0}}"#),
+    Some(UnixResponse::Status {
+      protocol_version: PROTOCOL_VERSION,
+      parse_metrics: Default::default(),
+    })
+  );
+  let newer = format!(
+    r#"{{"type":"register_session","name":"{SESSION}","client":null,"version":{{"major":{},"minor":99}}}}"#,
+    PROTOCOL_VERSION.major
+  );
+  assert_eq!(request(&newer), None);
+  wait_for("the session FIFOs", || {
+    server.session_fifo("commands").exists()
+  });
+}
+
+#[test]
+fn request_ids() {
+  let server = TestServer::start("request-ids");
+
+  let request = |json: &str| {
+    let mut stream = UnixStream::connect(&server.socket).unwrap();
+    stream.write_all(json.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    serde_json::from_str::<serde_json::Value>(&resp).unwrap()
+  };
+
+  // answers echo the id of their request, even when it is rejected
+  let resp = request(&UnixRequest::Status.to_json_with_id("status-1"));
+  assert_eq!(resp["type"], "status");
+  assert_eq!(resp["id"], "status-1");
+
+  let resp = request(r#"{"type":"session_exit","nam":"s","id":"exit-1"}"#);
+  assert_eq!(resp["type"], "error");
+  assert_eq!(resp["id"], "exit-1");
+
+  // and do not have one otherwise
+  let resp = request(&UnixRequest::Status.to_json());
+  assert_eq!(resp.get("id"), None);
+}
+
+#[test]
+fn status() {
+  let server = TestServer::start("status");
+  server.register_session();
+
+  // the second request is about the same content, so the tree is reused
+  let req = Request::highlight(CLIENT, "/tmp/highlight.rs", "rust", 1);
+  for _ in 0..2 {
+    server.send_with_buffer(&req, include_str!("fixtures/highlight.rs"));
+    sleep(Duration::from_millis(200));
+  }
+  server.wait_kak_cmd("kts_highlighter_ranges");
+
+  let paths = ServerPaths::new(Some(server.socket.clone()));
+  let UnixResponse::Status {
+    protocol_version,
+    parse_metrics,
+  } = Server::request_status(&paths, Some(Duration::from_secs(5))).unwrap()
+  else {
+    panic!("unexpected response to a status request");
+  };
+  assert_eq!(protocol_version, PROTOCOL_VERSION);
+  let rust = &parse_metrics["rust"];
+  assert_eq!(rust.parses, 1);
+  assert_eq!(rust.cache_hits, 1);
+  assert_eq!(
+    rust.bytes,
+    include_str!("fixtures/highlight.rs").len() as u64
+  );
+}
+
+#[test]
+fn private_runtime_files() {
+  let server = TestServer::isolated("private-runtime-files");
+
+  // a runtime directory left readable by everyone is made private again
+  let runtime_dir = server.runtime_dir();
+  fs::create_dir_all(&runtime_dir).unwrap();
+  fs::set_permissions(&runtime_dir, fs::Permissions::from_mode(0o755)).unwrap();
+  let socket_dir = server.socket.parent().unwrap().to_owned();
+  fs::set_permissions(&socket_dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let server = server.run();
+  server.register_session();
+
+  let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+  assert_eq!(mode(&runtime_dir), 0o700);
+  assert_eq!(mode(&server.socket), 0o600);
+
+  // the directory the socket was put in is not the runtime directory, and is left as is
+  assert_eq!(mode(&socket_dir), 0o755);
+  assert!(!socket_dir.join("commands").exists());
+
+  // nothing is written to the default runtime directory, which belongs to the default server
+  assert!(!server.dir.join("runtime/kak-tree-sitter").exists());
+}
+
+#[test]
+fn relative_socket() {
+  let server = TestServer::isolated("relative-socket");
+  let socket_dir = server.socket.parent().unwrap().to_owned();
+  fs::set_permissions(&socket_dir, fs::Permissions::from_mode(0o755)).unwrap();
+  let run = |args: &[&str]| {
+    server
+      .command()
+      .env("KAK_TREE_SITTER_SOCKET", "kts.sock")
+      .current_dir(&socket_dir)
+      .args(args)
+      .output()
+      .unwrap()
+  };
+
+  let output = run(&["server", "start", "--daemonize"]);
+  assert!(output.status.success(), "{output:?}");
+  wait_for("the server socket", || server.socket.exists());
+
+  // the daemon leaves the current directory, but still listens on the socket it was given
+  let output = run(&["server", "status"]);
+  assert!(output.status.success(), "{output:?}");
+  assert!(server.dir.join("isolated/kts.pid").exists());
+
+  let mode = fs::metadata(&socket_dir).unwrap().permissions().mode() & 0o777;
+  assert_eq!(mode, 0o755);
+}
+
+#[test]
+fn concurrent_starts() {
+  let mut server = TestServer::isolated("concurrent-starts");
+  let mut children: Vec<_> = (0..4)
+    .map(|_| {
+      server
+        .command()
+        .args(["server", "start"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap()
+    })
+    .collect();
+
+  // exactly one server starts; the other ones see it running, and exit successfully
+  let mut statuses = Vec::new();
+  wait_for("all servers but one to exit", || {
+    children.retain_mut(|child| match child.try_wait().unwrap() {
+      Some(status) => {
+        statuses.push(status);
+        false
+      }
+      None => true,
+    });
+    children.len() <= 1
+  });
+  assert_eq!(children.len(), 1, "{statuses:?}");
+  assert!(
+    statuses.iter().all(|status| status.success()),
+    "{statuses:?}"
+  );
+
+  server.child = children.pop();
+  server.register_session();
+}
+
+#[test]
+fn autostart() {
+  let server = TestServer::isolated("autostart");
+
+  // other requests fail, telling that the server is not running
+  let output = server
+    .command()
+    .args(["request", "reload"])
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(exit_code::SERVER_NOT_RUNNING));
+  assert!(!server.socket.exists());
+
+  // the status is only reported once, as an error
+  let output = server
+    .command()
+    .args(["server", "status"])
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(exit_code::SERVER_NOT_RUNNING));
+  assert!(output.stdout.is_empty(), "{output:?}");
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert_eq!(stderr.matches("not running").count(), 1, "{stderr}");
+
+  // registering a session without a running server starts one
+  let status = server
+    .command()
+    .args(["request", "register-session", "--session", SESSION])
+    .status()
+    .unwrap();
+  assert!(status.success());
+
+  server.wait_kak_cmd("kts_cmd_fifo_path");
+  assert!(server.socket.exists());
+}
+
+#[test]
+fn stuck_server() {
+  let server = TestServer::isolated("stuck-server");
+
+  // connections to a bound socket succeed, even if the server never gets to handle them
+  let listener = UnixListener::bind(&server.socket).unwrap();
+
+  let start = Instant::now();
+  let output = server
+    .command()
+    .args(["--timeout-ms", "200", "request", "reload"])
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(exit_code::CONNECTION_FAILED));
+  assert!(start.elapsed() < Duration::from_secs(2));
+
+  // the error is reported on stderr, even without logs
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert_eq!(
+    stderr,
+    "kak-tree-sitter: the server did not handle the request within 200ms; is it stuck?\n"
+  );
+
+  // without timeout, clients wait for as long as it takes; here, until the socket is closed
+  let start = Instant::now();
+  let closing = thread::spawn(move || {
+    sleep(Duration::from_millis(500));
+    drop(listener);
+  });
+  let output = server
+    .command()
+    .args(["--timeout-ms", "0", "request", "reload"])
+    .output()
+    .unwrap();
+  assert!(start.elapsed() >= Duration::from_millis(500));
+  closing.join().unwrap();
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(!stderr.contains("did not handle the request"), "{stderr}");
+}
+
+#[test]
+fn idle_shutdown() {
+  let server = TestServer::isolated("idle-shutdown");
+  server.configure("[server]\nidle_timeout_ms = 300\n");
+  let mut server = server.run();
+
+  // the server is not idle as long as it has sessions
+  server.register_session();
+  sleep(Duration::from_millis(600));
+  let child = server.child.as_mut().unwrap();
+  assert!(child.try_wait().unwrap().is_none());
+
+  server.send(&UnixRequest::SessionExit {
+    name: SESSION.to_owned(),
+  });
+  let child = server.child.as_mut().unwrap();
+  wait_for("the server to shut down", || {
+    child.try_wait().unwrap().is_some()
+  });
+}
+
+#[test]
+fn large_response() {
+  let server = TestServer::isolated("large-response");
+  server.configure("[server]\nmax_inline_response_bytes = 100\n");
+  let server = server.run();
+  server.register_session();
+
+  server.send_with_buffer(
+    &Request::highlight(CLIENT, "/tmp/highlight.rs", "rust", 1),
+    include_str!("fixtures/highlight.rs"),
+  );
+
+  // the highlights are sourced from a file, which Kakoune removes afterwards — but the stub does not
+  let responses = server.runtime_dir().join("responses");
+  let mut path = None;
+  wait_for("the highlights file", || {
+    path = fs::read_dir(&responses)
+      .into_iter()
+      .flatten()
+      .flatten()
+      .map(|dentry| dentry.path())
+      .find(|path| {
+        fs::read_to_string(path).map_or(false, |cmds| cmds.contains("kts_highlighter_ranges"))
+      });
+    path.is_some()
+  });
+  let path = path.unwrap();
+  server.wait_kak_cmd(&format!("source ''{}''", path.display()));
+
+  // files left behind are removed with their session
+  server.send(&UnixRequest::SessionExit {
+    name: SESSION.to_owned(),
+  });
+  wait_for("the response file to be removed", || !path.exists());
+}
+
+#[test]
+fn session_gone() {
+  let server = TestServer::isolated("session-gone");
+  server.configure("[server]\nmax_inline_response_bytes = 100000000\n");
+  let server = server.run();
+  server.register_session();
+
+  // Kakoune is gone: kak -p exits without reading its input
+  let stub = server.dir.join("bin/fake-kak.new");
+  fs::write(&stub, "#!/bin/sh\nexit 1\n").unwrap();
+  fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+  fs::rename(&stub, &server.fake_kak).unwrap();
+
+  // the highlights do not fit in the pipe to kak -p
+  let buf = "fn main() { let a = 1; }\n".repeat(10_000);
+  server.send_with_buffer(&Request::highlight(CLIENT, "/tmp/gone.rs", "rust", 1), buf);
+
+  // the session is recycled as if it had exited, and the server keeps running
+  wait_for("the session FIFOs to be removed", || {
+    !server.session_fifo("commands").exists() && !server.session_fifo("buffers").exists()
+  });
+  assert!(matches!(
+    server.request(&UnixRequest::Status),
+    Some(UnixResponse::Status { .. })
+  ));
+}
+
+#[test]
+fn request_from_stdin() {
+  let server = TestServer::start("request-from-stdin");
+
+  let mut client = server
+    .command()
+    .args(["--request", "-", "--session", SESSION])
+    .stdin(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let req = UnixRequest::register_session("overridden", Some(CLIENT.to_owned()));
+  client
+    .stdin
+    .take()
+    .unwrap()
+    .write_all(serde_json::to_string(&req).unwrap().as_bytes())
+    .unwrap();
+  assert!(client.wait().unwrap().success());
+
+  // the session passed on the command line still takes precedence
+  wait_for("the session FIFOs", || {
+    server.session_fifo("commands").exists() && server.session_fifo("buffers").exists()
+  });
+}
+
+#[test]
+fn request_from_file() {
+  let server = TestServer::start("request-from-file");
+
+  let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("request-from-file.json");
+  let req = UnixRequest::register_session(SESSION, Some(CLIENT.to_owned()));
+  fs::write(&path, serde_json::to_string(&req).unwrap()).unwrap();
+
+  let status = server
+    .command()
+    .arg("--request-file")
+    .arg(&path)
+    .status()
+    .unwrap();
+  assert!(status.success());
+  wait_for("the session FIFOs", || {
+    server.session_fifo("commands").exists() && server.session_fifo("buffers").exists()
+  });
+
+  // the file must be readable, and is not confused with an inline request
+  let output = server
+    .command()
+    .args(["--request-file", "/nonexistent/request.json"])
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(exit_code::INVALID_REQUEST));
+  assert!(String::from_utf8_lossy(&output.stderr)
+    .contains("cannot read request from /nonexistent/request.json"));
+
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn dump_captures() {
+  let server = TestServer::start("dump-captures");
+
+  let output = server
+    .command()
+    .args(["request", "dump-captures", "--lang", "rust"])
+    .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/highlight.rs"))
+    .output()
+    .unwrap();
+  assert!(output.status.success());
+
+  let captures: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+  let add = captures
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|capture| capture["name"] == "function")
+    .unwrap();
+  assert_eq!(
+    add,
+    &serde_json::json!({
+      "name": "function",
+      "bytes": [24, 27],
+      "start": { "line": 2, "col": 4 },
+      "end": { "line": 2, "col": 6 },
+      "face": "ts_function",
+    })
+  );
+
+  // an unknown language is an error, not an empty dump
+  let output = server
+    .command()
+    .args([
+      "request",
+      "dump-captures",
+      "--lang",
+      "cobol",
+      "/tmp/main.cob",
+    ])
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+}
+
+#[test]
+fn languages() {
+  let server = TestServer::isolated("languages");
+
+  let output = server
+    .command()
+    .args(["languages", "--json"])
+    .output()
+    .unwrap();
+  assert!(output.status.success());
+
+  let langs: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+  let lang = |name: &str| {
+    langs
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|lang| lang["name"] == name)
+      .unwrap()
+      .clone()
+  };
+
+  let rust = lang("rust");
+  assert_eq!(rust["grammar_loads"], true);
+  assert_eq!(rust["queries"], serde_json::json!(["highlights"]));
+
+  // configured, but not installed
+  let python = lang("python");
+  assert_eq!(python["grammar_loads"], false);
+  assert_eq!(python["queries"], serde_json::json!([]));
+}
+
+#[test]
+fn check_config() {
+  let server = TestServer::isolated("check-config");
+  let check = || server.command().arg("--check-config").output().unwrap();
+
+  let output = check();
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  assert!(output.status.success(), "{stdout}");
+  assert!(stdout.contains("rust: OK\n"), "{stdout}");
+  assert!(stdout.contains("python: not installed\n"), "{stdout}");
+
+  fs::write(
+    server
+      .dir
+      .join("data/kak-tree-sitter/queries/rust/indents.scm"),
+    "(block) @indent\n(no_such_node) @outdent\n",
+  )
+  .unwrap();
+
+  let output = check();
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  assert!(!output.status.success(), "{stdout}");
+  assert!(
+    stdout.contains("rust: error: invalid indents.scm query at line 2"),
+    "{stdout}"
+  );
+}
+
+#[test]
+fn request_limits() {
+  let server = TestServer::isolated("request-limits");
+  server.configure("[server]\nmax_request_size = 128\nrequest_timeout_ms = 200\n");
+  let server = server.run();
+
+  // the error is reported back to the client
+  let output = server
+    .command()
+    .args(["-v", "request", "register-session", "--session"])
+    .arg("s".repeat(128))
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+  let logs = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+  assert!(
+    logs.contains("request rejected by the server: request larger than 128 bytes"),
+    "{logs}"
+  );
+
+  // a client that does not send its request in time is dropped
+  let mut stream = UnixStream::connect(&server.socket).unwrap();
+  stream.write_all(b"{").unwrap();
+  let mut resp = String::new();
+  stream.read_to_string(&mut resp).unwrap();
+  assert_eq!(
+    serde_json::from_str::<UnixResponse>(&resp).unwrap(),
+    UnixResponse::Error {
+      reason: "request not received within 200ms".to_owned(),
+      exit_code: exit_code::INVALID_REQUEST,
+    }
+  );
+
+  // the timeout applies to the whole request, not to each read
+  let mut stream = UnixStream::connect(&server.socket).unwrap();
+  let mut writer = stream.try_clone().unwrap();
+  let start = Instant::now();
+  let drip = thread::spawn(move || {
+    for _ in 0..20 {
+      if writer.write_all(b" ").is_err() {
+        break;
+      }
+      sleep(Duration::from_millis(100));
+    }
+  });
+  let mut resp = String::new();
+  stream.read_to_string(&mut resp).unwrap();
+  assert!(start.elapsed() < Duration::from_secs(1), "{resp}");
+  assert!(resp.contains("request not received within 200ms"), "{resp}");
+  drop(stream);
+  drip.join().unwrap();
+
+  // the server is still serving other clients
+  server.register_session();
+}
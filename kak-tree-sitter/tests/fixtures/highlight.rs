@@ -0,0 +1,9 @@
+/// Add two numbers.
+fn add(a: i32, b: i32) -> i32 {
+  a + b
+}
+
+fn main() {
+  let message = "hello";
+  println!("{message}: {}", add(1, 2));
+}
@@ -0,0 +1,1107 @@
+//! In-process tests, handling requests with a [`Handler`] directly.
+
+use std::{fs, path::Path, sync::Once};
+
+use kak_tree_sitter::{
+  edit::TextEdit,
+  error::OhNo,
+  indent::IndentStyle,
+  request::BATCH_DELIMITER,
+  selection::Pos,
+  tree_sitter_state::{HighlightCapture, Injection, NodeInfo},
+  Handler, HighlightItem, Request, Response,
+};
+use kak_tree_sitter_config::{source::Source, CommentConfig, Config, LanguagesConfig, OnStale};
+
+mod common;
+
+/// Create a handler, with the Rust grammar installed.
+fn handler() -> Handler {
+  setup();
+
+  let config = Config::load_default_user().unwrap();
+  Handler::new(&config).unwrap()
+}
+
+/// Create a handler whose Rust queries also inject Rust in macro invocations.
+fn handler_with_injections() -> Handler {
+  setup();
+
+  let queries_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handler-injections");
+  fs::create_dir_all(&queries_dir).unwrap();
+  fs::write(
+    queries_dir.join("highlights.scm"),
+    tree_sitter_rust::HIGHLIGHT_QUERY,
+  )
+  .unwrap();
+  fs::write(
+    queries_dir.join("injections.scm"),
+    tree_sitter_rust::INJECTIONS_QUERY,
+  )
+  .unwrap();
+
+  let mut config = Config::load_default_user().unwrap();
+  if let Some(rust) = config.languages.language.get_mut("rust") {
+    rust.queries.source = Some(Source::local(queries_dir));
+  }
+  Handler::new(&config).unwrap()
+}
+
+/// Install the Rust grammar in an isolated environment, once per test binary.
+fn setup() {
+  static SETUP: Once = Once::new();
+
+  SETUP.call_once(|| {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handler");
+    let _ = fs::remove_dir_all(&dir);
+    common::install_rust(&dir.join("data"));
+    fs::create_dir_all(dir.join("config")).unwrap();
+
+    // every test of this binary uses the same environment, so altering it once is fine
+    std::env::set_var("XDG_CONFIG_HOME", dir.join("config"));
+    std::env::set_var("XDG_DATA_HOME", dir.join("data"));
+  });
+}
+
+#[test]
+fn highlight() {
+  let mut handler = handler();
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::highlight("client0", "/tmp/highlight.rs", "rust", 1),
+      include_str!("fixtures/highlight.rs"),
+    )
+    .unwrap();
+
+  // the server sends the very same command to Kakoune
+  common::assert_golden(
+    "highlight.rs.kak",
+    &resp.to_kak_cmd(Some("client0")).unwrap(),
+  );
+}
+
+#[test]
+fn highlight_to_file() {
+  let mut handler = handler();
+  let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("highlight-to-file.kak");
+  let _ = fs::remove_file(&output);
+
+  let req = Request::highlight("client0", "/tmp/to-file.rs", "rust", 1).with_output(&output);
+  let resp = handler
+    .handle_request("kts-test", &req, include_str!("fixtures/highlight.rs"))
+    .unwrap();
+  assert_eq!(
+    resp,
+    Response::status(format!(
+      "highlights of /tmp/to-file.rs written to {}",
+      output.display()
+    ))
+  );
+
+  // the script sets the same highlights as the ones sent to the session
+  let script = fs::read_to_string(&output).unwrap();
+  assert!(script.starts_with("evaluate-commands -buffer '/tmp/to-file.rs' "));
+  let Response::Highlights { ranges, .. } = handler
+    .handle_request(
+      "kts-test",
+      &Request::highlight("client0", "/tmp/to-file.rs", "rust", 1),
+      include_str!("fixtures/highlight.rs"),
+    )
+    .unwrap()
+  else {
+    panic!("no highlights");
+  };
+  assert!(ranges
+    .iter()
+    .all(|range| script.contains(&range.to_kak_range_str())));
+}
+
+#[test]
+fn dump_captures() {
+  let mut handler = handler();
+  let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/highlight.rs");
+  let captures = handler.dump_captures("rust", &path).unwrap();
+
+  let add = captures
+    .iter()
+    .find(|capture| capture.name == "function")
+    .unwrap();
+  assert_eq!(
+    add,
+    &HighlightCapture {
+      name: "function".to_owned(),
+      bytes: (24, 27),
+      start: Pos { line: 2, col: 4 },
+      end: Pos { line: 2, col: 6 },
+      face: Some("ts_function".to_owned()),
+    }
+  );
+  assert!(captures
+    .iter()
+    .any(|capture| capture.name == "function.macro"
+      && capture.face.as_deref() == Some("ts_function_macro")));
+
+  // dumping captures is a debugging aid, and is not accounted in the parse metrics
+  assert!(handler.parse_metrics().is_empty());
+
+  assert!(matches!(
+    handler.dump_captures("cobol", &path),
+    Err(OhNo::UnsupportedLanguage { .. })
+  ));
+}
+
+#[test]
+fn highlight_empty_buffer() {
+  let mut handler = handler();
+  handler
+    .handle_request(
+      "kts-test",
+      &Request::highlight("client0", "/tmp/empty.rs", "rust", 1),
+      include_str!("fixtures/highlight.rs"),
+    )
+    .unwrap();
+
+  // the buffer was emptied; previous highlights must be removed
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::highlight("client0", "/tmp/empty.rs", "rust", 2),
+      "",
+    )
+    .unwrap();
+
+  assert_eq!(
+    resp,
+    Response::Highlights {
+      timestamp: 2,
+      ranges: Vec::new(),
+      activated: false,
+    }
+  );
+}
+
+#[test]
+fn stale_highlights() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let highlight = |handler: &mut Handler, req: Request, buf: &str| {
+    handler.handle_request("kts-test", &req, buf).unwrap()
+  };
+
+  highlight(
+    &mut handler,
+    Request::highlight("client0", "/tmp/stale.rs", "rust", 2),
+    buf,
+  );
+
+  // the request about the older buffer was received last; its highlights would replace the newer ones
+  let resp = highlight(
+    &mut handler,
+    Request::highlight("client0", "/tmp/stale.rs", "rust", 1),
+    "fn main() {}\n",
+  );
+  assert_eq!(
+    resp,
+    Response::StaleHighlights {
+      timestamp: 1,
+      current: 2,
+    }
+  );
+  assert_eq!(resp.to_kak_cmd(Some("client0")), None);
+
+  // the same timestamp is asked again when the buffer is idle
+  let resp = highlight(
+    &mut handler,
+    Request::highlight("client0", "/tmp/stale.rs", "rust", 2),
+    buf,
+  );
+  assert!(matches!(resp, Response::Highlights { timestamp: 2, .. }));
+
+  // forcing a reparse starts from scratch; e.g. the buffer was deleted and created again
+  let resp = highlight(
+    &mut handler,
+    Request::highlight("client0", "/tmp/stale.rs", "rust", 1).with_force(),
+    buf,
+  );
+  assert!(matches!(resp, Response::Highlights { timestamp: 1, .. }));
+
+  // as does a reparse request
+  let resp = highlight(
+    &mut handler,
+    Request::reparse("client0", "/tmp/stale.rs", "rust", 1),
+    buf,
+  );
+  assert!(matches!(resp, Response::Highlights { timestamp: 1, .. }));
+}
+
+#[test]
+fn reparse_stale_highlights() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config.highlight.on_stale = OnStale::Reparse;
+  let mut handler = Handler::new(&config).unwrap();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  let req = Request::highlight("client0", "/tmp/stale.rs", "rust", 2);
+  handler.handle_request("kts-test", &req, buf).unwrap();
+
+  // the current content of the buffer is asked for instead of dropping the request
+  let req = Request::highlight("client0", "/tmp/stale.rs", "rust", 1);
+  let resp = handler
+    .handle_request("kts-test", &req, "fn main() {}\n")
+    .unwrap();
+  assert_eq!(
+    resp,
+    Response::RefreshBuffer {
+      buffer: "/tmp/stale.rs".to_owned(),
+    }
+  );
+  assert_eq!(
+    resp.to_kak_cmd(Some("client0")).unwrap(),
+    "eval -no-hooks -try-client client0 'evaluate-commands -buffer ''/tmp/stale.rs'' ''kak-tree-sitter-req-refresh-buffer ''''client0'''''''"
+  );
+}
+
+#[test]
+fn pause_highlighting() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let highlight = |handler: &mut Handler, timestamp| {
+    handler
+      .handle_request(
+        "kts-test",
+        &Request::highlight("client0", "/tmp/paused.rs", "rust", timestamp),
+        buf,
+      )
+      .unwrap()
+  };
+
+  let Response::Highlights { ranges, .. } = highlight(&mut handler, 1) else {
+    panic!("the buffer should be highlighted");
+  };
+
+  let resp = handler
+    .handle_request("kts-test", &Request::set_enabled("client0", false), "")
+    .unwrap();
+  assert_eq!(resp, Response::HighlightingEnabled { enabled: false });
+
+  // paused sessions stay paused across reloads
+  handler
+    .reload(&Config::load_default_user().unwrap())
+    .unwrap();
+  let resp = highlight(&mut handler, 2);
+  assert_eq!(resp, Response::HighlightsPaused);
+  assert_eq!(resp.to_kak_cmd(Some("client0")), None);
+
+  // other sessions are still highlighted
+  let resp = handler
+    .handle_request(
+      "kts-other",
+      &Request::highlight("client0", "/tmp/paused.rs", "rust", 1),
+      buf,
+    )
+    .unwrap();
+  assert!(matches!(resp, Response::Highlights { .. }));
+
+  handler
+    .handle_request("kts-test", &Request::set_enabled("client0", true), "")
+    .unwrap();
+
+  // the buffer was highlighted before pausing, so its status is not reported again
+  assert_eq!(
+    highlight(&mut handler, 2),
+    Response::Highlights {
+      timestamp: 2,
+      ranges,
+      activated: false,
+    }
+  );
+}
+
+#[test]
+fn node_info() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  // the a parameter of add
+  let req = Request::node_info("client0", "/tmp/node.rs", "rust", Pos { line: 2, col: 8 });
+  let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert_eq!(
+    resp,
+    Response::NodeInfo {
+      info: NodeInfo {
+        kind: "identifier".to_owned(),
+        bytes: (28, 29),
+        start: Pos { line: 2, col: 8 },
+        end: Pos { line: 2, col: 8 },
+        ancestors: vec![
+          "parameter".to_owned(),
+          "parameters".to_owned(),
+          "function_item".to_owned(),
+          "source_file".to_owned(),
+        ],
+      }
+    }
+  );
+  assert_eq!(
+    resp.to_kak_cmd(Some("client0")).unwrap(),
+    "eval -no-hooks -try-client client0 'info -title ''identifier'' ''source_file > function_item > parameters > \
+     parameter > identifier\n2.8,2.8 (bytes 28..29)'''"
+  );
+
+  // asking about another node of the same buffer reuses its tree
+  let req = Request::node_info("client0", "/tmp/node.rs", "rust", Pos { line: 2, col: 4 });
+  let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert!(
+    matches!(&resp, Response::NodeInfo { info } if info.kind == "identifier" && info.bytes == (24, 27)),
+    "{resp:?}"
+  );
+  assert_eq!(handler.parse_metrics()["rust"].cache_hits, 1);
+}
+
+#[test]
+fn extra_highlights() {
+  setup();
+  let extra = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handler-extra-highlights.scm");
+  fs::write(&extra, "(function_item name: (identifier) @keyword)\n").unwrap();
+
+  let mut config = Config::load_default_user().unwrap();
+  if let Some(rust) = config.languages.language.get_mut("rust") {
+    rust.queries.extra_highlights = vec![extra];
+  }
+  let mut handler = Handler::new(&config).unwrap();
+
+  // the extra capture takes precedence over the function one of the shipped query
+  let req = Request::highlight("client0", "/tmp/extra.rs", "rust", 1);
+  let Response::Highlights { ranges, .. } = handler
+    .handle_request("kts-test", &req, "fn main() {}\n")
+    .unwrap()
+  else {
+    panic!("expected highlights");
+  };
+  let ranges: Vec<_> = ranges
+    .iter()
+    .map(|range| range.to_kak_range_str())
+    .collect();
+  assert!(
+    ranges.contains(&"1.4,1.7|ts_keyword".to_owned()),
+    "{ranges:?}"
+  );
+}
+
+#[test]
+fn face_prefix() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config.highlight.face_prefix = "tree_sitter_".to_owned();
+  let mut handler = Handler::new(&config).unwrap();
+
+  let req = Request::highlight("client0", "/tmp/prefix.rs", "rust", 1);
+  let Response::Highlights { ranges, .. } = handler
+    .handle_request("kts-test", &req, "fn main() {\n")
+    .unwrap()
+  else {
+    panic!("expected highlights");
+  };
+  assert!(!ranges.is_empty());
+  assert!(ranges
+    .iter()
+    .all(|range| range.to_kak_range_str().contains("|tree_sitter_")));
+
+  let req = Request::syntax_errors("client0", "/tmp/prefix.rs", "rust", 1);
+  let resp = handler
+    .handle_request("kts-test", &req, "fn main() {\n")
+    .unwrap();
+  assert!(
+    matches!(resp, Response::SyntaxErrors { face, .. } if face == "tree_sitter_syntax_error")
+  );
+}
+
+#[test]
+fn syntax_error_overlay() {
+  setup();
+  let config = Config::load_default_user().unwrap();
+  let mut handler = Handler::new(&config).unwrap();
+  let buf = "fn main() {\n  let x = ;\n}\n";
+
+  let mut error_ranges = |req: &Request| {
+    let Response::Highlights { ranges, .. } = handler.handle_request("kts-test", req, buf).unwrap()
+    else {
+      panic!("expected highlights");
+    };
+    ranges
+      .iter()
+      .map(|range| range.to_kak_range_str())
+      .filter(|range| range.ends_with("|ts_syntax_error"))
+      .collect::<Vec<_>>()
+  };
+
+  // the overlay is only there when asked for, even if the buffer did not change in between
+  let req = Request::highlight("client0", "/tmp/overlay.rs", "rust", 1);
+  assert_eq!(error_ranges(&req), Vec::<String>::new());
+  let errors = error_ranges(&req.clone().with_syntax_errors());
+  assert!(!errors.is_empty());
+  assert!(
+    errors.iter().all(|range| range.starts_with("2.")),
+    "{errors:?}"
+  );
+  assert_eq!(error_ranges(&req), Vec::<String>::new());
+}
+
+#[test]
+fn indent_unit() {
+  setup();
+  let queries_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handler-indent");
+  fs::create_dir_all(queries_dir.join("rust")).unwrap();
+  fs::write(queries_dir.join("rust/indents.scm"), "(block) @indent\n").unwrap();
+
+  let mut config = Config::load_default_user().unwrap();
+  config.languages.queries_dirs = vec![queries_dir];
+  let rust = config.languages.language.get_mut("rust").unwrap();
+  rust.indent_width = Some(2);
+
+  let buf = "fn main() {\nlet a = 1;\n}\n";
+  let req = Request::indent(
+    "client0",
+    "/tmp/indent.rs",
+    "rust",
+    2,
+    IndentStyle::new(8, 4),
+  );
+  let indent = |config: &Config| {
+    let mut handler = Handler::new(config).unwrap();
+    match handler.handle_request("kts-test", &req, buf).unwrap() {
+      Response::Indent { indent, .. } => indent,
+      resp => panic!("expected an indent: {resp:?}"),
+    }
+  };
+
+  // the language overrides the indent width of the buffer
+  assert_eq!(indent(&config), "  ");
+
+  // indenting with tabs where indentation is significant uses one tab per level
+  let rust = config.languages.language.get_mut("rust").unwrap();
+  rust.use_tabs = Some(true);
+  rust.significant_indent = true;
+  assert_eq!(indent(&config), "\t");
+}
+
+#[test]
+fn disabled_subgroup() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config
+    .highlight
+    .disabled
+    .insert("punctuation.bracket".to_owned());
+  assert!(!config.highlight.is_disabled("punctuation"));
+  let mut handler = Handler::new(&config).unwrap();
+
+  let req = Request::highlight("client0", "/tmp/disabled.rs", "rust", 1);
+  let Response::Highlights { ranges, .. } = handler
+    .handle_request("kts-test", &req, "fn main() { a; }\n")
+    .unwrap()
+  else {
+    panic!("expected highlights");
+  };
+  let ranges: Vec<_> = ranges
+    .iter()
+    .map(|range| range.to_kak_range_str())
+    .collect();
+
+  // brackets are not highlighted as punctuation, their parent group, while delimiters still are
+  assert!(
+    !ranges
+      .iter()
+      .any(|range| range.ends_with("|ts_punctuation")),
+    "{ranges:?}"
+  );
+  assert!(
+    ranges.contains(&"1.14,1.14|ts_punctuation_delimiter".to_owned()),
+    "{ranges:?}"
+  );
+}
+
+#[test]
+fn drop_session() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let req = Request::highlight("client0", "/tmp/drop.rs", "rust", 1);
+  handler.handle_request("kts-test", &req, buf).unwrap();
+  handler.handle_request("kts-other", &req, buf).unwrap();
+  handler
+    .handle_request("kts-test", &Request::set_enabled("client0", false), "")
+    .unwrap();
+
+  // nothing is left of an exited session: its trees are parsed again and highlighting is not paused anymore…
+  handler.drop_session("kts-test");
+  let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert!(
+    matches!(
+      resp,
+      Response::Highlights {
+        activated: true,
+        ..
+      }
+    ),
+    "{resp:?}"
+  );
+  assert_eq!(handler.parse_metrics()["rust"].parses, 3);
+
+  // … while other sessions are left untouched
+  let req = Request::highlight("client0", "/tmp/drop.rs", "rust", 2);
+  let resp = handler.handle_request("kts-other", &req, buf).unwrap();
+  assert!(
+    matches!(
+      resp,
+      Response::Highlights {
+        activated: false,
+        ..
+      }
+    ),
+    "{resp:?}"
+  );
+  assert_eq!(handler.parse_metrics()["rust"].cache_hits, 1);
+}
+
+#[test]
+fn language_aliases() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config
+    .languages
+    .aliases
+    .insert("RustLang".to_owned(), "rust".to_owned());
+  let mut handler = Handler::new(&config).unwrap();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  // names are normalized, and aliases resolve to the language they stand for, case-insensitively
+  for (timestamp, lang) in ["Rust", " rust ", "rustlang", "RUSTLANG"]
+    .into_iter()
+    .enumerate()
+  {
+    let req = Request::highlight("client0", "/tmp/alias", lang, timestamp as u64);
+    let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+    assert!(
+      matches!(&resp, Response::Highlights { ranges, .. } if !ranges.is_empty()),
+      "{lang}: {resp:?}"
+    );
+  }
+
+  // the buffer is tracked under the name of the language, so its tree is reused whatever name it is asked with
+  let metrics = &handler.parse_metrics()["rust"];
+  assert_eq!((metrics.parses, metrics.cache_hits), (1, 3));
+
+  // names close to known ones are only suggested
+  let req = Request::highlight("client0", "/tmp/alias", "rsut", 4);
+  let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert!(
+    matches!(&resp, Response::StatusChanged { status } if status.contains("did you mean rust?")),
+    "{resp:?}"
+  );
+}
+
+#[test]
+fn highlight_scratch_buffer() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  // scratch buffers have no file, only a name; their content is all we need
+  let req = Request::highlight("client0", "*scratch*", "rust", 1);
+  let resp = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert!(
+    matches!(&resp, Response::Highlights { ranges, activated: true, .. } if !ranges.is_empty()),
+    "{resp:?}"
+  );
+
+  // the tree is cached for the buffer of the session, like for any other buffer
+  let req = Request::highlight("client0", "*scratch*", "rust", 2);
+  handler.handle_request("kts-test", &req, buf).unwrap();
+  let metrics = &handler.parse_metrics()["rust"];
+  assert_eq!((metrics.parses, metrics.cache_hits), (1, 1));
+
+  // but not shared with a scratch buffer of the same name in another session
+  handler.handle_request("kts-other", &req, buf).unwrap();
+  assert_eq!(handler.parse_metrics()["rust"].parses, 2);
+}
+
+#[test]
+fn highlight_status() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let activated = |resp: Response| match resp {
+    Response::Highlights { activated, .. } => activated,
+    resp => panic!("expected highlights, got {resp:?}"),
+  };
+  let highlight = |handler: &mut Handler, req: Request| {
+    activated(handler.handle_request("kts-test", &req, buf).unwrap())
+  };
+
+  // the status is only reported when it changes…
+  let req = Request::highlight("client0", "/tmp/status.rs", "rust", 1);
+  assert!(highlight(&mut handler, req.clone()));
+  assert!(!highlight(&mut handler, req.clone()));
+
+  // … which it does when highlighting is forced, the buffer opened again, or its session gone
+  assert!(highlight(&mut handler, req.clone().with_force()));
+  let enable = Request::TryEnableHighlight {
+    lang: "rust".to_owned(),
+    client: "client0".to_owned(),
+    buffer: Some("/tmp/status.rs".to_owned()),
+  };
+  handler.handle_request("kts-test", &enable, "").unwrap();
+  assert!(highlight(&mut handler, req.clone()));
+
+  // reloading does not change what Kakoune shows
+  handler
+    .reload(&Config::load_default_user().unwrap())
+    .unwrap();
+  assert!(!highlight(&mut handler, req.clone()));
+  handler.drop_session("kts-test");
+  assert!(highlight(&mut handler, req));
+
+  // buffers highlighted in a batch get their status too
+  let item = |buffer: &str| HighlightItem::new(buffer, "rust", 1);
+  let req = Request::highlight_batch("client0", [item("/tmp/status.rs"), item("/tmp/batch.rs")]);
+  let buf = format!("{buf}{delim}{buf}{delim}", delim = BATCH_DELIMITER);
+  let Response::HighlightBatch { highlights, .. } =
+    handler.handle_request("kts-test", &req, &buf).unwrap()
+  else {
+    panic!("expected batch highlights");
+  };
+  let activated: Vec<_> = highlights.iter().map(|hl| hl.activated).collect();
+  assert_eq!(activated, [false, true]);
+}
+
+/// Pathless buffers are only known by their name, which is all the tree cache and version tracking rely on.
+#[test]
+fn scratch_buffers_eviction_and_versions() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config.server.max_trees = Some(1);
+  let mut handler = Handler::new(&config).unwrap();
+  let buf = include_str!("fixtures/highlight.rs");
+  let mut highlight = |buffer: &str, timestamp| {
+    let req = Request::highlight("client0", buffer, "rust", timestamp);
+    handler.handle_request("kts-test", &req, buf).unwrap()
+  };
+
+  assert!(matches!(
+    highlight("*scratch*", 3),
+    Response::Highlights { .. }
+  ));
+  assert_eq!(
+    highlight("*scratch*", 2),
+    Response::StaleHighlights {
+      timestamp: 2,
+      current: 3,
+    }
+  );
+
+  // the tree of *scratch* is evicted in favor of the one of *debug*, forgetting its timestamp
+  assert!(matches!(
+    highlight("*debug*", 1),
+    Response::Highlights { .. }
+  ));
+  assert!(matches!(
+    highlight("*scratch*", 2),
+    Response::Highlights { timestamp: 2, .. }
+  ));
+
+  let metrics = &handler.parse_metrics()["rust"];
+  assert_eq!((metrics.parses, metrics.cache_hits), (3, 0));
+}
+
+#[test]
+fn highlight_progressively() {
+  let mut handler = handler_with_injections();
+  let buf = include_str!("fixtures/highlight.rs");
+  let req = Request::highlight("client0", "/tmp/progressive.rs", "rust", 1);
+
+  let mut emitted = Vec::new();
+  let added = handler
+    .handle_request_progressive("kts-test", &req, buf, &mut |resp| emitted.push(resp))
+    .unwrap();
+
+  // the buffer’s own highlights come first, once, then the ones with injections
+  let [Response::Highlights {
+    ranges: base,
+    activated: true,
+    ..
+  }] = emitted.as_slice()
+  else {
+    panic!("expected base highlights first, got {emitted:?}");
+  };
+  let Response::Highlights { ranges: full, .. } =
+    handler.handle_request("kts-test", &req, buf).unwrap()
+  else {
+    panic!("expected highlights");
+  };
+
+  // the injected highlights of the println! arguments override some base ones; adding them would leave Kakoune with
+  // overlapping ranges, so all the ranges are replaced, as if computed in one go
+  assert!(base.iter().any(|range| !full.contains(range)));
+  assert_eq!(
+    added,
+    Response::Highlights {
+      timestamp: 1,
+      ranges: full,
+      activated: false,
+    }
+  );
+}
+
+#[test]
+fn injections() {
+  let mut with_injections = handler_with_injections();
+  let buf = "fn main() {\n  println!(\"{}\", 1);\n}\n";
+  let req = Request::injections("client0", "/tmp/injections.rs", "rust");
+
+  // the arguments of the macro invocation are injected with Rust
+  let resp = with_injections
+    .handle_request("kts-test", &req, buf)
+    .unwrap();
+  assert_eq!(
+    resp,
+    Response::Injections {
+      injections: vec![Injection {
+        lang: "rust".to_owned(),
+        resolved: Some("rust".to_owned()),
+        bytes: (22, 31),
+        start: Pos { line: 2, col: 11 },
+        end: Pos { line: 2, col: 19 },
+      }]
+    }
+  );
+
+  let kak_cmd = resp.to_kak_cmd(Some("client0")).unwrap();
+  assert!(kak_cmd.contains("*tree-sitter-injections*"));
+  assert!(kak_cmd.contains("2.11,2.19 (bytes 22..31) rust"));
+
+  // without injections query, nothing is injected
+  let resp = handler().handle_request("kts-test", &req, buf).unwrap();
+  assert_eq!(
+    resp,
+    Response::Injections {
+      injections: Vec::new()
+    }
+  );
+}
+
+#[test]
+fn buffer_too_large() {
+  setup();
+  let mut config = Config::load_default_user().unwrap();
+  config.highlight.max_buffer_bytes = 32;
+  let mut handler = Handler::new(&config).unwrap();
+  let buf = include_str!("fixtures/highlight.rs");
+  let too_large = Response::BufferTooLarge {
+    size: buf.len() as u64,
+    max_size: 32,
+  };
+
+  // files are checked before their content is sent
+  let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("buffer-too-large");
+  fs::create_dir_all(&dir).unwrap();
+  let (large, small) = (dir.join("large.rs"), dir.join("small.rs"));
+  fs::write(&large, buf).unwrap();
+  fs::write(&small, "fn main() {}\n").unwrap();
+
+  let try_enable = |path: &Path| Request::TryEnableHighlight {
+    lang: "rust".to_owned(),
+    client: "client0".to_owned(),
+    buffer: Some(path.display().to_string()),
+  };
+  let resp = handler.handle_request("kts-test", &try_enable(&large), "");
+  assert_eq!(resp.unwrap(), too_large);
+  let resp = handler.handle_request("kts-test", &try_enable(&small), "");
+  assert!(
+    matches!(
+      resp,
+      Ok(Response::FiletypeSupported {
+        supported: true,
+        ..
+      })
+    ),
+    "{resp:?}"
+  );
+
+  // buffers growing past the limit are not parsed anymore
+  let req = Request::highlight("client0", small.display().to_string(), "rust", 1);
+  handler
+    .handle_request("kts-test", &req, "fn main() {}\n")
+    .unwrap();
+  let resp = handler.handle_request("kts-test", &req, buf);
+  assert_eq!(resp.unwrap(), too_large);
+  assert_eq!(handler.parse_metrics()["rust"].parses, 1);
+}
+
+#[test]
+fn tree_parsed_on_demand() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  // no highlight request came first; the buffer is parsed for this request
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::dump_tree("client0", "/tmp/on-demand.rs", "rust"),
+      buf,
+    )
+    .unwrap();
+  assert!(
+    matches!(&resp, Response::TreeDump { sexp } if sexp.starts_with("(source_file")),
+    "{resp:?}"
+  );
+
+  // and its tree is then reused by the following requests
+  handler
+    .handle_request(
+      "kts-test",
+      &Request::highlight("client0", "/tmp/on-demand.rs", "rust", 1),
+      buf,
+    )
+    .unwrap();
+  let metrics = &handler.parse_metrics()["rust"];
+  assert_eq!((metrics.parses, metrics.cache_hits), (1, 1));
+}
+
+#[test]
+fn forced_highlight() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let req = Request::highlight("client0", "/tmp/forced.rs", "rust", 1);
+
+  handler.handle_request("kts-test", &req, buf).unwrap();
+  handler.handle_request("kts-test", &req, buf).unwrap();
+  let metrics = &handler.parse_metrics()["rust"];
+  let (parses, cache_hits) = (metrics.parses, metrics.cache_hits);
+
+  // the buffer did not change, but its tree is not reused
+  let resp = handler
+    .handle_request("kts-test", &req.with_force(), buf)
+    .unwrap();
+  assert!(matches!(resp, Response::Highlights { .. }), "{resp:?}");
+  let metrics = &handler.parse_metrics()["rust"];
+  assert_eq!(
+    (metrics.parses, metrics.cache_hits),
+    (parses + 1, cache_hits)
+  );
+}
+
+#[test]
+fn unchanged_highlight() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+
+  let ranges = |resp| match resp {
+    Response::Highlights {
+      timestamp, ranges, ..
+    } => (timestamp, ranges),
+    resp => panic!("unexpected response: {resp:?}"),
+  };
+  let highlight = |handler: &mut Handler, timestamp, buf| {
+    let req = Request::highlight("client0", "/tmp/unchanged.rs", "rust", timestamp);
+    ranges(handler.handle_request("kts-test", &req, buf).unwrap())
+  };
+
+  // highlights of an unchanged buffer are reused, but still sent for the new timestamp
+  let (_, first) = highlight(&mut handler, 1, buf);
+  let (timestamp, second) = highlight(&mut handler, 2, buf);
+  assert_eq!(timestamp, 2);
+  assert_eq!(first, second);
+
+  let (_, changed) = highlight(&mut handler, 3, "fn main() {}\n");
+  assert_ne!(first, changed);
+}
+
+#[test]
+fn language_changed() {
+  setup();
+
+  // another language sharing the grammar of Rust, as its tree alone cannot tell them apart
+  let mut config = Config::load_default_user().unwrap();
+  let mut rusty = config.languages.language["rust"].clone();
+  rusty.grammar.source = Source::local(LanguagesConfig::get_grammar_path(&rusty, "rust").unwrap());
+  rusty.grammar.symbol = Some("tree_sitter_rust".to_owned());
+  rusty.queries.source = Some(Source::local(
+    LanguagesConfig::get_queries_dir(&rusty, "rust").unwrap(),
+  ));
+  config.languages.language.insert("rusty".to_owned(), rusty);
+  let mut handler = Handler::new(&config).unwrap();
+
+  let buf = include_str!("fixtures/highlight.rs");
+  let mut highlight = |lang| {
+    let req = Request::highlight("client0", "/tmp/language-changed", lang, 1);
+    handler.handle_request("kts-test", &req, buf).unwrap();
+    handler
+      .parse_metrics()
+      .get(lang)
+      .map(|metrics| (metrics.parses, metrics.cache_hits))
+  };
+
+  assert_eq!(highlight("rust"), Some((1, 0)));
+  assert_eq!(highlight("rust"), Some((1, 1)));
+
+  // the tree of the previous language is not reused, even though the buffer did not change
+  assert_eq!(highlight("rusty"), Some((1, 0)));
+
+  // nor is it kept once switching to an unsupported language
+  assert_eq!(highlight("python"), None);
+  assert_eq!(highlight("rust"), Some((2, 1)));
+}
+
+#[test]
+fn reload_updated_grammar() {
+  setup();
+
+  let grammar = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handler-reload/rust.so");
+  fs::create_dir_all(grammar.parent().unwrap()).unwrap();
+  fs::copy(common::rust_grammar(), &grammar).unwrap();
+
+  let mut config = Config::load_default_user().unwrap();
+  if let Some(rust) = config.languages.language.get_mut("rust") {
+    rust.grammar.source = Source::local(&grammar);
+  }
+  let mut handler = Handler::new(&config).unwrap();
+
+  let buf = include_str!("fixtures/highlight.rs");
+  let req = Request::highlight("client0", "/tmp/reload.rs", "rust", 1);
+  let Response::Highlights {
+    timestamp, ranges, ..
+  } = handler.handle_request("kts-test", &req, buf).unwrap()
+  else {
+    panic!("the buffer should be highlighted");
+  };
+
+  // replace the grammar, as a linker does
+  fs::remove_file(&grammar).unwrap();
+  fs::copy(common::rust_grammar(), &grammar).unwrap();
+  handler.reload(&config).unwrap();
+  assert!(handler.parse_metrics().is_empty());
+
+  // the buffer is still highlighted, so its status is not reported again
+  let after = handler.handle_request("kts-test", &req, buf).unwrap();
+  assert_eq!(
+    after,
+    Response::Highlights {
+      timestamp,
+      ranges,
+      activated: false,
+    }
+  );
+}
+
+#[test]
+fn toggle_comment() {
+  let mut handler = handler();
+
+  // the comment tokens of Rust come from the default configuration
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::toggle_comment("client0", "/tmp/comment.rs", "rust", "2.1,2.1"),
+      include_str!("fixtures/highlight.rs"),
+    )
+    .unwrap();
+
+  assert_eq!(
+    resp,
+    Response::Edits {
+      edits: vec![TextEdit::Insert {
+        pos: Pos { line: 2, col: 1 },
+        text: "// ".to_owned(),
+      }],
+    }
+  );
+}
+
+#[test]
+fn comment_tokens() {
+  setup();
+
+  // without configured tokens, they come from the grammar
+  let mut config = Config::load_default_user().unwrap();
+  if let Some(rust) = config.languages.language.get_mut("rust") {
+    rust.comment = CommentConfig::default();
+  }
+  let mut handler = Handler::new(&config).unwrap();
+
+  let resp = handler
+    .handle_request("kts-test", &Request::comment_tokens("client0", "rust"), "")
+    .unwrap();
+  assert_eq!(
+    resp.to_kak_cmd(Some("client0")).unwrap(),
+    "eval -no-hooks -try-client client0 'try ''set-option buffer comment_line ''''//''''''\n\
+     try ''set-option buffer comment_block_begin ''''/*''''''\n\
+     try ''set-option buffer comment_block_end ''''*/'''''''"
+  );
+}
+
+#[test]
+fn select_matching() {
+  let mut handler = handler();
+  let buf = include_str!("fixtures/highlight.rs");
+  let query = "(parameter pattern: (identifier) @param) (parameter type: (_) @type)";
+
+  // the captures of both patterns are selected in buffer order
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::select_matching("client0", "/tmp/select.rs", "rust", query),
+      buf,
+    )
+    .unwrap();
+  assert_eq!(
+    resp.to_kak_cmd(None).unwrap(),
+    "eval -no-hooks 'select 2.8,2.8 2.11,2.13 2.16,2.16 2.19,2.21'"
+  );
+
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::select_matching(
+        "client0",
+        "/tmp/select.rs",
+        "rust",
+        "(closure_expression) @closure",
+      ),
+      buf,
+    )
+    .unwrap();
+  assert_eq!(resp, Response::status("no node matches the query"));
+
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::select_matching("client0", "/tmp/select.rs", "rust", "(identifier @id"),
+      buf,
+    )
+    .unwrap();
+  assert!(
+    matches!(&resp, Response::StatusChanged { status } if status.starts_with("invalid query")),
+    "{resp:?}"
+  );
+}
+
+#[test]
+fn select_matching_capped() {
+  setup();
+
+  let mut config = Config::load_default_user().unwrap();
+  config.server.max_selections = 2;
+  let mut handler = Handler::new(&config).unwrap();
+
+  let resp = handler
+    .handle_request(
+      "kts-test",
+      &Request::select_matching("client0", "/tmp/capped.rs", "rust", "(identifier) @id"),
+      include_str!("fixtures/highlight.rs"),
+    )
+    .unwrap();
+  assert_eq!(
+    resp.to_kak_cmd(None).unwrap(),
+    "eval -no-hooks 'select 2.4,2.6 2.8,2.8'"
+  );
+}
@@ -150,14 +150,14 @@ impl Display for ReportIcon {
 fn runtime_dir() -> Result<PathBuf, AppError> {
   let dir = dirs::runtime_dir()
     .or_else(|| env::var("TMPDIR").map(PathBuf::from).ok())
-    .ok_or_else(|| AppError::NoRuntimeDir)?
+    .ok_or(AppError::NoRuntimeDir)?
     .join("ktsctl");
   Ok(dir)
 }
 
 fn kak_tree_sitter_data_dir() -> Result<PathBuf, AppError> {
   let dir = dirs::data_dir()
-    .ok_or_else(|| AppError::NoDataDir)?
+    .ok_or(AppError::NoDataDir)?
     .join("kak-tree-sitter");
   Ok(dir)
 }
@@ -580,27 +580,34 @@ fn display_queries_info(path: &Path, lang: &str) {
 
     let mut scm_count = 0;
     let mut scm_expected_count = 0;
-    let mut prefix_mark = |s: &str, desc: &str| {
+    let mut prefix_mark = |s: &str, desc: &str, features: &str| {
       scm_expected_count += 1;
 
-      if scm_files.contains(s) {
+      let sign = if scm_files.contains(s) {
         scm_count += 1;
-        format!(
-          "     {sign} {desc}",
-          sign = check_sign(),
-          desc = desc.blue()
-        )
+        check_sign().to_string()
       } else {
-        format!("     {sign} {desc}", sign = no_sign(), desc = desc.blue())
-      }
+        no_sign().to_string()
+      };
+
+      format!(
+        "     {sign} {desc}{delim} {features}",
+        desc = desc.blue(),
+        delim = delim(":"),
+      )
     };
 
+    // the grammar alone is enough to dump trees and run queries; each query file enables additional features
     let queries = [
-      prefix_mark("highlights.scm", "highlights"),
-      prefix_mark("indents.scm", "indents"),
-      prefix_mark("injections.scm", "injections"),
-      prefix_mark("locals.scm", "locals"),
-      prefix_mark("textobjects.scm", "text-objects"),
+      prefix_mark("highlights.scm", "highlights", "highlighting, prose ranges"),
+      prefix_mark("indents.scm", "indents", "indentation"),
+      prefix_mark(
+        "injections.scm",
+        "injections",
+        "highlighting of embedded languages",
+      ),
+      prefix_mark("locals.scm", "locals", "highlighting of local variables"),
+      prefix_mark("textobjects.scm", "text-objects", "text-objects"),
     ];
 
     if scm_count == scm_expected_count {
@@ -655,7 +662,7 @@ fn fetch_via_git(
     return Ok(false);
   }
 
-  fs::create_dir_all(&fetch_path).map_err(|err| AppError::CannotCreateDir {
+  fs::create_dir_all(fetch_path).map_err(|err| AppError::CannotCreateDir {
     dir: fetch_path.to_owned(),
     err,
   })?;
@@ -665,10 +672,7 @@ fn fetch_via_git(
       "clone",
       url,
       "-n",
-      fetch_path
-        .as_os_str()
-        .to_str()
-        .ok_or_else(|| AppError::BadPath)?,
+      fetch_path.as_os_str().to_str().ok_or(AppError::BadPath)?,
     ]
   } else {
     vec![
@@ -676,10 +680,7 @@ fn fetch_via_git(
       url,
       "--depth",
       "1",
-      fetch_path
-        .as_os_str()
-        .to_str()
-        .ok_or_else(|| AppError::BadPath)?,
+      fetch_path.as_os_str().to_str().ok_or(AppError::BadPath)?,
     ]
   };
 
@@ -833,7 +834,7 @@ fn copy_dir(from: &Path, to: &Path) -> Result<(), io::Error> {
     let new_to = to.join(entry.file_name());
 
     if entry.file_type()?.is_file() {
-      fs::copy(&entry.path(), &new_to)?;
+      fs::copy(entry.path(), &new_to)?;
     }
   }
 